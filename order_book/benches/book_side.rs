@@ -1,4 +1,4 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
 use itertools::izip;
 
 use order_book::book_side::BookSide;
@@ -22,6 +22,28 @@ pub fn criterion_benchmark(c: &mut Criterion) {
             })
         })
     });
+
+    // Repeatedly delete and restore the best level of a deep side, which
+    // forces a search for the next best level, and read the top levels.
+    let mut deep_book = BookSide::new(true);
+    for price in 0..1000i64 {
+        deep_book.add_qty(price, 10i64);
+    }
+    c.bench_function("book_side_deep_best_level_churn", |b| {
+        b.iter(|| {
+            deep_book.delete_level(999).expect("Best level not found");
+            deep_book.add_qty(999, 10);
+            black_box(deep_book.top_n_levels(10));
+        })
+    });
+
+    let mut group = c.benchmark_group("book_side_top_n_levels");
+    for n in [10, 25, 50] {
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, &n| {
+            b.iter(|| black_box(deep_book.top_n_levels(n)))
+        });
+    }
+    group.finish();
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,165 @@
+use std::fs;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use order_book::book_side::BookSide;
+use order_book::order_book::OrderBook;
+
+/// The numbers of top levels read after each update, as the top-N
+/// expressions do.
+const DEPTHS: [usize; 4] = [1, 5, 10, 20];
+
+/// `(is_bid, price, qty)`, where a negative qty deletes.
+type Update = (bool, i64, i64);
+
+fn apply(book: &mut OrderBook<i64, i64>, (is_bid, price, qty): Update) {
+    if qty < 0 {
+        book.delete_qty(is_bid, price, -qty);
+    } else {
+        book.add_qty(is_bid, price, qty);
+    }
+}
+
+/// A reproducible stream of `n` adds and deletes within 50 ticks of a mid of
+/// 10_000, bids below it and asks above. Deletes never exceed the qty
+/// resting at their level, so the stream replays cleanly.
+fn synthetic_updates(n: usize) -> Vec<Update> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut random = move |bound: u64| {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state % bound
+    };
+    let mut book = OrderBook::new();
+    let mut updates = Vec::with_capacity(n);
+    while updates.len() < n {
+        let is_bid = random(2) == 0;
+        let offset = 1 + random(50) as i64;
+        let price = if is_bid {
+            10_000 - offset
+        } else {
+            10_000 + offset
+        };
+        let resting = book
+            .get_book_side(is_bid)
+            .get_level(price)
+            .map_or(0, |level| level.qty);
+        let qty = if resting > 0 && random(2) == 0 {
+            -(1 + random(resting as u64) as i64)
+        } else {
+            1 + random(100) as i64
+        };
+        apply(&mut book, (is_bid, price, qty));
+        updates.push((is_bid, price, qty));
+    }
+    updates
+}
+
+/// The recorded NinjaTrader depth updates that the `ninja` bench replays.
+fn recorded_updates() -> Vec<Update> {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/ninja_order_book.csv");
+    let csv = fs::read_to_string(path).expect("Failed to read the recorded updates");
+    csv.lines()
+        .skip(1)
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            let parse = |field: &str| field.parse().expect("Invalid number in recorded updates");
+            (fields[0] == "true", parse(fields[1]), parse(fields[3]))
+        })
+        .collect()
+}
+
+fn bench_hot_paths(c: &mut Criterion) {
+    let mut book = OrderBook::new();
+    for update in synthetic_updates(10_000) {
+        apply(&mut book, update);
+    }
+    let (bid, _, ask, _) = book.best_bid_and_ask().expect("Synthetic book is empty");
+
+    let mut group = c.benchmark_group("order_book_hot_paths");
+    group.bench_function("add_delete_best_level", |b| {
+        b.iter(|| {
+            book.add_qty(true, bid, black_box(5));
+            book.delete_qty(true, bid, black_box(5));
+        })
+    });
+    group.bench_function("add_delete_new_level", |b| {
+        b.iter(|| {
+            book.add_qty(true, black_box(bid + 1), 5);
+            book.delete_qty(true, black_box(bid + 1), 5);
+        })
+    });
+    group.bench_function("modify_across_levels", |b| {
+        b.iter(|| {
+            book.modify_qty(false, ask, black_box(1), ask + 1, 1);
+            book.modify_qty(false, ask + 1, black_box(1), ask, 1);
+        })
+    });
+    group.finish();
+}
+
+fn bench_bounded_side(c: &mut Criterion) {
+    // Each add is a new best level, so a full side evicts its worst level.
+    let mut group = c.benchmark_group("bounded_side_insert");
+    for n in DEPTHS {
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, &n| {
+            b.iter(|| {
+                let mut side = BookSide::with_max_levels(true, n);
+                for price in 0..100i64 {
+                    side.add_qty(price, 1i64);
+                }
+                black_box(side)
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_nth_best_level(c: &mut Criterion) {
+    let mut side = BookSide::new(false);
+    for price in 0..1000i64 {
+        side.add_qty(price, 10i64);
+    }
+    let mut group = c.benchmark_group("nth_best_level");
+    for n in DEPTHS {
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, &n| {
+            b.iter(|| black_box(side.top_n_levels(n).last().copied()))
+        });
+    }
+    group.finish();
+}
+
+/// Replay `updates` into an empty book, reading the best `n` levels of each
+/// side after every update.
+fn bench_replay(c: &mut Criterion, name: &str, updates: &[Update]) {
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(updates.len() as u64));
+    for n in DEPTHS {
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, &n| {
+            b.iter(|| {
+                let mut book = OrderBook::new();
+                for &update in updates {
+                    apply(&mut book, update);
+                    black_box(book.view(n));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_replays(c: &mut Criterion) {
+    bench_replay(c, "replay_synthetic", &synthetic_updates(100_000));
+    bench_replay(c, "replay_recorded", &recorded_updates());
+}
+
+criterion_group!(
+    benches,
+    bench_hot_paths,
+    bench_bounded_side,
+    bench_nth_best_level,
+    bench_replays
+);
+criterion_main!(benches);
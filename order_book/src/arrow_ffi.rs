@@ -0,0 +1,610 @@
+//! Export of book snapshots and BBO events through the Arrow C data and C
+//! stream interfaces, so that consumers in other languages, e.g. Arrow C++
+//! or Java, can import them without going through Polars. See
+//! <https://arrow.apache.org/docs/format/CDataInterface.html> and
+//! <https://arrow.apache.org/docs/format/CStreamInterface.html>.
+//!
+//! A `Batch` is exported as a struct array whose children are its columns,
+//! matching how Arrow libraries import record batches.
+
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::ptr;
+
+use crate::order_book::{BookSnapshot, OrderBook};
+
+/// The field is nullable, from the C data interface.
+const ARROW_FLAG_NULLABLE: i64 = 2;
+/// `EINVAL`, returned by `get_next` for a batch not matching the schema.
+const EINVAL: c_int = 22;
+
+/// `struct ArrowSchema` of the C data interface.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ArrowSchema {
+    pub format: *const c_char,
+    pub name: *const c_char,
+    pub metadata: *const c_char,
+    pub flags: i64,
+    pub n_children: i64,
+    pub children: *mut *mut ArrowSchema,
+    pub dictionary: *mut ArrowSchema,
+    pub release: Option<unsafe extern "C" fn(*mut ArrowSchema)>,
+    pub private_data: *mut c_void,
+}
+
+/// `struct ArrowArray` of the C data interface.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ArrowArray {
+    pub length: i64,
+    pub null_count: i64,
+    pub offset: i64,
+    pub n_buffers: i64,
+    pub n_children: i64,
+    pub buffers: *mut *const c_void,
+    pub children: *mut *mut ArrowArray,
+    pub dictionary: *mut ArrowArray,
+    pub release: Option<unsafe extern "C" fn(*mut ArrowArray)>,
+    pub private_data: *mut c_void,
+}
+
+/// `struct ArrowArrayStream` of the C stream interface.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ArrowArrayStream {
+    pub get_schema: Option<unsafe extern "C" fn(*mut ArrowArrayStream, *mut ArrowSchema) -> c_int>,
+    pub get_next: Option<unsafe extern "C" fn(*mut ArrowArrayStream, *mut ArrowArray) -> c_int>,
+    pub get_last_error: Option<unsafe extern "C" fn(*mut ArrowArrayStream) -> *const c_char>,
+    pub release: Option<unsafe extern "C" fn(*mut ArrowArrayStream)>,
+    pub private_data: *mut c_void,
+}
+
+impl ArrowSchema {
+    /// A released schema, e.g. for a consumer to pass to `get_schema`.
+    pub fn empty() -> Self {
+        ArrowSchema {
+            format: ptr::null(),
+            name: ptr::null(),
+            metadata: ptr::null(),
+            flags: 0,
+            n_children: 0,
+            children: ptr::null_mut(),
+            dictionary: ptr::null_mut(),
+            release: None,
+            private_data: ptr::null_mut(),
+        }
+    }
+}
+
+impl ArrowArray {
+    /// A released array, e.g. for a consumer to pass to `get_next`.
+    pub fn empty() -> Self {
+        ArrowArray {
+            length: 0,
+            null_count: 0,
+            offset: 0,
+            n_buffers: 0,
+            n_children: 0,
+            buffers: ptr::null_mut(),
+            children: ptr::null_mut(),
+            dictionary: ptr::null_mut(),
+            release: None,
+            private_data: ptr::null_mut(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    Boolean,
+    Int64,
+}
+
+impl DataType {
+    fn format(self) -> &'static str {
+        match self {
+            DataType::Boolean => "b",
+            DataType::Int64 => "l",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub data_type: DataType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColumnData {
+    Boolean(Vec<Option<bool>>),
+    Int64(Vec<Option<i64>>),
+}
+
+impl ColumnData {
+    fn data_type(&self) -> DataType {
+        match self {
+            ColumnData::Boolean(_) => DataType::Boolean,
+            ColumnData::Int64(_) => DataType::Int64,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            ColumnData::Boolean(values) => values.len(),
+            ColumnData::Int64(values) => values.len(),
+        }
+    }
+}
+
+/// Equal-length named columns, exported as one struct array.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Batch {
+    columns: Vec<(String, ColumnData)>,
+}
+
+impl Batch {
+    /// # Panics
+    /// If the columns differ in length.
+    pub fn new(columns: Vec<(String, ColumnData)>) -> Self {
+        let mut lengths = columns.iter().map(|(_, data)| data.len());
+        if let Some(length) = lengths.next() {
+            assert!(
+                lengths.all(|other| other == length),
+                "Batch columns must all have the same length"
+            );
+        }
+        Batch { columns }
+    }
+
+    /// The `is_bid`, `price` and `qty` columns of a book snapshot.
+    pub fn from_snapshot(snapshot: &BookSnapshot<i64, i64>) -> Self {
+        Batch::new(vec![
+            (
+                "is_bid".to_string(),
+                ColumnData::Boolean(snapshot.is_bid.iter().copied().map(Some).collect()),
+            ),
+            (
+                "price".to_string(),
+                ColumnData::Int64(snapshot.price.iter().copied().map(Some).collect()),
+            ),
+            (
+                "qty".to_string(),
+                ColumnData::Int64(snapshot.qty.iter().copied().map(Some).collect()),
+            ),
+        ])
+    }
+
+    pub fn len(&self) -> usize {
+        self.columns.first().map_or(0, |(_, data)| data.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn fields(&self) -> Vec<Field> {
+        self.columns
+            .iter()
+            .map(|(name, data)| Field {
+                name: name.clone(),
+                data_type: data.data_type(),
+            })
+            .collect()
+    }
+
+    /// Export the batch's schema, to be released by the consumer.
+    pub fn export_schema(&self) -> ArrowSchema {
+        export_schema(&self.fields())
+    }
+
+    /// Export the batch as a struct array, to be released by the consumer.
+    pub fn export_array(self) -> ArrowArray {
+        let length = self.len();
+        let children = self
+            .columns
+            .into_iter()
+            .map(|(_, data)| export_column(data))
+            .collect();
+        new_array(length, 0, vec![None], children)
+    }
+}
+
+/// Accumulates the best bid and ask of a book after each update, like the
+/// `calculate_bbo` expression, into a `Batch` with nullable `best_bid`,
+/// `best_bid_qty`, `best_ask` and `best_ask_qty` columns.
+#[derive(Debug, Clone, Default)]
+pub struct BboBatchBuilder {
+    best_bid: Vec<Option<i64>>,
+    best_bid_qty: Vec<Option<i64>>,
+    best_ask: Vec<Option<i64>>,
+    best_ask_qty: Vec<Option<i64>>,
+}
+
+impl BboBatchBuilder {
+    pub fn append(&mut self, book: &OrderBook<i64, i64>) {
+        let bids = book.get_book_side(true);
+        let asks = book.get_book_side(false);
+        self.best_bid.push(bids.best_price);
+        self.best_bid_qty.push(bids.best_price_qty);
+        self.best_ask.push(asks.best_price);
+        self.best_ask_qty.push(asks.best_price_qty);
+    }
+
+    pub fn len(&self) -> usize {
+        self.best_bid.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.best_bid.is_empty()
+    }
+
+    /// The rows appended so far, leaving the builder empty for the next
+    /// batch.
+    pub fn finish(&mut self) -> Batch {
+        let builder = std::mem::take(self);
+        Batch::new(vec![
+            ("best_bid".to_string(), ColumnData::Int64(builder.best_bid)),
+            (
+                "best_bid_qty".to_string(),
+                ColumnData::Int64(builder.best_bid_qty),
+            ),
+            ("best_ask".to_string(), ColumnData::Int64(builder.best_ask)),
+            (
+                "best_ask_qty".to_string(),
+                ColumnData::Int64(builder.best_ask_qty),
+            ),
+        ])
+    }
+}
+
+/// Export `batches` as a C stream whose schema is `fields`, e.g. BBO events
+/// in batches from a `BboBatchBuilder` as a replay runs. The stream pulls
+/// the next batch from the iterator each time the consumer asks for one, and
+/// fails with `EINVAL` on a batch whose fields differ from `fields`.
+pub fn export_stream(
+    fields: Vec<Field>,
+    batches: impl Iterator<Item = Batch> + Send + 'static,
+) -> ArrowArrayStream {
+    let private = Box::new(StreamPrivate {
+        fields,
+        batches: Box::new(batches),
+        last_error: None,
+    });
+    ArrowArrayStream {
+        get_schema: Some(stream_get_schema),
+        get_next: Some(stream_get_next),
+        get_last_error: Some(stream_get_last_error),
+        release: Some(release_stream),
+        private_data: Box::into_raw(private) as *mut c_void,
+    }
+}
+
+struct SchemaPrivate {
+    format: CString,
+    name: CString,
+    children: Box<[*mut ArrowSchema]>,
+}
+
+fn new_schema(format: &str, name: &str, flags: i64, children: Vec<ArrowSchema>) -> ArrowSchema {
+    let children: Box<[*mut ArrowSchema]> = children
+        .into_iter()
+        .map(|child| Box::into_raw(Box::new(child)))
+        .collect();
+    let mut private = Box::new(SchemaPrivate {
+        format: CString::new(format).expect("Arrow format contains a nul"),
+        name: CString::new(name).expect("Arrow field name contains a nul"),
+        children,
+    });
+    ArrowSchema {
+        format: private.format.as_ptr(),
+        name: private.name.as_ptr(),
+        metadata: ptr::null(),
+        flags,
+        n_children: private.children.len() as i64,
+        children: private.children.as_mut_ptr(),
+        dictionary: ptr::null_mut(),
+        release: Some(release_schema),
+        private_data: Box::into_raw(private) as *mut c_void,
+    }
+}
+
+fn export_schema(fields: &[Field]) -> ArrowSchema {
+    let children = fields
+        .iter()
+        .map(|field| {
+            new_schema(
+                field.data_type.format(),
+                &field.name,
+                ARROW_FLAG_NULLABLE,
+                Vec::new(),
+            )
+        })
+        .collect();
+    new_schema("+s", "", 0, children)
+}
+
+unsafe extern "C" fn release_schema(schema: *mut ArrowSchema) {
+    let Some(schema) = schema.as_mut() else {
+        return;
+    };
+    let private = Box::from_raw(schema.private_data as *mut SchemaPrivate);
+    for &child in private.children.iter() {
+        // Children moved out by the consumer are already released.
+        if let Some(release) = (*child).release {
+            release(child);
+        }
+        drop(Box::from_raw(child));
+    }
+    schema.release = None;
+}
+
+/// Buffers are held as 64-bit words so that they are 8-byte aligned.
+struct ArrayPrivate {
+    _buffers: Vec<Option<Box<[u64]>>>,
+    buffer_ptrs: Box<[*const c_void]>,
+    children: Box<[*mut ArrowArray]>,
+}
+
+fn new_array(
+    length: usize,
+    null_count: usize,
+    buffers: Vec<Option<Box<[u64]>>>,
+    children: Vec<ArrowArray>,
+) -> ArrowArray {
+    let buffer_ptrs = buffers
+        .iter()
+        .map(|buffer| match buffer {
+            Some(buffer) => buffer.as_ptr() as *const c_void,
+            None => ptr::null(),
+        })
+        .collect();
+    let children = children
+        .into_iter()
+        .map(|child| Box::into_raw(Box::new(child)))
+        .collect();
+    let mut private = Box::new(ArrayPrivate {
+        _buffers: buffers,
+        buffer_ptrs,
+        children,
+    });
+    ArrowArray {
+        length: length as i64,
+        null_count: null_count as i64,
+        offset: 0,
+        n_buffers: private.buffer_ptrs.len() as i64,
+        n_children: private.children.len() as i64,
+        buffers: private.buffer_ptrs.as_mut_ptr(),
+        children: private.children.as_mut_ptr(),
+        dictionary: ptr::null_mut(),
+        release: Some(release_array),
+        private_data: Box::into_raw(private) as *mut c_void,
+    }
+}
+
+unsafe extern "C" fn release_array(array: *mut ArrowArray) {
+    let Some(array) = array.as_mut() else {
+        return;
+    };
+    let private = Box::from_raw(array.private_data as *mut ArrayPrivate);
+    for &child in private.children.iter() {
+        if let Some(release) = (*child).release {
+            release(child);
+        }
+        drop(Box::from_raw(child));
+    }
+    array.release = None;
+}
+
+/// Pack bits least significant first, as Arrow bitmaps are laid out.
+fn bitmap(bits: impl ExactSizeIterator<Item = bool>) -> Box<[u64]> {
+    let mut words = vec![0u64; bits.len().div_ceil(64)];
+    for (i, bit) in bits.enumerate() {
+        words[i / 64] |= u64::from(bit) << (i % 64);
+    }
+    words.into_iter().map(u64::to_le).collect()
+}
+
+fn export_column(data: ColumnData) -> ArrowArray {
+    let length = data.len();
+    let (validity, values) = match data {
+        ColumnData::Boolean(values) => (
+            bitmap(values.iter().map(Option::is_some)),
+            bitmap(values.iter().map(|value| value.unwrap_or(false))),
+        ),
+        ColumnData::Int64(values) => (
+            bitmap(values.iter().map(Option::is_some)),
+            values
+                .iter()
+                .map(|value| value.unwrap_or(0).to_le() as u64)
+                .collect(),
+        ),
+    };
+    let null_count = (0..length)
+        .filter(|&i| u64::from_le(validity[i / 64]) & (1 << (i % 64)) == 0)
+        .count();
+    let validity = (null_count > 0).then_some(validity);
+    new_array(length, null_count, vec![validity, Some(values)], Vec::new())
+}
+
+struct StreamPrivate {
+    fields: Vec<Field>,
+    batches: Box<dyn Iterator<Item = Batch> + Send>,
+    last_error: Option<CString>,
+}
+
+unsafe extern "C" fn stream_get_schema(
+    stream: *mut ArrowArrayStream,
+    out: *mut ArrowSchema,
+) -> c_int {
+    let private = &*((*stream).private_data as *const StreamPrivate);
+    out.write(export_schema(&private.fields));
+    0
+}
+
+unsafe extern "C" fn stream_get_next(stream: *mut ArrowArrayStream, out: *mut ArrowArray) -> c_int {
+    let private = &mut *((*stream).private_data as *mut StreamPrivate);
+    match private.batches.next() {
+        Some(batch) if batch.fields() != private.fields => {
+            let message = format!(
+                "Batch fields {:?} don't match the stream's {:?}",
+                batch.fields(),
+                private.fields
+            );
+            private.last_error = CString::new(message).ok();
+            EINVAL
+        }
+        Some(batch) => {
+            out.write(batch.export_array());
+            0
+        }
+        // A released array marks the end of the stream.
+        None => {
+            out.write(ArrowArray::empty());
+            0
+        }
+    }
+}
+
+unsafe extern "C" fn stream_get_last_error(stream: *mut ArrowArrayStream) -> *const c_char {
+    let private = &*((*stream).private_data as *const StreamPrivate);
+    private
+        .last_error
+        .as_ref()
+        .map_or(ptr::null(), |error| error.as_ptr())
+}
+
+unsafe extern "C" fn release_stream(stream: *mut ArrowArrayStream) {
+    let Some(stream) = stream.as_mut() else {
+        return;
+    };
+    drop(Box::from_raw(stream.private_data as *mut StreamPrivate));
+    stream.release = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+
+    /// Read an exported column back as a consumer would.
+    unsafe fn import_int64(array: &ArrowArray) -> Vec<Option<i64>> {
+        let validity = *array.buffers as *const u8;
+        let values = *array.buffers.add(1) as *const i64;
+        (0..array.length as usize)
+            .map(|i| {
+                let valid = validity.is_null() || *validity.add(i / 8) & (1 << (i % 8)) != 0;
+                valid.then(|| *values.add(i))
+            })
+            .collect()
+    }
+
+    unsafe fn import_bool(array: &ArrowArray) -> Vec<bool> {
+        let values = *array.buffers.add(1) as *const u8;
+        (0..array.length as usize)
+            .map(|i| *values.add(i / 8) & (1 << (i % 8)) != 0)
+            .collect()
+    }
+
+    unsafe fn child_names(schema: &ArrowSchema) -> Vec<(String, String)> {
+        (0..schema.n_children as usize)
+            .map(|i| {
+                let child = &**schema.children.add(i);
+                (
+                    CStr::from_ptr(child.name).to_str().unwrap().to_string(),
+                    CStr::from_ptr(child.format).to_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_export_snapshot() {
+        let mut book = OrderBook::default();
+        book.add_qty(true, 100, 5);
+        book.add_qty(false, 101, 7);
+        book.add_qty(false, 102, 1);
+        let batch = Batch::from_snapshot(&book.to_snapshot());
+
+        let mut schema = batch.export_schema();
+        let mut array = batch.export_array();
+        unsafe {
+            assert_eq!(CStr::from_ptr(schema.format).to_str(), Ok("+s"));
+            assert_eq!(
+                child_names(&schema),
+                [("is_bid", "b"), ("price", "l"), ("qty", "l")]
+                    .map(|(name, format)| (name.to_string(), format.to_string()))
+            );
+            assert_eq!(array.length, 3);
+            assert_eq!(array.n_children, 3);
+            let column = |i: usize| &**array.children.add(i);
+            assert_eq!(import_bool(column(0)), [true, false, false]);
+            assert_eq!(import_int64(column(1)), [Some(100), Some(101), Some(102)]);
+            assert_eq!(import_int64(column(2)), [Some(5), Some(7), Some(1)]);
+            assert!((*column(1).buffers).is_null());
+
+            (schema.release.unwrap())(&mut schema);
+            (array.release.unwrap())(&mut array);
+        }
+        assert!(schema.release.is_none());
+        assert!(array.release.is_none());
+    }
+
+    #[test]
+    fn test_export_bbo_stream() {
+        let mut book = OrderBook::default();
+        let mut builder = BboBatchBuilder::default();
+        let mut batches = Vec::new();
+        for (is_bid, price) in [(true, 100), (false, 101), (true, 99)] {
+            book.add_qty(is_bid, price, 1);
+            builder.append(&book);
+            if builder.len() == 2 {
+                batches.push(builder.finish());
+            }
+        }
+        batches.push(builder.finish());
+        let fields = batches[0].fields();
+        let mut stream = export_stream(fields, batches.into_iter());
+
+        unsafe {
+            let mut schema = ArrowSchema::empty();
+            assert_eq!((stream.get_schema.unwrap())(&mut stream, &mut schema), 0);
+            assert_eq!(child_names(&schema)[0].0, "best_bid");
+            (schema.release.unwrap())(&mut schema);
+
+            let mut best_asks = Vec::new();
+            loop {
+                let mut array = ArrowArray::empty();
+                assert_eq!((stream.get_next.unwrap())(&mut stream, &mut array), 0);
+                let Some(release) = array.release else {
+                    break;
+                };
+                best_asks.extend(import_int64(&**array.children.add(2)));
+                release(&mut array);
+            }
+            assert_eq!(best_asks, [None, Some(101), Some(101)]);
+            assert!((stream.get_last_error.unwrap())(&mut stream).is_null());
+            (stream.release.unwrap())(&mut stream);
+        }
+        assert!(stream.release.is_none());
+    }
+
+    #[test]
+    fn test_stream_rejects_mismatched_batch() {
+        let batch = Batch::new(vec![("qty".to_string(), ColumnData::Int64(vec![Some(1)]))]);
+        let fields = vec![Field {
+            name: "price".to_string(),
+            data_type: DataType::Int64,
+        }];
+        let mut stream = export_stream(fields, std::iter::once(batch));
+        unsafe {
+            let mut array = ArrowArray::empty();
+            assert_eq!((stream.get_next.unwrap())(&mut stream, &mut array), EINVAL);
+            assert!(array.release.is_none());
+            let error = CStr::from_ptr((stream.get_last_error.unwrap())(&mut stream));
+            assert!(error.to_str().unwrap().contains("don't match"));
+            (stream.release.unwrap())(&mut stream);
+        }
+    }
+}
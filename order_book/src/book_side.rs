@@ -1,17 +1,77 @@
-use std::fmt::Debug;
+#[cfg(not(feature = "btree_levels"))]
+use std::collections::BinaryHeap;
+use std::fmt::{Debug, Display};
 use std::hash::Hash;
+use std::ops::RangeBounds;
 
-use hashbrown::HashMap;
-use num::traits::Num;
+#[cfg(not(feature = "btree_levels"))]
+use hashbrown::hash_map::{Entry, HashMap as LevelMap};
+#[cfg(feature = "btree_levels")]
+use itertools::Either;
+use num::traits::{CheckedAdd, Num, Signed, ToPrimitive};
+#[cfg(feature = "btree_levels")]
+use std::collections::btree_map::{BTreeMap as LevelMap, Entry};
 use thiserror::Error;
 
 use super::price_level::PriceLevel;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FoundLevelType {
     New,
     Existing,
 }
 
+/// What `BookSide::add_qty` did. `level` is the level after the add, so its
+/// qty is the level's new total, while `qty_delta` is the qty added by this
+/// call alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AddOutcome<Price, Qty> {
+    pub level: PriceLevel<Price, Qty>,
+    pub qty_delta: Qty,
+    pub found: FoundLevelType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeleteLevelType {
+    Deleted,
+    QtyDecreased,
+    /// The delete exceeded the level's qty and was dropped under
+    /// `OverDeletePolicy::Ignore`.
+    Ignored,
+}
+
+/// What `BookSide::delete_qty` does when asked to delete more qty than a
+/// level holds, as some venues do after executions against hidden size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum OverDeletePolicy {
+    /// Fail with `DeleteError::QtyExceedsAvailable`.
+    #[default]
+    Error,
+    /// Delete the whole level.
+    Clamp,
+    /// Leave the level unchanged.
+    Ignore,
+}
+
+/// Returned by `BookSide::checked_add_qty` when adding to a level would
+/// overflow its qty type, or that of the side's total qty.
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("Qty overflow at price level {price:?}")]
+pub struct QtyOverflowError<Price> {
+    pub price: Price,
+}
+
+/// How many of the best prices `BookSide` caches. See `BookSide::best_prices`.
+const CACHED_BEST_PRICES: usize = 32;
+
 #[derive(Error, Debug, PartialEq, Eq)]
 pub enum LevelError {
     #[error("Level not found")]
@@ -26,12 +86,73 @@ pub enum DeleteError {
     QtyExceedsAvailable,
 }
 
+/// One side of an order book, aggregated by price level.
+///
+/// `max_levels` optionally bounds the number of price levels held. When a
+/// new level arrives at the cap, the worst existing level is evicted to make
+/// room for it; if the new level would itself be the worst, the add is
+/// dropped instead. Later deletes against an evicted or dropped level fail
+/// with `LevelNotFound`, so the cap should sit well beyond the depth of
+/// interest.
+///
+/// `invert_prices` flips which end of the price axis is "best", for
+/// instruments quoted so that a higher number is a worse bid (e.g. yields).
+///
+/// Levels holding less than `min_qty` stay in the book but are ignored by
+/// `best_price`, `get_best_price_level` and `top_n_levels`, so that dust
+/// quotes don't push out meaningful size. A level becomes eligible as soon
+/// as its qty reaches `min_qty` and is demoted when it drops below it.
+/// Sweeps still execute against every level.
+///
+/// `over_delete_policy` sets how deletes of more qty than a level holds are
+/// handled; by default they fail.
+///
+/// Levels are held in a hash map by default, which makes adds and deletes
+/// O(1) but means every ordered traversal scans all levels. The next best
+/// level after the best one is removed is taken from a short cache of the
+/// best prices, which is only rebuilt by a scan once it runs dry, so deep
+/// books don't pay for a scan per delete at the top. The `btree_levels` feature
+/// holds them in a `BTreeMap` instead, making those O(log levels) at the
+/// cost of slower updates away from the top of the book. Compare the two on
+/// a given feed with `cargo bench --bench book_side [--features btree_levels]`.
+///
+/// With `track_level_timestamps` the side also records when each level was
+/// last modified, as of the time given to `set_timestamp`, for level-age and
+/// staleness analytics.
+///
+/// With the `serde` feature a side can be serialised in full, including its
+/// configuration, and restored later.
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(
+        bound(deserialize = "Price: serde::Deserialize<'de> + Eq + Hash + Ord, \
+                               Qty: serde::Deserialize<'de>")
+    )
+)]
 pub struct BookSide<Price, Qty> {
     is_bid: bool,
-    levels: HashMap<Price, PriceLevel<Price, Qty>>,
+    invert_prices: bool,
+    max_levels: Option<usize>,
+    min_qty: Qty,
+    over_delete_policy: OverDeletePolicy,
+    levels: LevelMap<Price, PriceLevel<Price, Qty>>,
+    /// Up to `CACHED_BEST_PRICES` of the best prices, best first, whatever
+    /// their qty. Every level not listed is worse than the last one listed.
+    /// Empty when unknown, in which case it is rebuilt on the next search.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    best_prices: Vec<Price>,
     pub best_price: Option<Price>,
     pub best_price_qty: Option<Qty>,
+    /// The sum of the qty of every level, kept up to date as levels change.
+    total_qty: Qty,
+    /// When each level was last modified, if tracked.
+    #[cfg_attr(feature = "serde", serde(default))]
+    level_timestamps: Option<LevelMap<Price, u64>>,
+    /// The time stamped on levels modified from now on.
+    #[cfg_attr(feature = "serde", serde(default))]
+    timestamp: u64,
 }
 
 impl<Price: Debug + Copy + Eq + Ord + Hash, Qty: Debug + Copy + PartialEq + Ord + Num>
@@ -41,118 +162,711 @@ impl<Price: Debug + Copy + Eq + Ord + Hash, Qty: Debug + Copy + PartialEq + Ord
     pub fn new(is_bid: bool) -> Self {
         BookSide {
             is_bid,
-            levels: HashMap::new(),
+            invert_prices: false,
+            max_levels: None,
+            min_qty: Qty::zero(),
+            over_delete_policy: OverDeletePolicy::Error,
+            levels: LevelMap::new(),
+            best_prices: Vec::new(),
             best_price: None,
             best_price_qty: None,
+            total_qty: Qty::zero(),
+            level_timestamps: None,
+            timestamp: 0,
         }
     }
 
+    #[must_use]
+    pub fn with_max_levels(is_bid: bool, max_levels: usize) -> Self {
+        BookSide {
+            max_levels: Some(max_levels),
+            ..Self::new(is_bid)
+        }
+    }
+
+    #[must_use]
+    pub fn with_inverted_prices(is_bid: bool) -> Self {
+        BookSide {
+            invert_prices: true,
+            ..Self::new(is_bid)
+        }
+    }
+
+    #[must_use]
+    pub fn with_min_qty(is_bid: bool, min_qty: Qty) -> Self {
+        BookSide {
+            min_qty,
+            ..Self::new(is_bid)
+        }
+    }
+
+    #[must_use]
+    pub fn with_over_delete_policy(is_bid: bool, over_delete_policy: OverDeletePolicy) -> Self {
+        BookSide {
+            over_delete_policy,
+            ..Self::new(is_bid)
+        }
+    }
+
+    /// Change the policy of a side that has already been built, e.g. from
+    /// initial levels.
+    pub fn set_over_delete_policy(&mut self, over_delete_policy: OverDeletePolicy) {
+        self.over_delete_policy = over_delete_policy;
+    }
+
+    /// Whether higher prices are better on this side: true for normal bids
+    /// and for asks of an inverted book.
+    #[inline]
+    fn prefers_higher_prices(&self) -> bool {
+        self.is_bid != self.invert_prices
+    }
+
     #[inline]
     pub fn get_level(&self, price: Price) -> Option<&PriceLevel<Price, Qty>> {
         self.levels.get(&price)
     }
 
+    /// Every level, in no particular order. Use `top_n_levels` for levels
+    /// sorted from the best.
+    #[inline]
+    pub fn levels(&self) -> impl Iterator<Item = &PriceLevel<Price, Qty>> {
+        self.levels.values()
+    }
+
+    /// The sum of the qty of every level, including levels below `min_qty`.
+    /// Kept as a running total, so this doesn't scan the levels.
+    #[inline]
+    pub fn total_qty(&self) -> Qty {
+        self.total_qty
+    }
+
+    /// The number of price levels, including levels below `min_qty`.
+    #[inline]
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Start recording when each level was last modified. Levels already in
+    /// the side have no timestamp until they are next modified.
+    pub fn track_level_timestamps(&mut self) {
+        self.level_timestamps.get_or_insert_with(LevelMap::new);
+    }
+
+    /// Set the time stamped on levels modified from now on, e.g. the event
+    /// time of the next update.
+    #[inline]
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.timestamp = timestamp;
+    }
+
+    /// When the level at `price` was last modified, or `None` if there is no
+    /// such level or timestamps aren't tracked.
+    #[inline]
+    pub fn level_timestamp(&self, price: Price) -> Option<u64> {
+        self.level_timestamps.as_ref()?.get(&price).copied()
+    }
+
+    #[inline]
+    fn stamp_level(&mut self, price: Price) {
+        if let Some(level_timestamps) = &mut self.level_timestamps {
+            level_timestamps.insert(price, self.timestamp);
+        }
+    }
+
+    #[inline]
+    fn unstamp_level(&mut self, price: Price) {
+        if let Some(level_timestamps) = &mut self.level_timestamps {
+            level_timestamps.remove(&price);
+        }
+    }
+
     #[inline]
-    pub fn find_or_create_level(
+    fn find_or_create_level(
         &mut self,
         price: Price,
     ) -> (FoundLevelType, &mut PriceLevel<Price, Qty>) {
+        if !self.levels.contains_key(&price) {
+            self.cache_new_price(price);
+        }
+        self.stamp_level(price);
         match self.levels.entry(price) {
-            hashbrown::hash_map::Entry::Occupied(o) => (FoundLevelType::Existing, o.into_mut()),
-            hashbrown::hash_map::Entry::Vacant(v) => {
-                (FoundLevelType::New, v.insert(PriceLevel::new(price)))
-            }
+            Entry::Occupied(o) => (FoundLevelType::Existing, o.into_mut()),
+            Entry::Vacant(v) => (FoundLevelType::New, v.insert(PriceLevel::new(price))),
         }
     }
 
     #[inline]
-    fn update_best_price_after_add(
-        &mut self,
-        found_level_type: FoundLevelType,
-        added_price: Price,
-        added_qty: Qty,
-    ) {
-        match (
-            found_level_type,
-            self.is_bid,
-            self.best_price.map(|px| px.cmp(&added_price)),
-        ) {
+    fn update_best_price_after_add(&mut self, added_price: Price, level_qty: Qty) {
+        if level_qty < self.min_qty {
+            return;
+        }
+        match self.best_price {
             // Adding qty to existing best price
-            (FoundLevelType::Existing, _, Some(std::cmp::Ordering::Equal)) => {
-                self.best_price_qty = self.best_price_qty.map(|qty| qty + added_qty);
+            Some(best_price) if best_price == added_price => {
+                self.best_price_qty = Some(level_qty);
             }
-            // New price is better than current best price
-            (FoundLevelType::New, _, None)
-            | (FoundLevelType::New, true, Some(std::cmp::Ordering::Less))
-            | (FoundLevelType::New, false, Some(std::cmp::Ordering::Greater)) => {
+            // Level is worse than current best price
+            Some(best_price) if !self.is_better_price(added_price, best_price) => {}
+            // Level is better than current best price, or there was no
+            // eligible best price before this add
+            _ => {
                 self.best_price = Some(added_price);
-                self.best_price_qty = Some(added_qty);
-            }
-            (FoundLevelType::New, _, Some(std::cmp::Ordering::Equal)) => panic!(
-                "update_best_price_after_add: New level has same price as current best price"
-            ),
-            (FoundLevelType::Existing, _, None) => {
-                panic!(
-                    "update_best_price_after_add: If there is an existing level then best price should not be None"
-                )
+                self.best_price_qty = Some(level_qty);
             }
-            _ => {}
         }
     }
 
     #[inline]
     fn update_best_price_after_level_delete(&mut self, deleted_price: Price) {
         if self.best_price == Some(deleted_price) {
-            (self.best_price, self.best_price_qty) = self
-                .get_best_price_level()
-                .map_or((None, None), |l| (Some(l.price), Some(l.qty)));
+            self.reset_best_price();
         }
     }
 
     #[inline]
-    fn update_best_price_after_qty_delete(&mut self, deleted_price: Price, deleted_qty: Qty) {
+    fn update_best_price_after_qty_delete(&mut self, deleted_price: Price, level_qty: Qty) {
         if self.best_price == Some(deleted_price) {
-            self.best_price_qty = self.best_price_qty.map(|qty| qty - deleted_qty);
+            if level_qty < self.min_qty {
+                self.reset_best_price();
+            } else {
+                self.best_price_qty = Some(level_qty);
+            }
+        }
+    }
+
+    #[inline]
+    fn reset_best_price(&mut self) {
+        if self.best_prices.is_empty() {
+            self.refill_best_prices();
+        }
+        let cached = self
+            .best_prices
+            .iter()
+            .map(|price| &self.levels[price])
+            .find(|l| l.qty >= self.min_qty);
+        let best = match cached {
+            // Every level may be below min_qty, so search the rest.
+            None if self.best_prices.len() < self.levels.len() => self.get_best_price_level(),
+            best => best,
+        };
+        (self.best_price, self.best_price_qty) =
+            best.map_or((None, None), |l| (Some(l.price), Some(l.qty)));
+    }
+
+    /// Rebuild `best_prices` from every level.
+    fn refill_best_prices(&mut self) {
+        let prefers_higher_prices = self.prefers_higher_prices();
+        let best_first = |a: &Price, b: &Price| {
+            if prefers_higher_prices {
+                b.cmp(a)
+            } else {
+                a.cmp(b)
+            }
+        };
+        let mut prices: Vec<_> = self.levels.keys().copied().collect();
+        if CACHED_BEST_PRICES < prices.len() {
+            prices.select_nth_unstable_by(CACHED_BEST_PRICES, best_first);
+            prices.truncate(CACHED_BEST_PRICES);
+        }
+        prices.sort_unstable_by(best_first);
+        self.best_prices = prices;
+    }
+
+    /// Keep `best_prices` up to date with a level created at `price`.
+    #[inline]
+    fn cache_new_price(&mut self, price: Price) {
+        if let Some(&last) = self.best_prices.last() {
+            if self.is_better_price(price, last) {
+                let index = self
+                    .best_prices
+                    .partition_point(|&p| self.is_better_price(p, price));
+                self.best_prices.insert(index, price);
+                self.best_prices.truncate(CACHED_BEST_PRICES);
+            }
         }
     }
 
+    /// Keep `best_prices` up to date with the level at `price` being removed.
     #[inline]
-    pub fn add_qty(&mut self, price: Price, qty: Qty) {
-        let (found_level_type, level) = self.find_or_create_level(price);
+    fn uncache_price(&mut self, price: Price) {
+        if let Some(index) = self.best_prices.iter().position(|&p| p == price) {
+            self.best_prices.remove(index);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_better_price(&self, price: Price, other: Price) -> bool {
+        if self.prefers_higher_prices() {
+            price > other
+        } else {
+            price < other
+        }
+    }
+
+    /// Evict the worst level if adding a new level at `price` would exceed
+    /// `max_levels`. Returns false if the add should be dropped instead.
+    #[inline]
+    fn make_room_for_level(&mut self, price: Price) -> bool {
+        match self.max_levels {
+            Some(max_levels)
+                if self.levels.len() >= max_levels && !self.levels.contains_key(&price) =>
+            {
+                match self.get_worst_price_level().map(|l| l.price) {
+                    Some(worst_price) if self.is_better_price(price, worst_price) => {
+                        let evicted = self
+                            .levels
+                            .remove(&worst_price)
+                            .expect("make_room_for_level: worst price level should exist");
+                        self.total_qty = self.total_qty - evicted.qty;
+                        self.uncache_price(worst_price);
+                        self.unstamp_level(worst_price);
+                        self.update_best_price_after_level_delete(worst_price);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            _ => true,
+        }
+    }
+
+    /// Add qty at a price level, returning the level's new total and whether
+    /// it already existed, or `None` if the add was dropped because of
+    /// `max_levels`.
+    #[inline]
+    pub fn add_qty(&mut self, price: Price, qty: Qty) -> Option<AddOutcome<Price, Qty>> {
+        if !self.make_room_for_level(price) {
+            return None;
+        }
+        let (found, level) = self.find_or_create_level(price);
         level.add_qty(qty);
-        self.update_best_price_after_add(found_level_type, price, qty);
+        let level = *level;
+        self.total_qty = self.total_qty + qty;
+        self.update_best_price_after_add(price, level.qty);
+        Some(AddOutcome {
+            level,
+            qty_delta: qty,
+            found,
+        })
     }
 
     #[inline]
-    pub fn delete_qty(&mut self, price: Price, qty: Qty) -> Result<(), DeleteError> {
+    pub fn delete_qty(&mut self, price: Price, qty: Qty) -> Result<DeleteLevelType, DeleteError> {
         let level = self
             .levels
             .get_mut(&price)
             .ok_or(LevelError::LevelNotFound)?;
-        match level.qty.cmp(&qty) {
-            std::cmp::Ordering::Less => return Err(DeleteError::QtyExceedsAvailable),
-            std::cmp::Ordering::Equal => {
+        match (level.qty.cmp(&qty), self.over_delete_policy) {
+            (std::cmp::Ordering::Less, OverDeletePolicy::Error) => {
+                Err(DeleteError::QtyExceedsAvailable)
+            }
+            (std::cmp::Ordering::Less, OverDeletePolicy::Ignore) => Ok(DeleteLevelType::Ignored),
+            (std::cmp::Ordering::Less, OverDeletePolicy::Clamp)
+            | (std::cmp::Ordering::Equal, _) => {
+                self.total_qty = self.total_qty - level.qty;
                 self.levels.remove(&price);
+                self.uncache_price(price);
+                self.unstamp_level(price);
                 self.update_best_price_after_level_delete(price);
+                Ok(DeleteLevelType::Deleted)
             }
-            std::cmp::Ordering::Greater => {
+            (std::cmp::Ordering::Greater, _) => {
                 level.delete_qty(qty);
-                self.update_best_price_after_qty_delete(price, qty);
+                let level_qty = level.qty;
+                self.total_qty = self.total_qty - qty;
+                self.stamp_level(price);
+                self.update_best_price_after_qty_delete(price, level_qty);
+                Ok(DeleteLevelType::QtyDecreased)
             }
         }
-        Ok(())
     }
 
+    /// Remove the whole level at `price` regardless of its qty.
+    #[inline]
+    pub fn delete_level(&mut self, price: Price) -> Result<PriceLevel<Price, Qty>, LevelError> {
+        let level = self
+            .levels
+            .remove(&price)
+            .ok_or(LevelError::LevelNotFound)?;
+        self.total_qty = self.total_qty - level.qty;
+        self.uncache_price(price);
+        self.unstamp_level(price);
+        self.update_best_price_after_level_delete(price);
+        Ok(level)
+    }
+
+    /// Remove every level, keeping the side's configuration.
+    pub fn clear(&mut self) {
+        self.levels.clear();
+        if let Some(level_timestamps) = &mut self.level_timestamps {
+            level_timestamps.clear();
+        }
+        self.best_prices.clear();
+        self.best_price = None;
+        self.best_price_qty = None;
+        self.total_qty = Qty::zero();
+    }
+
+    /// Sweep resting qty from the best price outwards until `qty` is filled
+    /// or the side is empty. Returns the fills, best price first.
+    pub fn immediate_or_cancel(&mut self, qty: Qty) -> Vec<PriceLevel<Price, Qty>> {
+        self.sweep(qty, None)
+    }
+
+    /// Like `immediate_or_cancel` for an aggressive order limited to
+    /// `limit_price`: levels worse than it are left alone.
+    pub fn match_qty(&mut self, limit_price: Price, qty: Qty) -> Vec<PriceLevel<Price, Qty>> {
+        self.sweep(qty, Some(limit_price))
+    }
+
+    fn sweep(&mut self, qty: Qty, limit_price: Option<Price>) -> Vec<PriceLevel<Price, Qty>> {
+        let mut fills = Vec::new();
+        let mut remaining = qty;
+        while remaining > Qty::zero() {
+            let Some((price, level_qty)) =
+                self.best_level_where(|_| true).map(|l| (l.price, l.qty))
+            else {
+                break;
+            };
+            if limit_price.is_some_and(|limit_price| self.is_better_price(limit_price, price)) {
+                break;
+            }
+            let fill_qty = remaining.min(level_qty);
+            self.delete_qty(price, fill_qty)
+                .expect("sweep: best price level should hold fill qty");
+            remaining = remaining - fill_qty;
+            fills.push(PriceLevel {
+                price,
+                qty: fill_qty,
+            });
+        }
+        fills
+    }
+
+    /// Sweep the side for the full `qty`, or do nothing and return `None`
+    /// if there is not enough resting qty to fill it.
+    pub fn try_fill_or_kill(&mut self, qty: Qty) -> Option<Vec<PriceLevel<Price, Qty>>> {
+        let mut available = Qty::zero();
+        let mut levels = self.levels.values();
+        while available < qty {
+            available = available + levels.next()?.qty;
+        }
+        Some(self.immediate_or_cancel(qty))
+    }
+
+    /// The best level holding at least `min_qty`.
     #[inline]
     pub fn get_best_price_level(&self) -> Option<&PriceLevel<Price, Qty>> {
-        if self.is_bid {
-            self.levels.values().max_by_key(|l| l.price)
+        self.best_level_where(|l| l.qty >= self.min_qty)
+    }
+
+    #[cfg(not(feature = "btree_levels"))]
+    #[inline]
+    fn best_level_where(
+        &self,
+        eligible: impl Fn(&PriceLevel<Price, Qty>) -> bool,
+    ) -> Option<&PriceLevel<Price, Qty>> {
+        let levels = self.levels.values().filter(|l| eligible(l));
+        if self.prefers_higher_prices() {
+            levels.max_by_key(|l| l.price)
+        } else {
+            levels.min_by_key(|l| l.price)
+        }
+    }
+
+    #[cfg(feature = "btree_levels")]
+    #[inline]
+    fn best_level_where(
+        &self,
+        eligible: impl Fn(&PriceLevel<Price, Qty>) -> bool,
+    ) -> Option<&PriceLevel<Price, Qty>> {
+        self.iter_levels().find(|l| eligible(l))
+    }
+
+    /// Every level from best to worst. With the `btree_levels` feature this
+    /// walks the tree; otherwise the levels are heapified up front, in linear
+    /// time, and each one is popped off the heap only as it is reached, so
+    /// stopping after a few levels doesn't pay for a full sort.
+    pub fn iter_levels(&self) -> impl Iterator<Item = &PriceLevel<Price, Qty>> {
+        self.iter_range(..)
+    }
+
+    /// The levels with a price in `range`, from best to worst. See
+    /// `iter_levels`.
+    #[cfg(not(feature = "btree_levels"))]
+    pub fn iter_range(
+        &self,
+        range: impl RangeBounds<Price>,
+    ) -> impl Iterator<Item = &PriceLevel<Price, Qty>> {
+        let prefers_higher_prices = self.prefers_higher_prices();
+        let mut heap: BinaryHeap<_> = self
+            .levels
+            .values()
+            .filter(|l| range.contains(&l.price))
+            .map(|level| BestFirst {
+                level,
+                prefers_higher_prices,
+            })
+            .collect();
+        std::iter::from_fn(move || heap.pop().map(|entry| entry.level))
+    }
+
+    /// The levels with a price in `range`, from best to worst. See
+    /// `iter_levels`.
+    #[cfg(feature = "btree_levels")]
+    pub fn iter_range(
+        &self,
+        range: impl RangeBounds<Price>,
+    ) -> impl Iterator<Item = &PriceLevel<Price, Qty>> {
+        let levels = self.levels.range(range).map(|(_, l)| l);
+        if self.prefers_higher_prices() {
+            Either::Left(levels.rev())
         } else {
+            Either::Right(levels)
+        }
+    }
+
+    /// Every level at or better than `price`, in no particular order.
+    #[cfg(not(feature = "btree_levels"))]
+    fn levels_at_or_better_than(
+        &self,
+        price: Price,
+    ) -> impl Iterator<Item = &PriceLevel<Price, Qty>> {
+        self.levels
+            .values()
+            .filter(move |l| !self.is_better_price(price, l.price))
+    }
+
+    /// Every level at or better than `price`, from best to worst.
+    #[cfg(feature = "btree_levels")]
+    fn levels_at_or_better_than(
+        &self,
+        price: Price,
+    ) -> impl Iterator<Item = &PriceLevel<Price, Qty>> {
+        if self.prefers_higher_prices() {
+            Either::Left(self.levels.range(price..).map(|(_, l)| l))
+        } else {
+            Either::Right(self.levels.range(..=price).map(|(_, l)| l))
+        }
+    }
+
+    /// The total qty of the levels at or better than `price`, e.g. the depth
+    /// within a band around the mid. With the `btree_levels` feature only
+    /// those levels are visited; otherwise every level is.
+    pub fn qty_better_than(&self, price: Price) -> Qty {
+        self.levels_at_or_better_than(price)
+            .fold(Qty::zero(), |qty, l| qty + l.qty)
+    }
+
+    /// The best `n` levels holding at least `min_qty`, sorted from best to
+    /// worst. There is no tracked window behind this, so without the
+    /// `btree_levels` feature every call scans all levels.
+    #[cfg(not(feature = "btree_levels"))]
+    pub fn top_n_levels(&self, n: usize) -> Vec<&PriceLevel<Price, Qty>> {
+        let prefers_higher_prices = self.prefers_higher_prices();
+        let best_first = |a: &&PriceLevel<Price, Qty>, b: &&PriceLevel<Price, Qty>| {
+            if prefers_higher_prices {
+                b.price.cmp(&a.price)
+            } else {
+                a.price.cmp(&b.price)
+            }
+        };
+        let mut levels: Vec<_> = self
+            .levels
+            .values()
+            .filter(|l| l.qty >= self.min_qty)
+            .collect();
+        if n < levels.len() {
+            levels.select_nth_unstable_by(n, best_first);
+            levels.truncate(n);
+        }
+        levels.sort_unstable_by(best_first);
+        levels
+    }
+
+    /// The best `n` levels holding at least `min_qty`, sorted from best to
+    /// worst.
+    #[cfg(feature = "btree_levels")]
+    pub fn top_n_levels(&self, n: usize) -> Vec<&PriceLevel<Price, Qty>> {
+        self.iter_levels()
+            .filter(|l| l.qty >= self.min_qty)
+            .take(n)
+            .collect()
+    }
+
+    /// Consume the side, returning every level sorted from best to worst.
+    pub fn into_sorted_levels(self) -> Vec<PriceLevel<Price, Qty>> {
+        let prefers_higher_prices = self.prefers_higher_prices();
+        let mut levels: Vec<_> = self.levels.into_values().collect();
+        if prefers_higher_prices {
+            levels.sort_unstable_by_key(|l| std::cmp::Reverse(l.price));
+        } else {
+            levels.sort_unstable_by_key(|l| l.price);
+        }
+        levels
+    }
+
+    #[cfg(not(feature = "btree_levels"))]
+    #[inline]
+    pub fn get_worst_price_level(&self) -> Option<&PriceLevel<Price, Qty>> {
+        if self.prefers_higher_prices() {
             self.levels.values().min_by_key(|l| l.price)
+        } else {
+            self.levels.values().max_by_key(|l| l.price)
+        }
+    }
+
+    #[cfg(feature = "btree_levels")]
+    #[inline]
+    pub fn get_worst_price_level(&self) -> Option<&PriceLevel<Price, Qty>> {
+        if self.prefers_higher_prices() {
+            self.levels.values().next()
+        } else {
+            self.levels.values().next_back()
+        }
+    }
+}
+
+impl<Price: Debug + Copy + Eq + Ord + Hash, Qty: Debug + Copy + PartialEq + Ord + Num + Signed>
+    BookSide<Price, Qty>
+{
+    /// Apply a signed change in qty at a price level: positive deltas are
+    /// added, negative deltas are deleted and a zero delta is a no-op.
+    #[inline]
+    pub fn apply_qty_delta(&mut self, price: Price, delta: Qty) -> Result<(), DeleteError> {
+        if delta.is_positive() {
+            self.add_qty(price, delta);
+        } else if delta.is_negative() {
+            self.delete_qty(price, delta.abs())?;
+        }
+        Ok(())
+    }
+}
+
+impl<
+        Price: Debug + Copy + Eq + Ord + Hash,
+        Qty: Debug + Copy + PartialEq + Ord + Num + CheckedAdd,
+    > BookSide<Price, Qty>
+{
+    /// Like `add_qty`, but fails without changing the side if the level's
+    /// qty or the side's total qty would overflow, rather than wrapping
+    /// around in release builds. Deletes never take a level below zero, so
+    /// only adds need checking.
+    pub fn checked_add_qty(
+        &mut self,
+        price: Price,
+        qty: Qty,
+    ) -> Result<Option<AddOutcome<Price, Qty>>, QtyOverflowError<Price>> {
+        if let Some(level) = self.levels.get(&price) {
+            if level.qty.checked_add(&qty).is_none() {
+                return Err(QtyOverflowError { price });
+            }
+        }
+        if self.total_qty.checked_add(&qty).is_none() {
+            return Err(QtyOverflowError { price });
+        }
+        Ok(self.add_qty(price, qty))
+    }
+}
+
+impl<
+        Price: Debug + Copy + Eq + Ord + Hash + ToPrimitive,
+        Qty: Debug + Copy + PartialEq + Ord + Num + ToPrimitive,
+    > BookSide<Price, Qty>
+{
+    /// The average price a sweep of `qty` from the best price outwards would
+    /// fill at, without modifying the side. `None` if `qty` is not positive
+    /// or there is not enough resting qty to fill it.
+    pub fn fill_price_for_qty(&self, qty: Qty) -> Option<f64> {
+        if qty <= Qty::zero() {
+            return None;
+        }
+        let mut remaining = qty;
+        let mut notional = 0.0;
+        for level in self.iter_levels() {
+            let fill_qty = remaining.min(level.qty);
+            notional += level.price.to_f64()? * fill_qty.to_f64()?;
+            remaining = remaining - fill_qty;
+            if remaining == Qty::zero() {
+                return Some(notional / qty.to_f64()?);
+            }
+        }
+        None
+    }
+
+    /// The total `price * qty` of the levels at or better than `price`, as
+    /// for `qty_better_than`. `None` if a price or qty doesn't fit an `f64`.
+    pub fn notional_better_than(&self, price: Price) -> Option<f64> {
+        self.levels_at_or_better_than(price)
+            .map(|l| Some(l.price.to_f64()? * l.qty.to_f64()?))
+            .sum()
+    }
+}
+
+/// Each level on its own line from best to worst, as `price qty` with both
+/// columns right-aligned. A precision, e.g. `{:.5}`, shows only that many of
+/// the best levels.
+impl<
+        Price: Debug + Copy + Eq + Ord + Hash + Display,
+        Qty: Debug + Copy + PartialEq + Ord + Num + Display,
+    > Display for BookSide<Price, Qty>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let depth = f.precision().unwrap_or(usize::MAX);
+        let rows: Vec<_> = self
+            .iter_levels()
+            .take(depth)
+            .map(|l| (l.price.to_string(), l.qty.to_string()))
+            .collect();
+        let price_width = rows.iter().map(|(price, _)| price.len()).max();
+        let qty_width = rows.iter().map(|(_, qty)| qty.len()).max();
+        let (price_width, qty_width) = (price_width.unwrap_or(0), qty_width.unwrap_or(0));
+        for (i, (price, qty)) in rows.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{price:>price_width$} {qty:>qty_width$}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A level ordered so that the best price is the greatest, for the max-heap
+/// behind `BookSide::iter_range`.
+#[cfg(not(feature = "btree_levels"))]
+struct BestFirst<'a, Price, Qty> {
+    level: &'a PriceLevel<Price, Qty>,
+    prefers_higher_prices: bool,
+}
+
+#[cfg(not(feature = "btree_levels"))]
+impl<Price: Ord, Qty> Ord for BestFirst<'_, Price, Qty> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        if self.prefers_higher_prices {
+            self.level.price.cmp(&other.level.price)
+        } else {
+            other.level.price.cmp(&self.level.price)
         }
     }
 }
 
+#[cfg(not(feature = "btree_levels"))]
+impl<Price: Ord, Qty> PartialOrd for BestFirst<'_, Price, Qty> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(not(feature = "btree_levels"))]
+impl<Price: Ord, Qty> PartialEq for BestFirst<'_, Price, Qty> {
+    fn eq(&self, other: &Self) -> bool {
+        self.level.price == other.level.price
+    }
+}
+
+#[cfg(not(feature = "btree_levels"))]
+impl<Price: Ord, Qty> Eq for BestFirst<'_, Price, Qty> {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -255,6 +969,45 @@ mod tests {
         assert_eq!(book_side.best_price_qty, None);
     }
 
+    #[test]
+    fn test_add_and_delete_qty_outcomes() {
+        let mut book_side = BookSide::new(true);
+        let (price, qty) = (100, 10);
+        assert_eq!(
+            book_side.add_qty(price, qty),
+            Some(AddOutcome {
+                level: PriceLevel { price, qty },
+                qty_delta: qty,
+                found: FoundLevelType::New,
+            })
+        );
+        assert_eq!(
+            book_side.add_qty(price, qty),
+            Some(AddOutcome {
+                level: PriceLevel {
+                    price,
+                    qty: 2 * qty
+                },
+                qty_delta: qty,
+                found: FoundLevelType::Existing,
+            })
+        );
+        assert_eq!(book_side.best_price, Some(price));
+        assert_eq!(book_side.best_price_qty, Some(2 * qty));
+
+        assert_eq!(
+            book_side.delete_qty(price, qty),
+            Ok(DeleteLevelType::QtyDecreased)
+        );
+        assert_eq!(
+            book_side.delete_qty(price, qty),
+            Ok(DeleteLevelType::Deleted)
+        );
+        assert_eq!(book_side.levels.len(), 0);
+        assert_eq!(book_side.best_price, None);
+        assert_eq!(book_side.best_price_qty, None);
+    }
+
     #[test]
     fn test_best_price_after_add_better() {
         let mut book_side = BookSide::new(true);
@@ -315,4 +1068,676 @@ mod tests {
         assert_eq!(book_side.best_price, Some(100));
         assert_eq!(book_side.best_price_qty, Some(15));
     }
+
+    #[test]
+    fn test_immediate_or_cancel() {
+        let mut book_side = create_book_side_with_orders();
+        let fills = book_side.immediate_or_cancel(150);
+        assert_eq!(
+            fills,
+            vec![
+                PriceLevel { price: 4, qty: 98 },
+                PriceLevel { price: 3, qty: 52 }
+            ]
+        );
+        assert_eq!(book_side.best_price, Some(3));
+        assert_eq!(book_side.best_price_qty, Some(49));
+
+        let fills = book_side.immediate_or_cancel(1000);
+        assert_eq!(fills.len(), 3);
+        assert_eq!(book_side.levels.len(), 0);
+        assert_eq!(book_side.best_price, None);
+        assert_eq!(book_side.best_price_qty, None);
+    }
+
+    #[test]
+    fn test_match_qty() {
+        let mut book_side = create_book_side_with_orders();
+        let fills = book_side.match_qty(3, 1000);
+        assert_eq!(
+            fills,
+            vec![
+                PriceLevel { price: 4, qty: 98 },
+                PriceLevel { price: 3, qty: 101 }
+            ]
+        );
+        assert_eq!(book_side.best_price, Some(2));
+        assert_eq!(book_side.match_qty(3, 10), vec![]);
+    }
+
+    #[test]
+    fn test_fill_or_kill_unfillable_leaves_book_unchanged() {
+        let mut book_side = create_book_side_with_orders();
+        assert_eq!(book_side.try_fill_or_kill(400), None);
+        assert_eq!(book_side.levels.len(), 4);
+        assert_eq!(book_side.get_level(4).unwrap().qty, 98);
+        assert_eq!(book_side.best_price, Some(4));
+        assert_eq!(book_side.best_price_qty, Some(98));
+    }
+
+    #[test]
+    fn test_fill_or_kill_fillable() {
+        let mut book_side = create_book_side_with_orders();
+        let fills = book_side.try_fill_or_kill(399).unwrap();
+        assert_eq!(
+            fills,
+            vec![
+                PriceLevel { price: 4, qty: 98 },
+                PriceLevel { price: 3, qty: 101 },
+                PriceLevel { price: 2, qty: 100 },
+                PriceLevel { price: 1, qty: 100 },
+            ]
+        );
+        assert_eq!(book_side.levels.len(), 0);
+        assert_eq!(book_side.best_price, None);
+
+        let mut book_side: BookSide<u32, u32> = BookSide::new(false);
+        book_side.add_qty(10, 5);
+        book_side.add_qty(11, 5);
+        let fills = book_side.try_fill_or_kill(7).unwrap();
+        assert_eq!(
+            fills,
+            vec![
+                PriceLevel { price: 10, qty: 5 },
+                PriceLevel { price: 11, qty: 2 }
+            ]
+        );
+        assert_eq!(book_side.best_price, Some(11));
+        assert_eq!(book_side.best_price_qty, Some(3));
+    }
+
+    #[test]
+    fn test_max_levels_evicts_worst_level() {
+        let mut book_side = BookSide::with_max_levels(true, 3);
+        book_side.add_qty(1, 10);
+        book_side.add_qty(2, 20);
+        book_side.add_qty(3, 30);
+
+        book_side.add_qty(4, 40);
+        assert_eq!(book_side.levels.len(), 3);
+        assert!(book_side.get_level(1).is_none());
+        assert_eq!(book_side.best_price, Some(4));
+        assert_eq!(book_side.best_price_qty, Some(40));
+
+        book_side.add_qty(2, 5);
+        assert_eq!(book_side.levels.len(), 3);
+        assert_eq!(book_side.get_level(2).unwrap().qty, 25);
+
+        book_side.delete_qty(4, 40).unwrap();
+        assert_eq!(book_side.best_price, Some(3));
+        assert_eq!(book_side.best_price_qty, Some(30));
+    }
+
+    #[test]
+    fn test_max_levels_drops_add_worse_than_all_levels() {
+        let mut book_side = BookSide::with_max_levels(false, 2);
+        book_side.add_qty(10, 1);
+        book_side.add_qty(11, 2);
+
+        assert_eq!(book_side.add_qty(12, 3), None);
+        assert_eq!(book_side.levels.len(), 2);
+        assert!(book_side.get_level(12).is_none());
+        assert_eq!(
+            book_side.delete_qty(12, 3),
+            Err(DeleteError::LevelError(LevelError::LevelNotFound))
+        );
+
+        book_side.add_qty(9, 4);
+        assert!(book_side.get_level(11).is_none());
+        assert_eq!(book_side.best_price, Some(9));
+        assert_eq!(book_side.best_price_qty, Some(4));
+        assert_eq!(book_side.get_worst_price_level().unwrap().price, 10);
+    }
+
+    #[test]
+    fn test_readd_price_after_full_delete() {
+        for is_bid in [true, false] {
+            let (best, middle, worst) = if is_bid { (3, 2, 1) } else { (1, 2, 3) };
+            let mut book_side: BookSide<u32, u32> = BookSide::new(is_bid);
+            book_side.add_qty(best, 30);
+            book_side.add_qty(middle, 20);
+            book_side.add_qty(worst, 10);
+
+            book_side.delete_qty(middle, 20).unwrap();
+            assert!(book_side.get_level(middle).is_none());
+            book_side.add_qty(middle, 25);
+            assert_eq!(book_side.levels.len(), 3);
+            assert_eq!(book_side.get_level(middle).unwrap().qty, 25);
+            assert_eq!(book_side.best_price, Some(best));
+            assert_eq!(book_side.best_price_qty, Some(30));
+
+            book_side.delete_qty(best, 30).unwrap();
+            assert_eq!(book_side.best_price, Some(middle));
+            assert_eq!(book_side.best_price_qty, Some(25));
+            book_side.add_qty(best, 35);
+            assert_eq!(book_side.best_price, Some(best));
+            assert_eq!(book_side.best_price_qty, Some(35));
+
+            book_side.delete_qty(best, 35).unwrap();
+            book_side.delete_qty(middle, 25).unwrap();
+            assert_eq!(book_side.best_price, Some(worst));
+            assert_eq!(book_side.best_price_qty, Some(10));
+        }
+    }
+
+    #[test]
+    fn test_apply_qty_delta() {
+        let mut book_side: BookSide<i32, i32> = BookSide::new(false);
+        book_side.apply_qty_delta(100, 0).unwrap();
+        assert!(book_side.get_level(100).is_none());
+        assert_eq!(book_side.best_price, None);
+
+        book_side.apply_qty_delta(100, 10).unwrap();
+        book_side.apply_qty_delta(100, -4).unwrap();
+        book_side.apply_qty_delta(100, 0).unwrap();
+        book_side.apply_qty_delta(100, 7).unwrap();
+        assert_eq!(book_side.get_level(100).unwrap().qty, 13);
+        assert_eq!(book_side.best_price_qty, Some(13));
+
+        assert_eq!(
+            book_side.apply_qty_delta(100, -14),
+            Err(DeleteError::QtyExceedsAvailable)
+        );
+        book_side.apply_qty_delta(100, -13).unwrap();
+        assert!(book_side.get_level(100).is_none());
+        assert_eq!(book_side.best_price, None);
+    }
+
+    #[test]
+    fn test_into_sorted_levels() {
+        let levels = create_book_side_with_orders().into_sorted_levels();
+        assert_eq!(
+            levels,
+            vec![
+                PriceLevel { price: 4, qty: 98 },
+                PriceLevel { price: 3, qty: 101 },
+                PriceLevel { price: 2, qty: 100 },
+                PriceLevel { price: 1, qty: 100 },
+            ]
+        );
+
+        let mut book_side: BookSide<u32, u32> = BookSide::new(false);
+        book_side.add_qty(3, 30);
+        book_side.add_qty(1, 10);
+        book_side.add_qty(2, 20);
+        let prices: Vec<u32> = book_side
+            .into_sorted_levels()
+            .iter()
+            .map(|l| l.price)
+            .collect();
+        assert_eq!(prices, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_inverted_prices() {
+        let mut bids: BookSide<u32, u32> = BookSide::with_inverted_prices(true);
+        bids.add_qty(3, 30);
+        bids.add_qty(1, 10);
+        bids.add_qty(2, 20);
+        assert_eq!(bids.best_price, Some(1));
+        assert_eq!(bids.best_price_qty, Some(10));
+        assert_eq!(bids.get_worst_price_level().unwrap().price, 3);
+
+        bids.delete_qty(1, 10).unwrap();
+        assert_eq!(bids.best_price, Some(2));
+        let prices: Vec<u32> = bids.into_sorted_levels().iter().map(|l| l.price).collect();
+        assert_eq!(prices, vec![2, 3]);
+
+        let mut asks: BookSide<u32, u32> = BookSide::with_inverted_prices(false);
+        asks.add_qty(1, 10);
+        asks.add_qty(3, 30);
+        asks.add_qty(2, 20);
+        assert_eq!(asks.best_price, Some(3));
+        assert_eq!(asks.immediate_or_cancel(35).len(), 2);
+        assert_eq!(asks.best_price, Some(2));
+        assert_eq!(asks.best_price_qty, Some(15));
+    }
+
+    #[test]
+    fn test_top_n_levels() {
+        let book_side = create_book_side_with_orders();
+        let prices =
+            |n| -> Vec<u32> { book_side.top_n_levels(n).iter().map(|l| l.price).collect() };
+        assert_eq!(prices(0), vec![]);
+        assert_eq!(prices(2), vec![4, 3]);
+        assert_eq!(prices(4), vec![4, 3, 2, 1]);
+        assert_eq!(prices(10), vec![4, 3, 2, 1]);
+
+        let mut book_side: BookSide<u32, u32> = BookSide::new(false);
+        for price in [7, 3, 9, 5, 1] {
+            book_side.add_qty(price, 1);
+        }
+        let levels = book_side.top_n_levels(3);
+        assert_eq!(
+            levels,
+            vec![
+                &PriceLevel { price: 1, qty: 1 },
+                &PriceLevel { price: 3, qty: 1 },
+                &PriceLevel { price: 5, qty: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_delete_level() {
+        let mut book_side = create_book_side_with_orders();
+        assert_eq!(
+            book_side.delete_level(2),
+            Ok(PriceLevel { price: 2, qty: 100 })
+        );
+        assert!(book_side.get_level(2).is_none());
+        assert_eq!(book_side.best_price, Some(4));
+        assert_eq!(book_side.best_price_qty, Some(98));
+
+        assert_eq!(
+            book_side.delete_level(4),
+            Ok(PriceLevel { price: 4, qty: 98 })
+        );
+        assert_eq!(book_side.best_price, Some(3));
+        assert_eq!(book_side.best_price_qty, Some(101));
+
+        assert_eq!(book_side.delete_level(4), Err(LevelError::LevelNotFound));
+        assert_eq!(book_side.levels.len(), 2);
+    }
+
+    #[test]
+    fn test_min_qty_threshold() {
+        let mut book_side: BookSide<u32, u32> = BookSide::with_min_qty(true, 10);
+        book_side.add_qty(100, 20);
+        book_side.add_qty(101, 1);
+        assert_eq!(book_side.best_price, Some(100));
+        assert_eq!(book_side.best_price_qty, Some(20));
+        assert_eq!(book_side.get_level(101).unwrap().qty, 1);
+
+        // Dust level grows to exactly min_qty and becomes best
+        book_side.add_qty(101, 9);
+        assert_eq!(book_side.best_price, Some(101));
+        assert_eq!(book_side.best_price_qty, Some(10));
+        let prices: Vec<u32> = book_side.top_n_levels(5).iter().map(|l| l.price).collect();
+        assert_eq!(prices, vec![101, 100]);
+
+        // Cancel takes it back below min_qty, so it is demoted
+        book_side.delete_qty(101, 1).unwrap();
+        assert_eq!(book_side.best_price, Some(100));
+        assert_eq!(book_side.best_price_qty, Some(20));
+        let prices: Vec<u32> = book_side.top_n_levels(5).iter().map(|l| l.price).collect();
+        assert_eq!(prices, vec![100]);
+
+        // Best level cancelled down below min_qty with no other eligible level
+        book_side.delete_qty(100, 11).unwrap();
+        assert_eq!(book_side.best_price, None);
+        assert_eq!(book_side.best_price_qty, None);
+        assert_eq!(book_side.levels.len(), 2);
+
+        // Sweeps still execute against dust levels
+        let fills = book_side.immediate_or_cancel(12);
+        assert_eq!(
+            fills,
+            vec![
+                PriceLevel { price: 101, qty: 9 },
+                PriceLevel { price: 100, qty: 3 }
+            ]
+        );
+        assert_eq!(book_side.levels.len(), 1);
+        assert_eq!(book_side.best_price, None);
+
+        book_side.add_qty(99, 10);
+        assert_eq!(book_side.best_price, Some(99));
+        book_side.add_qty(100, 4);
+        assert_eq!(book_side.best_price, Some(100));
+        assert_eq!(book_side.best_price_qty, Some(10));
+    }
+
+    #[test]
+    fn test_fill_price_for_qty() {
+        let mut book_side = BookSide::new(false);
+        book_side.add_qty(101, 2);
+        book_side.add_qty(100, 1);
+        book_side.add_qty(103, 5);
+
+        assert_eq!(book_side.fill_price_for_qty(1), Some(100.0));
+        assert_eq!(book_side.fill_price_for_qty(3), Some(302.0 / 3.0));
+        assert_eq!(book_side.fill_price_for_qty(5), Some(508.0 / 5.0));
+        assert_eq!(book_side.fill_price_for_qty(9), None);
+        assert_eq!(book_side.fill_price_for_qty(0), None);
+        assert_eq!(book_side.get_level(100).unwrap().qty, 1);
+
+        let mut book_side = BookSide::new(true);
+        book_side.add_qty(100, 1);
+        book_side.add_qty(99, 3);
+        assert_eq!(book_side.fill_price_for_qty(2), Some(199.0 / 2.0));
+    }
+
+    #[test]
+    fn test_qty_better_than() {
+        let mut book_side = BookSide::new(true);
+        book_side.add_qty(100, 1);
+        book_side.add_qty(99, 3);
+        book_side.add_qty(97, 5);
+        assert_eq!(book_side.qty_better_than(101), 0);
+        assert_eq!(book_side.qty_better_than(99), 4);
+        assert_eq!(book_side.qty_better_than(98), 4);
+        assert_eq!(book_side.notional_better_than(98), Some(397.0));
+
+        let mut book_side = BookSide::new(false);
+        book_side.add_qty(101, 2);
+        book_side.add_qty(103, 5);
+        assert_eq!(book_side.qty_better_than(100), 0);
+        assert_eq!(book_side.qty_better_than(103), 7);
+        assert_eq!(book_side.notional_better_than(102), Some(202.0));
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut book_side = BookSide::with_max_levels(true, 2);
+        book_side.add_qty(100, 1);
+        book_side.add_qty(101, 2);
+        book_side.clear();
+        assert_eq!(book_side.best_price, None);
+        assert_eq!(book_side.best_price_qty, None);
+        assert_eq!(book_side.get_level(100), None);
+
+        book_side.add_qty(99, 1);
+        book_side.add_qty(98, 1);
+        book_side.add_qty(97, 1);
+        assert_eq!(book_side.get_level(97), None);
+        assert_eq!(book_side.best_price, Some(99));
+    }
+
+    #[test]
+    fn test_total_qty() {
+        let mut book_side = BookSide::with_max_levels(true, 3);
+        book_side.add_qty(100, 5);
+        book_side.add_qty(100, 2);
+        book_side.add_qty(99, 4);
+        book_side.add_qty(98, 1);
+        assert_eq!((book_side.total_qty(), book_side.num_levels()), (12, 3));
+
+        book_side.delete_qty(100, 3).unwrap();
+        book_side.delete_qty(99, 4).unwrap();
+        assert_eq!((book_side.total_qty(), book_side.num_levels()), (5, 2));
+
+        // Evicting the worst level drops its qty.
+        book_side.add_qty(101, 2);
+        book_side.add_qty(102, 3);
+        assert_eq!((book_side.total_qty(), book_side.num_levels()), (9, 3));
+
+        book_side.immediate_or_cancel(4);
+        book_side.delete_level(100).unwrap();
+        assert_eq!((book_side.total_qty(), book_side.num_levels()), (1, 1));
+
+        book_side.clear();
+        assert_eq!((book_side.total_qty(), book_side.num_levels()), (0, 0));
+    }
+
+    #[test]
+    fn test_best_price_after_deleting_past_cached_prices() {
+        let mut book_side = BookSide::new(false);
+        for price in (0..100u32).rev() {
+            book_side.add_qty(price, 1);
+        }
+        book_side.add_qty(1000, 1);
+        for price in 0..100 {
+            assert_eq!(book_side.best_price, Some(price));
+            if price % 10 == 0 {
+                // Levels created behind the best must be found in their turn.
+                book_side.add_qty(price + 1, 1);
+                book_side.delete_qty(price + 1, 1).unwrap();
+                book_side.add_qty(price + 1, 1);
+            }
+            book_side.delete_level(price).unwrap();
+        }
+        assert_eq!(book_side.best_price, Some(1000));
+        book_side.delete_qty(1000, 1).unwrap();
+        assert_eq!(book_side.best_price, None);
+    }
+
+    #[test]
+    fn test_over_delete_policy() {
+        let mut book_side = BookSide::with_over_delete_policy(true, OverDeletePolicy::Ignore);
+        book_side.add_qty(100, 5);
+        assert_eq!(book_side.delete_qty(100, 6), Ok(DeleteLevelType::Ignored));
+        assert_eq!(book_side.best_price_qty, Some(5));
+
+        book_side.set_over_delete_policy(OverDeletePolicy::Clamp);
+        assert_eq!(book_side.delete_qty(100, 6), Ok(DeleteLevelType::Deleted));
+        assert_eq!(book_side.best_price, None);
+
+        book_side.set_over_delete_policy(OverDeletePolicy::Error);
+        book_side.add_qty(100, 5);
+        assert_eq!(
+            book_side.delete_qty(100, 6),
+            Err(DeleteError::QtyExceedsAvailable)
+        );
+        assert_eq!(
+            book_side.delete_qty(101, 1),
+            Err(DeleteError::LevelError(LevelError::LevelNotFound))
+        );
+    }
+
+    #[test]
+    fn test_checked_add_qty() {
+        let mut book_side: BookSide<i32, i8> = BookSide::new(true);
+        assert_eq!(
+            book_side
+                .checked_add_qty(100, 100)
+                .map(|outcome| outcome.map(|outcome| outcome.found)),
+            Ok(Some(FoundLevelType::New))
+        );
+        assert_eq!(
+            book_side.checked_add_qty(100, 28),
+            Err(QtyOverflowError { price: 100 })
+        );
+        assert_eq!(book_side.best_price_qty, Some(100));
+        assert_eq!(
+            book_side
+                .checked_add_qty(100, 27)
+                .map(|outcome| outcome.map(|outcome| outcome.found)),
+            Ok(Some(FoundLevelType::Existing))
+        );
+        assert_eq!(book_side.best_price_qty, Some(127));
+    }
+
+    #[test]
+    fn test_checked_add_qty_total_overflow() {
+        let mut book_side: BookSide<i32, i8> = BookSide::new(true);
+        book_side.checked_add_qty(100, 100).unwrap();
+        // Neither level overflows on its own, but the side's total would.
+        assert_eq!(
+            book_side.checked_add_qty(99, 50),
+            Err(QtyOverflowError { price: 99 })
+        );
+        assert_eq!(book_side.num_levels(), 1);
+        assert_eq!(book_side.total_qty(), 100);
+        assert!(book_side.get_level(99).is_none());
+        assert!(book_side.checked_add_qty(99, 27).unwrap().is_some());
+        assert_eq!(book_side.total_qty(), 127);
+    }
+
+    #[test]
+    fn test_level_timestamps() {
+        let mut book_side: BookSide<i32, i32> = BookSide::new(true);
+        book_side.add_qty(100, 5);
+        book_side.track_level_timestamps();
+        assert_eq!(book_side.level_timestamp(100), None);
+
+        book_side.set_timestamp(1);
+        book_side.add_qty(99, 2);
+        book_side.set_timestamp(2);
+        book_side.add_qty(100, 1);
+        book_side.set_timestamp(3);
+        book_side.delete_qty(99, 1).unwrap();
+        assert_eq!(book_side.level_timestamp(100), Some(2));
+        assert_eq!(book_side.level_timestamp(99), Some(3));
+
+        book_side.delete_qty(99, 1).unwrap();
+        assert_eq!(book_side.level_timestamp(99), None);
+        book_side.clear();
+        assert_eq!(book_side.level_timestamp(100), None);
+    }
+
+    /// A naive reference side: every level in a `BTreeMap`, with the best
+    /// levels found by sorting on each query.
+    struct ReferenceSide {
+        levels: std::collections::BTreeMap<i64, i64>,
+        prefers_higher_prices: bool,
+        max_levels: Option<usize>,
+    }
+
+    impl ReferenceSide {
+        fn best_first(&self) -> Vec<(i64, i64)> {
+            let levels = self.levels.iter().map(|(&price, &qty)| (price, qty));
+            if self.prefers_higher_prices {
+                levels.rev().collect()
+            } else {
+                levels.collect()
+            }
+        }
+
+        fn add_qty(&mut self, price: i64, qty: i64) {
+            let best_first = self.best_first();
+            if let Some(max_levels) = self.max_levels {
+                if best_first.len() >= max_levels && !self.levels.contains_key(&price) {
+                    let (worst, _) = best_first[best_first.len() - 1];
+                    let is_better = if self.prefers_higher_prices {
+                        price > worst
+                    } else {
+                        price < worst
+                    };
+                    if !is_better {
+                        return;
+                    }
+                    self.levels.remove(&worst);
+                }
+            }
+            *self.levels.entry(price).or_insert(0) += qty;
+        }
+
+        fn delete_qty(&mut self, price: i64, qty: i64) {
+            let level = self.levels.get_mut(&price).unwrap();
+            *level -= qty;
+            if *level == 0 {
+                self.levels.remove(&price);
+            }
+        }
+
+        fn sweep(&mut self, mut qty: i64) -> Vec<(i64, i64)> {
+            let mut fills = Vec::new();
+            for (price, level_qty) in self.best_first() {
+                if qty == 0 {
+                    break;
+                }
+                let fill = qty.min(level_qty);
+                self.delete_qty(price, fill);
+                fills.push((price, fill));
+                qty -= fill;
+            }
+            fills
+        }
+    }
+
+    /// Replay random valid updates into a `BookSide` and a `ReferenceSide`,
+    /// for both sides, normal and inverted prices, and with and without
+    /// `max_levels`, checking after every update that they agree on the best
+    /// level, the top levels at several depths and the totals. Prices are
+    /// drawn from a narrow range so that levels are often reused, emptied
+    /// and recreated around the best price.
+    #[test]
+    fn test_matches_reference_side() {
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut random = move |bound: u64| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        for is_bid in [true, false] {
+            for invert_prices in [false, true] {
+                for max_levels in [None, Some(8)] {
+                    let mut book_side = BookSide {
+                        max_levels,
+                        ..if invert_prices {
+                            BookSide::with_inverted_prices(is_bid)
+                        } else {
+                            BookSide::new(is_bid)
+                        }
+                    };
+                    let mut reference = ReferenceSide {
+                        levels: Default::default(),
+                        prefers_higher_prices: is_bid != invert_prices,
+                        max_levels,
+                    };
+                    for step in 0..5000 {
+                        let price = random(40) as i64;
+                        let resting = reference.levels.get(&price).copied();
+                        match (random(10), resting) {
+                            (0, Some(_)) => {
+                                book_side.delete_level(price).unwrap();
+                                reference.levels.remove(&price);
+                            }
+                            (1, _) => {
+                                let qty = 1 + random(30) as i64;
+                                let fills: Vec<_> = book_side
+                                    .immediate_or_cancel(qty)
+                                    .iter()
+                                    .map(|level| (level.price, level.qty))
+                                    .collect();
+                                assert_eq!(fills, reference.sweep(qty), "sweep at step {step}");
+                            }
+                            (2..=4, Some(resting)) => {
+                                let qty = 1 + random(resting as u64) as i64;
+                                book_side.delete_qty(price, qty).unwrap();
+                                reference.delete_qty(price, qty);
+                            }
+                            _ => {
+                                let qty = 1 + random(10) as i64;
+                                book_side.add_qty(price, qty);
+                                reference.add_qty(price, qty);
+                            }
+                        }
+
+                        let best_first = reference.best_first();
+                        assert_eq!(
+                            book_side.best_price.zip(book_side.best_price_qty),
+                            best_first.first().copied(),
+                            "best level at step {step}"
+                        );
+                        for n in [1, 5, 10, 20] {
+                            let top_n: Vec<_> = book_side
+                                .top_n_levels(n)
+                                .iter()
+                                .map(|level| (level.price, level.qty))
+                                .collect();
+                            assert_eq!(
+                                top_n,
+                                best_first[..n.min(best_first.len())],
+                                "top {n} levels at step {step}"
+                            );
+                        }
+                        let levels: Vec<_> = book_side
+                            .iter_levels()
+                            .map(|level| (level.price, level.qty))
+                            .collect();
+                        assert_eq!(levels, best_first, "levels at step {step}");
+                        let in_range: Vec<_> = book_side
+                            .iter_range(10..25)
+                            .map(|level| (level.price, level.qty))
+                            .collect();
+                        let expected_in_range: Vec<_> = best_first
+                            .iter()
+                            .copied()
+                            .filter(|(price, _)| (10..25).contains(price))
+                            .collect();
+                        assert_eq!(in_range, expected_in_range, "range at step {step}");
+                        assert_eq!(book_side.num_levels(), best_first.len());
+                        assert_eq!(
+                            book_side.total_qty(),
+                            best_first.iter().map(|&(_, qty)| qty).sum::<i64>()
+                        );
+                    }
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,306 @@
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use num::traits::Num;
+use thiserror::Error;
+
+use crate::book_side::DeleteError;
+use crate::book_view::BookView;
+use crate::order_book::{NegativeQtyError, OrderBook};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum BookStreamError<Price: Debug, Qty: Debug> {
+    #[error(transparent)]
+    DeleteError(#[from] DeleteError),
+    #[error(transparent)]
+    NegativeQty(#[from] NegativeQtyError<Price, Qty>),
+}
+
+/// One price-level update from a live feed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelUpdate<Price, Qty> {
+    /// Add qty at a price level, creating it if needed.
+    Add {
+        is_bid: bool,
+        price: Price,
+        qty: Qty,
+    },
+    /// Delete qty from a price level, removing it once empty.
+    Delete {
+        is_bid: bool,
+        price: Price,
+        qty: Qty,
+    },
+    /// Set the absolute qty of a price level, as in market-by-price
+    /// snapshot feeds. A qty of zero deletes the level.
+    Set {
+        is_bid: bool,
+        price: Price,
+        qty: Qty,
+    },
+}
+
+/// Applies a stream of `LevelUpdate`s to an order book, yielding a
+/// `BookView` of the best `depth` levels of each side whenever they change.
+/// A depth of 1 yields the best bid and offer. Updates that leave the top of
+/// the book unchanged are applied without yielding anything. Updates behind
+/// the deepest level of the last view can't change it, so only the others
+/// pay for building a new view to compare, which copies up to `depth`
+/// levels of each side.
+///
+/// The input is only polled when the `BookStream` is, so a slow consumer
+/// slows the reads from the feed rather than letting views queue up. It is
+/// runtime agnostic: with tokio, wrap a channel receiver in
+/// `tokio_stream::wrappers::ReceiverStream` to get the input stream.
+///
+/// A delete that can't be applied, or a set to a negative qty, yields its
+/// error and leaves the book unchanged, after which the stream carries on with the next update.
+pub struct BookStream<S, Price, Qty> {
+    updates: S,
+    book: OrderBook<Price, Qty>,
+    depth: usize,
+    last_view: BookView<Price, Qty>,
+}
+
+impl<S, Price: Copy + Debug + Display + Hash + Ord, Qty: Copy + Debug + Display + Num + Ord>
+    BookStream<S, Price, Qty>
+{
+    /// Apply `updates` on top of `book`. Only changes from `book`'s current
+    /// top levels are yielded.
+    pub fn new(updates: S, book: OrderBook<Price, Qty>, depth: usize) -> Self {
+        let last_view = book.view(depth);
+        BookStream {
+            updates,
+            book,
+            depth,
+            last_view,
+        }
+    }
+
+    /// The book as of the last update applied.
+    #[inline]
+    pub fn book(&self) -> &OrderBook<Price, Qty> {
+        &self.book
+    }
+
+    /// The input stream and the book, e.g. to resume from a fresh snapshot.
+    pub fn into_inner(self) -> (S, OrderBook<Price, Qty>) {
+        (self.updates, self.book)
+    }
+
+    /// Whether applying `update` can change the view, i.e. it isn't behind
+    /// the deepest level of a side whose view is full.
+    fn can_change_view(&self, update: &LevelUpdate<Price, Qty>) -> bool {
+        let (LevelUpdate::Add { is_bid, price, .. }
+        | LevelUpdate::Delete { is_bid, price, .. }
+        | LevelUpdate::Set { is_bid, price, .. }) = *update;
+        let levels = self.last_view.top_n(is_bid);
+        match levels.last() {
+            Some(worst) if levels.len() == self.depth => !self
+                .book
+                .get_book_side(is_bid)
+                .is_better_price(worst.price, price),
+            _ => self.depth > 0,
+        }
+    }
+
+    fn apply(
+        &mut self,
+        update: LevelUpdate<Price, Qty>,
+    ) -> Result<(), BookStreamError<Price, Qty>> {
+        match update {
+            LevelUpdate::Add { is_bid, price, qty } => self.book.add_qty(is_bid, price, qty),
+            LevelUpdate::Delete { is_bid, price, qty } => {
+                self.book.try_delete_qty(is_bid, price, qty)?;
+            }
+            LevelUpdate::Set { is_bid, price, qty } => {
+                self.book.set_level(is_bid, price, qty)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S, Price, Qty> Stream for BookStream<S, Price, Qty>
+where
+    S: Stream<Item = LevelUpdate<Price, Qty>> + Unpin,
+    Price: Copy + Debug + Display + Hash + Ord + Unpin,
+    Qty: Copy + Debug + Display + Num + Ord + Unpin,
+{
+    type Item = Result<BookView<Price, Qty>, BookStreamError<Price, Qty>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            let Some(update) = std::task::ready!(Pin::new(&mut this.updates).poll_next(cx)) else {
+                return Poll::Ready(None);
+            };
+            let can_change_view = this.can_change_view(&update);
+            if let Err(e) = this.apply(update) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            if !can_change_view {
+                continue;
+            }
+            let view = this.book.view(this.depth);
+            if view != this.last_view {
+                this.last_view = view.clone();
+                return Poll::Ready(Some(Ok(view)));
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.updates.size_hint().1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::task::Waker;
+
+    use super::*;
+    use crate::book_side::LevelError;
+    use crate::price_level::PriceLevel;
+
+    /// Yields its updates one at a time, returning `Pending` whenever it
+    /// reaches a `None`, like a feed waiting for the next message.
+    struct FeedStream(VecDeque<Option<LevelUpdate<i64, i64>>>);
+
+    impl Stream for FeedStream {
+        type Item = LevelUpdate<i64, i64>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            match self.0.pop_front() {
+                Some(Some(update)) => Poll::Ready(Some(update)),
+                Some(None) => Poll::Pending,
+                None => Poll::Ready(None),
+            }
+        }
+    }
+
+    fn poll(
+        stream: &mut BookStream<FeedStream, i64, i64>,
+    ) -> Poll<Option<<BookStream<FeedStream, i64, i64> as Stream>::Item>> {
+        Pin::new(stream).poll_next(&mut Context::from_waker(Waker::noop()))
+    }
+
+    fn bbo(bid: (i64, i64), ask: (i64, i64)) -> BookView<i64, i64> {
+        BookView::new(
+            vec![PriceLevel {
+                price: bid.0,
+                qty: bid.1,
+            }],
+            vec![PriceLevel {
+                price: ask.0,
+                qty: ask.1,
+            }],
+        )
+    }
+
+    #[test]
+    fn test_yields_top_of_book_changes() {
+        let book = OrderBook::from_levels(&[(100, 5)], &[(101, 5)]).unwrap();
+        let feed = FeedStream(VecDeque::from([
+            Some(LevelUpdate::Add {
+                is_bid: true,
+                price: 99,
+                qty: 1,
+            }),
+            Some(LevelUpdate::Add {
+                is_bid: true,
+                price: 100,
+                qty: 1,
+            }),
+            None,
+            Some(LevelUpdate::Delete {
+                is_bid: false,
+                price: 102,
+                qty: 1,
+            }),
+            Some(LevelUpdate::Set {
+                is_bid: false,
+                price: 101,
+                qty: 2,
+            }),
+            Some(LevelUpdate::Set {
+                is_bid: true,
+                price: 100,
+                qty: -1,
+            }),
+        ]));
+        let mut stream = BookStream::new(feed, book, 1);
+
+        // The add at 99 is below the best bid, so only the second add yields.
+        assert_eq!(
+            poll(&mut stream),
+            Poll::Ready(Some(Ok(bbo((100, 6), (101, 5)))))
+        );
+        assert_eq!(poll(&mut stream), Poll::Pending);
+        assert_eq!(
+            poll(&mut stream),
+            Poll::Ready(Some(Err(BookStreamError::DeleteError(
+                DeleteError::LevelError(LevelError::LevelNotFound)
+            ))))
+        );
+        assert_eq!(
+            poll(&mut stream),
+            Poll::Ready(Some(Ok(bbo((100, 6), (101, 2)))))
+        );
+        assert_eq!(
+            poll(&mut stream),
+            Poll::Ready(Some(Err(BookStreamError::NegativeQty(NegativeQtyError {
+                price: 100,
+                qty: -1
+            }))))
+        );
+        assert_eq!(poll(&mut stream), Poll::Ready(None));
+        assert_eq!(stream.book().get_book_side(true).num_levels(), 2);
+    }
+
+    #[test]
+    fn test_updates_behind_the_view() {
+        let book = OrderBook::from_levels(&[(100, 5), (99, 2)], &[(101, 5)]).unwrap();
+        let feed = FeedStream(VecDeque::from([
+            Some(LevelUpdate::Add {
+                is_bid: true,
+                price: 98,
+                qty: 1,
+            }),
+            Some(LevelUpdate::Delete {
+                is_bid: true,
+                price: 98,
+                qty: 1,
+            }),
+            Some(LevelUpdate::Add {
+                is_bid: false,
+                price: 102,
+                qty: 3,
+            }),
+            Some(LevelUpdate::Delete {
+                is_bid: true,
+                price: 99,
+                qty: 2,
+            }),
+        ]));
+        let mut stream = BookStream::new(feed, book, 2);
+
+        // Only the ask side has room for another level and the delete at 99
+        // is within the view, so the updates at 98 yield nothing.
+        let view = match poll(&mut stream) {
+            Poll::Ready(Some(Ok(view))) => view,
+            other => panic!("expected a view, got {:?}", other),
+        };
+        assert_eq!(view.top_n(false).len(), 2);
+        let view = match poll(&mut stream) {
+            Poll::Ready(Some(Ok(view))) => view,
+            other => panic!("expected a view, got {:?}", other),
+        };
+        assert_eq!(view.top_n(true).len(), 1);
+        assert_eq!(poll(&mut stream), Poll::Ready(None));
+    }
+}
@@ -0,0 +1,37 @@
+use crate::price_level::PriceLevel;
+
+/// An immutable copy of the top of an order book, taken by
+/// `OrderBook::view`. It owns its levels, so it can be handed to readers
+/// while the book keeps being updated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookView<Price, Qty> {
+    bids: Vec<PriceLevel<Price, Qty>>,
+    asks: Vec<PriceLevel<Price, Qty>>,
+}
+
+impl<Price, Qty> BookView<Price, Qty> {
+    /// `bids` and `asks` must each be sorted from best to worst.
+    pub fn new(bids: Vec<PriceLevel<Price, Qty>>, asks: Vec<PriceLevel<Price, Qty>>) -> Self {
+        BookView { bids, asks }
+    }
+
+    #[inline]
+    pub fn best_bid(&self) -> Option<&PriceLevel<Price, Qty>> {
+        self.bids.first()
+    }
+
+    #[inline]
+    pub fn best_ask(&self) -> Option<&PriceLevel<Price, Qty>> {
+        self.asks.first()
+    }
+
+    /// Levels of one side, sorted from best to worst.
+    #[inline]
+    pub fn top_n(&self, is_bid: bool) -> &[PriceLevel<Price, Qty>] {
+        if is_bid {
+            &self.bids
+        } else {
+            &self.asks
+        }
+    }
+}
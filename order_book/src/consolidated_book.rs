@@ -0,0 +1,175 @@
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+
+use hashbrown::HashMap;
+use num::traits::Num;
+
+use crate::book_side::DeleteError;
+use crate::order_book::OrderBook;
+
+/// The best price on one side across every venue of a `ConsolidatedBook`,
+/// with the qty resting at it summed over the venues that are at it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsolidatedLevel<Venue, Price, Qty> {
+    pub price: Price,
+    pub qty: Qty,
+    /// The venues at the best price, sorted.
+    pub venues: Vec<Venue>,
+}
+
+/// A consolidated book merging the price-level books of several venues, e.g.
+/// to replay a combined feed and track the national best bid and offer. Each
+/// venue keeps its own `OrderBook`, so updates only touch their venue's
+/// levels, and the best prices are found across venues when queried.
+///
+/// Levels at the same price on different venues are kept apart rather than
+/// merged, so the venues at the best price can be attributed. Books of
+/// different venues may lock or cross each other.
+pub struct ConsolidatedBook<Venue, Price, Qty> {
+    venues: HashMap<Venue, OrderBook<Price, Qty>>,
+}
+
+impl<
+        Venue: Clone + Eq + Hash + Ord,
+        Price: Copy + Debug + Display + Hash + Ord,
+        Qty: Copy + Debug + Display + Num + Ord,
+    > Default for ConsolidatedBook<Venue, Price, Qty>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<
+        Venue: Clone + Eq + Hash + Ord,
+        Price: Copy + Debug + Display + Hash + Ord,
+        Qty: Copy + Debug + Display + Num + Ord,
+    > ConsolidatedBook<Venue, Price, Qty>
+{
+    pub fn new() -> Self {
+        ConsolidatedBook {
+            venues: HashMap::new(),
+        }
+    }
+
+    /// The book of one venue, or `None` if it hasn't had any updates.
+    #[inline]
+    pub fn venue_book(&self, venue: &Venue) -> Option<&OrderBook<Price, Qty>> {
+        self.venues.get(venue)
+    }
+
+    /// The book of one venue, created empty on its first update.
+    #[inline]
+    pub fn venue_book_mut(&mut self, venue: Venue) -> &mut OrderBook<Price, Qty> {
+        self.venues.entry(venue).or_default()
+    }
+
+    pub fn add_qty(&mut self, venue: Venue, is_bid: bool, price: Price, qty: Qty) {
+        self.venue_book_mut(venue).add_qty(is_bid, price, qty);
+    }
+
+    pub fn try_delete_qty(
+        &mut self,
+        venue: Venue,
+        is_bid: bool,
+        price: Price,
+        qty: Qty,
+    ) -> Result<(), DeleteError> {
+        self.venue_book_mut(venue)
+            .try_delete_qty(is_bid, price, qty)
+            .map(|_| ())
+    }
+
+    /// Remove every level of one venue, e.g. when its feed disconnects.
+    pub fn clear_venue(&mut self, venue: &Venue) {
+        if let Some(book) = self.venues.get_mut(venue) {
+            book.clear();
+        }
+    }
+
+    /// The best price on one side across all venues, the total qty at it and
+    /// the venues at it, or `None` if every venue's side is empty.
+    pub fn best_level(&self, is_bid: bool) -> Option<ConsolidatedLevel<Venue, Price, Qty>> {
+        let mut best: Option<ConsolidatedLevel<Venue, Price, Qty>> = None;
+        for (venue, book) in &self.venues {
+            let side = book.get_book_side(is_bid);
+            let Some((price, qty)) = side.best_price.zip(side.best_price_qty) else {
+                continue;
+            };
+            match &mut best {
+                Some(best) if best.price == price => {
+                    best.qty = best.qty + qty;
+                    best.venues.push(venue.clone());
+                }
+                Some(best) if !side.is_better_price(price, best.price) => {}
+                _ => {
+                    best = Some(ConsolidatedLevel {
+                        price,
+                        qty,
+                        venues: vec![venue.clone()],
+                    })
+                }
+            }
+        }
+        if let Some(best) = &mut best {
+            best.venues.sort_unstable();
+        }
+        best
+    }
+
+    /// Whether the best bid of one venue is at or through the best ask of
+    /// another, or of the same venue.
+    pub fn is_crossed(&self) -> bool {
+        match (self.best_level(true), self.best_level(false)) {
+            (Some(bid), Some(ask)) => bid.price >= ask.price,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book_side::LevelError;
+
+    #[test]
+    fn test_best_level_across_venues() {
+        let mut book = ConsolidatedBook::new();
+        assert_eq!(book.best_level(true), None);
+
+        book.add_qty("a", true, 100, 5);
+        book.add_qty("b", true, 101, 2);
+        book.add_qty("c", true, 101, 3);
+        book.add_qty("a", false, 103, 1);
+        book.add_qty("b", false, 102, 4);
+        assert_eq!(
+            book.best_level(true),
+            Some(ConsolidatedLevel {
+                price: 101,
+                qty: 5,
+                venues: vec!["b", "c"],
+            })
+        );
+        assert_eq!(
+            book.best_level(false),
+            Some(ConsolidatedLevel {
+                price: 102,
+                qty: 4,
+                venues: vec!["b"],
+            })
+        );
+        assert!(!book.is_crossed());
+
+        book.try_delete_qty("b", true, 101, 2).unwrap();
+        assert_eq!(book.best_level(true).unwrap().venues, vec!["c"]);
+        assert_eq!(
+            book.try_delete_qty("a", true, 101, 1),
+            Err(DeleteError::LevelError(LevelError::LevelNotFound))
+        );
+
+        book.clear_venue(&"c");
+        book.add_qty("a", true, 103, 1);
+        assert_eq!(book.best_level(true).unwrap().price, 103);
+        assert!(book.is_crossed());
+    }
+}
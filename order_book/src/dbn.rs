@@ -0,0 +1,315 @@
+use std::io::{self, Read};
+
+use thiserror::Error;
+
+use crate::order_book::OrderBook;
+use crate::order_book_with_orders::{OrderBookWithOrders, OrderError};
+
+/// Databento's sentinel for a missing price.
+pub const UNDEF_PRICE: i64 = i64::MAX;
+
+const MBP_1_RTYPE: u8 = 0x01;
+const MBP_10_RTYPE: u8 = 0x0A;
+const MBO_RTYPE: u8 = 0xA0;
+
+#[derive(Error, Debug)]
+pub enum DbnError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Not an uncompressed DBN stream")]
+    InvalidHeader,
+    #[error("Record of rtype {rtype:#04x} is {len} bytes, expected at least {expected}")]
+    Truncated {
+        rtype: u8,
+        len: usize,
+        expected: usize,
+    },
+    #[error("Stream ended inside a record")]
+    UnexpectedEof,
+}
+
+/// One price level of an MBP record. Prices are in units of 1e-9 and are
+/// `UNDEF_PRICE` when the side is empty at that depth. The counts are the
+/// number of orders resting at each level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BidAskPair {
+    pub bid_px: i64,
+    pub ask_px: i64,
+    pub bid_sz: u32,
+    pub ask_sz: u32,
+    pub bid_ct: u32,
+    pub ask_ct: u32,
+}
+
+/// The Databento DBN records that affect the order book.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DbnRecord {
+    /// An `mbo` record: one event for a single order.
+    Mbo {
+        instrument_id: u32,
+        ts_event: u64,
+        order_id: u64,
+        price: i64,
+        size: u32,
+        action: u8,
+        side: u8,
+    },
+    /// An `mbp-1` or `mbp-10` record, carrying the top levels of the book
+    /// after the event.
+    Mbp {
+        instrument_id: u32,
+        ts_event: u64,
+        levels: Vec<BidAskPair>,
+    },
+    /// Any other record type, which leaves the book unchanged.
+    Other { rtype: u8 },
+}
+
+impl DbnRecord {
+    /// Parse a single record, including its header.
+    pub fn parse(buf: &[u8]) -> Result<Self, DbnError> {
+        let rtype = buf.get(1).copied().unwrap_or_default();
+        let expected = match rtype {
+            MBO_RTYPE => 56,
+            MBP_1_RTYPE => 80,
+            MBP_10_RTYPE => 368,
+            _ => return Ok(DbnRecord::Other { rtype }),
+        };
+        if buf.len() < expected {
+            return Err(DbnError::Truncated {
+                rtype,
+                len: buf.len(),
+                expected,
+            });
+        }
+
+        let instrument_id = le_u32(&buf[4..8]);
+        let ts_event = le_u64(&buf[8..16]);
+        let record = match rtype {
+            MBO_RTYPE => DbnRecord::Mbo {
+                instrument_id,
+                ts_event,
+                order_id: le_u64(&buf[16..24]),
+                price: le_u64(&buf[24..32]) as i64,
+                size: le_u32(&buf[32..36]),
+                action: buf[38],
+                side: buf[39],
+            },
+            _ => DbnRecord::Mbp {
+                instrument_id,
+                ts_event,
+                levels: buf[48..expected]
+                    .chunks_exact(32)
+                    .map(|level| BidAskPair {
+                        bid_px: le_u64(&level[0..8]) as i64,
+                        ask_px: le_u64(&level[8..16]) as i64,
+                        bid_sz: le_u32(&level[16..20]),
+                        ask_sz: le_u32(&level[20..24]),
+                        bid_ct: le_u32(&level[24..28]),
+                        ask_ct: le_u32(&level[28..32]),
+                    })
+                    .collect(),
+            },
+        };
+        Ok(record)
+    }
+
+    pub fn instrument_id(&self) -> Option<u32> {
+        match *self {
+            DbnRecord::Mbo { instrument_id, .. } | DbnRecord::Mbp { instrument_id, .. } => {
+                Some(instrument_id)
+            }
+            DbnRecord::Other { .. } => None,
+        }
+    }
+
+    pub fn ts_event(&self) -> Option<u64> {
+        match *self {
+            DbnRecord::Mbo { ts_event, .. } | DbnRecord::Mbp { ts_event, .. } => Some(ts_event),
+            DbnRecord::Other { .. } => None,
+        }
+    }
+}
+
+/// Apply an `mbo` event to `book`, following Databento's conventions: "A"
+/// adds an order, "C" cancels `size` of it, "M" moves it to a new price and
+/// size, and "R" clears the book. Trades, fills and other actions leave the
+/// book unchanged, as the resting orders are updated by their own events.
+pub fn apply_mbo(
+    book: &mut OrderBookWithOrders<i64, i64, u64>,
+    order_id: u64,
+    price: i64,
+    size: u32,
+    action: u8,
+    side: u8,
+) -> Result<(), OrderError> {
+    match action {
+        b'A' => book.add_order(order_id, side == b'B', price, size.into()),
+        b'C' => book.execute_order(&order_id, size.into()),
+        b'M' => book.replace_order(&order_id, price, size.into()),
+        b'R' => {
+            book.clear();
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Reset `book` to the levels of an `mbp` record.
+pub fn apply_mbp(book: &mut OrderBook<i64, i64>, levels: &[BidAskPair]) {
+    book.clear();
+    for level in levels {
+        if level.bid_px != UNDEF_PRICE && level.bid_sz > 0 {
+            book.add_qty(true, level.bid_px, level.bid_sz.into());
+        }
+        if level.ask_px != UNDEF_PRICE && level.ask_sz > 0 {
+            book.add_qty(false, level.ask_px, level.ask_sz.into());
+        }
+    }
+}
+
+/// Reads records from an uncompressed DBN stream, skipping its metadata.
+/// Zstandard-compressed `.dbn.zst` files need decompressing first.
+pub struct DbnReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> DbnReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, DbnError> {
+        let mut prelude = [0u8; 8];
+        reader.read_exact(&mut prelude)?;
+        if &prelude[..3] != b"DBN" {
+            return Err(DbnError::InvalidHeader);
+        }
+        let metadata_len = le_u32(&prelude[4..8]) as u64;
+        io::copy(&mut (&mut reader).take(metadata_len), &mut io::sink())?;
+        Ok(DbnReader {
+            reader,
+            buf: Vec::new(),
+        })
+    }
+}
+
+impl<R: Read> Iterator for DbnReader<R> {
+    type Item = Result<DbnRecord, DbnError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut length = [0u8; 1];
+        match self.reader.read(&mut length) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e.into())),
+        }
+        // The first byte of each record is its length in 4-byte words.
+        if length[0] == 0 {
+            return Some(Err(DbnError::Truncated {
+                rtype: 0,
+                len: 0,
+                expected: 16,
+            }));
+        }
+        self.buf.resize(length[0] as usize * 4, 0);
+        self.buf[0] = length[0];
+        match self.reader.read_exact(&mut self.buf[1..]) {
+            Ok(()) => Some(DbnRecord::parse(&self.buf)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Some(Err(DbnError::UnexpectedEof))
+            }
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(len: usize, rtype: u8, instrument_id: u32, ts_event: u64) -> Vec<u8> {
+        let mut buf = vec![(len / 4) as u8, rtype, 1, 0];
+        buf.extend_from_slice(&instrument_id.to_le_bytes());
+        buf.extend_from_slice(&ts_event.to_le_bytes());
+        buf
+    }
+
+    fn mbo(order_id: u64, price: i64, size: u32, action: u8, side: u8) -> Vec<u8> {
+        let mut buf = header(56, MBO_RTYPE, 7, 100);
+        buf.extend_from_slice(&order_id.to_le_bytes());
+        buf.extend_from_slice(&price.to_le_bytes());
+        buf.extend_from_slice(&size.to_le_bytes());
+        buf.extend_from_slice(&[0, 0, action, side]);
+        buf.resize(56, 0);
+        buf
+    }
+
+    fn stream(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = b"DBN\x02".to_vec();
+        buf.extend_from_slice(&3u32.to_le_bytes());
+        buf.extend_from_slice(b"xyz");
+        for record in records {
+            buf.extend_from_slice(record);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_read_and_apply_mbo() {
+        let data = stream(&[
+            mbo(1, 100, 5, b'A', b'B'),
+            mbo(2, 102, 3, b'A', b'A'),
+            mbo(1, 101, 4, b'M', b'B'),
+            mbo(2, 102, 1, b'T', b'B'),
+            mbo(2, 102, 1, b'C', b'A'),
+        ]);
+        let mut book = OrderBookWithOrders::new();
+        for record in DbnReader::new(data.as_slice()).unwrap() {
+            if let DbnRecord::Mbo {
+                order_id,
+                price,
+                size,
+                action,
+                side,
+                ..
+            } = record.unwrap()
+            {
+                apply_mbo(&mut book, order_id, price, size, action, side).unwrap();
+            }
+        }
+        assert_eq!(book.book().best_bid_and_ask(), Some((101, 4, 102, 2)));
+    }
+
+    #[test]
+    fn test_parse_mbp_1() {
+        let mut buf = header(80, MBP_1_RTYPE, 7, 100);
+        buf.resize(48, 0);
+        buf.extend_from_slice(&100i64.to_le_bytes());
+        buf.extend_from_slice(&UNDEF_PRICE.to_le_bytes());
+        buf.extend_from_slice(&5u32.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        buf.resize(80, 0);
+
+        let DbnRecord::Mbp { levels, .. } = DbnRecord::parse(&buf).unwrap() else {
+            panic!("Expected an MBP record");
+        };
+        assert_eq!((levels[0].bid_ct, levels[0].ask_ct), (2, 0));
+        let mut book = OrderBook::new();
+        apply_mbp(&mut book, &levels);
+        assert_eq!(book.get_book_side(true).best_price, Some(100));
+        assert_eq!(book.get_book_side(false).best_price, None);
+        assert!(DbnRecord::parse(&buf[..60]).is_err());
+    }
+
+    #[test]
+    fn test_reader_rejects_other_formats() {
+        assert!(DbnReader::new(b"ITCH1234".as_slice()).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+
+use num::traits::Num;
+use thiserror::Error;
+
+use crate::order_book::{InitialStateError, NegativeQtyError, OrderBook};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DepthStreamError {
+    #[error("First event {first_update_id}..={last_update_id} does not cover the snapshot's next update {expected}")]
+    SnapshotNotCovered {
+        expected: u64,
+        first_update_id: u64,
+        last_update_id: u64,
+    },
+    #[error("Gap in update ids: expected {expected}, got event starting at {first_update_id}")]
+    Gap { expected: u64, first_update_id: u64 },
+    #[error("Event {first_update_id}..={last_update_id} has a negative qty")]
+    NegativeQty {
+        first_update_id: u64,
+        last_update_id: u64,
+    },
+}
+
+/// A book rebuilt from a REST depth snapshot plus a stream of diff-depth
+/// events, as published by Binance and similar venues. Each event carries
+/// the absolute qty of every level that changed between its first and last
+/// update ids, and is applied following the venue's resynchronization rules:
+///
+/// - Events whose last update id is at or before the snapshot's are stale
+///   and dropped.
+/// - The first event applied must span the update after the snapshot.
+/// - Every later event must start right after the previous one ended.
+///
+/// A violation means updates were missed and the book must be rebuilt from
+/// a fresh snapshot.
+pub struct DepthStreamBook<Price, Qty> {
+    book: OrderBook<Price, Qty>,
+    last_update_id: u64,
+    synced: bool,
+}
+
+impl<Price: Copy + Debug + Display + Hash + Ord, Qty: Copy + Debug + Display + Num + Ord>
+    DepthStreamBook<Price, Qty>
+{
+    pub fn new(
+        bids: &[(Price, Qty)],
+        asks: &[(Price, Qty)],
+        last_update_id: u64,
+    ) -> Result<Self, InitialStateError> {
+        Ok(DepthStreamBook {
+            book: OrderBook::from_levels(bids, asks)?,
+            last_update_id,
+            synced: false,
+        })
+    }
+
+    #[inline]
+    pub fn book(&self) -> &OrderBook<Price, Qty> {
+        &self.book
+    }
+
+    /// Mutable access to the book for adjustments that aren't part of the
+    /// stream, e.g. uncrossing it.
+    #[inline]
+    pub fn book_mut(&mut self) -> &mut OrderBook<Price, Qty> {
+        &mut self.book
+    }
+
+    /// The last update id reflected in the book.
+    #[inline]
+    pub fn last_update_id(&self) -> u64 {
+        self.last_update_id
+    }
+
+    /// Check the update ids of the next event, returning whether its levels
+    /// should be applied (false if it is stale). On success the book is
+    /// considered up to date with `last_update_id`.
+    pub fn begin_event(
+        &mut self,
+        first_update_id: u64,
+        last_update_id: u64,
+    ) -> Result<bool, DepthStreamError> {
+        let expected = self.last_update_id + 1;
+        if !self.synced {
+            if last_update_id < expected {
+                return Ok(false);
+            }
+            if first_update_id > expected {
+                return Err(DepthStreamError::SnapshotNotCovered {
+                    expected,
+                    first_update_id,
+                    last_update_id,
+                });
+            }
+            self.synced = true;
+        } else if first_update_id != expected {
+            return Err(DepthStreamError::Gap {
+                expected,
+                first_update_id,
+            });
+        }
+        self.last_update_id = last_update_id;
+        Ok(true)
+    }
+
+    /// Set the absolute qty of a level of the current event. A qty of zero
+    /// removes the level, and a negative qty fails without changing it.
+    pub fn set_level(
+        &mut self,
+        is_bid: bool,
+        price: Price,
+        qty: Qty,
+    ) -> Result<(), NegativeQtyError<Price, Qty>> {
+        self.book.set_level(is_bid, price, qty)
+    }
+
+    /// Apply a whole event, returning whether it was applied (false if it
+    /// was stale). An event with a negative qty fails before any of its
+    /// levels are applied.
+    pub fn apply_event(
+        &mut self,
+        first_update_id: u64,
+        last_update_id: u64,
+        bids: &[(Price, Qty)],
+        asks: &[(Price, Qty)],
+    ) -> Result<bool, DepthStreamError> {
+        if bids.iter().chain(asks).any(|&(_, qty)| qty < Qty::zero()) {
+            return Err(DepthStreamError::NegativeQty {
+                first_update_id,
+                last_update_id,
+            });
+        }
+        if !self.begin_event(first_update_id, last_update_id)? {
+            return Ok(false);
+        }
+        for (is_bid, levels) in [(true, bids), (false, asks)] {
+            for &(price, qty) in levels {
+                self.book
+                    .set_level(is_bid, price, qty)
+                    .expect("apply_event: qtys were checked to be non-negative");
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_events_after_snapshot() {
+        let mut book = DepthStreamBook::new(&[(100, 5)], &[(102, 3)], 10).unwrap();
+
+        assert_eq!(book.apply_event(5, 9, &[(100, 1)], &[]), Ok(false));
+        assert_eq!(book.apply_event(8, 12, &[(100, 0), (99, 2)], &[]), Ok(true));
+        assert_eq!(book.apply_event(13, 13, &[], &[(101, 4)]), Ok(true));
+        assert_eq!(book.last_update_id(), 13);
+        assert_eq!(book.book().best_bid_and_ask(), Some((99, 2, 101, 4)));
+
+        assert_eq!(
+            book.apply_event(15, 16, &[], &[]),
+            Err(DepthStreamError::Gap {
+                expected: 14,
+                first_update_id: 15
+            })
+        );
+    }
+
+    #[test]
+    fn test_first_event_must_cover_snapshot() {
+        let mut book: DepthStreamBook<i32, i32> = DepthStreamBook::new(&[], &[], 10).unwrap();
+        assert_eq!(
+            book.apply_event(12, 14, &[], &[]),
+            Err(DepthStreamError::SnapshotNotCovered {
+                expected: 11,
+                first_update_id: 12,
+                last_update_id: 14
+            })
+        );
+    }
+
+    #[test]
+    fn test_negative_qty_fails_the_whole_event() {
+        let mut book = DepthStreamBook::new(&[(100, 5)], &[(102, 3)], 10).unwrap();
+        assert_eq!(
+            book.apply_event(11, 11, &[(99, 2)], &[(103, -1)]),
+            Err(DepthStreamError::NegativeQty {
+                first_update_id: 11,
+                last_update_id: 11
+            })
+        );
+        assert_eq!(book.last_update_id(), 10);
+        assert_eq!(book.book().get_book_side(true).num_levels(), 1);
+        assert_eq!(
+            book.set_level(true, 100, -2),
+            Err(NegativeQtyError {
+                price: 100,
+                qty: -2
+            })
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+
+use num::traits::Num;
+
+use crate::book_side::DeleteError;
+use crate::order_book::OrderBook;
+
+/// One of the three books of a calendar spread: the two outright futures
+/// and the spread between them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Contract {
+    /// The nearer expiry, the spread's first leg.
+    Front,
+    /// The further expiry, the spread's second leg.
+    Back,
+    /// The calendar spread, priced as front minus back. Buying the spread
+    /// buys the front and sells the back.
+    Spread,
+}
+
+/// The best price on one side of a contract once implied liquidity is merged
+/// in. `qty` is the total at `price`, of which `implied_qty` is implied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImpliedLevel<Price, Qty> {
+    pub price: Price,
+    pub qty: Qty,
+    pub implied_qty: Qty,
+}
+
+/// The books of two outright futures and their calendar spread, with the
+/// liquidity each pair of books implies in the third, as exchanges like CME
+/// publish it.
+///
+/// A resting spread bid and a back-month bid together imply a front-month
+/// bid at their price sum (implied in), and outright bids and offers imply
+/// spread prices (implied out):
+///
+/// * front bid = spread bid + back bid, front ask = spread ask + back ask
+/// * back bid = front bid - spread ask, back ask = front ask - spread bid
+/// * spread bid = front bid - back ask, spread ask = front ask - back bid
+///
+/// The implied qty is the smaller of the two qtys it is made of. Only first
+/// generation implieds from the best levels of the outright books are
+/// derived: implied prices aren't fed back into further implieds.
+pub struct ImpliedBook<Price, Qty> {
+    front: OrderBook<Price, Qty>,
+    back: OrderBook<Price, Qty>,
+    spread: OrderBook<Price, Qty>,
+}
+
+impl<Price: Copy + Debug + Display + Hash + Num + Ord, Qty: Copy + Debug + Display + Num + Ord>
+    Default for ImpliedBook<Price, Qty>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Price: Copy + Debug + Display + Hash + Num + Ord, Qty: Copy + Debug + Display + Num + Ord>
+    ImpliedBook<Price, Qty>
+{
+    pub fn new() -> Self {
+        ImpliedBook {
+            front: OrderBook::new(),
+            back: OrderBook::new(),
+            spread: OrderBook::new(),
+        }
+    }
+
+    /// The outright book of one contract, without implied liquidity.
+    #[inline]
+    pub fn book(&self, contract: Contract) -> &OrderBook<Price, Qty> {
+        match contract {
+            Contract::Front => &self.front,
+            Contract::Back => &self.back,
+            Contract::Spread => &self.spread,
+        }
+    }
+
+    #[inline]
+    pub fn book_mut(&mut self, contract: Contract) -> &mut OrderBook<Price, Qty> {
+        match contract {
+            Contract::Front => &mut self.front,
+            Contract::Back => &mut self.back,
+            Contract::Spread => &mut self.spread,
+        }
+    }
+
+    pub fn add_qty(&mut self, contract: Contract, is_bid: bool, price: Price, qty: Qty) {
+        self.book_mut(contract).add_qty(is_bid, price, qty);
+    }
+
+    pub fn try_delete_qty(
+        &mut self,
+        contract: Contract,
+        is_bid: bool,
+        price: Price,
+        qty: Qty,
+    ) -> Result<(), DeleteError> {
+        self.book_mut(contract)
+            .try_delete_qty(is_bid, price, qty)
+            .map(|_| ())
+    }
+
+    /// The best implied price and qty on one side of a contract, or `None`
+    /// if either of the books it is implied from is empty on the side needed.
+    pub fn implied_best(&self, contract: Contract, is_bid: bool) -> Option<(Price, Qty)> {
+        let best = |contract: Contract, is_bid: bool| {
+            let side = self.book(contract).get_book_side(is_bid);
+            side.best_price.zip(side.best_price_qty)
+        };
+        let (price, first_qty, second_qty) = match contract {
+            Contract::Front => {
+                let (spread, spread_qty) = best(Contract::Spread, is_bid)?;
+                let (back, back_qty) = best(Contract::Back, is_bid)?;
+                (spread + back, spread_qty, back_qty)
+            }
+            Contract::Back => {
+                let (front, front_qty) = best(Contract::Front, is_bid)?;
+                let (spread, spread_qty) = best(Contract::Spread, !is_bid)?;
+                (front - spread, front_qty, spread_qty)
+            }
+            Contract::Spread => {
+                let (front, front_qty) = best(Contract::Front, is_bid)?;
+                let (back, back_qty) = best(Contract::Back, !is_bid)?;
+                (front - back, front_qty, back_qty)
+            }
+        };
+        Some((price, first_qty.min(second_qty)))
+    }
+
+    /// The best price on one side of a contract merging its outright book
+    /// with the implied best, the qty of both summed when they share a price.
+    pub fn best_level(&self, contract: Contract, is_bid: bool) -> Option<ImpliedLevel<Price, Qty>> {
+        let side = self.book(contract).get_book_side(is_bid);
+        let outright = side.best_price.zip(side.best_price_qty);
+        match (outright, self.implied_best(contract, is_bid)) {
+            (Some((price, qty)), Some((implied_price, implied_qty))) if price == implied_price => {
+                Some(ImpliedLevel {
+                    price,
+                    qty: qty + implied_qty,
+                    implied_qty,
+                })
+            }
+            (Some((price, qty)), Some((implied_price, implied_qty))) => {
+                if side.is_better_price(price, implied_price) {
+                    Some(ImpliedLevel {
+                        price,
+                        qty,
+                        implied_qty: Qty::zero(),
+                    })
+                } else {
+                    Some(ImpliedLevel {
+                        price: implied_price,
+                        qty: implied_qty,
+                        implied_qty,
+                    })
+                }
+            }
+            (Some((price, qty)), None) => Some(ImpliedLevel {
+                price,
+                qty,
+                implied_qty: Qty::zero(),
+            }),
+            (None, Some((price, qty))) => Some(ImpliedLevel {
+                price,
+                qty,
+                implied_qty: qty,
+            }),
+            (None, None) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_implied_in_and_out() {
+        let mut book = ImpliedBook::new();
+        assert_eq!(book.best_level(Contract::Front, true), None);
+
+        book.add_qty(Contract::Spread, true, -5, 4);
+        book.add_qty(Contract::Back, true, 100, 3);
+        assert_eq!(book.implied_best(Contract::Front, true), Some((95, 3)));
+        assert_eq!(
+            book.best_level(Contract::Front, true),
+            Some(ImpliedLevel {
+                price: 95,
+                qty: 3,
+                implied_qty: 3,
+            })
+        );
+
+        book.add_qty(Contract::Front, true, 95, 2);
+        assert_eq!(book.best_level(Contract::Front, true).unwrap().qty, 5);
+        book.add_qty(Contract::Front, true, 96, 1);
+        assert_eq!(
+            book.best_level(Contract::Front, true),
+            Some(ImpliedLevel {
+                price: 96,
+                qty: 1,
+                implied_qty: 0,
+            })
+        );
+
+        book.add_qty(Contract::Back, false, 102, 6);
+        assert_eq!(book.implied_best(Contract::Spread, true), Some((-6, 1)));
+        assert_eq!(book.best_level(Contract::Spread, true).unwrap().price, -5);
+        book.add_qty(Contract::Front, false, 98, 2);
+        assert_eq!(book.implied_best(Contract::Back, false), Some((103, 2)));
+        assert_eq!(book.best_level(Contract::Back, false).unwrap().price, 102);
+
+        book.try_delete_qty(Contract::Spread, true, -5, 4).unwrap();
+        assert_eq!(book.implied_best(Contract::Front, true), None);
+        assert_eq!(book.implied_best(Contract::Back, false), None);
+    }
+}
@@ -0,0 +1,407 @@
+use std::io::{self, Read};
+
+use thiserror::Error;
+
+use crate::order_book_with_orders::{OrderBookWithOrders, OrderError};
+
+#[derive(Error, Debug)]
+pub enum ItchError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Message of type {msg_type:?} is {len} bytes, expected at least {expected}")]
+    Truncated {
+        msg_type: char,
+        len: usize,
+        expected: usize,
+    },
+    #[error("Stream ended inside a message")]
+    UnexpectedEof,
+}
+
+/// The NASDAQ TotalView-ITCH 5.0 messages that affect the order book.
+/// Prices are in units of 1/10000 of a dollar and timestamps in nanoseconds
+/// since midnight, as on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItchMessage {
+    /// `R`: maps a stock symbol to the locate code used by later messages.
+    StockDirectory { stock_locate: u16, stock: [u8; 8] },
+    /// `A` and `F`: a new order resting on the book.
+    AddOrder {
+        stock_locate: u16,
+        timestamp: u64,
+        order_ref: u64,
+        is_bid: bool,
+        shares: u32,
+        stock: [u8; 8],
+        price: u32,
+    },
+    /// `E` and `C`: part or all of an order was executed.
+    OrderExecuted {
+        stock_locate: u16,
+        timestamp: u64,
+        order_ref: u64,
+        shares: u32,
+    },
+    /// `X`: part of an order was cancelled.
+    OrderCancel {
+        stock_locate: u16,
+        timestamp: u64,
+        order_ref: u64,
+        shares: u32,
+    },
+    /// `D`: the rest of an order was cancelled.
+    OrderDelete {
+        stock_locate: u16,
+        timestamp: u64,
+        order_ref: u64,
+    },
+    /// `U`: an order was cancelled and replaced by a new one on the same
+    /// side under a new reference number.
+    OrderReplace {
+        stock_locate: u16,
+        timestamp: u64,
+        order_ref: u64,
+        new_order_ref: u64,
+        shares: u32,
+        price: u32,
+    },
+    /// Any other message type, which leaves the book unchanged.
+    Other { msg_type: u8 },
+}
+
+impl ItchMessage {
+    /// Parse a single message, without its length prefix.
+    pub fn parse(buf: &[u8]) -> Result<Self, ItchError> {
+        let Some(&msg_type) = buf.first() else {
+            return Err(ItchError::Truncated {
+                msg_type: '?',
+                len: 0,
+                expected: 1,
+            });
+        };
+        let expected = match msg_type {
+            b'R' => 19,
+            b'A' => 36,
+            b'F' => 40,
+            b'E' => 31,
+            b'C' => 36,
+            b'X' => 23,
+            b'D' => 19,
+            b'U' => 35,
+            _ => return Ok(ItchMessage::Other { msg_type }),
+        };
+        if buf.len() < expected {
+            return Err(ItchError::Truncated {
+                msg_type: msg_type as char,
+                len: buf.len(),
+                expected,
+            });
+        }
+
+        let stock_locate = be_u16(&buf[1..3]);
+        let timestamp = be_u48(&buf[5..11]);
+        let order_ref = be_u64(&buf[11..19]);
+        let message = match msg_type {
+            b'R' => ItchMessage::StockDirectory {
+                stock_locate,
+                stock: buf[11..19].try_into().unwrap(),
+            },
+            b'A' | b'F' => ItchMessage::AddOrder {
+                stock_locate,
+                timestamp,
+                order_ref,
+                is_bid: buf[19] == b'B',
+                shares: be_u32(&buf[20..24]),
+                stock: buf[24..32].try_into().unwrap(),
+                price: be_u32(&buf[32..36]),
+            },
+            b'E' | b'C' => ItchMessage::OrderExecuted {
+                stock_locate,
+                timestamp,
+                order_ref,
+                shares: be_u32(&buf[19..23]),
+            },
+            b'X' => ItchMessage::OrderCancel {
+                stock_locate,
+                timestamp,
+                order_ref,
+                shares: be_u32(&buf[19..23]),
+            },
+            b'D' => ItchMessage::OrderDelete {
+                stock_locate,
+                timestamp,
+                order_ref,
+            },
+            b'U' => ItchMessage::OrderReplace {
+                stock_locate,
+                timestamp,
+                order_ref,
+                new_order_ref: be_u64(&buf[19..27]),
+                shares: be_u32(&buf[27..31]),
+                price: be_u32(&buf[31..35]),
+            },
+            _ => unreachable!(),
+        };
+        Ok(message)
+    }
+
+    /// The locate code of the stock the message refers to, if any.
+    pub fn stock_locate(&self) -> Option<u16> {
+        match *self {
+            ItchMessage::StockDirectory { stock_locate, .. }
+            | ItchMessage::AddOrder { stock_locate, .. }
+            | ItchMessage::OrderExecuted { stock_locate, .. }
+            | ItchMessage::OrderCancel { stock_locate, .. }
+            | ItchMessage::OrderDelete { stock_locate, .. }
+            | ItchMessage::OrderReplace { stock_locate, .. } => Some(stock_locate),
+            ItchMessage::Other { .. } => None,
+        }
+    }
+
+    /// The timestamp of order messages, in nanoseconds since midnight.
+    pub fn timestamp(&self) -> Option<u64> {
+        match *self {
+            ItchMessage::AddOrder { timestamp, .. }
+            | ItchMessage::OrderExecuted { timestamp, .. }
+            | ItchMessage::OrderCancel { timestamp, .. }
+            | ItchMessage::OrderDelete { timestamp, .. }
+            | ItchMessage::OrderReplace { timestamp, .. } => Some(timestamp),
+            ItchMessage::StockDirectory { .. } | ItchMessage::Other { .. } => None,
+        }
+    }
+
+    /// Apply the message to `book`, returning whether it was an order
+    /// message. The book should only hold a single stock's orders.
+    pub fn apply(&self, book: &mut OrderBookWithOrders<i64, i64, u64>) -> Result<bool, OrderError> {
+        match *self {
+            ItchMessage::AddOrder {
+                order_ref,
+                is_bid,
+                shares,
+                price,
+                ..
+            } => book.add_order(order_ref, is_bid, price.into(), shares.into())?,
+            ItchMessage::OrderExecuted {
+                order_ref, shares, ..
+            }
+            | ItchMessage::OrderCancel {
+                order_ref, shares, ..
+            } => book.execute_order(&order_ref, shares.into())?,
+            ItchMessage::OrderDelete { order_ref, .. } => {
+                book.cancel_order(&order_ref)?;
+            }
+            ItchMessage::OrderReplace {
+                order_ref,
+                new_order_ref,
+                shares,
+                price,
+                ..
+            } => {
+                let order = book.cancel_order(&order_ref)?;
+                if let Err(e) =
+                    book.add_order(new_order_ref, order.is_bid, price.into(), shares.into())
+                {
+                    // Put the original order back, so a failed replace leaves
+                    // the book unchanged.
+                    book.add_order(order_ref, order.is_bid, order.price, order.qty)
+                        .expect("the cancelled order fitted before");
+                    return Err(e);
+                }
+            }
+            ItchMessage::StockDirectory { .. } | ItchMessage::Other { .. } => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// Reads ITCH 5.0 messages from a stream in which each message is preceded
+/// by its length as a big-endian u16, as in NASDAQ's daily files.
+pub struct ItchReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> ItchReader<R> {
+    pub fn new(reader: R) -> Self {
+        ItchReader {
+            reader,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<R: Read> Iterator for ItchReader<R> {
+    type Item = Result<ItchMessage, ItchError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut len = [0u8; 2];
+        match read_exact_or_eof(&mut self.reader, &mut len) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        self.buf.resize(u16::from_be_bytes(len) as usize, 0);
+        match read_exact_or_eof(&mut self.reader, &mut self.buf) {
+            Ok(true) => Some(ItchMessage::parse(&self.buf)),
+            Ok(false) => Some(Err(ItchError::UnexpectedEof)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Fill `buf`, returning false if the stream ends before the first byte.
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, ItchError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(ItchError::UnexpectedEof),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes(bytes.try_into().unwrap())
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().unwrap())
+}
+
+fn be_u48(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, &b| (acc << 8) | u64::from(b))
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().unwrap())
+}
+
+/// Pad a stock symbol with spaces to the 8 bytes used on the wire.
+pub fn stock_symbol(stock: &str) -> [u8; 8] {
+    let mut symbol = [b' '; 8];
+    for (dst, src) in symbol.iter_mut().zip(stock.bytes()) {
+        *dst = src;
+    }
+    symbol
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(msg_type: u8, stock_locate: u16, timestamp: u64) -> Vec<u8> {
+        let mut buf = vec![msg_type];
+        buf.extend_from_slice(&stock_locate.to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&timestamp.to_be_bytes()[2..]);
+        buf
+    }
+
+    fn add_order(order_ref: u64, is_bid: bool, shares: u32, price: u32) -> Vec<u8> {
+        let mut buf = header(b'A', 7, 1_000);
+        buf.extend_from_slice(&order_ref.to_be_bytes());
+        buf.push(if is_bid { b'B' } else { b'S' });
+        buf.extend_from_slice(&shares.to_be_bytes());
+        buf.extend_from_slice(&stock_symbol("AAPL"));
+        buf.extend_from_slice(&price.to_be_bytes());
+        buf
+    }
+
+    fn framed(messages: &[Vec<u8>]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for message in messages {
+            stream.extend_from_slice(&(message.len() as u16).to_be_bytes());
+            stream.extend_from_slice(message);
+        }
+        stream
+    }
+
+    #[test]
+    fn test_parse_add_order() {
+        let message = ItchMessage::parse(&add_order(42, true, 100, 1_500_000)).unwrap();
+        assert_eq!(
+            message,
+            ItchMessage::AddOrder {
+                stock_locate: 7,
+                timestamp: 1_000,
+                order_ref: 42,
+                is_bid: true,
+                shares: 100,
+                stock: stock_symbol("AAPL"),
+                price: 1_500_000,
+            }
+        );
+        assert!(ItchMessage::parse(&add_order(42, true, 100, 1)[..20]).is_err());
+    }
+
+    #[test]
+    fn test_read_and_apply_stream() {
+        let mut replace = header(b'U', 7, 3_000);
+        replace.extend_from_slice(&1u64.to_be_bytes());
+        replace.extend_from_slice(&3u64.to_be_bytes());
+        replace.extend_from_slice(&50u32.to_be_bytes());
+        replace.extend_from_slice(&1_510_000u32.to_be_bytes());
+        let mut cancel = header(b'X', 7, 4_000);
+        cancel.extend_from_slice(&2u64.to_be_bytes());
+        cancel.extend_from_slice(&10u32.to_be_bytes());
+        let stream = framed(&[
+            add_order(1, true, 100, 1_500_000),
+            add_order(2, false, 30, 1_520_000),
+            vec![b'S'; 12],
+            replace,
+            cancel,
+        ]);
+
+        let mut book = OrderBookWithOrders::new();
+        let mut order_messages = 0;
+        for message in ItchReader::new(stream.as_slice()) {
+            if message.unwrap().apply(&mut book).unwrap() {
+                order_messages += 1;
+            }
+        }
+        assert_eq!(order_messages, 4);
+        assert_eq!(
+            book.book().best_bid_and_ask(),
+            Some((1_510_000, 50, 1_520_000, 20))
+        );
+    }
+
+    #[test]
+    fn test_failed_replace_keeps_order() {
+        let mut book = OrderBookWithOrders::new();
+        book.add_order(1, true, 1_500_000, 100).unwrap();
+        book.add_order(2, true, 1_490_000, 10).unwrap();
+        let replace = |new_order_ref, shares| ItchMessage::OrderReplace {
+            stock_locate: 7,
+            timestamp: 3_000,
+            order_ref: 1,
+            new_order_ref,
+            shares,
+            price: 1_510_000,
+        };
+
+        assert_eq!(
+            replace(2, 50).apply(&mut book),
+            Err(OrderError::DuplicateOrderId)
+        );
+        assert_eq!(
+            replace(3, 0).apply(&mut book),
+            Err(OrderError::NonPositiveQty)
+        );
+        assert_eq!(book.get_order(&1).map(|order| order.qty), Some(100));
+        assert!(book.get_order(&3).is_none());
+        assert_eq!(book.book().best_bid_and_ask(), None);
+        assert_eq!(book.total_qty(true), 110);
+    }
+
+    #[test]
+    fn test_reader_rejects_truncated_stream() {
+        let stream = framed(&[add_order(1, true, 100, 1)]);
+        let mut reader = ItchReader::new(&stream[..stream.len() - 1]);
+        assert!(matches!(reader.next(), Some(Err(ItchError::UnexpectedEof))));
+    }
+}
@@ -1,3 +1,17 @@
+#[cfg(feature = "arrow_ffi")]
+pub mod arrow_ffi;
 pub mod book_side;
+#[cfg(feature = "stream")]
+pub mod book_stream;
+pub mod book_view;
+pub mod consolidated_book;
+pub mod dbn;
+pub mod depth_stream;
+pub mod implied_book;
+pub mod itch;
 pub mod order_book;
+pub mod order_book_with_orders;
 mod price_level;
+pub mod queue_position;
+pub mod shared_book;
+pub mod venues;
@@ -1,14 +1,131 @@
+use std::cmp::{Ordering, Reverse};
 use std::fmt::{Debug, Display};
 use std::hash::Hash;
 
 use anyhow::Context;
-use num::traits::Num;
+use num::traits::{CheckedAdd, Num, Signed};
+use thiserror::Error;
 
-use crate::book_side::BookSide;
+use crate::book_side::{
+    BookSide, DeleteError, DeleteLevelType, FoundLevelType, LevelError, OverDeletePolicy,
+    QtyOverflowError,
+};
+use crate::book_view::BookView;
+use crate::price_level::PriceLevel;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OpKind {
+    Add(FoundLevelType),
+    Delete(DeleteLevelType),
+    Modify(DeleteLevelType, FoundLevelType),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum InitialStateError {
+    #[error("Initial level qty must be positive")]
+    NonPositiveQty,
+    #[error("Initial levels repeat a price on the same side")]
+    DuplicateLevel,
+    #[error("Initial best bid is at or above the best ask")]
+    CrossedBook,
+}
+
+/// Returned by `OrderBook::set_level` for a negative qty, which no level can
+/// hold.
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("Negative qty {qty:?} at price level {price:?}")]
+pub struct NegativeQtyError<Price, Qty> {
+    pub price: Price,
+    pub qty: Qty,
+}
+
+/// How a modify changed the order it applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModifyOutcome {
+    Unchanged,
+    QtyChanged,
+    PriceMoved,
+    PriceMovedAndResized,
+}
+
+impl ModifyOutcome {
+    pub fn new<Price: PartialEq, Qty: PartialEq>(
+        prev_price: Price,
+        prev_qty: Qty,
+        new_price: Price,
+        new_qty: Qty,
+    ) -> Self {
+        match (prev_price == new_price, prev_qty == new_qty) {
+            (true, true) => ModifyOutcome::Unchanged,
+            (true, false) => ModifyOutcome::QtyChanged,
+            (false, true) => ModifyOutcome::PriceMoved,
+            (false, false) => ModifyOutcome::PriceMovedAndResized,
+        }
+    }
+}
+
+/// The price at which a crossed book would uncross in an auction, from
+/// `OrderBook::indicative_uncross`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndicativeUncross<Price, Qty> {
+    pub price: Price,
+    /// The qty that would match at `price`: the lesser of `bid_qty` and
+    /// `ask_qty`.
+    pub volume: Qty,
+    /// The bid qty at or above `price`, which would buy at it.
+    pub bid_qty: Qty,
+    /// The ask qty at or below `price`, which would sell at it.
+    pub ask_qty: Qty,
+}
+
+impl<Price, Qty: Copy + Num + Signed> IndicativeUncross<Price, Qty> {
+    /// The qty left unmatched at the uncross price, positive if it is on the
+    /// bid side and negative if on the ask side.
+    pub fn imbalance(&self) -> Qty {
+        self.bid_qty - self.ask_qty
+    }
+}
+
+/// A price level whose qty differs between two books, from `OrderBook::diff`.
+/// A level missing from a book has a qty of zero in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelDelta<Price, Qty> {
+    pub is_bid: bool,
+    pub price: Price,
+    pub qty: Qty,
+    pub other_qty: Qty,
+}
+
+impl<Price, Qty: Copy + Num> LevelDelta<Price, Qty> {
+    /// The change in qty going from the first book to the other.
+    pub fn qty_change(&self) -> Qty {
+        self.other_qty - self.qty
+    }
+}
+
+/// Every level of a book as parallel columns, from `OrderBook::to_snapshot`.
+/// Bids come first, then asks, each sorted from best to worst price.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BookSnapshot<Price, Qty> {
+    pub is_bid: Vec<bool>,
+    pub price: Vec<Price>,
+    pub qty: Vec<Qty>,
+}
+
+/// With the `serde` feature the whole book, including its event counters, can
+/// be checkpointed (e.g. to JSON or bincode) and restored to resume replay.
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound(deserialize = "Price: serde::Deserialize<'de> + Eq + Hash, \
+                               Qty: serde::Deserialize<'de>"))
+)]
 pub struct OrderBook<Price, Qty> {
     bids: BookSide<Price, Qty>,
     offers: BookSide<Price, Qty>,
+    events_applied: u64,
+    last_op_kind: Option<OpKind>,
 }
 
 impl<Price: Copy + Debug + Display + Hash + Ord, Qty: Copy + Debug + Display + Num + Ord> Default
@@ -26,7 +143,87 @@ impl<Price: Copy + Debug + Display + Hash + Ord, Qty: Copy + Debug + Display + N
         OrderBook {
             bids: BookSide::new(true),
             offers: BookSide::new(false),
+            events_applied: 0,
+            last_op_kind: None,
+        }
+    }
+
+    /// A book pre-loaded with `(price, qty)` levels, e.g. from a snapshot taken
+    /// before an intraday restart. Levels may be in any order. Loading them is
+    /// not counted in `events_applied`.
+    pub fn from_levels(
+        bids: &[(Price, Qty)],
+        asks: &[(Price, Qty)],
+    ) -> Result<Self, InitialStateError> {
+        let best_bid = bids.iter().map(|&(price, _)| price).max();
+        let best_ask = asks.iter().map(|&(price, _)| price).min();
+        if let (Some(best_bid), Some(best_ask)) = (best_bid, best_ask) {
+            if best_bid >= best_ask {
+                return Err(InitialStateError::CrossedBook);
+            }
+        }
+
+        let mut book = Self::new();
+        for (is_bid, levels) in [(true, bids), (false, asks)] {
+            let book_side = book.book_side(is_bid);
+            for &(price, qty) in levels {
+                if qty <= Qty::zero() {
+                    return Err(InitialStateError::NonPositiveQty);
+                }
+                if book_side.get_level(price).is_some() {
+                    return Err(InitialStateError::DuplicateLevel);
+                }
+                book_side.add_qty(price, qty);
+            }
         }
+        Ok(book)
+    }
+
+    /// A book for instruments where a higher number is a worse bid price,
+    /// such as yield-quoted bonds. See `BookSide::with_inverted_prices`.
+    pub fn with_inverted_prices() -> Self {
+        OrderBook {
+            bids: BookSide::with_inverted_prices(true),
+            offers: BookSide::with_inverted_prices(false),
+            ..Self::new()
+        }
+    }
+
+    /// Record when each level of both sides was last modified. See
+    /// `BookSide::track_level_timestamps`.
+    pub fn track_level_timestamps(&mut self) {
+        self.bids.track_level_timestamps();
+        self.offers.track_level_timestamps();
+    }
+
+    /// Set the time stamped on levels modified by the following updates.
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.bids.set_timestamp(timestamp);
+        self.offers.set_timestamp(timestamp);
+    }
+
+    /// When the level at `price` was last modified, if timestamps are tracked.
+    pub fn level_timestamp(&self, is_bid: bool, price: Price) -> Option<u64> {
+        self.get_book_side(is_bid).level_timestamp(price)
+    }
+
+    /// The total qty resting on one side. See `BookSide::total_qty`.
+    #[inline]
+    pub fn total_qty(&self, is_bid: bool) -> Qty {
+        self.get_book_side(is_bid).total_qty()
+    }
+
+    /// The number of price levels on one side.
+    #[inline]
+    pub fn num_levels(&self, is_bid: bool) -> usize {
+        self.get_book_side(is_bid).num_levels()
+    }
+
+    /// Set how both sides handle deletes of more qty than a level holds. See
+    /// `OverDeletePolicy`.
+    pub fn set_over_delete_policy(&mut self, over_delete_policy: OverDeletePolicy) {
+        self.bids.set_over_delete_policy(over_delete_policy);
+        self.offers.set_over_delete_policy(over_delete_policy);
     }
 
     #[inline]
@@ -38,8 +235,156 @@ impl<Price: Copy + Debug + Display + Hash + Ord, Qty: Copy + Debug + Display + N
         }
     }
 
+    #[inline]
+    pub fn get_book_side(&self, is_bid: bool) -> &BookSide<Price, Qty> {
+        if is_bid {
+            &self.bids
+        } else {
+            &self.offers
+        }
+    }
+
+    /// Copy the best `depth` levels of each side into a read-only view.
+    pub fn view(&self, depth: usize) -> BookView<Price, Qty> {
+        let copy_levels = |book_side: &BookSide<Price, Qty>| {
+            book_side.top_n_levels(depth).into_iter().copied().collect()
+        };
+        BookView::new(copy_levels(&self.bids), copy_levels(&self.offers))
+    }
+
+    /// Best bid and ask as `(bid, bid_qty, ask, ask_qty)`, or `None` if either
+    /// side is empty. Crossed books are returned as-is. Metrics derived from
+    /// both sides of the book should go through this so that one-sided books
+    /// are handled the same way everywhere.
+    pub fn best_bid_and_ask(&self) -> Option<(Price, Qty, Price, Qty)> {
+        let bid = self.bids.get_best_price_level()?;
+        let ask = self.offers.get_best_price_level()?;
+        Some((bid.price, bid.qty, ask.price, ask.qty))
+    }
+
+    /// Whether the best bid is at or through the best ask, e.g. because
+    /// deltas arrived out of order.
+    pub fn is_crossed(&self) -> bool {
+        match self.best_bid_and_ask() {
+            Some((bid, _, ask, _)) => !self.bids.is_better_price(ask, bid),
+            None => false,
+        }
+    }
+
+    /// Remove levels on the side opposite `keep_bid` which are at or through
+    /// the best price of the kept side, treating them as stale. Returns the
+    /// removed levels, best first.
+    pub fn uncross(&mut self, keep_bid: bool) -> Vec<PriceLevel<Price, Qty>> {
+        let mut removed = Vec::new();
+        while self.is_crossed() {
+            let stale_side = self.book_side(!keep_bid);
+            let Some(price) = stale_side.get_best_price_level().map(|l| l.price) else {
+                break;
+            };
+            let level = stale_side
+                .delete_level(price)
+                .expect("uncross: best price level should exist");
+            removed.push(level);
+        }
+        removed
+    }
+
+    /// The price that would match the most qty if the book were uncrossed
+    /// in an auction, as during an opening or closing auction call phase.
+    /// `None` unless the book is crossed.
+    ///
+    /// Every price of a crossing level is a candidate. Ties on volume go to
+    /// the price leaving the smallest imbalance, and then to the lowest price.
+    pub fn indicative_uncross(&self) -> Option<IndicativeUncross<Price, Qty>> {
+        if !self.is_crossed() {
+            return None;
+        }
+        let (best_bid, _, best_ask, _) = self.best_bid_and_ask()?;
+        let bids: Vec<_> = self
+            .bids
+            .iter_levels()
+            .take_while(|l| !self.bids.is_better_price(best_ask, l.price))
+            .collect();
+        let asks: Vec<_> = self
+            .offers
+            .iter_levels()
+            .take_while(|l| !self.offers.is_better_price(best_bid, l.price))
+            .collect();
+        let cumulative_qty =
+            |side: &BookSide<Price, Qty>, levels: &[&PriceLevel<Price, Qty>], price| {
+                levels
+                    .iter()
+                    .take_while(|l| !side.is_better_price(price, l.price))
+                    .fold(Qty::zero(), |qty, l| qty + l.qty)
+            };
+
+        let mut best: Option<(IndicativeUncross<Price, Qty>, Qty)> = None;
+        for price in bids.iter().chain(asks.iter()).map(|l| l.price) {
+            let bid_qty = cumulative_qty(&self.bids, &bids, price);
+            let ask_qty = cumulative_qty(&self.offers, &asks, price);
+            let volume = bid_qty.min(ask_qty);
+            let surplus = bid_qty.max(ask_qty) - volume;
+            let is_better = match &best {
+                None => true,
+                Some((uncross, best_surplus)) => {
+                    (volume, Reverse(surplus), Reverse(price))
+                        > (
+                            uncross.volume,
+                            Reverse(*best_surplus),
+                            Reverse(uncross.price),
+                        )
+                }
+            };
+            if is_better {
+                let uncross = IndicativeUncross {
+                    price,
+                    volume,
+                    bid_qty,
+                    ask_qty,
+                };
+                best = Some((uncross, surplus));
+            }
+        }
+        best.map(|(uncross, _)| uncross)
+    }
+
+    /// Match an aggressive order on the `is_bid` side against the opposite
+    /// side, sweeping levels at or better than its limit `price` from the
+    /// best outwards until `qty` is filled. Returns the fills, best price
+    /// first. Unfilled qty is cancelled rather than rested, as for an
+    /// immediate-or-cancel order; rest it with `add_qty` if needed.
+    pub fn match_order(
+        &mut self,
+        is_bid: bool,
+        price: Price,
+        qty: Qty,
+    ) -> Vec<PriceLevel<Price, Qty>> {
+        self.book_side(!is_bid).match_qty(price, qty)
+    }
+
+    /// Number of add, delete and modify operations successfully applied.
+    /// Failed deletes and adds dropped by a level cap are not counted.
+    #[inline]
+    pub fn events_applied(&self) -> u64 {
+        self.events_applied
+    }
+
+    /// The kind of the most recent successfully applied operation.
+    #[inline]
+    pub fn last_op_kind(&self) -> Option<OpKind> {
+        self.last_op_kind
+    }
+
+    #[inline]
+    fn record_op(&mut self, op_kind: OpKind) {
+        self.events_applied += 1;
+        self.last_op_kind = Some(op_kind);
+    }
+
     pub fn add_qty(&mut self, is_bid: bool, price: Price, qty: Qty) {
-        self.book_side(is_bid).add_qty(price, qty)
+        if let Some(outcome) = self.book_side(is_bid).add_qty(price, qty) {
+            self.record_op(OpKind::Add(outcome.found));
+        }
     }
 
     pub fn modify_qty(
@@ -49,12 +394,154 @@ impl<Price: Copy + Debug + Display + Hash + Ord, Qty: Copy + Debug + Display + N
         prev_qty: Qty,
         new_price: Price,
         new_qty: Qty,
-    ) {
-        self.delete_qty(is_bid, prev_price, prev_qty);
-        self.add_qty(is_bid, new_price, new_qty);
+    ) -> ModifyOutcome {
+        let delete_level_type = self.delete_qty_from_side(is_bid, prev_price, prev_qty);
+        if let Some(outcome) = self.book_side(is_bid).add_qty(new_price, new_qty) {
+            self.record_op(OpKind::Modify(delete_level_type, outcome.found));
+        }
+        ModifyOutcome::new(prev_price, prev_qty, new_price, new_qty)
+    }
+
+    /// Set the absolute qty at a price level, as in market-by-price snapshot
+    /// feeds. A qty of zero deletes the level, and a negative qty fails
+    /// without changing the book.
+    pub fn set_level(
+        &mut self,
+        is_bid: bool,
+        price: Price,
+        qty: Qty,
+    ) -> Result<(), NegativeQtyError<Price, Qty>> {
+        if qty < Qty::zero() {
+            return Err(NegativeQtyError { price, qty });
+        }
+        let current_qty = self
+            .get_book_side(is_bid)
+            .get_level(price)
+            .map_or(Qty::zero(), |level| level.qty);
+        if qty > current_qty {
+            self.add_qty(is_bid, price, qty - current_qty);
+        } else if qty < current_qty {
+            self.delete_qty(is_bid, price, current_qty - qty);
+        }
+        Ok(())
+    }
+
+    /// Remove every level from both sides, e.g. on a feed's clear-book
+    /// message. The event counters are left as they are.
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.offers.clear();
+    }
+
+    /// Consume the book, returning every resting level as `(is_bid, price, qty)`.
+    /// Bids come first, then asks, each sorted from best to worst price.
+    pub fn into_sorted_levels(self) -> Vec<(bool, Price, Qty)> {
+        let bids = self.bids.into_sorted_levels().into_iter();
+        let asks = self.offers.into_sorted_levels().into_iter();
+        bids.map(|l| (true, l.price, l.qty))
+            .chain(asks.map(|l| (false, l.price, l.qty)))
+            .collect()
+    }
+
+    /// Every resting level as columns, e.g. for a full-depth DataFrame of the
+    /// book. Unlike `view`, levels below a side's `min_qty` are included.
+    pub fn to_snapshot(&self) -> BookSnapshot<Price, Qty> {
+        self.to_snapshot_with_depth(usize::MAX)
+    }
+
+    /// `to_snapshot` limited to the best `depth` levels of each side.
+    pub fn to_snapshot_with_depth(&self, depth: usize) -> BookSnapshot<Price, Qty> {
+        let mut snapshot = BookSnapshot {
+            is_bid: Vec::new(),
+            price: Vec::new(),
+            qty: Vec::new(),
+        };
+        for is_bid in [true, false] {
+            for level in self.get_book_side(is_bid).iter_levels().take(depth) {
+                snapshot.is_bid.push(is_bid);
+                snapshot.price.push(level.price);
+                snapshot.qty.push(level.qty);
+            }
+        }
+        snapshot
+    }
+
+    /// Every level whose qty differs between this book and `other`, e.g. to
+    /// validate a reconstructed book against a vendor snapshot. Bids come
+    /// first, then asks, each sorted from best to worst price.
+    pub fn diff(&self, other: &Self) -> Vec<LevelDelta<Price, Qty>> {
+        let mut deltas = Vec::new();
+        for is_bid in [true, false] {
+            let side = self.get_book_side(is_bid);
+            let other_side = other.get_book_side(is_bid);
+            let mut side_deltas: Vec<_> = side
+                .levels()
+                .filter_map(|level| {
+                    let other_qty = other_side
+                        .get_level(level.price)
+                        .map_or(Qty::zero(), |other| other.qty);
+                    (level.qty != other_qty).then_some(LevelDelta {
+                        is_bid,
+                        price: level.price,
+                        qty: level.qty,
+                        other_qty,
+                    })
+                })
+                .chain(
+                    other_side
+                        .levels()
+                        .filter(|other| side.get_level(other.price).is_none())
+                        .map(|other| LevelDelta {
+                            is_bid,
+                            price: other.price,
+                            qty: Qty::zero(),
+                            other_qty: other.qty,
+                        }),
+                )
+                .collect();
+            side_deltas.sort_unstable_by(|a, b| {
+                if a.price == b.price {
+                    Ordering::Equal
+                } else if side.is_better_price(a.price, b.price) {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                }
+            });
+            deltas.extend(side_deltas);
+        }
+        deltas
     }
 
     pub fn delete_qty(&mut self, is_bid: bool, price: Price, qty: Qty) {
+        let delete_level_type = self.delete_qty_from_side(is_bid, price, qty);
+        self.record_op(OpKind::Delete(delete_level_type));
+    }
+
+    /// Like `delete_qty`, but returns the error instead of panicking.
+    pub fn try_delete_qty(
+        &mut self,
+        is_bid: bool,
+        price: Price,
+        qty: Qty,
+    ) -> Result<DeleteLevelType, DeleteError> {
+        let delete_level_type = self.book_side(is_bid).delete_qty(price, qty)?;
+        self.record_op(OpKind::Delete(delete_level_type));
+        Ok(delete_level_type)
+    }
+
+    /// Remove the whole level at `price` regardless of its qty.
+    pub fn delete_level(
+        &mut self,
+        is_bid: bool,
+        price: Price,
+    ) -> Result<PriceLevel<Price, Qty>, LevelError> {
+        let level = self.book_side(is_bid).delete_level(price)?;
+        self.record_op(OpKind::Delete(DeleteLevelType::Deleted));
+        Ok(level)
+    }
+
+    fn delete_qty_from_side(&mut self, is_bid: bool, price: Price, qty: Qty) -> DeleteLevelType {
         self.book_side(is_bid)
             .delete_qty(price, qty)
             .with_context(|| {
@@ -63,7 +550,76 @@ impl<Price: Copy + Debug + Display + Hash + Ord, Qty: Copy + Debug + Display + N
                     is_bid, price, qty
                 )
             })
-            .unwrap();
+            .unwrap()
+    }
+}
+
+impl<
+        Price: Copy + Debug + Display + Hash + Ord,
+        Qty: Copy + Debug + Display + Num + Ord + CheckedAdd,
+    > OrderBook<Price, Qty>
+{
+    /// Like `add_qty`, but fails if the level's or the side's total qty would
+    /// overflow. See `BookSide::checked_add_qty`.
+    pub fn checked_add_qty(
+        &mut self,
+        is_bid: bool,
+        price: Price,
+        qty: Qty,
+    ) -> Result<(), QtyOverflowError<Price>> {
+        if let Some(outcome) = self.book_side(is_bid).checked_add_qty(price, qty)? {
+            self.record_op(OpKind::Add(outcome.found));
+        }
+        Ok(())
+    }
+}
+
+impl<
+        Price: Copy + Debug + Display + Hash + Ord,
+        Qty: Copy + Debug + Display + Num + Ord + Signed,
+    > OrderBook<Price, Qty>
+{
+    pub fn apply_qty_delta(&mut self, is_bid: bool, price: Price, delta: Qty) {
+        if delta.is_positive() {
+            self.add_qty(is_bid, price, delta);
+        } else if delta.is_negative() {
+            self.delete_qty(is_bid, price, delta.abs());
+        }
+    }
+}
+
+/// A price ladder, one level per line with the best prices in the middle:
+/// asks from worst to best, then bids from best to worst. Bid qtys are left
+/// of the price and ask qtys right of it, each column aligned. A precision,
+/// e.g. `{:.5}`, shows only that many of the best levels of each side. An
+/// empty book renders as nothing.
+impl<Price: Copy + Debug + Display + Hash + Ord, Qty: Copy + Debug + Display + Num + Ord> Display
+    for OrderBook<Price, Qty>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let depth = f.precision().unwrap_or(usize::MAX);
+        let asks: Vec<_> = self.offers.iter_levels().take(depth).collect();
+        let rows: Vec<[String; 3]> = asks
+            .into_iter()
+            .rev()
+            .map(|l| [String::new(), l.price.to_string(), l.qty.to_string()])
+            .chain(
+                self.bids
+                    .iter_levels()
+                    .take(depth)
+                    .map(|l| [l.qty.to_string(), l.price.to_string(), String::new()]),
+            )
+            .collect();
+        let width = |column: usize| rows.iter().map(|row| row[column].len()).max();
+        let (bid_width, price_width) = (width(0).unwrap_or(0), width(1).unwrap_or(0));
+        for (i, [bid_qty, price, ask_qty]) in rows.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let line = format!("{bid_qty:>bid_width$} | {price:>price_width$} | {ask_qty}");
+            write!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
     }
 }
 
@@ -126,4 +682,436 @@ mod tests {
             assert_eq!(order_book.book_side(is_bid).get_level(1).unwrap().qty, 1);
         }
     }
+
+    #[test]
+    fn test_apply_qty_delta() {
+        let mut order_book = OrderBook::default();
+        order_book.apply_qty_delta(true, 100, 10);
+        order_book.apply_qty_delta(true, 100, -3);
+        order_book.apply_qty_delta(false, 101, 0);
+        assert_eq!(order_book.book_side(true).get_level(100).unwrap().qty, 7);
+        assert!(order_book.book_side(false).get_level(101).is_none());
+    }
+
+    #[test]
+    fn test_into_sorted_levels() {
+        let mut order_book = OrderBook::default();
+        for (is_bid, price, qty) in [
+            (true, 99, 1),
+            (false, 103, 2),
+            (true, 101, 3),
+            (false, 102, 4),
+            (true, 100, 5),
+            (false, 104, 6),
+        ] {
+            order_book.add_qty(is_bid, price, qty);
+        }
+        assert_eq!(
+            order_book.into_sorted_levels(),
+            vec![
+                (true, 101, 3),
+                (true, 100, 5),
+                (true, 99, 1),
+                (false, 102, 4),
+                (false, 103, 2),
+                (false, 104, 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inverted_prices() {
+        let mut order_book = OrderBook::with_inverted_prices();
+        order_book.add_qty(true, 5, 1);
+        order_book.add_qty(true, 4, 2);
+        order_book.add_qty(false, 3, 3);
+        order_book.add_qty(false, 2, 4);
+        assert_eq!(order_book.book_side(true).best_price, Some(4));
+        assert_eq!(order_book.book_side(false).best_price, Some(3));
+        assert_eq!(
+            order_book.into_sorted_levels(),
+            vec![(true, 4, 2), (true, 5, 1), (false, 3, 3), (false, 2, 4)]
+        );
+    }
+
+    #[test]
+    fn test_to_snapshot() {
+        let mut order_book = OrderBook::default();
+        assert_eq!(order_book.to_snapshot(), BookSnapshot::default());
+        order_book.add_qty(true, 99, 5);
+        order_book.add_qty(true, 100, 1);
+        order_book.add_qty(false, 102, 7);
+        order_book.add_qty(false, 101, 2);
+        assert_eq!(
+            order_book.to_snapshot(),
+            BookSnapshot {
+                is_bid: vec![true, true, false, false],
+                price: vec![100, 99, 101, 102],
+                qty: vec![1, 5, 2, 7],
+            }
+        );
+        assert_eq!(
+            order_book.to_snapshot_with_depth(1),
+            BookSnapshot {
+                is_bid: vec![true, false],
+                price: vec![100, 101],
+                qty: vec![1, 2],
+            }
+        );
+    }
+
+    #[test]
+    fn test_display_ladder() {
+        let mut order_book = OrderBook::default();
+        assert_eq!(order_book.to_string(), "");
+        order_book.add_qty(true, 99, 5);
+        order_book.add_qty(true, 98, 120);
+        order_book.add_qty(false, 101, 7);
+        order_book.add_qty(false, 1000, 1);
+        assert_eq!(
+            order_book.to_string(),
+            [
+                "    | 1000 | 1",
+                "    |  101 | 7",
+                "  5 |   99 |",
+                "120 |   98 |",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            format!("{:.1}", order_book),
+            ["  | 101 | 7", "5 |  99 |"].join("\n")
+        );
+        assert_eq!(
+            format!("{}", order_book.get_book_side(true)),
+            "99   5\n98 120"
+        );
+    }
+
+    #[test]
+    fn test_events_applied_and_last_op_kind() {
+        let mut order_book = OrderBook::default();
+        assert_eq!(order_book.events_applied(), 0);
+        assert_eq!(order_book.last_op_kind(), None);
+
+        order_book.add_qty(true, 100, 10);
+        assert_eq!(
+            order_book.last_op_kind(),
+            Some(OpKind::Add(FoundLevelType::New))
+        );
+        order_book.add_qty(true, 100, 5);
+        assert_eq!(
+            order_book.last_op_kind(),
+            Some(OpKind::Add(FoundLevelType::Existing))
+        );
+        order_book.delete_qty(true, 100, 5);
+        assert_eq!(
+            order_book.last_op_kind(),
+            Some(OpKind::Delete(DeleteLevelType::QtyDecreased))
+        );
+        assert_eq!(order_book.events_applied(), 3);
+
+        assert_eq!(
+            order_book.try_delete_qty(true, 100, 11),
+            Err(DeleteError::QtyExceedsAvailable)
+        );
+        assert_eq!(order_book.events_applied(), 3);
+        assert_eq!(
+            order_book.last_op_kind(),
+            Some(OpKind::Delete(DeleteLevelType::QtyDecreased))
+        );
+
+        order_book.modify_qty(true, 100, 10, 101, 10);
+        assert_eq!(
+            order_book.last_op_kind(),
+            Some(OpKind::Modify(
+                DeleteLevelType::Deleted,
+                FoundLevelType::New
+            ))
+        );
+        order_book.apply_qty_delta(false, 102, 0);
+        assert_eq!(order_book.events_applied(), 4);
+        assert_eq!(
+            order_book.try_delete_qty(true, 101, 10),
+            Ok(DeleteLevelType::Deleted)
+        );
+        assert_eq!(order_book.events_applied(), 5);
+    }
+
+    #[test]
+    fn test_delete_level() {
+        let mut order_book = OrderBook::default();
+        order_book.add_qty(false, 100, 10);
+        order_book.add_qty(false, 100, 5);
+        order_book.add_qty(false, 101, 1);
+        assert_eq!(order_book.delete_level(false, 100).unwrap().qty, 15);
+        assert!(order_book.book_side(false).get_level(100).is_none());
+        assert_eq!(order_book.book_side(false).best_price, Some(101));
+        assert_eq!(
+            order_book.last_op_kind(),
+            Some(OpKind::Delete(DeleteLevelType::Deleted))
+        );
+        assert_eq!(order_book.events_applied(), 4);
+
+        assert_eq!(
+            order_book.delete_level(true, 101),
+            Err(LevelError::LevelNotFound)
+        );
+        assert_eq!(order_book.events_applied(), 4);
+    }
+
+    #[test]
+    fn test_modify_outcome() {
+        let mut order_book = OrderBook::default();
+        order_book.add_qty(true, 100, 10);
+        assert_eq!(
+            order_book.modify_qty(true, 100, 10, 100, 10),
+            ModifyOutcome::Unchanged
+        );
+        assert_eq!(
+            order_book.modify_qty(true, 100, 10, 100, 20),
+            ModifyOutcome::QtyChanged
+        );
+        assert_eq!(
+            order_book.modify_qty(true, 100, 20, 101, 20),
+            ModifyOutcome::PriceMoved
+        );
+        assert_eq!(
+            order_book.modify_qty(true, 101, 20, 99, 5),
+            ModifyOutcome::PriceMovedAndResized
+        );
+        assert_eq!(order_book.book_side(true).get_level(99).unwrap().qty, 5);
+        assert_eq!(order_book.book_side(true).best_price, Some(99));
+    }
+
+    #[test]
+    fn test_view() {
+        let mut order_book = OrderBook::default();
+        order_book.add_qty(true, 100, 1);
+        order_book.add_qty(true, 101, 2);
+        order_book.add_qty(true, 99, 3);
+        order_book.add_qty(false, 103, 4);
+
+        let view = order_book.view(2);
+        order_book.delete_qty(true, 101, 2);
+        order_book.add_qty(false, 102, 5);
+
+        assert_eq!(view.best_bid(), Some(&PriceLevel { price: 101, qty: 2 }));
+        assert_eq!(view.best_ask(), Some(&PriceLevel { price: 103, qty: 4 }));
+        assert_eq!(
+            view.top_n(true),
+            &[
+                PriceLevel { price: 101, qty: 2 },
+                PriceLevel { price: 100, qty: 1 }
+            ]
+        );
+        assert_eq!(view.top_n(false), &[PriceLevel { price: 103, qty: 4 }]);
+
+        let view = order_book.view(2);
+        assert_eq!(view.best_bid(), Some(&PriceLevel { price: 100, qty: 1 }));
+        assert_eq!(view.best_ask(), Some(&PriceLevel { price: 102, qty: 5 }));
+    }
+
+    #[test]
+    fn test_best_bid_and_ask() {
+        let mut order_book = OrderBook::default();
+        assert_eq!(order_book.best_bid_and_ask(), None);
+
+        order_book.add_qty(true, 100, 1);
+        assert_eq!(order_book.best_bid_and_ask(), None);
+
+        order_book.add_qty(false, 101, 2);
+        assert_eq!(order_book.best_bid_and_ask(), Some((100, 1, 101, 2)));
+
+        order_book.delete_qty(true, 100, 1);
+        assert_eq!(order_book.best_bid_and_ask(), None);
+
+        order_book.add_qty(true, 102, 3);
+        assert_eq!(order_book.best_bid_and_ask(), Some((102, 3, 101, 2)));
+    }
+
+    #[test]
+    fn test_from_levels() {
+        let mut order_book: OrderBook<i32, i32> =
+            OrderBook::from_levels(&[(99, 3), (100, 1)], &[(102, 2), (101, 4)]).unwrap();
+        assert_eq!(order_book.best_bid_and_ask(), Some((100, 1, 101, 4)));
+        assert_eq!(order_book.events_applied(), 0);
+
+        order_book.delete_qty(false, 101, 4);
+        assert_eq!(order_book.best_bid_and_ask(), Some((100, 1, 102, 2)));
+    }
+
+    #[test]
+    fn test_from_levels_invalid() {
+        let from_levels = OrderBook::<i32, i32>::from_levels;
+        assert_eq!(
+            from_levels(&[(101, 1)], &[(101, 2)]).err(),
+            Some(InitialStateError::CrossedBook)
+        );
+        assert_eq!(
+            from_levels(&[(100, 0)], &[]).err(),
+            Some(InitialStateError::NonPositiveQty)
+        );
+        assert_eq!(
+            from_levels(&[], &[(101, 1), (101, 2)]).err(),
+            Some(InitialStateError::DuplicateLevel)
+        );
+    }
+
+    #[test]
+    fn test_set_level() {
+        let mut order_book = OrderBook::default();
+        order_book.set_level(true, 100, 5).unwrap();
+        order_book.set_level(true, 101, 2).unwrap();
+        assert_eq!(order_book.bids.best_price, Some(101));
+
+        order_book.set_level(true, 101, 7).unwrap();
+        assert_eq!(order_book.bids.best_price_qty, Some(7));
+        order_book.set_level(true, 101, 3).unwrap();
+        assert_eq!(order_book.bids.best_price_qty, Some(3));
+
+        order_book.set_level(true, 101, 0).unwrap();
+        assert_eq!(order_book.bids.best_price, Some(100));
+        assert_eq!(order_book.bids.best_price_qty, Some(5));
+
+        order_book.set_level(false, 102, 0).unwrap();
+        assert_eq!(order_book.offers.best_price, None);
+        assert_eq!(order_book.events_applied(), 5);
+    }
+
+    #[test]
+    fn test_set_level_negative_qty() {
+        let mut order_book = OrderBook::default();
+        order_book.set_level(true, 100, 5).unwrap();
+        for price in [100, 101] {
+            assert_eq!(
+                order_book.set_level(true, price, -1),
+                Err(NegativeQtyError { price, qty: -1 })
+            );
+        }
+        assert_eq!(order_book.bids.best_price_qty, Some(5));
+        assert_eq!(order_book.bids.num_levels(), 1);
+    }
+
+    #[test]
+    fn test_is_crossed_and_uncross() {
+        let mut order_book = OrderBook::default();
+        order_book.add_qty(false, 101, 1);
+        order_book.add_qty(false, 102, 2);
+        order_book.add_qty(false, 104, 3);
+        order_book.add_qty(true, 100, 4);
+        assert!(!order_book.is_crossed());
+
+        order_book.add_qty(true, 102, 5);
+        assert!(order_book.is_crossed());
+
+        let removed = order_book.uncross(true);
+        assert_eq!(
+            removed,
+            vec![
+                PriceLevel { price: 101, qty: 1 },
+                PriceLevel { price: 102, qty: 2 }
+            ]
+        );
+        assert!(!order_book.is_crossed());
+        assert_eq!(order_book.best_bid_and_ask(), Some((102, 5, 104, 3)));
+        assert!(order_book.uncross(false).is_empty());
+    }
+
+    #[test]
+    fn test_is_crossed_with_inverted_prices() {
+        let mut order_book = OrderBook::with_inverted_prices();
+        order_book.add_qty(true, 101, 1);
+        order_book.add_qty(false, 100, 1);
+        assert!(!order_book.is_crossed());
+        order_book.add_qty(false, 101, 1);
+        assert!(order_book.is_crossed());
+    }
+
+    #[test]
+    fn test_match_order() {
+        let mut order_book = OrderBook::new();
+        order_book.add_qty(false, 101, 2);
+        order_book.add_qty(false, 102, 3);
+        order_book.add_qty(false, 104, 5);
+        let fills = order_book.match_order(true, 102, 10);
+        assert_eq!(
+            fills,
+            vec![
+                PriceLevel { price: 101, qty: 2 },
+                PriceLevel { price: 102, qty: 3 }
+            ]
+        );
+        assert_eq!(order_book.get_book_side(false).best_price, Some(104));
+        assert!(order_book.match_order(true, 103, 1).is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let mut order_book: OrderBook<i32, i32> = OrderBook::default();
+        order_book.add_qty(true, 100, 4);
+        order_book.add_qty(true, 99, 2);
+        order_book.add_qty(false, 101, 3);
+        order_book.try_delete_qty(true, 100, 1).unwrap();
+
+        let json = serde_json::to_string(&order_book).unwrap();
+        let mut restored: OrderBook<i32, i32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.view(10), order_book.view(10));
+        assert_eq!(restored.events_applied(), order_book.events_applied());
+
+        restored.add_qty(false, 102, 1);
+        assert_eq!(restored.best_bid_and_ask(), Some((100, 3, 101, 3)));
+    }
+
+    #[test]
+    fn test_indicative_uncross() {
+        let mut order_book = OrderBook::new();
+        order_book.add_qty(true, 100, 5);
+        order_book.add_qty(false, 101, 5);
+        assert_eq!(order_book.indicative_uncross(), None);
+
+        order_book.add_qty(true, 103, 4);
+        order_book.add_qty(true, 102, 3);
+        order_book.add_qty(false, 102, 2);
+        order_book.add_qty(false, 100, 6);
+        // At 100: bids 12, asks 6. At 101: bids 7, asks 11. At 102: bids 7,
+        // asks 13. At 103: bids 4, asks 13.
+        let uncross = order_book.indicative_uncross().unwrap();
+        assert_eq!(
+            uncross,
+            IndicativeUncross {
+                price: 101,
+                volume: 7,
+                bid_qty: 7,
+                ask_qty: 11,
+            }
+        );
+        assert_eq!(uncross.imbalance(), -4);
+    }
+
+    #[test]
+    fn test_diff() {
+        let book = OrderBook::from_levels(&[(100, 5), (99, 2)], &[(101, 3)]).unwrap();
+        assert_eq!(book.diff(&book), vec![]);
+
+        let other = OrderBook::from_levels(&[(100, 4), (98, 1)], &[(101, 3), (102, 6)]).unwrap();
+        let delta = |is_bid, price, qty, other_qty| LevelDelta {
+            is_bid,
+            price,
+            qty,
+            other_qty,
+        };
+        let deltas = book.diff(&other);
+        assert_eq!(
+            deltas,
+            vec![
+                delta(true, 100, 5, 4),
+                delta(true, 99, 2, 0),
+                delta(true, 98, 0, 1),
+                delta(false, 102, 0, 6),
+            ]
+        );
+        assert_eq!(deltas[0].qty_change(), -1);
+    }
 }
@@ -0,0 +1,302 @@
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+
+use hashbrown::HashMap;
+use num::traits::Num;
+use thiserror::Error;
+
+use crate::book_side::DeleteError;
+use crate::order_book::OrderBook;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum OrderError {
+    #[error("Order id already exists")]
+    DuplicateOrderId,
+    #[error("Order not found")]
+    OrderNotFound,
+    #[error("Executed qty exceeds order qty")]
+    QtyExceedsOrder,
+    #[error("Order qty must be positive")]
+    NonPositiveQty,
+    #[error(transparent)]
+    DeleteError(#[from] DeleteError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Order<Price, Qty> {
+    pub is_bid: bool,
+    pub price: Price,
+    pub qty: Qty,
+}
+
+/// An order-by-order (market-by-order) book. Orders are keyed by id and
+/// aggregated into the price levels of an `OrderBook` underneath, so raw MBO
+/// feeds can be replayed without first converting them to price-level deltas.
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound(deserialize = "Price: serde::Deserialize<'de> + Eq + Hash, \
+                               Qty: serde::Deserialize<'de>, \
+                               OrderId: serde::Deserialize<'de> + Eq + Hash"))
+)]
+pub struct OrderBookWithOrders<Price, Qty, OrderId> {
+    book: OrderBook<Price, Qty>,
+    orders: HashMap<OrderId, Order<Price, Qty>>,
+}
+
+impl<
+        Price: Copy + Debug + Display + Hash + Ord,
+        Qty: Copy + Debug + Display + Num + Ord,
+        OrderId: Eq + Hash,
+    > Default for OrderBookWithOrders<Price, Qty, OrderId>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<
+        Price: Copy + Debug + Display + Hash + Ord,
+        Qty: Copy + Debug + Display + Num + Ord,
+        OrderId: Eq + Hash,
+    > OrderBookWithOrders<Price, Qty, OrderId>
+{
+    pub fn new() -> Self {
+        OrderBookWithOrders {
+            book: OrderBook::new(),
+            orders: HashMap::new(),
+        }
+    }
+
+    /// The aggregated price-level book.
+    #[inline]
+    pub fn book(&self) -> &OrderBook<Price, Qty> {
+        &self.book
+    }
+
+    /// The total qty resting on one side. See `BookSide::total_qty`.
+    #[inline]
+    pub fn total_qty(&self, is_bid: bool) -> Qty {
+        self.book.total_qty(is_bid)
+    }
+
+    /// The number of price levels on one side.
+    #[inline]
+    pub fn num_levels(&self, is_bid: bool) -> usize {
+        self.book.num_levels(is_bid)
+    }
+
+    #[inline]
+    pub fn get_order(&self, order_id: &OrderId) -> Option<&Order<Price, Qty>> {
+        self.orders.get(order_id)
+    }
+
+    /// Remove every order and level.
+    pub fn clear(&mut self) {
+        self.orders.clear();
+        self.book.clear();
+    }
+
+    pub fn add_order(
+        &mut self,
+        order_id: OrderId,
+        is_bid: bool,
+        price: Price,
+        qty: Qty,
+    ) -> Result<(), OrderError> {
+        if qty <= Qty::zero() {
+            return Err(OrderError::NonPositiveQty);
+        }
+        if self.orders.contains_key(&order_id) {
+            return Err(OrderError::DuplicateOrderId);
+        }
+        self.book.add_qty(is_bid, price, qty);
+        self.orders.insert(order_id, Order { is_bid, price, qty });
+        Ok(())
+    }
+
+    /// Remove the order, returning it as it was before the cancel. The order
+    /// is kept if its qty can't be removed from the book.
+    pub fn cancel_order(&mut self, order_id: &OrderId) -> Result<Order<Price, Qty>, OrderError> {
+        let order = *self.orders.get(order_id).ok_or(OrderError::OrderNotFound)?;
+        self.book
+            .try_delete_qty(order.is_bid, order.price, order.qty)?;
+        self.orders.remove(order_id);
+        Ok(order)
+    }
+
+    /// Move the order to a new price and qty, keeping its side.
+    pub fn replace_order(
+        &mut self,
+        order_id: &OrderId,
+        price: Price,
+        qty: Qty,
+    ) -> Result<(), OrderError> {
+        if qty <= Qty::zero() {
+            return Err(OrderError::NonPositiveQty);
+        }
+        let order = self
+            .orders
+            .get_mut(order_id)
+            .ok_or(OrderError::OrderNotFound)?;
+        self.book
+            .modify_qty(order.is_bid, order.price, order.qty, price, qty);
+        order.price = price;
+        order.qty = qty;
+        Ok(())
+    }
+
+    /// Fill `qty` of the order, removing it once fully executed.
+    pub fn execute_order(&mut self, order_id: &OrderId, qty: Qty) -> Result<(), OrderError> {
+        if qty <= Qty::zero() {
+            return Err(OrderError::NonPositiveQty);
+        }
+        let order = self
+            .orders
+            .get_mut(order_id)
+            .ok_or(OrderError::OrderNotFound)?;
+        if qty > order.qty {
+            return Err(OrderError::QtyExceedsOrder);
+        }
+        self.book.try_delete_qty(order.is_bid, order.price, qty)?;
+        order.qty = order.qty - qty;
+        if order.qty == Qty::zero() {
+            self.orders.remove(order_id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn best_bid_and_ask(book: &OrderBookWithOrders<i32, i32, u64>) -> Option<(i32, i32, i32, i32)> {
+        book.book().best_bid_and_ask()
+    }
+
+    #[test]
+    fn test_add_and_cancel_order() {
+        let mut book = OrderBookWithOrders::new();
+        book.add_order(1u64, true, 100, 5).unwrap();
+        book.add_order(2, true, 100, 3).unwrap();
+        book.add_order(3, false, 101, 4).unwrap();
+        assert_eq!(best_bid_and_ask(&book), Some((100, 8, 101, 4)));
+        assert_eq!(
+            book.add_order(1, false, 102, 1),
+            Err(OrderError::DuplicateOrderId)
+        );
+
+        let order = book.cancel_order(&1).unwrap();
+        assert_eq!(
+            order,
+            Order {
+                is_bid: true,
+                price: 100,
+                qty: 5
+            }
+        );
+        assert_eq!(best_bid_and_ask(&book), Some((100, 3, 101, 4)));
+        assert_eq!(book.cancel_order(&1), Err(OrderError::OrderNotFound));
+    }
+
+    #[test]
+    fn test_replace_order() {
+        let mut book = OrderBookWithOrders::new();
+        book.add_order(1u64, true, 100, 5).unwrap();
+        book.add_order(2, false, 103, 4).unwrap();
+        book.replace_order(&1, 101, 2).unwrap();
+        assert_eq!(best_bid_and_ask(&book), Some((101, 2, 103, 4)));
+        assert!(book.book().get_book_side(true).get_level(100).is_none());
+        assert_eq!(book.get_order(&1).unwrap().price, 101);
+        assert_eq!(
+            book.replace_order(&3, 101, 2),
+            Err(OrderError::OrderNotFound)
+        );
+    }
+
+    #[test]
+    fn test_execute_order() {
+        let mut book = OrderBookWithOrders::new();
+        book.add_order(1u64, false, 101, 5).unwrap();
+        book.add_order(2, false, 102, 4).unwrap();
+
+        book.execute_order(&1, 2).unwrap();
+        assert_eq!(book.get_order(&1).unwrap().qty, 3);
+        assert_eq!(book.execute_order(&1, 4), Err(OrderError::QtyExceedsOrder));
+
+        book.execute_order(&1, 3).unwrap();
+        assert!(book.get_order(&1).is_none());
+        assert_eq!(
+            book.book()
+                .get_book_side(false)
+                .get_best_price_level()
+                .unwrap()
+                .price,
+            102
+        );
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut book = OrderBookWithOrders::new();
+        book.add_order(1u64, true, 100, 5).unwrap();
+        book.add_order(2, false, 101, 4).unwrap();
+        book.clear();
+        assert_eq!(book.get_order(&1), None);
+        assert_eq!(book.book().get_book_side(true).best_price, None);
+        assert_eq!(book.cancel_order(&2), Err(OrderError::OrderNotFound));
+
+        book.add_order(1, true, 99, 1).unwrap();
+        assert_eq!(book.book().get_book_side(true).best_price, Some(99));
+    }
+
+    #[test]
+    fn test_totals() {
+        let mut book = OrderBookWithOrders::new();
+        book.add_order(1u64, true, 100, 5).unwrap();
+        book.add_order(2, true, 100, 2).unwrap();
+        book.add_order(3, true, 99, 4).unwrap();
+        book.add_order(4, false, 101, 3).unwrap();
+        assert_eq!((book.total_qty(true), book.num_levels(true)), (11, 2));
+        assert_eq!((book.total_qty(false), book.num_levels(false)), (3, 1));
+
+        book.execute_order(&1, 2).unwrap();
+        book.cancel_order(&3).unwrap();
+        assert_eq!((book.total_qty(true), book.num_levels(true)), (5, 1));
+    }
+
+    #[test]
+    fn test_non_positive_qty() {
+        let mut book = OrderBookWithOrders::new();
+        assert_eq!(
+            book.add_order(1u64, true, 100, 0),
+            Err(OrderError::NonPositiveQty)
+        );
+        assert_eq!(
+            book.add_order(1, true, 100, -5),
+            Err(OrderError::NonPositiveQty)
+        );
+        assert!(book.get_order(&1).is_none());
+        assert_eq!(book.num_levels(true), 0);
+
+        book.add_order(1, true, 100, 5).unwrap();
+        assert_eq!(
+            book.replace_order(&1, 101, 0),
+            Err(OrderError::NonPositiveQty)
+        );
+        assert_eq!(book.execute_order(&1, 0), Err(OrderError::NonPositiveQty));
+        assert_eq!(book.execute_order(&1, -2), Err(OrderError::NonPositiveQty));
+        assert_eq!(
+            book.get_order(&1),
+            Some(&Order {
+                is_bid: true,
+                price: 100,
+                qty: 5
+            })
+        );
+        assert_eq!(best_bid_and_ask(&book), None);
+        assert_eq!(book.total_qty(true), 5);
+    }
+}
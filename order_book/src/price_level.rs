@@ -1,6 +1,7 @@
 use num::traits::Num;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PriceLevel<Price, Qty> {
     pub price: Price,
     pub qty: Qty,
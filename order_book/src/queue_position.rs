@@ -0,0 +1,195 @@
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+
+use num::traits::{Num, ToPrimitive};
+
+use crate::order_book::OrderBook;
+
+/// Where `QueuePosition` assumes qty removed from its level comes from.
+/// Aggregated level feeds don't say whether a decrease was a cancel or a
+/// trade, nor where in the queue the removed orders were.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum QueueModel {
+    /// From behind the order first, so the qty ahead only shrinks once the
+    /// level holds less than it.
+    Pessimistic,
+    /// From ahead of and behind the order in proportion to their qty.
+    #[default]
+    Proportional,
+    /// From ahead of the order first.
+    Optimistic,
+}
+
+/// The estimated queue position of a hypothetical order of our own resting
+/// at `price`, while replaying a feed that doesn't contain it.
+///
+/// The order joins the back of the queue when entered: everything at its
+/// level then is ahead of it, and later adds are behind it. Decreases of the
+/// level are split between ahead and behind following the `QueueModel`.
+/// The order counts as filled once the opposite side reaches its price, or
+/// its level is emptied while it is the best of its side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuePosition<Price> {
+    is_bid: bool,
+    price: Price,
+    model: QueueModel,
+    ahead_at_entry: f64,
+    ahead: f64,
+    /// The level's qty when last observed.
+    level_qty: f64,
+    /// Whether the level was the best of its side when last observed.
+    at_best: bool,
+    filled: bool,
+}
+
+impl<Price: Copy + Debug + Display + Hash + Ord> QueuePosition<Price> {
+    /// Enter the order at the back of the queue at `price` in `book` as it
+    /// stands. An order that is already marketable is filled at once.
+    pub fn enter<Qty: Copy + Debug + Display + Num + Ord + ToPrimitive>(
+        book: &OrderBook<Price, Qty>,
+        is_bid: bool,
+        price: Price,
+        model: QueueModel,
+    ) -> Self {
+        let mut position = QueuePosition {
+            is_bid,
+            price,
+            model,
+            ahead_at_entry: 0.0,
+            ahead: 0.0,
+            level_qty: 0.0,
+            at_best: false,
+            filled: false,
+        };
+        position.observe(book);
+        if !position.filled {
+            position.ahead = position.level_qty;
+            position.ahead_at_entry = position.level_qty;
+        }
+        position
+    }
+
+    /// Update the estimate from `book` after it changed, e.g. after each
+    /// update of the feed.
+    pub fn observe<Qty: Copy + Debug + Display + Num + Ord + ToPrimitive>(
+        &mut self,
+        book: &OrderBook<Price, Qty>,
+    ) {
+        if self.filled {
+            return;
+        }
+        let side = book.get_book_side(self.is_bid);
+        let level_qty = side
+            .get_level(self.price)
+            .and_then(|level| level.qty.to_f64())
+            .unwrap_or(0.0);
+        let decrease = self.level_qty - level_qty;
+        if decrease > 0.0 {
+            let behind = self.level_qty - self.ahead;
+            self.ahead -= match self.model {
+                QueueModel::Pessimistic => (decrease - behind).max(0.0),
+                QueueModel::Proportional => decrease * self.ahead / self.level_qty,
+                QueueModel::Optimistic => decrease.min(self.ahead),
+            };
+        }
+
+        let reached = book
+            .get_book_side(!self.is_bid)
+            .best_price
+            .is_some_and(|opposite| !side.is_better_price(opposite, self.price));
+        let emptied_at_best = self.at_best && level_qty == 0.0;
+        if reached || emptied_at_best {
+            self.filled = true;
+            self.ahead = 0.0;
+        }
+        self.level_qty = level_qty;
+        self.at_best = side.best_price == Some(self.price);
+    }
+
+    /// The estimated qty ahead of the order in its level's queue.
+    #[inline]
+    pub fn qty_ahead(&self) -> f64 {
+        self.ahead
+    }
+
+    #[inline]
+    pub fn is_filled(&self) -> bool {
+        self.filled
+    }
+
+    /// A heuristic probability that the order has been filled: the share of
+    /// the qty ahead of it at entry that has since left the queue, or 1 once
+    /// it is filled or if nothing was ahead of it.
+    pub fn fill_probability(&self) -> f64 {
+        if self.filled || self.ahead_at_entry == 0.0 {
+            1.0
+        } else {
+            1.0 - self.ahead / self.ahead_at_entry
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decreases_follow_the_model() {
+        for (model, ahead) in [
+            (QueueModel::Pessimistic, 9.0),
+            (QueueModel::Proportional, 6.0),
+            (QueueModel::Optimistic, 4.0),
+        ] {
+            let mut book = OrderBook::new();
+            book.add_qty(true, 100, 10);
+            let mut position = QueuePosition::enter(&book, true, 100, model);
+            assert_eq!(position.qty_ahead(), 10.0);
+
+            book.add_qty(true, 100, 5);
+            position.observe(&book);
+            assert_eq!(position.qty_ahead(), 10.0);
+
+            book.delete_qty(true, 100, 6);
+            position.observe(&book);
+            assert_eq!(position.qty_ahead(), ahead);
+            assert_eq!(position.fill_probability(), 1.0 - ahead / 10.0);
+            assert!(!position.is_filled());
+        }
+    }
+
+    #[test]
+    fn test_filled_when_reached_or_emptied_at_best() {
+        let mut book = OrderBook::new();
+        book.add_qty(true, 100, 10);
+        book.add_qty(false, 102, 3);
+        let mut position = QueuePosition::enter(&book, true, 100, QueueModel::default());
+        book.add_qty(false, 100, 1);
+        position.observe(&book);
+        assert!(position.is_filled());
+        assert_eq!(position.qty_ahead(), 0.0);
+
+        let mut book = OrderBook::new();
+        book.add_qty(false, 102, 3);
+        let mut position = QueuePosition::enter(&book, false, 102, QueueModel::default());
+        book.delete_qty(false, 102, 3);
+        position.observe(&book);
+        assert!(position.is_filled());
+        assert_eq!(position.fill_probability(), 1.0);
+
+        // A bid at or above the best ask is marketable, so filled on entry.
+        let mut book = OrderBook::new();
+        book.add_qty(true, 102, 4);
+        book.add_qty(false, 102, 3);
+        let mut position = QueuePosition::enter(&book, true, 102, QueueModel::default());
+        assert!(position.is_filled());
+        assert_eq!(position.qty_ahead(), 0.0);
+        book.add_qty(true, 102, 1);
+        position.observe(&book);
+        assert_eq!(position.qty_ahead(), 0.0);
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use num::traits::Num;
+
+use crate::book_view::BookView;
+use crate::order_book::OrderBook;
+
+type Published<Price, Qty> = Arc<RwLock<Arc<BookView<Price, Qty>>>>;
+
+/// An order book that one thread updates while other threads read it, e.g.
+/// when embedding the book in a live trading process.
+///
+/// The writer owns the book and, after each `update`, publishes an immutable
+/// `BookView` of its best `depth` levels per side. Readers only ever clone
+/// the `Arc` of the latest view, so they never wait on an update being
+/// applied, only on the pointer swap that publishes it, and every view they
+/// see is the consistent state between two updates.
+pub struct SharedOrderBook<Price, Qty> {
+    book: OrderBook<Price, Qty>,
+    depth: usize,
+    published: Published<Price, Qty>,
+}
+
+/// A handle to the views published by a `SharedOrderBook`, from
+/// `SharedOrderBook::reader`. Clone it to give each reading thread its own.
+pub struct BookReader<Price, Qty> {
+    published: Published<Price, Qty>,
+}
+
+impl<Price, Qty> Clone for BookReader<Price, Qty> {
+    fn clone(&self) -> Self {
+        BookReader {
+            published: Arc::clone(&self.published),
+        }
+    }
+}
+
+impl<Price: Copy + Debug + Display + Hash + Ord, Qty: Copy + Debug + Display + Num + Ord>
+    SharedOrderBook<Price, Qty>
+{
+    /// Share `book`, publishing up to `depth` levels of each side. A depth of
+    /// 1 publishes just the best bid and offer.
+    pub fn new(book: OrderBook<Price, Qty>, depth: usize) -> Self {
+        let published = Arc::new(RwLock::new(Arc::new(book.view(depth))));
+        SharedOrderBook {
+            book,
+            depth,
+            published,
+        }
+    }
+
+    pub fn reader(&self) -> BookReader<Price, Qty> {
+        BookReader {
+            published: Arc::clone(&self.published),
+        }
+    }
+
+    /// The book itself, as of the last `update`.
+    #[inline]
+    pub fn book(&self) -> &OrderBook<Price, Qty> {
+        &self.book
+    }
+
+    /// Apply `update` to the book, then publish its new top levels. Readers
+    /// see all of the changes made by `update` or none of them, so apply the
+    /// updates of one feed message in a single call.
+    pub fn update<R>(&mut self, update: impl FnOnce(&mut OrderBook<Price, Qty>) -> R) -> R {
+        let result = update(&mut self.book);
+        let view = Arc::new(self.book.view(self.depth));
+        // The write lock is only held for the swap, which can't panic, but
+        // recover from poisoning anyway rather than wedging every reader.
+        *self
+            .published
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = view;
+        result
+    }
+}
+
+impl<Price: Copy, Qty: Copy> BookReader<Price, Qty> {
+    /// The latest published view. It stays valid, and unchanged, however
+    /// many updates are published after it.
+    pub fn view(&self) -> Arc<BookView<Price, Qty>> {
+        Arc::clone(
+            &self
+                .published
+                .read()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+
+    /// Best bid and ask of the latest view as `(bid, bid_qty, ask, ask_qty)`,
+    /// or `None` if either side is empty. See `OrderBook::best_bid_and_ask`.
+    pub fn best_bid_and_ask(&self) -> Option<(Price, Qty, Price, Qty)> {
+        let view = self.view();
+        let (bid, ask) = (view.best_bid()?, view.best_ask()?);
+        Some((bid.price, bid.qty, ask.price, ask.qty))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readers_see_published_updates() {
+        let mut shared = SharedOrderBook::new(OrderBook::default(), 2);
+        let reader = shared.reader();
+        assert_eq!(reader.best_bid_and_ask(), None);
+
+        let before = reader.view();
+        shared.update(|book| {
+            book.add_qty(true, 100, 5);
+            book.add_qty(true, 99, 3);
+            book.add_qty(true, 98, 1);
+            book.add_qty(false, 101, 4);
+        });
+        assert_eq!(reader.best_bid_and_ask(), Some((100, 5, 101, 4)));
+        assert_eq!(reader.view().top_n(true).len(), 2);
+        assert_eq!(before.best_bid(), None);
+        assert_eq!(shared.book().get_book_side(true).num_levels(), 3);
+    }
+
+    #[test]
+    fn test_concurrent_readers_see_consistent_views() {
+        let mut shared = SharedOrderBook::new(OrderBook::default(), 1);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                let reader = shared.reader();
+                scope.spawn(move || {
+                    for _ in 0..1000 {
+                        // Every update moves both sides together, so a view
+                        // mixing two updates would have unequal qtys.
+                        if let Some((bid, bid_qty, ask, ask_qty)) = reader.best_bid_and_ask() {
+                            assert_eq!(bid_qty, ask_qty);
+                            assert_eq!(ask - bid, 2);
+                        }
+                    }
+                });
+            }
+            for i in 1..1000 {
+                shared.update(|book| {
+                    book.clear();
+                    book.add_qty(true, i, i);
+                    book.add_qty(false, i + 2, i);
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,184 @@
+use thiserror::Error;
+
+use crate::order_book::OrderBook;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum VenueError {
+    #[error("Invalid decimal {0:?}")]
+    InvalidDecimal(String),
+    #[error("{value:?} has more than {decimals} decimal places")]
+    TooManyDecimals { value: String, decimals: u32 },
+    #[error("Gap in sequence numbers: expected {expected}, got {sequence}")]
+    Gap { expected: u64, sequence: u64 },
+    #[error("Checksum mismatch: venue sent {expected:#010x}, book gives {actual:#010x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+/// Parse a decimal string as sent by crypto venues, e.g. `"0.05005"`, into
+/// an integer count of units of `10^-decimals`, exactly and without going
+/// through a float. `decimals` is the venue's price or qty precision for the
+/// instrument, so a price of `"0.05005"` with 5 decimals is 5005 ticks.
+/// Trailing zeros beyond `decimals` are accepted, other digits are not.
+pub fn parse_decimal(value: &str, decimals: u32) -> Result<i64, VenueError> {
+    let invalid = || VenueError::InvalidDecimal(value.to_string());
+    let (negative, unsigned) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return Err(invalid());
+    }
+    let all_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if !all_digits(whole) || !all_digits(fraction) {
+        return Err(invalid());
+    }
+    let (kept, dropped) = fraction.split_at(fraction.len().min(decimals as usize));
+    if dropped.bytes().any(|b| b != b'0') {
+        return Err(VenueError::TooManyDecimals {
+            value: value.to_string(),
+            decimals,
+        });
+    }
+    let scaled = whole
+        .bytes()
+        .chain(kept.bytes())
+        .chain(std::iter::repeat_n(b'0', decimals as usize - kept.len()))
+        .try_fold(0i64, |acc, digit| {
+            acc.checked_mul(10)?.checked_add(i64::from(digit - b'0'))
+        })
+        .ok_or_else(invalid)?;
+    Ok(if negative { -scaled } else { scaled })
+}
+
+/// Checks that each message of a feed carries the sequence number after the
+/// previous one's, as for Coinbase's `sequence_num`. A gap means messages
+/// were missed and the book must be rebuilt from a fresh snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct SequenceTracker {
+    last_sequence: Option<u64>,
+}
+
+impl SequenceTracker {
+    /// A tracker expecting the message after `sequence`, e.g. the sequence
+    /// number of the snapshot a book was built from.
+    pub fn new(sequence: u64) -> Self {
+        SequenceTracker {
+            last_sequence: Some(sequence),
+        }
+    }
+
+    /// Record the sequence number of the next message. The first message
+    /// seen by a tracker made with `default` is always accepted.
+    pub fn check(&mut self, sequence: u64) -> Result<(), VenueError> {
+        if let Some(last_sequence) = self.last_sequence {
+            let expected = last_sequence + 1;
+            if sequence != expected {
+                return Err(VenueError::Gap { expected, sequence });
+            }
+        }
+        self.last_sequence = Some(sequence);
+        Ok(())
+    }
+}
+
+/// The CRC32 checksum Kraken sends with each book update, computed over the
+/// best 10 levels of `book`: each ask from best to worst, then each bid from
+/// best to worst, as its price then its qty with the decimal point and
+/// leading zeros removed. That is exactly the decimal representation of the
+/// integer ticks and lots parsed by `parse_decimal` at the instrument's
+/// precisions, so the book's levels are formatted as they are.
+pub fn kraken_checksum(book: &OrderBook<i64, i64>) -> u32 {
+    let mut payload = String::new();
+    for is_bid in [false, true] {
+        for level in book.get_book_side(is_bid).iter_levels().take(10) {
+            payload.push_str(&level.price.to_string());
+            payload.push_str(&level.qty.to_string());
+        }
+    }
+    crc32(payload.as_bytes())
+}
+
+/// Compare the checksum sent by Kraken with the book's, after applying the
+/// update it came with. See `kraken_checksum`.
+pub fn verify_kraken_checksum(book: &OrderBook<i64, i64>, expected: u32) -> Result<(), VenueError> {
+    let actual = kraken_checksum(book);
+    if actual != expected {
+        return Err(VenueError::ChecksumMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+/// CRC-32 (IEEE 802.3), as used by zlib.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_decimal() {
+        assert_eq!(parse_decimal("0.05005", 5), Ok(5005));
+        assert_eq!(parse_decimal("27123.4", 2), Ok(2712340));
+        assert_eq!(parse_decimal("1.500", 1), Ok(15));
+        assert_eq!(parse_decimal("-.5", 1), Ok(-5));
+        assert_eq!(parse_decimal("12", 0), Ok(12));
+        assert_eq!(
+            parse_decimal("1.05", 1),
+            Err(VenueError::TooManyDecimals {
+                value: "1.05".to_string(),
+                decimals: 1
+            })
+        );
+        for invalid in ["", ".", "1e5", "1.2.3", "+1", "99999999999999999999"] {
+            assert_eq!(
+                parse_decimal(invalid, 2),
+                Err(VenueError::InvalidDecimal(invalid.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_sequence_tracker() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.check(7), Ok(()));
+        assert_eq!(tracker.check(8), Ok(()));
+        assert_eq!(
+            tracker.check(10),
+            Err(VenueError::Gap {
+                expected: 9,
+                sequence: 10
+            })
+        );
+        assert_eq!(SequenceTracker::new(3).check(4), Ok(()));
+    }
+
+    #[test]
+    fn test_kraken_checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        // Prices with 1 decimal and qtys with 8, e.g. 0.05005 offered at 101.5.
+        let mut book = OrderBook::default();
+        book.add_qty(false, 1016, 150_000_000);
+        book.add_qty(false, 1015, 5_005_000);
+        book.add_qty(true, 1014, 1);
+        assert_eq!(
+            kraken_checksum(&book),
+            crc32(b"10155005000101615000000010141")
+        );
+        let checksum = kraken_checksum(&book);
+        assert_eq!(verify_kraken_checksum(&book, checksum), Ok(()));
+        book.add_qty(true, 1013, 1);
+        assert!(verify_kraken_checksum(&book, checksum).is_err());
+    }
+}
@@ -0,0 +1,369 @@
+//! Replay a raw feed file through the order book and write the best bid and
+//! ask, or the best `--n-levels` levels of each side, after each update to
+//! Parquet, for batch jobs that don't need Python.
+//!
+//! ```text
+//! order-book-replay <input> <output.parquet> [--format csv|itch|dbn]
+//!     [--n-levels N] [--stock SYMBOL] [--instrument-id ID]
+//! ```
+//!
+//! The format is taken from the input's extension unless given. CSV input
+//! holds `is_bid`, `price` and `qty` columns of signed qty deltas, as taken
+//! by `calculate_bbo`. ITCH input needs the `--stock` to replay. DBN input
+//! keeps a book per instrument unless `--instrument-id` picks one.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use hashbrown::HashMap;
+use polars::prelude::*;
+
+use order_book::book_side::DeleteError;
+use order_book::dbn::{apply_mbo, apply_mbp, DbnReader, DbnRecord};
+use order_book::itch::{stock_symbol, ItchMessage, ItchReader};
+use order_book::order_book::OrderBook;
+use order_book::order_book_with_orders::OrderBookWithOrders;
+
+const USAGE: &str = "Usage: order-book-replay <input> <output.parquet> [--format csv|itch|dbn] \
+                     [--n-levels N] [--stock SYMBOL] [--instrument-id ID]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Itch,
+    Dbn,
+}
+
+impl Format {
+    fn parse(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "itch" => Ok(Format::Itch),
+            "dbn" => Ok(Format::Dbn),
+            _ => bail!("Unknown format {:?}, expected csv, itch or dbn", name),
+        }
+    }
+
+    fn from_path(path: &Path) -> Result<Self> {
+        let extension = path.extension().and_then(|e| e.to_str()).ok_or_else(|| {
+            anyhow!(
+                "Can't tell the format of {} from its extension, pass --format",
+                path.display()
+            )
+        })?;
+        Format::parse(extension)
+    }
+}
+
+#[derive(Debug)]
+struct Args {
+    input: String,
+    output: String,
+    format: Format,
+    n_levels: usize,
+    stock: Option<String>,
+    instrument_id: Option<u32>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args> {
+    let mut positional = Vec::new();
+    let mut format = None;
+    let mut n_levels = 1;
+    let mut stock = None;
+    let mut instrument_id = None;
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().ok_or_else(|| anyhow!("{} needs a value", flag));
+        match arg.as_str() {
+            "--format" => format = Some(Format::parse(&value(&arg)?)?),
+            "--n-levels" => {
+                n_levels = value(&arg)?.parse().context("Invalid --n-levels")?;
+                if n_levels == 0 {
+                    bail!("--n-levels must be at least 1");
+                }
+            }
+            "--stock" => stock = Some(value(&arg)?),
+            "--instrument-id" => {
+                instrument_id = Some(value(&arg)?.parse().context("Invalid --instrument-id")?)
+            }
+            "-h" | "--help" => bail!("{}", USAGE),
+            flag if flag.starts_with("--") => bail!("Unknown flag {}\n{}", flag, USAGE),
+            _ => positional.push(arg),
+        }
+    }
+    let [input, output]: [String; 2] = positional
+        .try_into()
+        .map_err(|_| anyhow!("Expected an input and an output path\n{}", USAGE))?;
+    let format = match format {
+        Some(format) => format,
+        None => Format::from_path(Path::new(&input))?,
+    };
+    Ok(Args {
+        input,
+        output,
+        format,
+        n_levels,
+        stock,
+        instrument_id,
+    })
+}
+
+/// Accumulates the best `n` levels of each side after each update, as the
+/// plugin's top-N expressions do. With `n == 1` the columns are named like
+/// `calculate_bbo`'s struct fields, otherwise like its "flat" output style,
+/// e.g. `bid_price_1`. Levels beyond the depth of a side are null.
+struct LevelColumns {
+    n: usize,
+    /// bid prices, bid qtys, ask prices then ask qtys, `n` columns each.
+    columns: Vec<Vec<Option<i64>>>,
+}
+
+impl LevelColumns {
+    fn new(n: usize) -> Self {
+        LevelColumns {
+            n,
+            columns: vec![Vec::new(); 4 * n],
+        }
+    }
+
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        let side_columns = self.columns.chunks_mut(2 * self.n);
+        for (is_bid, columns) in [true, false].into_iter().zip(side_columns) {
+            let levels = book.get_book_side(is_bid).top_n_levels(self.n);
+            let (prices, qtys) = columns.split_at_mut(self.n);
+            for (i, (price, qty)) in prices.iter_mut().zip(qtys).enumerate() {
+                let level = levels.get(i);
+                price.push(level.map(|level| level.price));
+                qty.push(level.map(|level| level.qty));
+            }
+        }
+    }
+
+    fn names(&self) -> Vec<String> {
+        if self.n == 1 {
+            return ["best_bid", "best_bid_qty", "best_ask", "best_ask_qty"]
+                .map(String::from)
+                .to_vec();
+        }
+        ["bid_price", "bid_qty", "ask_price", "ask_qty"]
+            .into_iter()
+            .flat_map(|prefix| (1..=self.n).map(move |level| format!("{}_{}", prefix, level)))
+            .collect()
+    }
+
+    fn finish(self) -> Vec<Series> {
+        self.names()
+            .iter()
+            .zip(self.columns)
+            .map(|(name, column)| Series::new(name, column))
+            .collect()
+    }
+}
+
+fn replay_csv(path: &str, output: &mut LevelColumns) -> Result<Vec<Series>> {
+    let df = CsvReader::from_path(path)?.has_header(true).finish()?;
+    let is_bid = df.column("is_bid")?.cast(&DataType::Boolean)?;
+    let price = df.column("price")?.cast(&DataType::Int64)?;
+    let qty = df.column("qty")?.cast(&DataType::Int64)?;
+
+    let mut book = OrderBook::new();
+    let rows = is_bid.bool()?.into_iter().zip(price.i64()?).zip(qty.i64()?);
+    for (row, ((is_bid, price), qty)) in rows.enumerate() {
+        let (Some(is_bid), Some(price), Some(qty)) = (is_bid, price, qty) else {
+            bail!("Null in row {} of {}", row, path);
+        };
+        apply_delta(&mut book, is_bid, price, qty)
+            .with_context(|| format!("Row {} of {}", row, path))?;
+        output.append(&book);
+    }
+    Ok(Vec::new())
+}
+
+/// Apply a signed qty change as `calculate_bbo` does, where a zero delta
+/// leaves the book unchanged rather than creating an empty level.
+fn apply_delta(
+    book: &mut OrderBook<i64, i64>,
+    is_bid: bool,
+    price: i64,
+    qty: i64,
+) -> Result<(), DeleteError> {
+    book.book_side(is_bid).apply_qty_delta(price, qty)
+}
+
+fn replay_itch(path: &str, stock: &str, output: &mut LevelColumns) -> Result<Vec<Series>> {
+    let file = File::open(path).with_context(|| path.to_string())?;
+    let symbol = stock_symbol(stock);
+
+    let mut book: OrderBookWithOrders<i64, i64, u64> = OrderBookWithOrders::new();
+    let mut stock_locate = None;
+    let mut timestamps = Vec::new();
+    for message in ItchReader::new(BufReader::new(file)) {
+        let message = message?;
+        match message {
+            ItchMessage::StockDirectory { stock, .. } | ItchMessage::AddOrder { stock, .. }
+                if stock == symbol =>
+            {
+                stock_locate = message.stock_locate();
+            }
+            _ => {}
+        }
+        if stock_locate.is_none() || message.stock_locate() != stock_locate {
+            continue;
+        }
+        let applied = message
+            .apply(&mut book)
+            .with_context(|| format!("Message {:?}", message))?;
+        if applied {
+            timestamps.push(message.timestamp().map(|t| t as i64));
+            output.append(book.book());
+        }
+    }
+    Ok(vec![Series::new("timestamp", timestamps)])
+}
+
+fn replay_dbn(
+    path: &str,
+    instrument_id: Option<u32>,
+    output: &mut LevelColumns,
+) -> Result<Vec<Series>> {
+    let file = File::open(path).with_context(|| path.to_string())?;
+    let reader = DbnReader::new(BufReader::new(file))?;
+
+    let mut mbo_books: HashMap<u32, OrderBookWithOrders<i64, i64, u64>> = HashMap::new();
+    let mut mbp_books: HashMap<u32, OrderBook<i64, i64>> = HashMap::new();
+    let mut ts_events = Vec::new();
+    let mut instrument_ids = Vec::new();
+    for record in reader {
+        let record = record?;
+        let (Some(id), Some(ts_event)) = (record.instrument_id(), record.ts_event()) else {
+            continue;
+        };
+        if instrument_id.is_some_and(|wanted| wanted != id) {
+            continue;
+        }
+        match record {
+            DbnRecord::Mbo {
+                order_id,
+                price,
+                size,
+                action,
+                side,
+                ..
+            } => {
+                let book = mbo_books.entry(id).or_default();
+                apply_mbo(book, order_id, price, size, action, side)
+                    .with_context(|| format!("Record {:?}", record))?;
+                output.append(book.book());
+            }
+            DbnRecord::Mbp { ref levels, .. } => {
+                let book = mbp_books.entry(id).or_default();
+                apply_mbp(book, levels);
+                output.append(book);
+            }
+            DbnRecord::Other { .. } => continue,
+        }
+        ts_events.push(ts_event as i64);
+        instrument_ids.push(id);
+    }
+
+    let ts_event = Series::new("ts_event", ts_events).cast(&DataType::Datetime(
+        TimeUnit::Nanoseconds,
+        Some("UTC".into()),
+    ))?;
+    Ok(vec![ts_event, Series::new("instrument_id", instrument_ids)])
+}
+
+fn main() -> Result<()> {
+    let args = parse_args(std::env::args().skip(1))?;
+    let mut levels = LevelColumns::new(args.n_levels);
+    let mut columns = match args.format {
+        Format::Csv => replay_csv(&args.input, &mut levels)?,
+        Format::Itch => {
+            let stock = args
+                .stock
+                .as_deref()
+                .ok_or_else(|| anyhow!("ITCH input needs --stock"))?;
+            replay_itch(&args.input, stock, &mut levels)?
+        }
+        Format::Dbn => replay_dbn(&args.input, args.instrument_id, &mut levels)?,
+    };
+    columns.extend(levels.finish());
+
+    let mut df = DataFrame::new(columns)?;
+    let file = File::create(&args.output).with_context(|| args.output.clone())?;
+    ParquetWriter::new(file).finish(&mut df)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Args> {
+        parse_args(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = args("feed.ITCH out.parquet --n-levels 5 --stock AAPL").unwrap();
+        assert_eq!(parsed.format, Format::Itch);
+        assert_eq!(parsed.n_levels, 5);
+        assert_eq!(parsed.stock.as_deref(), Some("AAPL"));
+
+        let parsed = args("feed.bin out.parquet --format dbn --instrument-id 7").unwrap();
+        assert_eq!(parsed.format, Format::Dbn);
+        assert_eq!(parsed.instrument_id, Some(7));
+        assert_eq!(parsed.n_levels, 1);
+
+        assert!(args("feed.bin out.parquet").is_err());
+        assert!(args("feed.csv").is_err());
+        assert!(args("feed.csv out.parquet --n-levels 0").is_err());
+        assert!(args("feed.csv out.parquet --depth 3").is_err());
+    }
+
+    #[test]
+    fn test_level_columns() {
+        let mut levels = LevelColumns::new(2);
+        let mut book = OrderBook::new();
+        book.add_qty(true, 100, 5);
+        levels.append(&book);
+        book.add_qty(true, 101, 1);
+        book.add_qty(false, 103, 2);
+        levels.append(&book);
+
+        assert_eq!(
+            levels.names(),
+            [
+                "bid_price_1",
+                "bid_price_2",
+                "bid_qty_1",
+                "bid_qty_2",
+                "ask_price_1",
+                "ask_price_2",
+                "ask_qty_1",
+                "ask_qty_2"
+            ]
+        );
+        assert_eq!(levels.columns[0], [Some(100), Some(101)]);
+        assert_eq!(levels.columns[1], [None, Some(100)]);
+        assert_eq!(levels.columns[3], [None, Some(5)]);
+        assert_eq!(levels.columns[4], [None, Some(103)]);
+        assert_eq!(levels.columns[5], [None, None]);
+        assert_eq!(LevelColumns::new(1).names()[0], "best_bid");
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let mut book = OrderBook::new();
+        apply_delta(&mut book, true, 100, 5).unwrap();
+        apply_delta(&mut book, true, 101, 0).unwrap();
+        assert_eq!(book.get_book_side(true).num_levels(), 1);
+        assert_eq!(book.get_book_side(true).best_price, Some(100));
+
+        apply_delta(&mut book, true, 100, -2).unwrap();
+        assert_eq!(book.get_book_side(true).best_price_qty, Some(3));
+        assert!(apply_delta(&mut book, true, 100, -4).is_err());
+        assert!(apply_delta(&mut book, false, 102, -1).is_err());
+    }
+}
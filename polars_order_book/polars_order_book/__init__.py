@@ -1,10 +1,15 @@
 from __future__ import annotations
 
 from pathlib import Path
-from typing import TYPE_CHECKING
+from typing import TYPE_CHECKING, Any, Iterator, Literal, Sequence
 
 import polars as pl
 
+from polars_order_book.polars_order_book import (
+    OrderBook,
+    read_dbn_bbo,
+    read_itch_bbo,
+)
 from polars_order_book.utils import parse_into_expr, parse_version, register_plugin
 
 if TYPE_CHECKING:
@@ -17,32 +22,1623 @@ if parse_version(pl.__version__) < parse_version("0.20.16"):
 else:
     lib = Path(__file__).parent
 
+CrossedPolicy = Literal["ignore", "flag", "uncross", "raise"]
+SequenceGapPolicy = Literal["flag", "raise"]
+OverDeletePolicy = Literal["error", "clamp", "ignore"]
+NullPolicy = Literal["raise", "skip", "delete_level"]
+QueueModel = Literal["pessimistic", "proportional", "optimistic"]
 
-def calculate_bbo(
+
+def _initial_state_kwargs(
+    initial_bids: Sequence[tuple[int, int]] | None,
+    initial_asks: Sequence[tuple[int, int]] | None,
+) -> dict[str, list[tuple[int, int]]]:
+    return {
+        "initial_bids": [(price, qty) for price, qty in initial_bids or []],
+        "initial_asks": [(price, qty) for price, qty in initial_asks or []],
+    }
+
+
+def _parse_update_args(
     price: IntoExpr,
     qty: IntoExpr,
     is_bid: IntoExpr,
-    prev_price: IntoExpr | None = None,
-    prev_qty: IntoExpr | None = None,
-) -> pl.Expr:
+    prev_price: IntoExpr | None,
+    prev_qty: IntoExpr | None,
+) -> list[pl.Expr]:
     price = parse_into_expr(price)
     qty = parse_into_expr(qty)
     is_bid = parse_into_expr(is_bid)
     if (prev_price is not None) and (prev_qty is not None):
         prev_price = parse_into_expr(prev_price)
         prev_qty = parse_into_expr(prev_qty)
-        args = [price, qty, is_bid, prev_price, prev_qty]
+        return [price, qty, is_bid, prev_price, prev_qty]
     elif (prev_price is None) and (prev_qty is None):
-        args = [price, qty, is_bid]
+        return [price, qty, is_bid]
     else:
         raise ValueError(
             f"""Cannot provide only one of prev_price and prev_qty. Got:\n
             prev_price={prev_price},\nprev_qty={prev_qty}"""
         )
 
+
+def calculate_bbo(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    output_style: Literal["struct", "flat"] = "struct",
+    include_modify_outcome: bool = False,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    tick_size: float | None = None,
+    lot_size: float | None = None,
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+    include_depth_totals: bool = False,
+    over_delete_policy: OverDeletePolicy = "error",
+    sequence: IntoExpr | None = None,
+    sequence_gap_policy: SequenceGapPolicy = "flag",
+    passthrough: Sequence[IntoExpr] | None = None,
+    strict: bool = True,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask after each price-level update.
+
+    `output_style="flat"` names the struct fields `bid_price_1`, `bid_qty_1`,
+    `ask_price_1` and `ask_qty_1` instead of `best_bid`, `best_bid_qty`, etc.
+    An expression can only return a single Series, so the result is still a
+    struct; `DataFrame.unnest` turns it into flat columns.
+
+    `include_modify_outcome=True` adds a `modify_outcome` field when
+    `prev_price` and `prev_qty` are given, labelling each modify as
+    `"unchanged"`, `"qty_changed"`, `"price_moved"` or
+    `"price_moved_and_resized"`. Rows without a previous price and qty are null.
+
+    `initial_bids` and `initial_asks` pre-load the book with `(price, qty)`
+    levels, e.g. a snapshot to resume an intraday replay from, so that the
+    first output row reflects its update applied on top of them. A crossed
+    snapshot, a repeated price or a non-positive qty raises an error.
+
+    Float `price` and `qty` columns, e.g. from crypto feeds, are supported by
+    giving `tick_size` and `lot_size`. Values are rounded to the nearest tick
+    or lot internally and the output fields keep the input types. Decimal
+    columns need no sizes: they are replayed on their unscaled integer values,
+    so prices round-trip exactly with their scale. Initial levels are given in
+    these integer units.
+
+    Integer columns of any width or signedness, e.g. Int32 or UInt32, need no
+    cast to Int64 and the output fields keep their types. `is_bid` may also be
+    an integer column, where non-zero means a bid. Int32 prices and qtys, or
+    UInt32 prices with Int32 qtys, are replayed without widening them at all,
+    which halves the memory of large replays.
+
+    `crossed_policy` sets what happens when an update leaves the best bid at
+    or through the best ask: `"ignore"` outputs the crossed book as-is,
+    `"flag"` adds a boolean `crossed` field, `"uncross"` removes the stale
+    levels on the side opposite the update, and `"raise"` fails with the index
+    of the offending row.
+
+    `emit_on_change=True` nulls every field of rows on which the best bid and
+    ask are unchanged from the previous row, and adds a boolean `changed`
+    field marking the rows that were emitted. Filtering on `changed` keeps
+    only the updates that moved the top of the book, which for deep-book
+    feeds is a small fraction of them.
+
+    `include_depth_totals=True` adds `bid_total_qty` and `ask_total_qty`
+    fields with the total qty resting on each side, and `bid_levels` and
+    `ask_levels` fields with its number of price levels. The book keeps these
+    as running totals, so they cost no scan of the levels per row.
+
+    `sequence` is an optional column of feed sequence numbers, which should
+    increase by one per row. With `sequence_gap_policy="flag"` a boolean
+    `sequence_gap` field marks rows that don't follow the previous one; with
+    `"raise"` the first gap fails with its row index and the expected and
+    actual sequence numbers.
+
+    `passthrough` columns, e.g. event timestamps or ids, are copied verbatim
+    into the output struct after the computed fields, keeping their names, so
+    they stay aligned with the BBO rows when the struct is unnested.
+
+    `over_delete_policy` sets what happens to deletes of more qty than a
+    level holds, which some venues send after executions against hidden
+    size: `"error"` treats them as failed updates, `"clamp"` deletes the
+    whole level and `"ignore"` leaves it unchanged.
+
+    By default an update that can't be applied, such as deleting more qty
+    than a level holds or modifying a missing level, fails the expression.
+    `strict=False` skips such updates instead, leaving the book unchanged,
+    and adds an `error` string field describing why each skipped row was
+    skipped; it is null on rows that were applied.
+
+    `null_policy` sets how updates with a null price, qty or is_bid are
+    handled: `"raise"` fails with the row index, `"skip"` leaves the book
+    unchanged so the row repeats the previous output, and `"delete_level"`
+    treats a null qty as deleting the whole level at the row's price and
+    skips rows with other nulls. Every row still gets an output row.
+
+    Level qtys are accumulated in the qty column's integer type and wrap
+    around on overflow. `checked_qty=True` fails on an update that would
+    overflow a level's qty, or whose qty delta overflows, instead.
+    """
+    args = _parse_update_args(price, qty, is_bid, prev_price, prev_qty)
+    if sequence is not None:
+        args.append(parse_into_expr(sequence))
+    passthrough_args = [parse_into_expr(expr) for expr in passthrough or []]
+    args.extend(passthrough_args)
     return register_plugin(
         args=args,  # type: ignore
         symbol="pl_calculate_bbo",
         is_elementwise=False,
+        kwargs={
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+            "include_depth_totals": include_depth_totals,
+            "over_delete_policy": over_delete_policy,
+            "sequence_gap_policy": (
+                sequence_gap_policy if sequence is not None else None
+            ),
+            "tick_size": tick_size,
+            "lot_size": lot_size,
+            "include_modify_outcome": include_modify_outcome,
+            "n_passthrough": len(passthrough_args),
+            "strict": strict,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_bbo_signed_delta(
+    price: IntoExpr,
+    qty_delta: IntoExpr,
+    is_bid: IntoExpr,
+    output_style: Literal["struct", "flat"] = "struct",
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    tick_size: float | None = None,
+    lot_size: float | None = None,
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+    over_delete_policy: OverDeletePolicy = "error",
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask from a signed qty delta column.
+
+    Positive deltas add qty at the price level, negative deltas delete it and
+    zero deltas leave the book unchanged.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `tick_size`,
+    `lot_size`, `crossed_policy`, `emit_on_change`, `over_delete_policy`,
+    `null_policy` and `checked_qty`.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(price),
+            parse_into_expr(qty_delta),
+            parse_into_expr(is_bid),
+        ],
+        symbol="pl_calculate_bbo_signed_delta",
+        is_elementwise=False,
+        kwargs={
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+            "over_delete_policy": over_delete_policy,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            "tick_size": tick_size,
+            "lot_size": lot_size,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_bbo_set_level(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    output_style: Literal["struct", "flat"] = "struct",
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    tick_size: float | None = None,
+    lot_size: float | None = None,
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask from absolute price-level quantities.
+
+    Each row sets the total qty resting at its price level, as in
+    market-by-price snapshot feeds. A qty of zero deletes the level.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `tick_size`,
+    `lot_size`, `crossed_policy` and `emit_on_change`.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(price),
+            parse_into_expr(qty),
+            parse_into_expr(is_bid),
+        ],
+        symbol="pl_calculate_bbo_set_level",
+        is_elementwise=False,
+        kwargs={
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+            "tick_size": tick_size,
+            "lot_size": lot_size,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
         lib=lib,
     )
+
+
+def calculate_bbo_with_level_deletes(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    delete_level: IntoExpr,
+    output_style: Literal["struct", "flat"] = "struct",
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    tick_size: float | None = None,
+    lot_size: float | None = None,
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+    over_delete_policy: OverDeletePolicy = "error",
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask from signed qty mutations and level deletes.
+
+    Rows where `delete_level` is true remove the whole price level, whatever
+    its qty, and may leave `qty` null. Other rows add positive qty and delete
+    negative qty at the price level.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `tick_size`,
+    `lot_size`, `crossed_policy`, `emit_on_change` and `over_delete_policy`.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(price),
+            parse_into_expr(qty),
+            parse_into_expr(is_bid),
+            parse_into_expr(delete_level),
+        ],
+        symbol="pl_calculate_bbo_with_level_deletes",
+        is_elementwise=False,
+        kwargs={
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+            "over_delete_policy": over_delete_policy,
+            "tick_size": tick_size,
+            "lot_size": lot_size,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_bbo_with_reset(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    reset: IntoExpr,
+    output_style: Literal["struct", "flat"] = "struct",
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    tick_size: float | None = None,
+    lot_size: float | None = None,
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+    over_delete_policy: OverDeletePolicy = "error",
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask across sessions separated by book resets.
+
+    Rows where `reset` is true empty the book before applying their own update,
+    so several sessions can be replayed in one call. Reset rows may leave
+    `price`, `qty` and `is_bid` null to only clear the book. Other rows add
+    positive qty and delete negative qty at the price level.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `tick_size`,
+    `lot_size`, `crossed_policy`, `emit_on_change` and `over_delete_policy`.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(price),
+            parse_into_expr(qty),
+            parse_into_expr(is_bid),
+            parse_into_expr(reset),
+        ],
+        symbol="pl_calculate_bbo_with_reset",
+        is_elementwise=False,
+        kwargs={
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+            "over_delete_policy": over_delete_policy,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+            "tick_size": tick_size,
+            "lot_size": lot_size,
+        },
+        lib=lib,
+    )
+
+
+def calculate_bbo_with_market_phase(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    market_phase: IntoExpr,
+    clear_on_halt: bool = False,
+    output_style: Literal["struct", "flat"] = "struct",
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    tick_size: float | None = None,
+    lot_size: float | None = None,
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+    over_delete_policy: OverDeletePolicy = "error",
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask across changes of trading status.
+
+    `market_phase` gives the status of the market at each update, one of
+    "continuous", "auction" or "halted", so that a whole session can be
+    replayed in one call instead of slicing the data by phase and losing book
+    continuity. Updates add positive qty and delete negative qty at the price
+    level.
+
+    Auction books are expected to cross, so `crossed_policy` is only applied
+    outside auctions. With `clear_on_halt=True` the book is emptied on the
+    first row of each halt, for venues that purge resting orders on a halt.
+    The phase of each row is passed through as a `market_phase` field of the
+    output.
+
+    See `calculate_bbo` for the other parameters.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(price),
+            parse_into_expr(qty),
+            parse_into_expr(is_bid),
+            parse_into_expr(market_phase),
+        ],
+        symbol="pl_calculate_bbo_with_market_phase",
+        is_elementwise=False,
+        kwargs={
+            "clear_on_halt": clear_on_halt,
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+            "over_delete_policy": over_delete_policy,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+            "tick_size": tick_size,
+            "lot_size": lot_size,
+        },
+        lib=lib,
+    )
+
+
+def calculate_bbo_by_symbol(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    symbol: IntoExpr,
+    output_style: Literal["struct", "flat"] = "struct",
+    tick_size: float | None = None,
+    lot_size: float | None = None,
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+    over_delete_policy: OverDeletePolicy = "error",
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask for updates interleaving several symbols.
+
+    A separate book is kept for each distinct `symbol` value, and each row gets
+    the best bid and ask of its own symbol's book after the update. This avoids
+    partitioning by symbol and calling `calculate_bbo` once per partition.
+    Symbols are replayed in parallel, so a feed of several instruments takes
+    about as long as its busiest one.
+
+    See `calculate_bbo` for `tick_size`, `lot_size`, `crossed_policy`,
+    `emit_on_change` and `over_delete_policy`. With `emit_on_change=True`
+    each row is compared with the previous row of the same symbol.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(price),
+            parse_into_expr(qty),
+            parse_into_expr(is_bid),
+            parse_into_expr(symbol),
+        ],
+        symbol="pl_calculate_bbo_by_symbol",
+        is_elementwise=False,
+        kwargs={
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+            "over_delete_policy": over_delete_policy,
+            "tick_size": tick_size,
+            "lot_size": lot_size,
+        },
+        lib=lib,
+    )
+
+
+def calculate_bbo_from_actions(
+    action: IntoExpr,
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    output_style: Literal["struct", "flat"] = "struct",
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+    over_delete_policy: OverDeletePolicy = "error",
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask from a feed of mixed price-level events.
+
+    `action` is one of `"add"`, `"delete"`, `"trade"`, `"modify"` or `"clear"`.
+    Trades delete the traded qty from the resting level on the `is_bid` side.
+    Modifies move `prev_qty` at `prev_price` to `qty` at `price` and need the
+    `prev_price` and `prev_qty` columns. Clears empty the book and may leave
+    the other columns null.
+
+    See `calculate_bbo` for `crossed_policy`, `emit_on_change` and
+    `over_delete_policy`.
+    """
+    args = _parse_update_args(price, qty, is_bid, prev_price, prev_qty)
+    return register_plugin(
+        args=[parse_into_expr(action), *args],  # type: ignore
+        symbol="pl_calculate_bbo_from_actions",
+        is_elementwise=False,
+        kwargs={
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+            "over_delete_policy": over_delete_policy,
+        },
+        lib=lib,
+    )
+
+
+def calculate_bbo_asof(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    ts: IntoExpr,
+    sample_ts: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    output_style: Literal["struct", "flat"] = "struct",
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+    over_delete_policy: OverDeletePolicy = "error",
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask as of each of a series of sample times.
+
+    `ts` is the sorted time of each update and `sample_ts` a sorted series of
+    times to sample the book at, e.g. `pl.lit(pl.datetime_range(...))`. Each
+    output row reflects every update with `ts <= sample_ts`, so the result
+    has the length of `sample_ts` and intermediate rows are never built. The
+    two must have the same dtype.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `crossed_policy`,
+    `emit_on_change` and `over_delete_policy`.
+    """
+    args = _parse_update_args(price, qty, is_bid, prev_price, prev_qty)
+    return register_plugin(
+        args=[parse_into_expr(ts), parse_into_expr(sample_ts), *args],  # type: ignore
+        symbol="pl_calculate_bbo_asof",
+        is_elementwise=False,
+        changes_length=True,
+        kwargs={
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+            "over_delete_policy": over_delete_policy,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_bbo_conflated(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    ts: IntoExpr,
+    interval: int,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    output_style: Literal["struct", "flat"] = "struct",
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+    over_delete_policy: OverDeletePolicy = "error",
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask at the end of each time interval.
+
+    `ts` is the sorted time of each update and `interval` the length of each
+    interval in the units of `ts`, e.g. `100_000` for 100ms intervals of
+    microsecond timestamps. Intervals start at multiples of `interval`. Only
+    the book after the last update of each interval is emitted, with the
+    start of the interval as an `interval_start` field of the dtype of `ts`.
+    Intervals without updates have no row. This gives the same result as a
+    `group_by_dynamic(...).agg(pl.all().last())` over `calculate_bbo`, without
+    building a row per update first.
+
+    See `calculate_bbo` for the other parameters.
+    """
+    args = _parse_update_args(price, qty, is_bid, prev_price, prev_qty)
+    return register_plugin(
+        args=[parse_into_expr(ts), *args],  # type: ignore
+        symbol="pl_calculate_bbo_conflated",
+        is_elementwise=False,
+        changes_length=True,
+        kwargs={
+            "interval": interval,
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+            "over_delete_policy": over_delete_policy,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_bbo_depth_stream(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    first_update_id: IntoExpr,
+    last_update_id: IntoExpr,
+    snapshot_update_id: int,
+    output_style: Literal["struct", "flat"] = "struct",
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask from a REST snapshot and diff depth events.
+
+    This follows Binance's rules for maintaining a local book. `initial_bids`
+    and `initial_asks` hold the snapshot and `snapshot_update_id` its
+    `lastUpdateId`. Each row is one level of a diff depth event, with the
+    event's first (`U`) and last (`u`) update ids, and sets the absolute qty
+    of the level; a qty of zero deletes it. Consecutive rows with the same
+    update ids form one event.
+
+    Events with `last_update_id <= snapshot_update_id` predate the snapshot
+    and leave the book unchanged. The first event applied must span
+    `snapshot_update_id + 1`, and each later one must start right after the
+    previous one ended. Anything else means events were missed and raises an
+    error, as the book has to be rebuilt from a fresh snapshot.
+
+    See `calculate_bbo` for `crossed_policy` and `emit_on_change`.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(price),
+            parse_into_expr(qty),
+            parse_into_expr(is_bid),
+            parse_into_expr(first_update_id),
+            parse_into_expr(last_update_id),
+        ],
+        symbol="pl_calculate_bbo_depth_stream",
+        is_elementwise=False,
+        kwargs={
+            "snapshot_update_id": snapshot_update_id,
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_bbo_order_id(
+    order_id: IntoExpr,
+    action: IntoExpr,
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    output_style: Literal["struct", "flat"] = "struct",
+    crossed_policy: CrossedPolicy = "ignore",
+    emit_on_change: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask from a market-by-order feed.
+
+    `action` is one of `"add"`, `"cancel"`, `"replace"` or `"execute"`. Adds
+    need `price`, `qty` and `is_bid`; replaces need the new `price` and `qty`;
+    executes need the executed `qty`; cancels only need `order_id`. Orders are
+    aggregated into price levels internally, so the feed does not have to be
+    converted to price-level deltas first.
+
+    `crossed_policy` may only be `"ignore"` or `"flag"` here, since removing
+    levels would leave them out of step with the tracked orders. See
+    `calculate_bbo`.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(order_id),
+            parse_into_expr(action),
+            parse_into_expr(price),
+            parse_into_expr(qty),
+            parse_into_expr(is_bid),
+        ],
+        symbol="pl_calculate_bbo_order_id",
+        is_elementwise=False,
+        kwargs={
+            "output_style": output_style,
+            "crossed_policy": crossed_policy,
+            "emit_on_change": emit_on_change,
+        },
+        lib=lib,
+    )
+
+
+def mbo_to_mbp(
+    order_id: IntoExpr,
+    action: IntoExpr,
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+) -> pl.Expr:
+    """
+    Convert a market-by-order feed to price-level updates.
+
+    Takes the same columns as `calculate_bbo_order_id` and returns a struct
+    with one price-level update per event, with fields `price`, `qty`,
+    `is_bid`, `prev_price` and `prev_qty`. Adds give the order's qty, and
+    cancels and executes the negated qty they remove from its level. Replaces
+    are modifies: the order's remaining qty leaves `prev_price` and its new
+    qty rests at `price`.
+
+    The fields can be passed straight to `calculate_bbo` and the other
+    price-level expressions, or stored as a compact level-2 feed.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(order_id),
+            parse_into_expr(action),
+            parse_into_expr(price),
+            parse_into_expr(qty),
+            parse_into_expr(is_bid),
+        ],
+        symbol="pl_mbo_to_mbp",
+        is_elementwise=False,
+        lib=lib,
+    )
+
+
+def snapshots_to_deltas(
+    bid_prices: IntoExpr,
+    bid_qtys: IntoExpr,
+    ask_prices: IntoExpr,
+    ask_qtys: IntoExpr,
+) -> pl.Expr:
+    """
+    Convert full depth snapshots to price-level updates.
+
+    Each row holds a snapshot as list columns of bid prices, bid qtys, ask
+    prices and ask qtys, as many vendors distribute them. Returns a struct
+    with one row per level that changed between consecutive snapshots, with
+    fields `snapshot_row`, the index of the snapshot it leads to, `price`,
+    `qty`, the signed change in the level's qty, and `is_bid`. The first
+    snapshot is compared with an empty book. Within a snapshot, changes come
+    bids then asks, each best first. Levels with a qty of 0, as used to pad
+    fixed-depth snapshots, are ignored.
+
+    The `price`, `qty` and `is_bid` fields can be passed straight to
+    `calculate_bbo` and the other price-level expressions. The result has the
+    length of the update stream, so select it on its own.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(bid_prices),
+            parse_into_expr(bid_qtys),
+            parse_into_expr(ask_prices),
+            parse_into_expr(ask_qtys),
+        ],
+        symbol="pl_snapshots_to_deltas",
+        is_elementwise=False,
+        changes_length=True,
+        lib=lib,
+    )
+
+
+def calculate_top_n(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    n: int = 5,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+    order_count: IntoExpr | None = None,
+    timestamp: IntoExpr | None = None,
+    cumulative_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the best `n` price levels of each side after each update.
+
+    The result is a struct of wide fields, `bid_price_1` to `bid_price_n`,
+    then `bid_qty_*`, `ask_price_*` and `ask_qty_*`, so `DataFrame.unnest`
+    gives one column per level. Levels beyond the depth of a side are null.
+    With `n=1` the fields match `calculate_bbo(output_style="flat")`.
+
+    `cumulative_qty=True` adds `bid_cum_qty_*` and `ask_cum_qty_*` fields
+    after the qtys, with the running sum of the qty from the best level
+    outwards, so a depth profile needs no cumulative sum afterwards.
+
+    `order_count`, if given, is the signed change in the number of orders
+    resting at each update's level, e.g. 1 for a new order, -1 for a cancel
+    and 0 for a partial fill. It adds `bid_ct_*` and `ask_ct_*` fields with
+    the number of orders at each level, which are null for levels with no
+    counts given, such as the initial levels.
+
+    `timestamp`, if given, is the time of each update, e.g. a Datetime or
+    integer column. It adds `bid_ts_*` and `ask_ts_*` fields, of the same
+    type, with when each level was last modified, for level-age and
+    staleness analytics. Initial levels have no timestamp until modified.
+
+    `order_count` and `timestamp` can't be combined with `prev_price` and
+    `prev_qty`.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    args = _parse_update_args(price, qty, is_bid, prev_price, prev_qty)
+    if order_count is not None:
+        args.append(parse_into_expr(order_count))
+    if timestamp is not None:
+        args.append(parse_into_expr(timestamp))
+    return register_plugin(
+        args=args,  # type: ignore
+        symbol="pl_calculate_top_n",
+        is_elementwise=False,
+        kwargs={
+            "n": n,
+            "order_count": order_count is not None,
+            "level_timestamp": timestamp is not None,
+            "cumulative_qty": cumulative_qty,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_mid_spread(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    depth_weights: Sequence[float] | None = None,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the mid price, spread and spread in basis points after each update.
+
+    Takes the same inputs as `calculate_bbo` and returns a struct with Float64
+    fields `mid`, `spread` and `spread_bps`, which are null while either side
+    of the book is empty.
+
+    `depth_weights` adds a `weighted_mid` field, a fair value estimate using
+    the best `len(depth_weights)` levels of each side, e.g. decaying weights
+    like `[1.0, 0.5, 0.25]`. Each side's qty is weighted level by level, best
+    first, and its prices are averaged by the weighted qty. The two averages
+    are then combined with each weighted by the other side's weighted qty, so
+    the estimate leans towards the thinner side. With a single weight it is
+    the microprice.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_calculate_mid_spread",
+        is_elementwise=False,
+        kwargs={
+            "depth_weights": list(depth_weights or []),
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_queue_position(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    order_is_bid: bool,
+    order_price: int,
+    entry_row: int | None = None,
+    entry_time: Any | None = None,
+    timestamp: IntoExpr | None = None,
+    model: QueueModel = "proportional",
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Estimate the queue position of a hypothetical order of our own.
+
+    The order rests at `order_price` on the bid side if `order_is_bid`, and
+    joins the back of the queue after the update in `entry_row`, or after the
+    first update whose `timestamp` is at or after `entry_time`. Everything at
+    its level then is ahead of it and later adds are behind it. `model` sets
+    where decreases of the level are assumed to come from: `"pessimistic"`
+    takes them from behind the order first, `"optimistic"` from ahead of it
+    first and `"proportional"` from both in proportion to their qty.
+
+    The order is filled once the opposite side reaches its price, or its
+    level is emptied while it is the best of its side.
+
+    Returns a struct with fields `qty_ahead` (Float64), `fill_probability`
+    (Float64), a heuristic that is the share of the qty ahead at entry that
+    has since left the queue and 1 once filled, and `filled` (Boolean). All
+    are null before the order is entered.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    if (entry_row is None) == (entry_time is None):
+        raise ValueError("Exactly one of entry_row and entry_time must be given")
+    args = _parse_update_args(price, qty, is_bid, prev_price, prev_qty)
+    if entry_time is not None:
+        if timestamp is None:
+            raise ValueError("timestamp must be given with entry_time")
+        args.append(parse_into_expr(timestamp) >= entry_time)
+    return register_plugin(
+        args=args,  # type: ignore
+        symbol="pl_calculate_queue_position",
+        is_elementwise=False,
+        kwargs={
+            "order_is_bid": order_is_bid,
+            "order_price": order_price,
+            "entry_row": entry_row,
+            "model": model,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def match_orders(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Simulate execution by matching marketable adds against the book.
+
+    Replays signed qty updates like `calculate_bbo`, but treats every add as
+    a limit order: it first trades against opposite levels at or better than
+    its price, best price first, and only its unfilled qty rests on the book.
+    Deletes are applied as usual.
+
+    Returns a list of `{price, qty}` fills for each row, best price first,
+    which is empty for rows that didn't trade.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, None, None),  # type: ignore
+        symbol="pl_match_orders",
+        is_elementwise=False,
+        kwargs={
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_imbalance(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    depth: int = 1,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the order book imbalance over the best `depth` levels per side.
+
+    The imbalance is `(bid_qty - ask_qty) / (bid_qty + ask_qty)` with the qtys
+    summed over each side's best `depth` levels, so it ranges from -1 (asks
+    only) to 1 (bids only). It is null while the book is empty.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_calculate_imbalance",
+        is_elementwise=False,
+        kwargs={
+            "depth": depth,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_ofi(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    ts: IntoExpr | None = None,
+    window: int | None = None,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the order flow imbalance (OFI) of each update at the best quotes.
+
+    Following Cont, Kukanov and Stoikov, each update contributes the signed
+    change in depth at the best bid minus that at the best ask: size arriving
+    at or above the previous best bid adds, size leaving it subtracts, and the
+    reverse for the ask.
+
+    Given a sorted `ts` column and a `window`, each row is instead the sum of
+    the OFI over the trailing `(ts - window, ts]`, with `window` in the
+    integer units of `ts`, e.g. microseconds for a `Datetime("us")` column.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    if (ts is None) != (window is None):
+        raise ValueError("ts and window must be given together")
+    args = _parse_update_args(price, qty, is_bid, prev_price, prev_qty)
+    if ts is not None:
+        args.append(parse_into_expr(ts))
+    return register_plugin(
+        args=args,  # type: ignore
+        symbol="pl_calculate_ofi",
+        is_elementwise=False,
+        kwargs={
+            "window": window,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+def calculate_vwap(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    depth: int = 5,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the volume-weighted average price of the best `depth` levels.
+
+    Returns a struct with Float64 fields `bid_vwap` and `ask_vwap`, each null
+    while its side of the book is empty.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_calculate_vwap",
+        is_elementwise=False,
+        kwargs={
+            "depth": depth,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_sweep_cost(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    size: int,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the average fill price of a market order of `size` after each update.
+
+    Returns a struct with Float64 fields `buy_price`, from sweeping the asks,
+    and `sell_price`, from sweeping the bids. A price is null while that side
+    of the book holds less than `size` in total.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_calculate_sweep_cost",
+        is_elementwise=False,
+        kwargs={
+            "size": size,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_depth_band(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    bps: float,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the depth within `bps` basis points of the mid after each update.
+
+    Returns a struct with Int64 fields `bid_qty` and `ask_qty`, the qty resting
+    on each side within the band, and Float64 fields `bid_notional` and
+    `ask_notional`, the sum of price times qty over those levels. Levels
+    exactly on the edge of the band are included. All four are null while
+    either side of the book is empty.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_calculate_depth_band",
+        is_elementwise=False,
+        kwargs={
+            "bps": bps,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_depth_buckets(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    n_buckets: int,
+    tick_width: int,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the qty of each side in bands of `tick_width` ticks from the mid.
+
+    Returns a struct of `bid_band_1` to `bid_band_{n_buckets}` then the same
+    `ask_band_*` fields, with the total qty resting between `(k - 1) *
+    tick_width` and `k * tick_width` ticks away from the mid in band `k`,
+    including the lower edge. Ticks are units of the `price` column. Levels
+    further away than the last band are left out. All fields are null while
+    either side of the book is empty.
+
+    Every level of the book is visited, not just the best `n` as in
+    `calculate_top_n`, which suits liquidity heatmaps and model features.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_calculate_depth_buckets",
+        is_elementwise=False,
+        kwargs={
+            "n_buckets": n_buckets,
+            "tick_width": tick_width,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_indicative_uncross(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the indicative auction uncross after each update.
+
+    During an opening or closing auction the book can cross without trading.
+    The indicative price is the price that would match the most qty if the
+    book uncrossed now; ties go to the price leaving the smallest imbalance,
+    then to the lowest price.
+
+    Returns a struct with Int64 fields `price`, `volume`, the qty matched at
+    `price`, and `imbalance`, the qty left unmatched, positive if it is on the
+    bid side. All three are null while the book isn't crossed.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_calculate_indicative_uncross",
+        is_elementwise=False,
+        kwargs={
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_nbbo(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    venue: IntoExpr,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask across venues after each update.
+
+    `venue` names the venue of each update in a merged multi-venue feed, and
+    each venue's updates are applied to its own book. Updates add positive qty
+    and delete negative qty at the price level.
+
+    Returns a struct with `best_bid`, `best_bid_qty`, `best_ask` and
+    `best_ask_qty`, the qty summed over the venues at the best price, and
+    `best_bid_venues` and `best_ask_venues`, the sorted lists of venues at it.
+    Books of different venues may lock or cross each other, so crossed books
+    are not policed.
+
+    See `calculate_bbo` for `null_policy` and `checked_qty`.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(price),
+            parse_into_expr(qty),
+            parse_into_expr(is_bid),
+            parse_into_expr(venue),
+        ],
+        symbol="pl_calculate_nbbo",
+        is_elementwise=False,
+        kwargs={
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+        },
+        lib=lib,
+    )
+
+
+def calculate_implied_bbo(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    contract: IntoExpr,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask of a calendar spread and both its legs,
+    including implied liquidity.
+
+    `contract` names the book of each update, one of "front", "back" or
+    "spread", so the updates of both legs and the spread can be replayed as
+    one feed. The spread is priced as front minus back. As on CME, resting
+    spread and outright orders imply prices in the third book: e.g. a spread
+    bid and a back bid imply a front bid at their sum, and a front bid and a
+    back ask imply a spread bid at their difference. Implied qty is the
+    smaller qty of the two. Only first generation implieds from the best
+    levels are derived.
+
+    Returns a struct with `{contract}_bid`, `{contract}_bid_qty`,
+    `{contract}_ask` and `{contract}_ask_qty` for each of front, back and
+    spread, merging each outright book with its implied best, with qty summed
+    where the two share a price.
+
+    See `calculate_bbo` for `null_policy` and `checked_qty`.
+    """
+    return register_plugin(
+        args=[
+            parse_into_expr(price),
+            parse_into_expr(qty),
+            parse_into_expr(is_bid),
+            parse_into_expr(contract),
+        ],
+        symbol="pl_calculate_implied_bbo",
+        is_elementwise=False,
+        kwargs={
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+        },
+        lib=lib,
+    )
+
+
+def book_features(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    features: Sequence[str] = ("mid", "spread", "microprice", "imbalance_1", "ofi"),
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate a set of per-row book features in a single replay.
+
+    Replaying once for all features is cheaper than calling `calculate_mid_spread`,
+    `calculate_imbalance`, `calculate_ofi` and others one by one. `features`
+    chooses the fields of the returned struct, in order:
+
+    - `"mid"`: Float64 average of the best bid and ask.
+    - `"spread"`: Float64 best ask minus best bid.
+    - `"microprice"`: Float64 best bid and ask averaged with each weighted by
+      the qty at the other.
+    - `"imbalance_<depth>"`, e.g. `"imbalance_5"`: Float64 imbalance over the
+      best `depth` levels of each side, as in `calculate_imbalance`.
+    - `"depth_totals"`: Int64 `bid_total_qty` and `ask_total_qty`, the qty of
+      every level of each side.
+    - `"ofi"`: Int64 order flow imbalance of the update, as in
+      `calculate_ofi`.
+
+    The mid, spread and microprice are null while either side is empty.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_book_features",
+        is_elementwise=False,
+        kwargs={
+            "features": list(features),
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def book_json(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    depth: int = 5,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Serialise the best `depth` levels of each side to a JSON string per row.
+
+    Rows look like `{"bids":[[px,qty],...],"asks":[[px,qty],...]}`, best level
+    first, with sides shallower than `depth` giving shorter arrays. This is
+    convenient for JSON consumers but much slower than `calculate_bbo`.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_book_json",
+        is_elementwise=False,
+        kwargs={
+            "depth": depth,
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def final_book(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Snapshot the book left after the last update, one row per price level.
+
+    The result is a struct with `is_bid`, `price` and `qty` fields, bids then
+    asks, each best level first. Its length is the number of levels rather
+    than the number of updates, so select it on its own, e.g.
+    `df.select(final_book(...)).unnest("final_book")`. Feeding the levels back
+    as `initial_bids` and `initial_asks` warm-starts the next batch:
+
+        snapshot = df.select(final_book("price", "qty", "is_bid")).unnest("final_book")
+        initial_bids = snapshot.filter("is_bid").select("price", "qty").rows()
+        initial_asks = snapshot.filter(~pl.col("is_bid")).select("price", "qty").rows()
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_final_book",
+        is_elementwise=False,
+        changes_length=True,
+        kwargs={
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def validate_book_updates(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Audit a stream of updates, one row of diagnostics per update.
+
+    Takes the same inputs as `calculate_bbo` and replays them as it does with
+    `strict=False`, but instead of the best bid and ask returns a struct of
+    what was wrong with each row, so that a feed's quality can be measured
+    before its BBO output is trusted:
+
+    - `delete_missing_level`: deletes qty from a level the book doesn't hold.
+    - `qty_exceeds_available`: deletes more qty than its level holds.
+    - `crossed`: leaves the best bid at or through the best ask.
+    - `negative_qty`: has a negative `qty` or `prev_qty` where they are
+      absolute level qtys, i.e. when `prev_qty` is given.
+    - `duplicate_add`: an add repeating the previous row exactly.
+    - `error`: why the update couldn't be applied, or null if it was.
+
+    Updates that can't be applied are skipped, leaving the book unchanged,
+    and never fail the expression; with `null_policy="raise"` rows with a
+    null price, qty or is_bid get an `error` too. Summing the flags, e.g.
+    `df.select(validate_book_updates(...)).unnest("validation").sum()`,
+    counts each kind of problem.
+
+    See `calculate_bbo` for `initial_bids`, `initial_asks`, `null_policy` and
+    `checked_qty`.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_validate_book_updates",
+        is_elementwise=False,
+        kwargs={
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(initial_bids, initial_asks),
+        },
+        lib=lib,
+    )
+
+
+def diff_books(
+    book: pl.DataFrame,
+    other: pl.DataFrame,
+    price: str = "price",
+    qty: str = "qty",
+    is_bid: str = "is_bid",
+) -> pl.DataFrame:
+    """
+    Compare two book snapshots level by level.
+
+    Each snapshot has one row per price level with `price`, `qty` and
+    `is_bid` columns, such as the output of `final_book` or a vendor's depth
+    snapshot. To compare two points in a replay, take `final_book` of the
+    updates up to each point.
+
+    Returns every level whose qty differs as a DataFrame with `is_bid`,
+    `price`, `qty` and `other_qty` columns, bids then asks, each best first.
+    A level missing from a snapshot has a qty of 0 in it. An empty result
+    means the books match.
+    """
+
+    def to_book(snapshot: pl.DataFrame) -> OrderBook:
+        levels = snapshot.select(price, qty, is_bid)
+        return OrderBook(
+            initial_bids=levels.filter(pl.col(is_bid)).select(price, qty).rows(),
+            initial_asks=levels.filter(~pl.col(is_bid)).select(price, qty).rows(),
+        )
+
+    return to_book(book).diff(to_book(other))
+
+
+def calculate_bbo_reverse(
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    final_bids: Sequence[tuple[int, int]] | None = None,
+    final_asks: Sequence[tuple[int, int]] | None = None,
+    null_policy: NullPolicy = "raise",
+    checked_qty: bool = False,
+) -> pl.Expr:
+    """
+    Calculate the best bid and ask just before each update, walking the
+    updates backwards from the book after the last one.
+
+    `final_bids` and `final_asks` are the `(price, qty)` levels left after
+    the last update, e.g. from `final_book` or a vendor's end-of-day
+    snapshot. Each update is undone in turn, from the last row to the first,
+    so the book just before an event of interest, such as a trade, can be
+    found without replaying from the start of the day.
+
+    Returns a struct with `best_bid`, `best_bid_qty`, `best_ask` and
+    `best_ask_qty`, the first row holding the book before the first update.
+    `null_policy="delete_level"` can't be undone and isn't supported.
+
+    See `calculate_bbo` for the other parameters.
+    """
+    return register_plugin(
+        args=_parse_update_args(price, qty, is_bid, prev_price, prev_qty),  # type: ignore
+        symbol="pl_calculate_bbo_reverse",
+        is_elementwise=False,
+        kwargs={
+            "null_policy": null_policy,
+            "checked_qty": checked_qty,
+            **_initial_state_kwargs(final_bids, final_asks),
+        },
+        lib=lib,
+    )
+
+
+def calculate_bbo_batched(
+    frame: pl.DataFrame | pl.LazyFrame,
+    price: IntoExpr,
+    qty: IntoExpr,
+    is_bid: IntoExpr,
+    prev_price: IntoExpr | None = None,
+    prev_qty: IntoExpr | None = None,
+    batch_size: int = 1_000_000,
+    name: str = "bbo",
+    initial_bids: Sequence[tuple[int, int]] | None = None,
+    initial_asks: Sequence[tuple[int, int]] | None = None,
+    **kwargs: Any,
+) -> Iterator[pl.DataFrame]:
+    """
+    Calculate the best bid and ask over `frame` one batch of rows at a time.
+
+    The expressions replay a whole column at once, so they can't run under
+    Polars' streaming engine. This yields `frame` in batches of `batch_size`
+    rows instead, each with a `name` column holding `calculate_bbo` of the
+    batch, and carries the book from one batch into the next with
+    `final_book`. Only one batch is in memory at a time, so a `LazyFrame`,
+    e.g. from `pl.scan_parquet`, can be larger than RAM.
+
+    Other keyword arguments are passed to `calculate_bbo`.
+    """
+    lazy = frame.lazy()
+    update_args = (price, qty, is_bid, prev_price, prev_qty)
+    offset = 0
+    while True:
+        batch = lazy.slice(offset, batch_size).collect()
+        if batch.is_empty():
+            return
+        yield batch.with_columns(
+            calculate_bbo(
+                *update_args,
+                initial_bids=initial_bids,
+                initial_asks=initial_asks,
+                **kwargs,
+            ).alias(name)
+        )
+        snapshot = batch.select(
+            final_book(
+                *update_args, initial_bids=initial_bids, initial_asks=initial_asks
+            )
+        ).unnest("final_book")
+        initial_bids = snapshot.filter("is_bid").select("price", "qty").rows()
+        initial_asks = snapshot.filter(~pl.col("is_bid")).select("price", "qty").rows()
+        offset += batch_size
@@ -55,6 +55,7 @@ def register_plugin(
     *,
     symbol: str,
     is_elementwise: bool,
+    changes_length: bool = False,
     kwargs: dict[str, Any] | None = None,
     args: list[IntoExpr],
     lib: str | Path,
@@ -68,6 +69,7 @@ def register_plugin(
             args=args[1:],
             kwargs=kwargs,
             is_elementwise=is_elementwise,
+            changes_length=changes_length,
         )
     from polars.plugins import register_plugin_function
 
@@ -77,6 +79,7 @@ def register_plugin(
         function_name=symbol,
         kwargs=kwargs,
         is_elementwise=is_elementwise,
+        changes_length=changes_length,
     )
 
 
@@ -0,0 +1,145 @@
+use polars::prelude::*;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3_polars::PyDataFrame;
+
+use order_book::order_book::OrderBook;
+
+use crate::output::best_level;
+
+/// A stateful order book for interactive use from Python, e.g. to step
+/// through a feed by hand. It aggregates integer prices and qtys by level,
+/// like the book behind the expressions.
+#[pyclass(name = "OrderBook")]
+pub(crate) struct PyOrderBook {
+    book: OrderBook<i64, i64>,
+}
+
+#[pymethods]
+impl PyOrderBook {
+    #[new]
+    #[pyo3(signature = (initial_bids=Vec::new(), initial_asks=Vec::new()))]
+    fn new(initial_bids: Vec<(i64, i64)>, initial_asks: Vec<(i64, i64)>) -> PyResult<Self> {
+        let book = OrderBook::from_levels(&initial_bids, &initial_asks)
+            .map_err(|e| PyValueError::new_err(format!("Invalid initial book state: {}", e)))?;
+        Ok(PyOrderBook { book })
+    }
+
+    /// Add `qty` at the price level, creating it if needed.
+    fn add(&mut self, is_bid: bool, price: i64, qty: i64) -> PyResult<()> {
+        if qty <= 0 {
+            return Err(PyValueError::new_err(format!(
+                "qty must be positive, got {}",
+                qty
+            )));
+        }
+        self.book.add_qty(is_bid, price, qty);
+        Ok(())
+    }
+
+    /// Delete `qty` from the price level, removing it once empty.
+    fn delete(&mut self, is_bid: bool, price: i64, qty: i64) -> PyResult<()> {
+        self.book
+            .try_delete_qty(is_bid, price, qty)
+            .map(|_| ())
+            .map_err(|e| PyValueError::new_err(format!("{} at price {}", e, price)))
+    }
+
+    /// Move `prev_qty` at `prev_price` to `qty` at `price`.
+    fn modify(
+        &mut self,
+        is_bid: bool,
+        prev_price: i64,
+        prev_qty: i64,
+        price: i64,
+        qty: i64,
+    ) -> PyResult<()> {
+        let available = self
+            .book
+            .get_book_side(is_bid)
+            .get_level(prev_price)
+            .map_or(0, |level| level.qty);
+        if prev_qty > available {
+            return Err(PyValueError::new_err(format!(
+                "Cannot modify {} at price {}: only {} available",
+                prev_qty, prev_price, available
+            )));
+        }
+        self.book
+            .modify_qty(is_bid, prev_price, prev_qty, price, qty);
+        Ok(())
+    }
+
+    /// Remove every level from both sides.
+    fn clear(&mut self) {
+        self.book.clear();
+    }
+
+    /// The best bid as `(price, qty)`, or None if there are no bids.
+    fn best_bid(&self) -> Option<(i64, i64)> {
+        best_level(self.book.get_book_side(true))
+    }
+
+    /// The best ask as `(price, qty)`, or None if there are no asks.
+    fn best_ask(&self) -> Option<(i64, i64)> {
+        best_level(self.book.get_book_side(false))
+    }
+
+    /// The best `n` levels of each side as `(bids, asks)`, each a list of
+    /// `(price, qty)` sorted best first.
+    #[pyo3(signature = (n=5))]
+    fn top_n(&self, n: usize) -> (Vec<(i64, i64)>, Vec<(i64, i64)>) {
+        let view = self.book.view(n);
+        let levels = |is_bid| {
+            view.top_n(is_bid)
+                .iter()
+                .map(|level| (level.price, level.qty))
+                .collect()
+        };
+        (levels(true), levels(false))
+    }
+
+    /// Every level as a DataFrame with `is_bid`, `price` and `qty` columns,
+    /// bids then asks, each best first. `depth` limits the levels per side.
+    #[pyo3(signature = (depth=None))]
+    fn to_dataframe(&self, depth: Option<usize>) -> PyResult<PyDataFrame> {
+        let snapshot = self
+            .book
+            .to_snapshot_with_depth(depth.unwrap_or(usize::MAX));
+        let df = df! {
+            "is_bid" => snapshot.is_bid,
+            "price" => snapshot.price,
+            "qty" => snapshot.qty,
+        }
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        Ok(PyDataFrame(df))
+    }
+
+    /// Every level whose qty differs from `other` as a DataFrame with
+    /// `is_bid`, `price`, `qty` and `other_qty` columns, bids then asks, each
+    /// best first. A level missing from a book has a qty of 0 in it.
+    fn diff(&self, other: PyRef<'_, Self>) -> PyResult<PyDataFrame> {
+        let deltas = self.book.diff(&other.book);
+        let df = df! {
+            "is_bid" => deltas.iter().map(|delta| delta.is_bid).collect::<Vec<_>>(),
+            "price" => deltas.iter().map(|delta| delta.price).collect::<Vec<_>>(),
+            "qty" => deltas.iter().map(|delta| delta.qty).collect::<Vec<_>>(),
+            "other_qty" => deltas.iter().map(|delta| delta.other_qty).collect::<Vec<_>>(),
+        }
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        Ok(PyDataFrame(df))
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "OrderBook(best_bid={:?}, best_ask={:?})",
+            self.best_bid(),
+            self.best_ask()
+        )
+    }
+
+    /// The book as a price ladder; see `OrderBook`'s `Display`.
+    fn __str__(&self) -> String {
+        self.book.to_string()
+    }
+}
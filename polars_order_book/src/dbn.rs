@@ -0,0 +1,83 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use hashbrown::HashMap;
+use polars::prelude::*;
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3_polars::PyDataFrame;
+
+use order_book::dbn::{apply_mbo, apply_mbp, DbnReader, DbnRecord};
+use order_book::order_book::OrderBook;
+use order_book::order_book_with_orders::OrderBookWithOrders;
+
+use crate::output::{bbo_field_names, BboBuilder, BookOutputBuilder, CrossedPolicy};
+
+/// Replay an uncompressed Databento DBN file of `mbo`, `mbp-1` or `mbp-10`
+/// records and return a DataFrame with `ts_event`, `instrument_id` and the
+/// best bid and ask after each record. A book is kept per instrument, or
+/// only `instrument_id`'s records are replayed if given. Prices are in the
+/// DBN units of 1e-9.
+#[pyfunction]
+#[pyo3(signature = (path, instrument_id=None))]
+pub(crate) fn read_dbn_bbo(path: &str, instrument_id: Option<u32>) -> PyResult<PyDataFrame> {
+    let file = File::open(path).map_err(|e| PyIOError::new_err(format!("{}: {}", path, e)))?;
+    let to_py_err = |e: &dyn std::fmt::Display| PyValueError::new_err(e.to_string());
+    let reader = DbnReader::new(BufReader::new(file)).map_err(|e| to_py_err(&e))?;
+
+    let mut mbo_books: HashMap<u32, OrderBookWithOrders<i64, i64, u64>> = HashMap::new();
+    let mut mbp_books: HashMap<u32, OrderBook<i64, i64>> = HashMap::new();
+    let mut ts_events = Vec::new();
+    let mut instrument_ids = Vec::new();
+    let mut builder: BboBuilder = BboBuilder::new(
+        0,
+        bbo_field_names("struct").unwrap(),
+        CrossedPolicy::Ignore,
+        false,
+    );
+    for record in reader {
+        let record = record.map_err(|e| to_py_err(&e))?;
+        let (Some(id), Some(ts_event)) = (record.instrument_id(), record.ts_event()) else {
+            continue;
+        };
+        if instrument_id.is_some_and(|wanted| wanted != id) {
+            continue;
+        }
+        match record {
+            DbnRecord::Mbo {
+                order_id,
+                price,
+                size,
+                action,
+                side,
+                ..
+            } => {
+                let book = mbo_books.entry(id).or_default();
+                apply_mbo(book, order_id, price, size, action, side)
+                    .map_err(|e| PyValueError::new_err(format!("{} for record {:?}", e, record)))?;
+                builder.append(book.book());
+            }
+            DbnRecord::Mbp { ref levels, .. } => {
+                let book = mbp_books.entry(id).or_default();
+                apply_mbp(book, levels);
+                builder.append(book);
+            }
+            DbnRecord::Other { .. } => continue,
+        }
+        ts_events.push(ts_event as i64);
+        instrument_ids.push(id);
+    }
+
+    let polars_err = |e: PolarsError| to_py_err(&e);
+    let bbo = builder.finish().map_err(polars_err)?;
+    let ts_event = Series::new("ts_event", ts_events)
+        .cast(&DataType::Datetime(
+            TimeUnit::Nanoseconds,
+            Some("UTC".into()),
+        ))
+        .map_err(polars_err)?;
+    let mut columns = vec![ts_event, Series::new("instrument_id", instrument_ids)];
+    columns.extend(bbo.struct_().map_err(polars_err)?.fields().iter().cloned());
+    let df = DataFrame::new(columns).map_err(polars_err)?;
+    Ok(PyDataFrame(df))
+}
@@ -1,294 +1,5190 @@
 #![allow(clippy::unused_unit)]
 
+use hashbrown::HashMap;
 use itertools::izip;
+use num::{NumCast, Zero};
 use polars::datatypes::BooleanType;
 use polars::prelude::*;
 use pyo3_polars::derive::polars_expr;
+use rayon::prelude::*;
+use serde::Deserialize;
 
-use order_book::{book_side::BookSide, order_book::OrderBook};
+use order_book::book_side::OverDeletePolicy;
+use order_book::consolidated_book::{ConsolidatedBook, ConsolidatedLevel};
+use order_book::depth_stream::DepthStreamBook;
+use order_book::implied_book::{Contract, ImpliedBook};
+use order_book::order_book::{ModifyOutcome, OrderBook};
+use order_book::order_book_with_orders::OrderBookWithOrders;
+use order_book::queue_position::QueueModel;
 
-fn bbo_struct(input_fields: &[Field]) -> PolarsResult<Field> {
+use crate::output::{
+    bbo_field_names, depth_bucket_field_names, top_n_field_names, BboBuilder, BookFeature,
+    BookFeaturesBuilder, BookJsonBuilder, BookOutputBuilder, BookPrice, BookQty, CrossedPolicy,
+    DepthBandBuilder, DepthBucketsBuilder, FinalBookBuilder, ImbalanceBuilder,
+    IndicativeUncrossBuilder, MidSpreadBuilder, OfiBuilder, QueuePositionBuilder, SweepCostBuilder,
+    TopNBuilder, TopNExtras, VwapBuilder,
+};
+use crate::scaling::{coerce_update_inputs, replay_in_ticks};
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct BboKwargs {
+    /// "struct" names the output fields best_bid, best_bid_qty, etc.
+    /// "flat" names them bid_price_1, bid_qty_1, etc. so that the struct can
+    /// be unnested straight into columns for consumers that expect flat
+    /// names. Either way the expression returns a single struct Series, as
+    /// Polars requires.
+    output_style: String,
+    /// Add a `modify_outcome` field classifying rows that carry both
+    /// prev_price and prev_qty. Ignored when those columns aren't given.
+    include_modify_outcome: bool,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    /// Price and qty increments used to convert float price and qty columns
+    /// to integer ticks and lots. Required when the column is a float, and
+    /// ignored otherwise. See `replay_in_ticks`.
+    tick_size: Option<f64>,
+    lot_size: Option<f64>,
+    /// How to handle updates that leave the book crossed. See `CrossedPolicy`.
+    crossed_policy: CrossedPolicy,
+    /// Null out rows on which the bbo didn't change. See `BboBuilder::new`.
+    emit_on_change: bool,
+    /// Add the total qty and number of levels of each side. See
+    /// `BboBuilder::with_depth_totals`.
+    include_depth_totals: bool,
+    /// When set, the last input column holds feed sequence numbers, which
+    /// are checked to increase by exactly one per row.
+    sequence_gap_policy: Option<SequenceGapPolicy>,
+    /// The number of trailing input columns, after any sequence column, to
+    /// copy verbatim into the output struct. See `with_passthrough`.
+    n_passthrough: usize,
+    /// How deletes of more qty than a level holds are handled. See
+    /// `OverDeletePolicy`.
+    over_delete_policy: OverDeletePolicy,
+    /// When false, updates that can't be applied, e.g. deletes of more qty
+    /// than a level holds, are skipped and described in an `error` field
+    /// rather than failing the whole replay.
+    strict: bool,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SequenceGapPolicy {
+    /// Add a `sequence_gap` field, true on rows whose sequence number does
+    /// not follow the previous row's.
+    Flag,
+    /// Fail at the first gap with its row index and expected sequence number.
+    Raise,
+}
+
+/// Options for how replays apply each update. Flattened into the kwargs of
+/// every expression that replays updates through `replay_updates`.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(default)]
+pub struct ReplayOptions {
+    /// How updates with a null price, qty or is_bid are handled.
+    null_policy: NullPolicy,
+    /// Fail on updates that would overflow a level's qty, or whose qty
+    /// delta overflows, instead of letting the qty wrap around.
+    checked_qty: bool,
+}
+
+/// How replays handle updates with a null price, qty or is_bid. Rows are
+/// never dropped from the output: a skipped update repeats the book state
+/// of the previous row.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NullPolicy {
+    /// Fail with the update and its row index.
+    #[default]
+    Raise,
+    /// Leave the book unchanged.
+    Skip,
+    /// A null qty deletes the whole level at the update's price. Updates
+    /// with other nulls are skipped.
+    DeleteLevel,
+}
+
+impl Default for BboKwargs {
+    fn default() -> Self {
+        BboKwargs {
+            output_style: "struct".to_string(),
+            include_modify_outcome: false,
+            initial_state: InitialState::default(),
+            tick_size: None,
+            lot_size: None,
+            crossed_policy: CrossedPolicy::Ignore,
+            emit_on_change: false,
+            include_depth_totals: false,
+            sequence_gap_policy: None,
+            n_passthrough: 0,
+            over_delete_policy: OverDeletePolicy::Error,
+            strict: true,
+            options: ReplayOptions::default(),
+        }
+    }
+}
+
+/// `(price, qty)` levels to load into the book before the first update, e.g.
+/// a snapshot to resume an intraday replay from. Flattened into the kwargs of
+/// every expression that replays updates.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct InitialState {
+    initial_bids: Vec<(i64, i64)>,
+    initial_asks: Vec<(i64, i64)>,
+}
+
+impl InitialState {
+    fn book(&self) -> PolarsResult<OrderBook<i64, i64>> {
+        self.native_book()
+    }
+
+    /// The initial book in the physical price and qty types of a native
+    /// replay, failing if a level doesn't fit them. See `NativeTypes`.
+    fn native_book<Price: BookPrice, Qty: BookQty>(&self) -> PolarsResult<OrderBook<Price, Qty>> {
+        let convert = |levels: &[(i64, i64)]| -> PolarsResult<Vec<(Price, Qty)>> {
+            levels
+                .iter()
+                .map(|&(price, qty)| match (NumCast::from(price), NumCast::from(qty)) {
+                    (Some(price), Some(qty)) => Ok((price, qty)),
+                    _ => polars_bail!(
+                        ComputeError: "Initial level {:?} does not fit the input types", (price, qty)
+                    ),
+                })
+                .collect()
+        };
+        OrderBook::from_levels(&convert(&self.initial_bids)?, &convert(&self.initial_asks)?)
+            .map_err(|e| polars_err!(ComputeError: "Invalid initial book state: {}", e))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.initial_bids.is_empty() && self.initial_asks.is_empty()
+    }
+}
+
+impl BboKwargs {
+    fn initial_book(&self) -> PolarsResult<OrderBook<i64, i64>> {
+        self.native_initial_book()
+    }
+
+    fn native_initial_book<Price: BookPrice, Qty: BookQty>(
+        &self,
+    ) -> PolarsResult<OrderBook<Price, Qty>> {
+        let mut book = self.initial_state.native_book()?;
+        book.set_over_delete_policy(self.over_delete_policy);
+        Ok(book)
+    }
+
+    fn bbo_builder(&self, length: usize) -> PolarsResult<BboBuilder> {
+        self.native_bbo_builder(length)
+    }
+
+    fn native_bbo_builder<P: PolarsNumericType, Q: PolarsNumericType>(
+        &self,
+        length: usize,
+    ) -> PolarsResult<BboBuilder<P, Q>> {
+        let builder = BboBuilder::new(
+            length,
+            bbo_field_names(&self.output_style)?,
+            self.crossed_policy,
+            self.emit_on_change,
+        );
+        Ok(match self.include_depth_totals {
+            true => builder.with_depth_totals(length),
+            false => builder,
+        })
+    }
+
+    /// Fail if modify outcomes, sequence checks or passthrough columns are
+    /// asked for, which only `pl_calculate_bbo` supports. `context` says
+    /// which expression doesn't, e.g. "when sampling".
+    fn ensure_plain_replay(&self, context: &str) -> PolarsResult<()> {
+        polars_ensure!(
+            !self.include_modify_outcome && self.sequence_gap_policy.is_none() && self.n_passthrough == 0,
+            ComputeError: "Modify outcomes, sequence checks and passthrough columns are not supported {}", context
+        );
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MidSpreadKwargs {
+    /// Weights of the best levels of each side, best first, for a
+    /// `weighted_mid` field. Empty leaves the field out.
+    #[serde(default)]
+    depth_weights: Vec<f64>,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct QueuePositionKwargs {
+    /// The side and price of the hypothetical order.
+    order_is_bid: bool,
+    order_price: i64,
+    /// The order is entered after the update in `entry_row`, or if it isn't
+    /// given, after the first update for which the last input is true, e.g.
+    /// `timestamp >= entry_time`.
+    entry_row: Option<usize>,
+    #[serde(default)]
+    model: QueueModel,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct MatchOrdersKwargs {
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct DepthBandKwargs {
+    /// The half-width of the band around the mid, in basis points of the mid.
+    bps: f64,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct DepthBucketsKwargs {
+    n_buckets: usize,
+    /// The width of each band in ticks, i.e. units of the price column.
+    tick_width: i64,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct IndicativeUncrossKwargs {
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct NbboKwargs {
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct ImpliedBboKwargs {
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct BookJsonKwargs {
+    depth: usize,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct ImbalanceKwargs {
+    depth: usize,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct BookFeaturesKwargs {
+    /// The features to compute, by name. See `BookFeature`.
+    features: Vec<String>,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+impl BookFeaturesKwargs {
+    fn parse_features(&self) -> PolarsResult<Vec<BookFeature>> {
+        polars_ensure!(!self.features.is_empty(), ComputeError: "features must not be empty");
+        let features = self
+            .features
+            .iter()
+            .map(|name| BookFeature::parse(name))
+            .collect::<PolarsResult<Vec<_>>>()?;
+        for (i, feature) in features.iter().enumerate() {
+            polars_ensure!(
+                !features[..i].contains(feature),
+                ComputeError: "Book feature {:?} is repeated", self.features[i]
+            );
+        }
+        Ok(features)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OfiKwargs {
+    /// When set, the last input column holds the time of each update and
+    /// each row gets the sum of the OFI over the trailing `window` of time,
+    /// in the column's integer units.
+    window: Option<i64>,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct VwapKwargs {
+    depth: usize,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct TopNKwargs {
+    n: usize,
+    /// Whether an input after is_bid is the change in the number of orders
+    /// resting at each update's level. See `top_n_with_extras`.
+    #[serde(default)]
+    order_count: bool,
+    /// Whether the last input is the time of each update, for when each
+    /// level was last modified. See `top_n_with_extras`.
+    #[serde(default)]
+    level_timestamp: bool,
+    /// Add the running sum of the qty from the best level outwards.
+    #[serde(default)]
+    cumulative_qty: bool,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct FinalBookKwargs {
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct ValidateKwargs {
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct ReverseBboKwargs {
+    /// The book after the last update, which the replay starts from.
+    #[serde(flatten)]
+    final_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Deserialize)]
+pub struct DepthStreamKwargs {
+    /// The `lastUpdateId` of the REST snapshot given as the initial state.
+    snapshot_update_id: u64,
+    #[serde(flatten)]
+    bbo: BboKwargs,
+}
+
+#[derive(Deserialize)]
+pub struct ConflatedBboKwargs {
+    /// The length of each conflation interval, in the units of the ts column.
+    interval: i64,
+    #[serde(flatten)]
+    bbo: BboKwargs,
+}
+
+#[derive(Deserialize)]
+pub struct MarketPhaseKwargs {
+    /// Clear the book on the first row of each halt, for venues that purge
+    /// resting orders when trading is halted.
+    #[serde(default)]
+    clear_on_halt: bool,
+    #[serde(flatten)]
+    bbo: BboKwargs,
+}
+
+/// The trading status of the market when an update was published.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MarketPhase {
+    Continuous,
+    /// An auction call phase, e.g. the opening or closing auction, during
+    /// which the book may cross without trading.
+    Auction,
+    Halted,
+}
+
+impl MarketPhase {
+    fn parse(phase: &str) -> Option<Self> {
+        match phase {
+            "continuous" => Some(MarketPhase::Continuous),
+            "auction" => Some(MarketPhase::Auction),
+            "halted" => Some(MarketPhase::Halted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SweepCostKwargs {
+    size: i64,
+    #[serde(flatten)]
+    initial_state: InitialState,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+fn bbo_struct(input_fields: &[Field], kwargs: BboKwargs) -> PolarsResult<Field> {
+    polars_ensure!(
+        kwargs.n_passthrough <= input_fields.len(),
+        ComputeError: "n_passthrough {} exceeds the {} input columns", kwargs.n_passthrough, input_fields.len()
+    );
+    let (input_fields, passthrough) =
+        input_fields.split_at(input_fields.len() - kwargs.n_passthrough);
+    let input_fields = match kwargs.sequence_gap_policy {
+        Some(_) => &input_fields[..input_fields.len() - 1],
+        None => input_fields,
+    };
     let price_field = &input_fields[0];
     let qty_field = &input_fields[1];
+    let [bid_name, bid_qty_name, ask_name, ask_qty_name] = bbo_field_names(&kwargs.output_style)?;
+
+    let mut fields = vec![
+        Field::new(bid_name, price_field.data_type().clone()),
+        Field::new(bid_qty_name, qty_field.data_type().clone()),
+        Field::new(ask_name, price_field.data_type().clone()),
+        Field::new(ask_qty_name, qty_field.data_type().clone()),
+    ];
+    if kwargs.crossed_policy == CrossedPolicy::Flag {
+        fields.push(Field::new("crossed", DataType::Boolean));
+    }
+    if kwargs.emit_on_change {
+        fields.push(Field::new("changed", DataType::Boolean));
+    }
+    if kwargs.include_depth_totals {
+        fields.extend([
+            Field::new("bid_total_qty", qty_field.data_type().clone()),
+            Field::new("ask_total_qty", qty_field.data_type().clone()),
+            Field::new("bid_levels", DataType::UInt32),
+            Field::new("ask_levels", DataType::UInt32),
+        ]);
+    }
+    if kwargs.include_modify_outcome && input_fields.len() == 5 {
+        fields.push(Field::new("modify_outcome", DataType::String));
+    }
+    if kwargs.sequence_gap_policy == Some(SequenceGapPolicy::Flag) {
+        fields.push(Field::new("sequence_gap", DataType::Boolean));
+    }
+    if !kwargs.strict {
+        fields.push(Field::new("error", DataType::String));
+    }
+    fields.extend_from_slice(passthrough);
+    Ok(Field::new("bbo", DataType::Struct(fields)))
+}
+
+#[polars_expr(output_type_func_with_kwargs = bbo_struct)]
+pub fn pl_calculate_bbo(inputs: &[Series], kwargs: BboKwargs) -> PolarsResult<Series> {
+    with_passthrough(inputs, kwargs.n_passthrough, |inputs| {
+        let n_updates = inputs.len() - kwargs.sequence_gap_policy.is_some() as usize;
+        if NativeTypes::of(&inputs[..n_updates], &kwargs).is_some() {
+            return _pl_calculate_bbo(inputs, &kwargs);
+        }
+        replay_in_ticks(inputs, kwargs.tick_size, kwargs.lot_size, |inputs| {
+            _pl_calculate_bbo(inputs, &kwargs)
+        })
+    })
+}
+
+/// Physical price and qty types that `calculate_bbo` replays as they are
+/// rather than widening them to Int64, which halves the memory of the book
+/// and its output on large replays. Qtys must be signed because updates
+/// carry signed deltas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NativeTypes {
+    /// Int32 prices and qtys.
+    Int32,
+    /// UInt32 prices with Int32 qtys.
+    UInt32Price,
+}
+
+impl NativeTypes {
+    /// The native types of the update columns `inputs`, if they have any and
+    /// the replay doesn't need Int64, as skipping bad updates does.
+    fn of(inputs: &[Series], kwargs: &BboKwargs) -> Option<Self> {
+        let native = match (inputs[0].dtype(), inputs[1].dtype()) {
+            (DataType::Int32, DataType::Int32) => NativeTypes::Int32,
+            (DataType::UInt32, DataType::Int32) => NativeTypes::UInt32Price,
+            _ => return None,
+        };
+        // prev_price and prev_qty, if given, must match price and qty.
+        let consistent = inputs[2].dtype() == &DataType::Boolean
+            && inputs[3..]
+                .iter()
+                .zip(inputs)
+                .all(|(prev, current)| prev.dtype() == current.dtype());
+        (consistent && kwargs.strict).then_some(native)
+    }
+}
+
+/// `replay_updates` for `calculate_bbo` on price and qty columns of the
+/// physical types `P` and `Q`.
+fn replay_native_bbo<P, Q>(inputs: &[Series], kwargs: &BboKwargs) -> PolarsResult<Series>
+where
+    P: PolarsNumericType,
+    Q: PolarsNumericType,
+    P::Native: BookPrice,
+    Q::Native: BookQty,
+{
+    let builder = kwargs.native_bbo_builder::<P, Q>(inputs[0].len())?;
+    replay_native::<P, Q, _>(
+        inputs,
+        kwargs.native_initial_book()?,
+        builder,
+        kwargs.options,
+    )
+}
+
+/// Run `compute` on all but the last `n_passthrough` inputs, then append
+/// those verbatim as extra fields of the struct it returns, e.g. so that
+/// event timestamps stay aligned with the output rows.
+fn with_passthrough(
+    inputs: &[Series],
+    n_passthrough: usize,
+    compute: impl FnOnce(&[Series]) -> PolarsResult<Series>,
+) -> PolarsResult<Series> {
+    polars_ensure!(
+        n_passthrough <= inputs.len(),
+        ComputeError: "n_passthrough {} exceeds the {} input columns", n_passthrough, inputs.len()
+    );
+    let (inputs, passthrough) = inputs.split_at(inputs.len() - n_passthrough);
+    let output = compute(inputs)?;
+    if passthrough.is_empty() {
+        return Ok(output);
+    }
+    for s in passthrough {
+        polars_ensure!(
+            s.len() == output.len(),
+            ShapeMismatch: "Passthrough column {:?} has length {}, expected {}", s.name(), s.len(), output.len()
+        );
+    }
+    let mut fields = output.struct_()?.fields().to_vec();
+    fields.extend_from_slice(passthrough);
+    Ok(StructChunked::new(output.name(), &fields)?.into_series())
+}
+
+fn _pl_calculate_bbo(inputs: &[Series], kwargs: &BboKwargs) -> PolarsResult<Series> {
+    let (inputs, sequence_gaps) = match kwargs.sequence_gap_policy {
+        Some(policy) => {
+            let (sequence, inputs) = inputs.split_last().unwrap();
+            (inputs, check_sequence(sequence, policy)?)
+        }
+        None => (inputs, None),
+    };
+    let length = inputs[0].len();
+    let (bbo, errors) = match NativeTypes::of(inputs, kwargs) {
+        Some(NativeTypes::Int32) => (
+            replay_native_bbo::<Int32Type, Int32Type>(inputs, kwargs)?,
+            None,
+        ),
+        Some(NativeTypes::UInt32Price) => (
+            replay_native_bbo::<UInt32Type, Int32Type>(inputs, kwargs)?,
+            None,
+        ),
+        None if kwargs.strict => (
+            replay_updates(
+                inputs,
+                kwargs.initial_book()?,
+                kwargs.bbo_builder(length)?,
+                kwargs.options,
+            )?,
+            None,
+        ),
+        None => {
+            let (bbo, errors) = replay_updates_tolerant(
+                inputs,
+                kwargs.initial_book()?,
+                kwargs.bbo_builder(length)?,
+                kwargs.options,
+            )?;
+            (bbo, Some(errors))
+        }
+    };
+
+    let include_modify_outcome = kwargs.include_modify_outcome && inputs.len() == 5;
+    if !include_modify_outcome && sequence_gaps.is_none() && errors.is_none() {
+        return Ok(bbo);
+    }
+    let mut fields = bbo.struct_()?.fields().to_vec();
+    if include_modify_outcome {
+        fields.push(modify_outcomes(inputs)?);
+    }
+    fields.extend(sequence_gaps);
+    fields.extend(errors);
+    Ok(StructChunked::new("bbo", &fields)?.into_series())
+}
+
+/// Check that `sequence` increases by exactly one per row. With
+/// `SequenceGapPolicy::Flag`, returns a `sequence_gap` Series marking each row
+/// that doesn't follow its predecessor; with `Raise`, fails at the first one.
+fn check_sequence(sequence: &Series, policy: SequenceGapPolicy) -> PolarsResult<Option<Series>> {
+    let sequence = sequence.cast(&DataType::Int64)?;
+    let mut gaps = BooleanChunkedBuilder::new("sequence_gap", sequence.len());
+    let mut expected: Option<i64> = None;
+    for (row, seq_num) in sequence.i64()?.into_iter().enumerate() {
+        let Some(seq_num) = seq_num else {
+            polars_bail!(ComputeError: "Null sequence number in row {}", row);
+        };
+        let is_gap = expected.is_some_and(|expected| seq_num != expected);
+        if is_gap && policy == SequenceGapPolicy::Raise {
+            polars_bail!(
+                ComputeError: "Sequence gap in row {}: expected {}, got {}",
+                row, expected.unwrap(), seq_num
+            );
+        }
+        gaps.append_value(is_gap);
+        expected = Some(seq_num + 1);
+    }
+    Ok((policy == SequenceGapPolicy::Flag).then(|| gaps.finish().into_series()))
+}
+
+/// Classify each row carrying both prev_price and prev_qty by how the modify
+/// changed the order. Other rows are null.
+fn modify_outcomes(inputs: &[Series]) -> PolarsResult<Series> {
+    let [price, qty, prev_price, prev_qty] = [0, 1, 3, 4].map(|i| inputs[i].cast(&DataType::Int64));
+    let outcomes: StringChunked = izip!(
+        price?.i64()?,
+        qty?.i64()?,
+        prev_price?.i64()?,
+        prev_qty?.i64()?
+    )
+    .map(|tuple| match tuple {
+        (Some(price), Some(qty), Some(prev_price), Some(prev_qty)) => Some(modify_outcome_name(
+            ModifyOutcome::new(prev_price, prev_qty, price, qty),
+        )),
+        _ => None,
+    })
+    .collect();
+    Ok(outcomes.with_name("modify_outcome").into_series())
+}
+
+fn modify_outcome_name(outcome: ModifyOutcome) -> &'static str {
+    match outcome {
+        ModifyOutcome::Unchanged => "unchanged",
+        ModifyOutcome::QtyChanged => "qty_changed",
+        ModifyOutcome::PriceMoved => "price_moved",
+        ModifyOutcome::PriceMovedAndResized => "price_moved_and_resized",
+    }
+}
+
+/// Best bid and offer for feeds that encode adds and cancels in one signed
+/// `qty_delta` column: positive deltas add qty, negative deltas delete it and
+/// zero deltas leave the book unchanged.
+#[polars_expr(output_type_func_with_kwargs = bbo_struct)]
+pub fn pl_calculate_bbo_signed_delta(inputs: &[Series], kwargs: BboKwargs) -> PolarsResult<Series> {
+    replay_in_ticks(inputs, kwargs.tick_size, kwargs.lot_size, |inputs| {
+        _pl_calculate_bbo_signed_delta(inputs, &kwargs)
+    })
+}
+
+fn _pl_calculate_bbo_signed_delta(inputs: &[Series], kwargs: &BboKwargs) -> PolarsResult<Series> {
+    let price = inputs[0].i64()?;
+    let qty_delta = inputs[1].i64()?;
+    let is_bid = inputs[2].bool()?;
+    let mut book = kwargs.initial_book()?;
+    let mut builder = kwargs.bbo_builder(price.len())?;
+    replay_simple_mutations(
+        price,
+        qty_delta,
+        is_bid,
+        &mut book,
+        &mut builder,
+        kwargs.options,
+    )?;
+    builder.finish_with_book(&book)
+}
+
+/// Best bid and offer for market-by-price snapshot feeds where each row gives
+/// the absolute qty at a price level. A qty of zero deletes the level.
+#[polars_expr(output_type_func_with_kwargs = bbo_struct)]
+pub fn pl_calculate_bbo_set_level(inputs: &[Series], kwargs: BboKwargs) -> PolarsResult<Series> {
+    replay_in_ticks(inputs, kwargs.tick_size, kwargs.lot_size, |inputs| {
+        _pl_calculate_bbo_set_level(inputs, &kwargs)
+    })
+}
+
+fn _pl_calculate_bbo_set_level(inputs: &[Series], kwargs: &BboKwargs) -> PolarsResult<Series> {
+    let price = inputs[0].i64()?;
+    let qty = inputs[1].i64()?;
+    let is_bid = inputs[2].bool()?;
+    let mut builder = kwargs.bbo_builder(price.len())?;
+
+    let mut book = kwargs.initial_book()?;
+    for (row, tuple) in izip!(is_bid.into_iter(), price.into_iter(), qty.into_iter()).enumerate() {
+        if let (Some(is_bid), Some(price), Some(qty)) = tuple {
+            book.set_level(is_bid, price, qty)
+                .map_err(|e| polars_err!(ComputeError: "{} in row {}", e, row))?;
+            handle_crossed(&mut book, &builder, Some(is_bid), row)?;
+            builder.append(&book);
+        } else {
+            polars_bail!(ComputeError: "Invalid input in row {}: (is_bid, price, qty) = {:?}", row, tuple);
+        }
+    }
+    builder.finish_with_book(&book)
+}
+
+/// Best bid and offer for feeds that remove whole price levels without
+/// repeating their qty. Rows with `delete_level` set remove the level at
+/// `price` (their qty may be null); other rows are signed qty mutations.
+#[polars_expr(output_type_func_with_kwargs = bbo_struct)]
+pub fn pl_calculate_bbo_with_level_deletes(
+    inputs: &[Series],
+    kwargs: BboKwargs,
+) -> PolarsResult<Series> {
+    replay_in_ticks(inputs, kwargs.tick_size, kwargs.lot_size, |inputs| {
+        _pl_calculate_bbo_with_level_deletes(inputs, &kwargs)
+    })
+}
+
+fn _pl_calculate_bbo_with_level_deletes(
+    inputs: &[Series],
+    kwargs: &BboKwargs,
+) -> PolarsResult<Series> {
+    let price = inputs[0].i64()?;
+    let qty = inputs[1].i64()?;
+    let is_bid = inputs[2].bool()?;
+    let delete_level = inputs[3].bool()?;
+    let builder = kwargs.bbo_builder(price.len())?;
+    replay_with_level_deletes(
+        price,
+        qty,
+        is_bid,
+        delete_level,
+        kwargs.initial_book()?,
+        builder,
+    )
+}
+
+/// Best bid and offer for feeds spanning several sessions. Rows with `reset`
+/// set clear the book, e.g. at the start of day or before a recovery
+/// snapshot, and then apply their own update if price and qty are given.
+/// Other rows are signed qty mutations.
+#[polars_expr(output_type_func_with_kwargs = bbo_struct)]
+pub fn pl_calculate_bbo_with_reset(inputs: &[Series], kwargs: BboKwargs) -> PolarsResult<Series> {
+    replay_in_ticks(inputs, kwargs.tick_size, kwargs.lot_size, |inputs| {
+        _pl_calculate_bbo_with_reset(inputs, &kwargs)
+    })
+}
+
+fn _pl_calculate_bbo_with_reset(inputs: &[Series], kwargs: &BboKwargs) -> PolarsResult<Series> {
+    let price = inputs[0].i64()?;
+    let qty = inputs[1].i64()?;
+    let is_bid = inputs[2].bool()?;
+    let reset = inputs[3].bool()?;
+    let mut builder = kwargs.bbo_builder(price.len())?;
+
+    let mut book = kwargs.initial_book()?;
+    for (row, tuple) in izip!(
+        is_bid.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        reset.into_iter()
+    )
+    .enumerate()
+    {
+        match tuple {
+            (_, None, None, Some(true)) => book.clear(),
+            (Some(is_bid), Some(price), Some(qty), Some(reset)) => {
+                if reset {
+                    book.clear();
+                }
+                apply_simple_mutation(&mut book, is_bid, price, qty, row)?;
+            }
+            _ => polars_bail!(
+                ComputeError: "Invalid input in row {}: (is_bid, price, qty, reset) = {:?}", row, tuple
+            ),
+        }
+        handle_crossed(&mut book, &builder, tuple.0, row)?;
+        builder.append(&book);
+    }
+    builder.finish_with_book(&book)
+}
+
+/// Best bid and offer for feeds that span changes of trading status, given as
+/// signed qty mutations with a `market_phase` column of "continuous",
+/// "auction" or "halted". The crossed-book policy is only applied outside
+/// auctions, as auction books are expected to cross, and with
+/// `clear_on_halt` the book is cleared when a halt begins. The phase of each
+/// row is passed through as a `market_phase` field, so that the whole
+/// session can be replayed in one call without losing book continuity.
+#[polars_expr(output_type_func_with_kwargs = bbo_struct_market_phase)]
+pub fn pl_calculate_bbo_with_market_phase(
+    inputs: &[Series],
+    kwargs: MarketPhaseKwargs,
+) -> PolarsResult<Series> {
+    replay_in_ticks(
+        inputs,
+        kwargs.bbo.tick_size,
+        kwargs.bbo.lot_size,
+        |inputs| _pl_calculate_bbo_with_market_phase(inputs, &kwargs),
+    )
+}
+
+fn bbo_struct_market_phase(
+    input_fields: &[Field],
+    kwargs: MarketPhaseKwargs,
+) -> PolarsResult<Field> {
+    let bbo = bbo_struct(&input_fields[..3], kwargs.bbo)?;
+    let DataType::Struct(mut fields) = bbo.data_type().clone() else {
+        unreachable!("bbo_struct returns a struct field");
+    };
+    fields.push(Field::new("market_phase", DataType::String));
+    Ok(Field::new(bbo.name(), DataType::Struct(fields)))
+}
+
+fn _pl_calculate_bbo_with_market_phase(
+    inputs: &[Series],
+    kwargs: &MarketPhaseKwargs,
+) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() == 4,
+        ComputeError: "Expected 4 input columns: price, qty, is_bid, market_phase but got {}", inputs.len()
+    );
+    kwargs
+        .bbo
+        .ensure_plain_replay("with a market phase column")?;
+    polars_ensure!(
+        kwargs.bbo.strict,
+        ComputeError: "Non-strict replays are not supported with a market phase column"
+    );
+    let price = inputs[0].i64()?;
+    let qty = inputs[1].i64()?;
+    let is_bid = inputs[2].bool()?;
+    let market_phase = inputs[3].cast(&DataType::String)?;
+    let mut builder = kwargs.bbo.bbo_builder(price.len())?;
+
+    let mut book = kwargs.bbo.initial_book()?;
+    let mut prev_phase = None;
+    for (row, (is_bid, price, qty, phase)) in izip!(
+        is_bid.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        market_phase.str()?.into_iter()
+    )
+    .enumerate()
+    {
+        let Some(phase) = phase.and_then(MarketPhase::parse) else {
+            polars_bail!(
+                ComputeError: "Invalid market_phase in row {}: {:?}, expected \"continuous\", \"auction\" or \"halted\"", row, phase
+            );
+        };
+        if kwargs.clear_on_halt
+            && phase == MarketPhase::Halted
+            && prev_phase != Some(MarketPhase::Halted)
+        {
+            book.clear();
+        }
+        apply_update(
+            &mut book,
+            (is_bid, price, qty, None, None),
+            row,
+            kwargs.bbo.options,
+        )?;
+        if phase != MarketPhase::Auction {
+            handle_crossed(&mut book, &builder, is_bid, row)?;
+        }
+        builder.append(&book);
+        prev_phase = Some(phase);
+    }
+    let bbo = builder.finish_with_book(&book)?;
+    let mut fields = bbo.struct_()?.fields().to_vec();
+    fields.push(market_phase.with_name("market_phase"));
+    Ok(StructChunked::new(bbo.name(), &fields)?.into_series())
+}
+
+/// Best bid and offer for feeds interleaving several instruments. A separate
+/// book is kept per value of the `symbol` column, and each row gets the BBO of
+/// its own symbol's book after the update.
+#[polars_expr(output_type_func_with_kwargs = bbo_struct)]
+pub fn pl_calculate_bbo_by_symbol(inputs: &[Series], kwargs: BboKwargs) -> PolarsResult<Series> {
+    replay_in_ticks(inputs, kwargs.tick_size, kwargs.lot_size, |inputs| {
+        _pl_calculate_bbo_by_symbol(inputs, &kwargs)
+    })
+}
+
+fn _pl_calculate_bbo_by_symbol(inputs: &[Series], kwargs: &BboKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        kwargs.initial_state.is_empty(),
+        ComputeError: "Initial book state is not supported when replaying by symbol"
+    );
+    let price = inputs[0].i64()?;
+    let qty = inputs[1].i64()?;
+    let is_bid = inputs[2].bool()?;
+    let symbol = inputs[3].cast(&DataType::String)?;
+    replay_by_symbol(
+        price,
+        qty,
+        is_bid,
+        symbol.str()?,
+        kwargs.over_delete_policy,
+        |length| kwargs.bbo_builder(length),
+    )
+}
+
+/// Best bid and offer from a feed interleaving event types, given as action,
+/// price, qty, is_bid and optionally prev_price and prev_qty columns.
+/// `action` is one of:
+/// - "add": add qty at the price level.
+/// - "delete": delete qty from the price level.
+/// - "trade": a trade against resting liquidity, deleting the traded qty.
+/// - "modify": move prev_qty at prev_price to qty at price.
+/// - "clear": remove every level from the book; other columns may be null.
+#[polars_expr(output_type_func_with_kwargs = bbo_struct_from_actions)]
+pub fn pl_calculate_bbo_from_actions(inputs: &[Series], kwargs: BboKwargs) -> PolarsResult<Series> {
+    _pl_calculate_bbo_from_actions(inputs, &kwargs)
+}
+
+fn bbo_struct_from_actions(input_fields: &[Field], kwargs: BboKwargs) -> PolarsResult<Field> {
+    bbo_struct(&input_fields[1..3], kwargs)
+}
+
+fn _pl_calculate_bbo_from_actions(inputs: &[Series], kwargs: &BboKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() == 4 || inputs.len() == 6,
+        ComputeError: "Expected 4 or 6 input columns: action, price, qty, is_bid, (prev_price, prev_qty) but got {}", inputs.len()
+    );
+    let action = inputs[0].str()?;
+    let updates = coerce_update_inputs(&inputs[1..])?;
+    let price = updates[0].i64()?;
+    let qty = updates[1].i64()?;
+    let is_bid = updates[2].bool()?;
+    let no_prev = Int64Chunked::full_null("", price.len());
+    let prev_price = updates
+        .get(3)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+    let prev_qty = updates
+        .get(4)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+    let mut builder = kwargs.bbo_builder(price.len())?;
+
+    let mut book = kwargs.initial_book()?;
+    for (row, tuple) in izip!(
+        action.into_iter(),
+        is_bid.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        prev_price.into_iter(),
+        prev_qty.into_iter()
+    )
+    .enumerate()
+    {
+        match tuple {
+            (Some("add"), Some(is_bid), Some(price), Some(qty), _, _) => {
+                polars_ensure!(
+                    qty > 0,
+                    ComputeError: "add qty must be positive in row {}: {:?}", row, tuple
+                );
+                add_qty(&mut book, is_bid, price, qty, kwargs.options.checked_qty)
+                    .map_err(|e| polars_err!(ComputeError: "{} in row {}: {:?}", e, row, tuple))?;
+            }
+            (Some("delete" | "trade"), Some(is_bid), Some(price), Some(qty), _, _) => {
+                book.try_delete_qty(is_bid, price, qty)
+                    .map_err(|e| polars_err!(ComputeError: "{} in row {}: {:?}", e, row, tuple))?;
+            }
+            (Some("modify"), is_bid, price, qty, prev_price @ Some(_), prev_qty @ Some(_)) => {
+                apply_update(
+                    &mut book,
+                    (is_bid, price, qty, prev_price, prev_qty),
+                    row,
+                    kwargs.options,
+                )?;
+            }
+            (Some("clear"), _, _, _, _, _) => book.clear(),
+            _ => polars_bail!(ComputeError: "Invalid input in row {}: {:?}", row, tuple),
+        }
+        handle_crossed(&mut book, &builder, tuple.1, row)?;
+        builder.append(&book);
+    }
+    cast_bbo_back(builder.finish_with_book(&book)?, &inputs[1..])
+}
+
+/// Best bid and offer as of each of a sorted series of sample times, given
+/// as ts, sample_ts, price, qty, is_bid and optionally prev_price and
+/// prev_qty columns. `ts` is the sorted time of each update and the output
+/// has one row per sample, reflecting every update with `ts <= sample_ts`,
+/// so snapshots can be taken without materialising a row per update.
+#[polars_expr(output_type_func_with_kwargs = bbo_struct_asof)]
+pub fn pl_calculate_bbo_asof(inputs: &[Series], kwargs: BboKwargs) -> PolarsResult<Series> {
+    _pl_calculate_bbo_asof(inputs, &kwargs)
+}
+
+fn bbo_struct_asof(input_fields: &[Field], kwargs: BboKwargs) -> PolarsResult<Field> {
+    bbo_struct(&input_fields[2..], kwargs)
+}
+
+fn _pl_calculate_bbo_asof(inputs: &[Series], kwargs: &BboKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() == 5 || inputs.len() == 7,
+        ComputeError: "Expected 5 or 7 input columns: ts, sample_ts, price, qty, is_bid, (prev_price, prev_qty) but got {}", inputs.len()
+    );
+    polars_ensure!(
+        inputs[0].dtype() == inputs[1].dtype(),
+        ComputeError: "ts and sample_ts must have the same dtype, got {} and {}", inputs[0].dtype(), inputs[1].dtype()
+    );
+    kwargs.ensure_plain_replay("when sampling")?;
+    let ts = inputs[0].cast(&DataType::Int64)?;
+    let sample_ts = inputs[1].cast(&DataType::Int64)?;
+    let (ts, sample_ts) = (ts.i64()?, sample_ts.i64()?);
+    ensure_sorted(ts, "ts")?;
+    ensure_sorted(sample_ts, "sample_ts")?;
+    let updates = coerce_update_inputs(&inputs[2..])?;
+    let price = updates[0].i64()?;
+    let qty = updates[1].i64()?;
+    let is_bid = updates[2].bool()?;
+    let no_prev = Int64Chunked::full_null("", price.len());
+    let prev_price = updates
+        .get(3)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+    let prev_qty = updates
+        .get(4)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+    let mut builder = kwargs.bbo_builder(sample_ts.len())?;
+
+    let mut book = kwargs.initial_book()?;
+    let mut updates = izip!(
+        ts.into_no_null_iter(),
+        is_bid.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        prev_price.into_iter(),
+        prev_qty.into_iter()
+    )
+    .enumerate()
+    .peekable();
+    for sample in sample_ts.into_no_null_iter() {
+        while let Some((row, (_, is_bid, price, qty, prev_price, prev_qty))) =
+            updates.next_if(|(_, update)| update.0 <= sample)
+        {
+            apply_update(
+                &mut book,
+                (is_bid, price, qty, prev_price, prev_qty),
+                row,
+                kwargs.options,
+            )?;
+            handle_crossed(&mut book, &builder, is_bid, row)?;
+        }
+        builder.append(&book);
+    }
+    cast_bbo_back(builder.finish_with_book(&book)?, &inputs[2..])
+}
+
+/// Best bid and offer at the end of each time interval with updates, given
+/// as ts, price, qty, is_bid and optionally prev_price and prev_qty columns.
+/// `ts` is the sorted time of each update and intervals of `interval` units
+/// start at multiples of it. Only the book after the last update of each
+/// interval is emitted, with the start of the interval as an
+/// `interval_start` field, which shrinks the output of bursty feeds without
+/// materialising a row per update to group afterwards.
+#[polars_expr(output_type_func_with_kwargs = bbo_struct_conflated)]
+pub fn pl_calculate_bbo_conflated(
+    inputs: &[Series],
+    kwargs: ConflatedBboKwargs,
+) -> PolarsResult<Series> {
+    _pl_calculate_bbo_conflated(inputs, &kwargs)
+}
+
+fn bbo_struct_conflated(input_fields: &[Field], kwargs: ConflatedBboKwargs) -> PolarsResult<Field> {
+    let bbo = bbo_struct(&input_fields[1..], kwargs.bbo)?;
+    let DataType::Struct(mut fields) = bbo.data_type().clone() else {
+        unreachable!("bbo_struct returns a struct field");
+    };
+    fields.push(Field::new(
+        "interval_start",
+        input_fields[0].data_type().clone(),
+    ));
+    Ok(Field::new(bbo.name(), DataType::Struct(fields)))
+}
+
+fn _pl_calculate_bbo_conflated(
+    inputs: &[Series],
+    kwargs: &ConflatedBboKwargs,
+) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() == 4 || inputs.len() == 6,
+        ComputeError: "Expected 4 or 6 input columns: ts, price, qty, is_bid, (prev_price, prev_qty) but got {}", inputs.len()
+    );
+    polars_ensure!(
+        kwargs.interval > 0,
+        ComputeError: "interval must be positive, got {}", kwargs.interval
+    );
+    let bbo_kwargs = &kwargs.bbo;
+    bbo_kwargs.ensure_plain_replay("when conflating")?;
+    let ts = inputs[0].cast(&DataType::Int64)?;
+    let ts = ts.i64()?;
+    ensure_sorted(ts, "ts")?;
+    let updates = coerce_update_inputs(&inputs[1..])?;
+    let price = updates[0].i64()?;
+    let qty = updates[1].i64()?;
+    let is_bid = updates[2].bool()?;
+    let no_prev = Int64Chunked::full_null("", price.len());
+    let prev_price = updates
+        .get(3)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+    let prev_qty = updates
+        .get(4)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+    let mut builder = bbo_kwargs.bbo_builder(price.len())?;
+    let mut interval_start = Vec::new();
+
+    let mut book = bbo_kwargs.initial_book()?;
+    let mut updates = izip!(
+        ts.into_no_null_iter(),
+        is_bid.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        prev_price.into_iter(),
+        prev_qty.into_iter()
+    )
+    .enumerate()
+    .peekable();
+    while let Some((row, (ts, is_bid, price, qty, prev_price, prev_qty))) = updates.next() {
+        apply_update(
+            &mut book,
+            (is_bid, price, qty, prev_price, prev_qty),
+            row,
+            bbo_kwargs.options,
+        )?;
+        handle_crossed(&mut book, &builder, is_bid, row)?;
+        let start = ts - ts.rem_euclid(kwargs.interval);
+        let interval_ends = updates
+            .peek()
+            .is_none_or(|(_, next)| next.0 - start >= kwargs.interval);
+        if interval_ends {
+            builder.append(&book);
+            interval_start.push(start);
+        }
+    }
+    let bbo = cast_bbo_back(builder.finish_with_book(&book)?, &inputs[1..])?;
+    let mut fields = bbo.struct_()?.fields().to_vec();
+    fields.push(Series::new("interval_start", interval_start).cast(inputs[0].dtype())?);
+    Ok(StructChunked::new(bbo.name(), &fields)?.into_series())
+}
+
+fn ensure_sorted(ca: &Int64Chunked, name: &str) -> PolarsResult<()> {
+    polars_ensure!(ca.null_count() == 0, ComputeError: "{} must not contain nulls", name);
+    let mut values = ca.into_no_null_iter();
+    if let Some(mut prev) = values.next() {
+        for (row, value) in values.enumerate() {
+            polars_ensure!(
+                value >= prev,
+                ComputeError: "{} must be sorted, but row {} goes back in time", name, row + 1
+            );
+            prev = value;
+        }
+    }
+    Ok(())
+}
+
+/// Best bid and offer from a Binance-style diff depth stream, given as price,
+/// qty, is_bid, first_update_id and last_update_id columns with one row per
+/// level of each event. qty is the absolute qty of the level (zero removes
+/// it) and consecutive rows with the same update ids form one event. The
+/// initial state is the REST snapshot taken at `snapshot_update_id`: events
+/// at or before it are stale and leave the book unchanged, and a gap in the
+/// update ids after it is an error, as the book must be resynchronized from
+/// a fresh snapshot.
+#[polars_expr(output_type_func_with_kwargs = bbo_struct_depth_stream)]
+pub fn pl_calculate_bbo_depth_stream(
+    inputs: &[Series],
+    kwargs: DepthStreamKwargs,
+) -> PolarsResult<Series> {
+    _pl_calculate_bbo_depth_stream(inputs, &kwargs)
+}
+
+fn bbo_struct_depth_stream(
+    input_fields: &[Field],
+    kwargs: DepthStreamKwargs,
+) -> PolarsResult<Field> {
+    bbo_struct(&input_fields[..3], kwargs.bbo)
+}
+
+fn _pl_calculate_bbo_depth_stream(
+    inputs: &[Series],
+    kwargs: &DepthStreamKwargs,
+) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() == 5,
+        ComputeError: "Expected 5 input columns: price, qty, is_bid, first_update_id, last_update_id but got {}", inputs.len()
+    );
+    kwargs.bbo.ensure_plain_replay("for depth streams")?;
+    let updates = coerce_update_inputs(&inputs[..3])?;
+    let (price, qty, is_bid) = (updates[0].i64()?, updates[1].i64()?, updates[2].bool()?);
+    let first_update_id = inputs[3].cast(&DataType::UInt64)?;
+    let last_update_id = inputs[4].cast(&DataType::UInt64)?;
+    let (first_update_id, last_update_id) = (first_update_id.u64()?, last_update_id.u64()?);
+    polars_ensure!(
+        first_update_id.null_count() == 0 && last_update_id.null_count() == 0,
+        ComputeError: "first_update_id and last_update_id must not contain nulls"
+    );
+    let mut builder = kwargs.bbo.bbo_builder(price.len())?;
+
+    let initial_state = &kwargs.bbo.initial_state;
+    let mut book = DepthStreamBook::new(
+        &initial_state.initial_bids,
+        &initial_state.initial_asks,
+        kwargs.snapshot_update_id,
+    )
+    .map_err(|e| polars_err!(ComputeError: "Invalid initial book state: {}", e))?;
+    let mut event = None;
+    let mut apply = false;
+    for (row, (is_bid, price, qty, first_id, last_id)) in izip!(
+        is_bid.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        first_update_id.into_no_null_iter(),
+        last_update_id.into_no_null_iter()
+    )
+    .enumerate()
+    {
+        if event != Some((first_id, last_id)) {
+            event = Some((first_id, last_id));
+            apply = book
+                .begin_event(first_id, last_id)
+                .map_err(|e| polars_err!(ComputeError: "{} at row {}", e, row))?;
+        }
+        if apply {
+            if let (Some(is_bid), Some(price), Some(qty)) = (is_bid, price, qty) {
+                book.set_level(is_bid, price, qty)
+                    .map_err(|e| polars_err!(ComputeError: "{} in row {}", e, row))?;
+                handle_crossed(book.book_mut(), &builder, Some(is_bid), row)?;
+            } else {
+                polars_bail!(ComputeError: "price, qty and is_bid must not be null, but row {} has a null", row);
+            }
+        }
+        builder.append(book.book());
+    }
+    cast_bbo_back(builder.finish_with_book(book.book())?, inputs)
+}
+
+/// Best bid and offer from a market-by-order feed given as order_id, action,
+/// price, qty and is_bid columns. `action` is one of "add", "cancel",
+/// "replace" or "execute"; price and is_bid are only read where the action
+/// needs them, and qty is the executed qty for "execute" rows.
+#[polars_expr(output_type_func_with_kwargs = bbo_struct_order_id)]
+pub fn pl_calculate_bbo_order_id(inputs: &[Series], kwargs: BboKwargs) -> PolarsResult<Series> {
+    _pl_calculate_bbo_order_id(inputs, &kwargs)
+}
+
+fn bbo_struct_order_id(input_fields: &[Field], kwargs: BboKwargs) -> PolarsResult<Field> {
+    bbo_struct(&input_fields[2..], kwargs)
+}
+
+fn _pl_calculate_bbo_order_id(inputs: &[Series], kwargs: &BboKwargs) -> PolarsResult<Series> {
+    let order_id = inputs[0].cast(&DataType::Int64)?;
+    let order_id = order_id.i64()?;
+    let action = inputs[1].str()?;
+    let updates = coerce_update_inputs(&inputs[2..])?;
+    let (price, qty, is_bid) = (updates[0].i64()?, updates[1].i64()?, updates[2].bool()?);
+    polars_ensure!(
+        matches!(kwargs.crossed_policy, CrossedPolicy::Ignore | CrossedPolicy::Flag),
+        ComputeError: "crossed_policy {:?} is not supported for order id feeds", kwargs.crossed_policy
+    );
+    let mut builder = kwargs.bbo_builder(price.len())?;
+
+    let mut book: OrderBookWithOrders<i64, i64, i64> = OrderBookWithOrders::default();
+    for tuple in izip!(
+        order_id.into_iter(),
+        action.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        is_bid.into_iter()
+    ) {
+        let result = match tuple {
+            (Some(order_id), Some("add"), Some(price), Some(qty), Some(is_bid)) => {
+                book.add_order(order_id, is_bid, price, qty)
+            }
+            (Some(order_id), Some("cancel"), _, _, _) => book.cancel_order(&order_id).map(|_| ()),
+            (Some(order_id), Some("replace"), Some(price), Some(qty), _) => {
+                book.replace_order(&order_id, price, qty)
+            }
+            (Some(order_id), Some("execute"), _, Some(qty), _) => {
+                book.execute_order(&order_id, qty)
+            }
+            _ => polars_bail!(ComputeError: "Invalid input tuple: {:?}", tuple),
+        };
+        result.map_err(|e| polars_err!(ComputeError: "{} for input tuple: {:?}", e, tuple))?;
+        builder.append(book.book());
+    }
+    cast_bbo_back(builder.finish_with_book(book.book())?, &inputs[2..])
+}
+
+fn mbp_struct(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new("mbp", mbp_dtype(input_fields)))
+}
+
+fn mbp_dtype(input_fields: &[Field]) -> DataType {
+    let price_dtype = input_fields[2].data_type();
+    DataType::Struct(vec![
+        Field::new("price", price_dtype.clone()),
+        Field::new("qty", DataType::Int64),
+        Field::new("is_bid", DataType::Boolean),
+        Field::new("prev_price", price_dtype.clone()),
+        Field::new("prev_qty", DataType::Int64),
+    ])
+}
+
+/// Convert a market-by-order feed, given as for `pl_calculate_bbo_order_id`,
+/// to one price-level update per event in the `(price, qty, is_bid,
+/// prev_price, prev_qty)` form read by `pl_calculate_bbo`. Adds give the
+/// order's qty and cancels and executes the negated qty they remove, with
+/// null prev fields. Replaces are modifies: the order's remaining qty leaves
+/// prev_price and its new qty rests at price. Prices keep the input dtype,
+/// while qtys are Int64 as they are signed.
+#[polars_expr(output_type_func = mbp_struct)]
+pub fn pl_mbo_to_mbp(inputs: &[Series]) -> PolarsResult<Series> {
+    _pl_mbo_to_mbp(inputs)
+}
+
+fn _pl_mbo_to_mbp(inputs: &[Series]) -> PolarsResult<Series> {
+    let order_id = inputs[0].cast(&DataType::Int64)?;
+    let order_id = order_id.i64()?;
+    let action = inputs[1].str()?;
+    let updates = coerce_update_inputs(&inputs[2..])?;
+    let (price, qty, is_bid) = (updates[0].i64()?, updates[1].i64()?, updates[2].bool()?);
+    let len = order_id.len();
+    let mut out_price = PrimitiveChunkedBuilder::<Int64Type>::new("price", len);
+    let mut out_qty = PrimitiveChunkedBuilder::<Int64Type>::new("qty", len);
+    let mut out_is_bid = BooleanChunkedBuilder::new("is_bid", len);
+    let mut out_prev_price = PrimitiveChunkedBuilder::<Int64Type>::new("prev_price", len);
+    let mut out_prev_qty = PrimitiveChunkedBuilder::<Int64Type>::new("prev_qty", len);
+
+    let mut book: OrderBookWithOrders<i64, i64, i64> = OrderBookWithOrders::default();
+    for tuple in izip!(
+        order_id.into_iter(),
+        action.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        is_bid.into_iter()
+    ) {
+        let prev = match tuple.0 {
+            Some(order_id) => book.get_order(&order_id).copied(),
+            None => None,
+        };
+        let (update, result) = match (tuple, prev) {
+            ((Some(order_id), Some("add"), Some(price), Some(qty), Some(is_bid)), _) => (
+                (price, qty, is_bid, None, None),
+                book.add_order(order_id, is_bid, price, qty),
+            ),
+            ((Some(order_id), Some("cancel"), _, _, _), Some(order)) => (
+                (order.price, -order.qty, order.is_bid, None, None),
+                book.cancel_order(&order_id).map(|_| ()),
+            ),
+            ((Some(order_id), Some("replace"), Some(price), Some(qty), _), Some(order)) => (
+                (price, qty, order.is_bid, Some(order.price), Some(order.qty)),
+                book.replace_order(&order_id, price, qty),
+            ),
+            ((Some(order_id), Some("execute"), _, Some(qty), _), Some(order)) => (
+                (order.price, -qty, order.is_bid, None, None),
+                book.execute_order(&order_id, qty),
+            ),
+            ((Some(_), Some("cancel" | "replace" | "execute"), _, _, _), None) => {
+                polars_bail!(ComputeError: "Order id not found for input tuple: {:?}", tuple)
+            }
+            _ => polars_bail!(ComputeError: "Invalid input tuple: {:?}", tuple),
+        };
+        result.map_err(|e| polars_err!(ComputeError: "{} for input tuple: {:?}", e, tuple))?;
+        let (price, qty, is_bid, prev_price, prev_qty) = update;
+        out_price.append_value(price);
+        out_qty.append_value(qty);
+        out_is_bid.append_value(is_bid);
+        out_prev_price.append_option(prev_price);
+        out_prev_qty.append_option(prev_qty);
+    }
+
+    let fields = [
+        out_price.finish().into_series(),
+        out_qty.finish().into_series(),
+        out_is_bid.finish().into_series(),
+        out_prev_price.finish().into_series(),
+        out_prev_qty.finish().into_series(),
+    ];
+    StructChunked::new("mbp", &fields)?
+        .into_series()
+        .cast(&mbp_dtype(&input_fields(inputs)))
+}
+
+fn snapshot_deltas_struct(_input_fields: &[Field]) -> PolarsResult<Field> {
+    let fields = vec![
+        Field::new("snapshot_row", IDX_DTYPE),
+        Field::new("price", DataType::Int64),
+        Field::new("qty", DataType::Int64),
+        Field::new("is_bid", DataType::Boolean),
+    ];
+    Ok(Field::new("deltas", DataType::Struct(fields)))
+}
+
+/// Convert full depth snapshots, given as list columns of bid prices, bid
+/// qtys, ask prices and ask qtys with one snapshot per row, to the minimal
+/// stream of price-level updates between consecutive snapshots. The first
+/// snapshot is diffed against an empty book. Each update has the signed qty
+/// change of one level, in the `(price, qty, is_bid)` form read by
+/// `pl_calculate_bbo`, and the `snapshot_row` it leads to. Levels within a
+/// snapshot come bids then asks, best first, as from `OrderBook::diff`.
+/// Levels with zero qty, as used to pad fixed-depth snapshots, are ignored.
+#[polars_expr(output_type_func = snapshot_deltas_struct)]
+pub fn pl_snapshots_to_deltas(inputs: &[Series]) -> PolarsResult<Series> {
+    _pl_snapshots_to_deltas(inputs)
+}
+
+fn _pl_snapshots_to_deltas(inputs: &[Series]) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() == 4,
+        ComputeError: "Expected 4 input columns: bid_prices, bid_qtys, ask_prices, ask_qtys but got {}", inputs.len()
+    );
+    let lists = inputs
+        .iter()
+        .map(|s| s.cast(&DataType::List(Box::new(DataType::Int64))))
+        .collect::<PolarsResult<Vec<_>>>()?;
+    let mut out_row = Vec::new();
+    let mut out_price = Vec::new();
+    let mut out_qty = Vec::new();
+    let mut out_is_bid = Vec::new();
+
+    let mut prev_book = OrderBook::new();
+    for (row, (bid_prices, bid_qtys, ask_prices, ask_qtys)) in izip!(
+        lists[0].list()?.into_iter(),
+        lists[1].list()?.into_iter(),
+        lists[2].list()?.into_iter(),
+        lists[3].list()?.into_iter()
+    )
+    .enumerate()
+    {
+        let mut book = OrderBook::new();
+        for (is_bid, prices, qtys) in [(true, bid_prices, bid_qtys), (false, ask_prices, ask_qtys)]
+        {
+            let (Some(prices), Some(qtys)) = (prices, qtys) else {
+                polars_bail!(ComputeError: "Null snapshot in row {}", row);
+            };
+            polars_ensure!(
+                prices.len() == qtys.len(),
+                ComputeError: "Snapshot in row {} has {} prices but {} qtys", row, prices.len(), qtys.len()
+            );
+            for (price, qty) in prices.i64()?.into_iter().zip(qtys.i64()?) {
+                let (Some(price), Some(qty)) = (price, qty) else {
+                    polars_bail!(ComputeError: "Null level in the snapshot in row {}", row);
+                };
+                polars_ensure!(
+                    qty >= 0,
+                    ComputeError: "Negative qty {} at price {} in the snapshot in row {}", qty, price, row
+                );
+                if qty > 0 {
+                    book.add_qty(is_bid, price, qty);
+                }
+            }
+        }
+        for delta in prev_book.diff(&book) {
+            out_row.push(row as IdxSize);
+            out_price.push(delta.price);
+            out_qty.push(delta.qty_change());
+            out_is_bid.push(delta.is_bid);
+        }
+        prev_book = book;
+    }
+
+    let fields = [
+        Series::new("snapshot_row", out_row),
+        Series::new("price", out_price),
+        Series::new("qty", out_qty),
+        Series::new("is_bid", out_is_bid),
+    ];
+    Ok(StructChunked::new("deltas", &fields)?.into_series())
+}
+
+fn mid_spread_struct(_input_fields: &[Field], kwargs: MidSpreadKwargs) -> PolarsResult<Field> {
+    let mut fields = vec![
+        Field::new("mid", DataType::Float64),
+        Field::new("spread", DataType::Float64),
+        Field::new("spread_bps", DataType::Float64),
+    ];
+    if !kwargs.depth_weights.is_empty() {
+        fields.push(Field::new("weighted_mid", DataType::Float64));
+    }
+    Ok(Field::new("mid_spread", DataType::Struct(fields)))
+}
+
+/// Mid price, spread and spread in basis points of the mid after each update,
+/// for the same inputs as `pl_calculate_bbo`. Rows where either side of the
+/// book is empty are null. With `depth_weights`, a `weighted_mid` field
+/// weighs in the best levels of both sides. See `MidSpreadBuilder`.
+#[polars_expr(output_type_func_with_kwargs = mid_spread_struct)]
+pub fn pl_calculate_mid_spread(inputs: &[Series], kwargs: MidSpreadKwargs) -> PolarsResult<Series> {
+    _pl_calculate_mid_spread(inputs, &kwargs)
+}
+
+fn _pl_calculate_mid_spread(inputs: &[Series], kwargs: &MidSpreadKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        kwargs.depth_weights.iter().all(|weight| weight.is_finite() && *weight >= 0.0),
+        ComputeError: "depth_weights must be finite and non-negative, got {:?}", kwargs.depth_weights
+    );
+    let mut builder = MidSpreadBuilder::new(inputs[0].len());
+    if !kwargs.depth_weights.is_empty() {
+        builder = builder.with_depth_weights(inputs[0].len(), kwargs.depth_weights.clone());
+    }
+    replay_updates(
+        inputs,
+        kwargs.initial_state.book()?,
+        builder,
+        kwargs.options,
+    )
+}
+
+fn queue_position_struct(_input_fields: &[Field]) -> PolarsResult<Field> {
+    let fields = vec![
+        Field::new("qty_ahead", DataType::Float64),
+        Field::new("fill_probability", DataType::Float64),
+        Field::new("filled", DataType::Boolean),
+    ];
+    Ok(Field::new("queue_position", DataType::Struct(fields)))
+}
+
+/// Estimated queue position of a hypothetical order after each update, for
+/// the same inputs as `pl_calculate_bbo` plus a trailing boolean column of
+/// when to enter the order if `entry_row` isn't given. See
+/// `QueuePositionBuilder`.
+#[polars_expr(output_type_func = queue_position_struct)]
+pub fn pl_calculate_queue_position(
+    inputs: &[Series],
+    kwargs: QueuePositionKwargs,
+) -> PolarsResult<Series> {
+    _pl_calculate_queue_position(inputs, &kwargs)
+}
+
+fn _pl_calculate_queue_position(
+    inputs: &[Series],
+    kwargs: &QueuePositionKwargs,
+) -> PolarsResult<Series> {
+    let (inputs, entry_row) = match kwargs.entry_row {
+        Some(entry_row) => (inputs, entry_row),
+        None => {
+            let (entered, inputs) = inputs.split_last().unwrap();
+            let entry_row = entered
+                .bool()?
+                .into_iter()
+                .position(|entered| entered == Some(true))
+                .unwrap_or(usize::MAX);
+            (inputs, entry_row)
+        }
+    };
+    replay_updates(
+        inputs,
+        kwargs.initial_state.book()?,
+        QueuePositionBuilder::new(
+            inputs[0].len(),
+            kwargs.order_is_bid,
+            kwargs.order_price,
+            kwargs.model,
+            entry_row,
+        ),
+        kwargs.options,
+    )
+}
+
+fn fills_list(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new("fills", fills_dtype(input_fields)))
+}
+
+fn fills_dtype(input_fields: &[Field]) -> DataType {
+    DataType::List(Box::new(DataType::Struct(vec![
+        Field::new("price", input_fields[0].data_type().clone()),
+        Field::new("qty", input_fields[1].data_type().clone()),
+    ])))
+}
+
+/// Replay price, qty and is_bid updates, treating every add as an aggressive
+/// limit order: it is matched against the opposite side with
+/// `OrderBook::match_order` and any unfilled qty rests at its price. Adds
+/// that aren't marketable rest in full, so a book that never crosses
+/// replays as usual. Returns each row's list of `{price, qty}` fills, best
+/// price first, which is empty for rows that didn't trade.
+#[polars_expr(output_type_func = fills_list)]
+pub fn pl_match_orders(inputs: &[Series], kwargs: MatchOrdersKwargs) -> PolarsResult<Series> {
+    _pl_match_orders(inputs, &kwargs)
+}
+
+fn _pl_match_orders(inputs: &[Series], kwargs: &MatchOrdersKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() == 3,
+        ComputeError: "Expected price, qty and is_bid columns, got {}", inputs.len()
+    );
+    let updates = coerce_update_inputs(inputs)?;
+    let (price, qty, is_bid) = (updates[0].i64()?, updates[1].i64()?, updates[2].bool()?);
+
+    let mut book = kwargs.initial_state.book()?;
+    let mut fills = Vec::with_capacity(price.len());
+    for (row, tuple) in izip!(is_bid.into_iter(), price.into_iter(), qty.into_iter()).enumerate() {
+        let row_fills = match tuple {
+            (Some(is_bid), Some(price), Some(qty)) if qty > 0 => {
+                let row_fills = book.match_order(is_bid, price, qty);
+                let filled: i64 = row_fills.iter().map(|fill| fill.qty).sum();
+                if filled < qty {
+                    add_qty(
+                        &mut book,
+                        is_bid,
+                        price,
+                        qty - filled,
+                        kwargs.options.checked_qty,
+                    )
+                    .map_err(|e| polars_err!(ComputeError: "{} in row {}", e, row))?;
+                }
+                row_fills
+            }
+            (is_bid, price, qty) => {
+                apply_update(
+                    &mut book,
+                    (is_bid, price, qty, None, None),
+                    row,
+                    kwargs.options,
+                )?;
+                Vec::new()
+            }
+        };
+        let fill_prices: Vec<i64> = row_fills.iter().map(|fill| fill.price).collect();
+        let fill_qtys: Vec<i64> = row_fills.iter().map(|fill| fill.qty).collect();
+        let row_fills = StructChunked::new(
+            "",
+            &[
+                Series::new("price", fill_prices),
+                Series::new("qty", fill_qtys),
+            ],
+        )?;
+        fills.push(Some(row_fills.into_series()));
+    }
+    let fills: ListChunked = fills.into_iter().collect();
+    fills
+        .into_series()
+        .with_name("fills")
+        .cast(&fills_dtype(&input_fields(inputs)))
+}
+
+fn nbbo_struct(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new("nbbo", nbbo_dtype(input_fields)))
+}
+
+fn nbbo_dtype(input_fields: &[Field]) -> DataType {
+    let price_dtype = input_fields[0].data_type();
+    let qty_dtype = input_fields[1].data_type();
+    let venues_dtype = DataType::List(Box::new(DataType::String));
+    DataType::Struct(vec![
+        Field::new("best_bid", price_dtype.clone()),
+        Field::new("best_bid_qty", qty_dtype.clone()),
+        Field::new("best_ask", price_dtype.clone()),
+        Field::new("best_ask_qty", qty_dtype.clone()),
+        Field::new("best_bid_venues", venues_dtype.clone()),
+        Field::new("best_ask_venues", venues_dtype),
+    ])
+}
+
+/// The best bid and offer across venues after each update of a merged feed,
+/// given as price, qty and is_bid signed qty mutations and a venue column.
+/// Each venue's updates are applied to its own book in a
+/// `ConsolidatedBook`. Besides the best prices and the total qty at them,
+/// each row lists the venues at the best bid and at the best ask, sorted.
+/// Venue books may lock or cross each other, so crossed books aren't policed.
+#[polars_expr(output_type_func = nbbo_struct)]
+pub fn pl_calculate_nbbo(inputs: &[Series], kwargs: NbboKwargs) -> PolarsResult<Series> {
+    _pl_calculate_nbbo(inputs, &kwargs)
+}
+
+fn _pl_calculate_nbbo(inputs: &[Series], kwargs: &NbboKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() == 4,
+        ComputeError: "Expected 4 input columns: price, qty, is_bid, venue but got {}", inputs.len()
+    );
+    let updates = coerce_update_inputs(&inputs[..3])?;
+    let (price, qty, is_bid) = (updates[0].i64()?, updates[1].i64()?, updates[2].bool()?);
+    let venue = inputs[3].cast(&DataType::String)?;
+    let length = price.len();
+    let mut best_bid = PrimitiveChunkedBuilder::<Int64Type>::new("best_bid", length);
+    let mut best_bid_qty = PrimitiveChunkedBuilder::<Int64Type>::new("best_bid_qty", length);
+    let mut best_ask = PrimitiveChunkedBuilder::<Int64Type>::new("best_ask", length);
+    let mut best_ask_qty = PrimitiveChunkedBuilder::<Int64Type>::new("best_ask_qty", length);
+    let mut best_bid_venues = ListStringChunkedBuilder::new("best_bid_venues", length, length);
+    let mut best_ask_venues = ListStringChunkedBuilder::new("best_ask_venues", length, length);
+
+    let mut book = ConsolidatedBook::new();
+    for (row, (is_bid, price, qty, venue)) in izip!(
+        is_bid.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        venue.str()?.into_iter()
+    )
+    .enumerate()
+    {
+        let Some(venue) = venue else {
+            polars_bail!(ComputeError: "Null venue in row {}", row);
+        };
+        apply_update(
+            book.venue_book_mut(venue),
+            (is_bid, price, qty, None, None),
+            row,
+            kwargs.options,
+        )?;
+        append_consolidated_level(
+            book.best_level(true),
+            &mut best_bid,
+            &mut best_bid_qty,
+            &mut best_bid_venues,
+        );
+        append_consolidated_level(
+            book.best_level(false),
+            &mut best_ask,
+            &mut best_ask_qty,
+            &mut best_ask_venues,
+        );
+    }
+    let fields = [
+        best_bid.finish().into_series(),
+        best_bid_qty.finish().into_series(),
+        best_ask.finish().into_series(),
+        best_ask_qty.finish().into_series(),
+        best_bid_venues.finish().into_series(),
+        best_ask_venues.finish().into_series(),
+    ];
+    StructChunked::new("nbbo", &fields)?
+        .into_series()
+        .cast(&nbbo_dtype(&input_fields(inputs)))
+}
+
+/// Append one side of the consolidated best level, or nulls if it is empty.
+fn append_consolidated_level(
+    level: Option<ConsolidatedLevel<&str, i64, i64>>,
+    price: &mut PrimitiveChunkedBuilder<Int64Type>,
+    qty: &mut PrimitiveChunkedBuilder<Int64Type>,
+    venues: &mut ListStringChunkedBuilder,
+) {
+    match level {
+        Some(level) => {
+            price.append_value(level.price);
+            qty.append_value(level.qty);
+            venues.append_values_iter(level.venues.into_iter());
+        }
+        None => {
+            price.append_null();
+            qty.append_null();
+            venues.append_null();
+        }
+    }
+}
+
+const IMPLIED_CONTRACTS: [(Contract, &str); 3] = [
+    (Contract::Front, "front"),
+    (Contract::Back, "back"),
+    (Contract::Spread, "spread"),
+];
+
+fn parse_contract(contract: &str) -> Option<Contract> {
+    IMPLIED_CONTRACTS
+        .iter()
+        .find(|(_, name)| *name == contract)
+        .map(|(contract, _)| *contract)
+}
+
+fn implied_bbo_struct(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new("implied_bbo", implied_bbo_dtype(input_fields)))
+}
+
+fn implied_bbo_dtype(input_fields: &[Field]) -> DataType {
+    let price_dtype = input_fields[0].data_type();
+    let qty_dtype = input_fields[1].data_type();
+    let fields = IMPLIED_CONTRACTS
+        .iter()
+        .flat_map(|(_, name)| {
+            [
+                Field::new(&format!("{name}_bid"), price_dtype.clone()),
+                Field::new(&format!("{name}_bid_qty"), qty_dtype.clone()),
+                Field::new(&format!("{name}_ask"), price_dtype.clone()),
+                Field::new(&format!("{name}_ask_qty"), qty_dtype.clone()),
+            ]
+        })
+        .collect();
+    DataType::Struct(fields)
+}
+
+/// Best bid and offer of two outright futures and their calendar spread,
+/// including implied liquidity, from one feed interleaving the updates of
+/// the three books. The `contract` column names the book of each update:
+/// "front", "back" or "spread", the spread priced as front minus back.
+/// Every row gets the best prices of all three contracts after the update,
+/// merging each outright book with what the other two imply in it, with
+/// qty summed where outright and implied prices meet.
+#[polars_expr(output_type_func = implied_bbo_struct)]
+pub fn pl_calculate_implied_bbo(
+    inputs: &[Series],
+    kwargs: ImpliedBboKwargs,
+) -> PolarsResult<Series> {
+    _pl_calculate_implied_bbo(inputs, &kwargs)
+}
+
+fn _pl_calculate_implied_bbo(inputs: &[Series], kwargs: &ImpliedBboKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() == 4,
+        ComputeError: "Expected 4 input columns: price, qty, is_bid, contract but got {}", inputs.len()
+    );
+    let updates = coerce_update_inputs(&inputs[..3])?;
+    let (price, qty, is_bid) = (updates[0].i64()?, updates[1].i64()?, updates[2].bool()?);
+    let contract = inputs[3].cast(&DataType::String)?;
+    let length = price.len();
+    let mut builders: Vec<_> = IMPLIED_CONTRACTS
+        .iter()
+        .flat_map(|(_, name)| {
+            ["bid", "bid_qty", "ask", "ask_qty"].map(|field| {
+                PrimitiveChunkedBuilder::<Int64Type>::new(&format!("{name}_{field}"), length)
+            })
+        })
+        .collect();
+
+    let mut book = ImpliedBook::new();
+    for (row, (is_bid, price, qty, contract)) in izip!(
+        is_bid.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        contract.str()?.into_iter()
+    )
+    .enumerate()
+    {
+        let Some(contract) = contract.and_then(parse_contract) else {
+            polars_bail!(
+                ComputeError: "Invalid contract in row {}: {:?}, expected \"front\", \"back\" or \"spread\"", row, contract
+            );
+        };
+        apply_update(
+            book.book_mut(contract),
+            (is_bid, price, qty, None, None),
+            row,
+            kwargs.options,
+        )?;
+        for ((contract, _), builders) in IMPLIED_CONTRACTS.iter().zip(builders.chunks_mut(4)) {
+            for (is_bid, builders) in [true, false].into_iter().zip(builders.chunks_mut(2)) {
+                let level = book.best_level(*contract, is_bid);
+                builders[0].append_option(level.map(|level| level.price));
+                builders[1].append_option(level.map(|level| level.qty));
+            }
+        }
+    }
+    let fields: Vec<Series> = builders
+        .into_iter()
+        .map(|builder| builder.finish().into_series())
+        .collect();
+    StructChunked::new("implied_bbo", &fields)?
+        .into_series()
+        .cast(&implied_bbo_dtype(&input_fields(inputs)))
+}
+
+/// Order book imbalance over the best `depth` levels of each side after each
+/// update, for the same inputs as `pl_calculate_bbo`. See `ImbalanceBuilder`.
+#[polars_expr(output_type = Float64)]
+pub fn pl_calculate_imbalance(inputs: &[Series], kwargs: ImbalanceKwargs) -> PolarsResult<Series> {
+    _pl_calculate_imbalance(inputs, &kwargs)
+}
+
+fn _pl_calculate_imbalance(inputs: &[Series], kwargs: &ImbalanceKwargs) -> PolarsResult<Series> {
+    polars_ensure!(kwargs.depth > 0, ComputeError: "depth must be at least 1");
+    replay_updates(
+        inputs,
+        kwargs.initial_state.book()?,
+        ImbalanceBuilder::new(inputs[0].len(), kwargs.depth),
+        kwargs.options,
+    )
+}
+
+fn book_features_struct(
+    _input_fields: &[Field],
+    kwargs: BookFeaturesKwargs,
+) -> PolarsResult<Field> {
+    let fields = kwargs
+        .parse_features()?
+        .iter()
+        .flat_map(BookFeature::fields)
+        .collect();
+    Ok(Field::new("book_features", DataType::Struct(fields)))
+}
+
+/// A chosen set of per-row features of the book after each update, such as
+/// the mid, microprice, imbalance at several depths and OFI, computed in one
+/// replay rather than one per feature. Takes the same inputs as
+/// `pl_calculate_bbo`. See `BookFeature` for the features.
+#[polars_expr(output_type_func_with_kwargs = book_features_struct)]
+pub fn pl_book_features(inputs: &[Series], kwargs: BookFeaturesKwargs) -> PolarsResult<Series> {
+    _pl_book_features(inputs, &kwargs)
+}
+
+fn _pl_book_features(inputs: &[Series], kwargs: &BookFeaturesKwargs) -> PolarsResult<Series> {
+    let features = kwargs.parse_features()?;
+    let book = kwargs.initial_state.book()?;
+    let builder = BookFeaturesBuilder::new(inputs[0].len(), &features, &book);
+    replay_updates(inputs, book, builder, kwargs.options)
+}
+
+/// Order flow imbalance of each update at the best quotes, for the same
+/// inputs as `pl_calculate_bbo`, optionally summed over a trailing time
+/// window. See `OfiBuilder`.
+#[polars_expr(output_type = Int64)]
+pub fn pl_calculate_ofi(inputs: &[Series], kwargs: OfiKwargs) -> PolarsResult<Series> {
+    _pl_calculate_ofi(inputs, &kwargs)
+}
+
+fn _pl_calculate_ofi(inputs: &[Series], kwargs: &OfiKwargs) -> PolarsResult<Series> {
+    let (inputs, ts) = match kwargs.window {
+        Some(_) => {
+            let (ts, inputs) = inputs.split_last().unwrap();
+            (inputs, Some(ts))
+        }
+        None => (inputs, None),
+    };
+    let book = kwargs.initial_state.book()?;
+    let builder = OfiBuilder::new(inputs[0].len(), &book);
+    let ofi = replay_updates(inputs, book, builder, kwargs.options)?;
+    match (kwargs.window, ts) {
+        (Some(window), Some(ts)) => rolling_sum_by_time(ofi.i64()?, ts, window),
+        _ => Ok(ofi),
+    }
+}
+
+/// Sum `values` over the trailing `(t - window, t]` of the sorted times `ts`.
+fn rolling_sum_by_time(values: &Int64Chunked, ts: &Series, window: i64) -> PolarsResult<Series> {
+    polars_ensure!(window > 0, ComputeError: "window must be positive, got {}", window);
+    let ts = ts.cast(&DataType::Int64)?;
+    ensure_sorted(ts.i64()?, "ts")?;
+    let ts: Vec<i64> = ts.i64()?.into_no_null_iter().collect();
+    let name = values.name();
+    let values: Vec<i64> = values.into_no_null_iter().collect();
+
+    let mut start = 0;
+    let mut sum = 0;
+    let sums: Int64Chunked = ts
+        .iter()
+        .zip(&values)
+        .map(|(&t, &value)| {
+            sum += value;
+            while ts[start] <= t - window {
+                sum -= values[start];
+                start += 1;
+            }
+            Some(sum)
+        })
+        .collect();
+    Ok(sums.with_name(name).into_series())
+}
+
+fn vwap_struct(_input_fields: &[Field]) -> PolarsResult<Field> {
+    let fields = vec![
+        Field::new("bid_vwap", DataType::Float64),
+        Field::new("ask_vwap", DataType::Float64),
+    ];
+    Ok(Field::new("vwap", DataType::Struct(fields)))
+}
+
+/// Volume-weighted average price of the best `depth` levels of each side
+/// after each update, for the same inputs as `pl_calculate_bbo`.
+#[polars_expr(output_type_func = vwap_struct)]
+pub fn pl_calculate_vwap(inputs: &[Series], kwargs: VwapKwargs) -> PolarsResult<Series> {
+    _pl_calculate_vwap(inputs, &kwargs)
+}
+
+fn _pl_calculate_vwap(inputs: &[Series], kwargs: &VwapKwargs) -> PolarsResult<Series> {
+    polars_ensure!(kwargs.depth > 0, ComputeError: "depth must be at least 1");
+    replay_updates(
+        inputs,
+        kwargs.initial_state.book()?,
+        VwapBuilder::new(inputs[0].len(), kwargs.depth),
+        kwargs.options,
+    )
+}
+
+fn sweep_cost_struct(_input_fields: &[Field]) -> PolarsResult<Field> {
+    let fields = vec![
+        Field::new("buy_price", DataType::Float64),
+        Field::new("sell_price", DataType::Float64),
+    ];
+    Ok(Field::new("sweep_cost", DataType::Struct(fields)))
+}
+
+/// Average fill price of a market order of `size` against each side of the
+/// book after each update, for the same inputs as `pl_calculate_bbo`. See
+/// `SweepCostBuilder`.
+#[polars_expr(output_type_func = sweep_cost_struct)]
+pub fn pl_calculate_sweep_cost(inputs: &[Series], kwargs: SweepCostKwargs) -> PolarsResult<Series> {
+    _pl_calculate_sweep_cost(inputs, &kwargs)
+}
+
+fn _pl_calculate_sweep_cost(inputs: &[Series], kwargs: &SweepCostKwargs) -> PolarsResult<Series> {
+    polars_ensure!(kwargs.size > 0, ComputeError: "size must be positive, got {}", kwargs.size);
+    replay_updates(
+        inputs,
+        kwargs.initial_state.book()?,
+        SweepCostBuilder::new(inputs[0].len(), kwargs.size),
+        kwargs.options,
+    )
+}
+
+fn depth_band_struct(_input_fields: &[Field]) -> PolarsResult<Field> {
+    let fields = vec![
+        Field::new("bid_qty", DataType::Int64),
+        Field::new("ask_qty", DataType::Int64),
+        Field::new("bid_notional", DataType::Float64),
+        Field::new("ask_notional", DataType::Float64),
+    ];
+    Ok(Field::new("depth_band", DataType::Struct(fields)))
+}
+
+/// Qty and notional resting within `bps` basis points of the mid on each side
+/// after each update, for the same inputs as `pl_calculate_bbo`. See
+/// `DepthBandBuilder`.
+#[polars_expr(output_type_func = depth_band_struct)]
+pub fn pl_calculate_depth_band(inputs: &[Series], kwargs: DepthBandKwargs) -> PolarsResult<Series> {
+    _pl_calculate_depth_band(inputs, &kwargs)
+}
+
+fn _pl_calculate_depth_band(inputs: &[Series], kwargs: &DepthBandKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        kwargs.bps >= 0.0,
+        ComputeError: "bps must not be negative, got {}", kwargs.bps
+    );
+    replay_updates(
+        inputs,
+        kwargs.initial_state.book()?,
+        DepthBandBuilder::new(inputs[0].len(), kwargs.bps),
+        kwargs.options,
+    )
+}
+
+fn depth_buckets_struct(input_fields: &[Field], kwargs: DepthBucketsKwargs) -> PolarsResult<Field> {
+    Ok(Field::new(
+        "depth_buckets",
+        depth_buckets_dtype(input_fields, kwargs.n_buckets),
+    ))
+}
+
+fn depth_buckets_dtype(input_fields: &[Field], n_buckets: usize) -> DataType {
+    let qty_dtype = input_fields[1].data_type();
+    let fields = depth_bucket_field_names(n_buckets)
+        .iter()
+        .map(|name| Field::new(name, qty_dtype.clone()))
+        .collect();
+    DataType::Struct(fields)
+}
+
+/// Qty of each side in `n_buckets` bands of `tick_width` ticks away from the
+/// mid after each update, for the same inputs as `pl_calculate_bbo`, e.g. for
+/// liquidity heatmaps. Unlike `pl_calculate_top_n` every level of the book is
+/// visited. See `DepthBucketsBuilder`.
+#[polars_expr(output_type_func_with_kwargs = depth_buckets_struct)]
+pub fn pl_calculate_depth_buckets(
+    inputs: &[Series],
+    kwargs: DepthBucketsKwargs,
+) -> PolarsResult<Series> {
+    _pl_calculate_depth_buckets(inputs, &kwargs)
+}
+
+fn _pl_calculate_depth_buckets(
+    inputs: &[Series],
+    kwargs: &DepthBucketsKwargs,
+) -> PolarsResult<Series> {
+    polars_ensure!(kwargs.n_buckets > 0, ComputeError: "n_buckets must be at least 1");
+    polars_ensure!(
+        kwargs.tick_width > 0,
+        ComputeError: "tick_width must be positive, got {}", kwargs.tick_width
+    );
+    let buckets = replay_updates(
+        inputs,
+        kwargs.initial_state.book()?,
+        DepthBucketsBuilder::new(inputs[0].len(), kwargs.n_buckets, kwargs.tick_width),
+        kwargs.options,
+    )?;
+    buckets.cast(&depth_buckets_dtype(
+        &input_fields(inputs),
+        kwargs.n_buckets,
+    ))
+}
+
+fn indicative_uncross_struct(_input_fields: &[Field]) -> PolarsResult<Field> {
+    let fields = vec![
+        Field::new("price", DataType::Int64),
+        Field::new("volume", DataType::Int64),
+        Field::new("imbalance", DataType::Int64),
+    ];
+    Ok(Field::new("indicative_uncross", DataType::Struct(fields)))
+}
+
+/// The indicative auction price, matched volume and imbalance after each
+/// update, for the same inputs as `pl_calculate_bbo`. Updates during an
+/// auction call phase can leave the book crossed; rows where it isn't are
+/// null. See `OrderBook::indicative_uncross`.
+#[polars_expr(output_type_func = indicative_uncross_struct)]
+pub fn pl_calculate_indicative_uncross(
+    inputs: &[Series],
+    kwargs: IndicativeUncrossKwargs,
+) -> PolarsResult<Series> {
+    _pl_calculate_indicative_uncross(inputs, &kwargs)
+}
+
+fn _pl_calculate_indicative_uncross(
+    inputs: &[Series],
+    kwargs: &IndicativeUncrossKwargs,
+) -> PolarsResult<Series> {
+    replay_updates(
+        inputs,
+        kwargs.initial_state.book()?,
+        IndicativeUncrossBuilder::new(inputs[0].len()),
+        kwargs.options,
+    )
+}
+
+/// The best `depth` levels of both sides as a JSON string per row.
+/// See `BookJsonBuilder` for the format.
+#[polars_expr(output_type = String)]
+pub fn pl_book_json(inputs: &[Series], kwargs: BookJsonKwargs) -> PolarsResult<Series> {
+    replay_updates(
+        inputs,
+        kwargs.initial_state.book()?,
+        BookJsonBuilder::new(inputs[0].len(), kwargs.depth),
+        kwargs.options,
+    )
+}
+
+fn top_n_struct(input_fields: &[Field], kwargs: TopNKwargs) -> PolarsResult<Field> {
+    Ok(Field::new(
+        "top_n",
+        top_n_dtype(input_fields, kwargs.n, kwargs.extras()),
+    ))
+}
+
+fn top_n_dtype(input_fields: &[Field], n: usize, extras: TopNExtras) -> DataType {
+    let names = top_n_field_names(n, extras);
+    let fields = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            // Fields alternate between n prices and n qtys, followed by 2n
+            // cumulative qtys if any, then 2n fields for each extra input
+            // after is_bid, in input order.
+            let cumulative = 2 * n * extras.cumulative_qty as usize;
+            let input_field = if i < 4 * n {
+                &input_fields[(i / n) % 2]
+            } else if i < 4 * n + cumulative {
+                &input_fields[1]
+            } else {
+                &input_fields[3 + (i - 4 * n - cumulative) / (2 * n)]
+            };
+            Field::new(name, input_field.data_type().clone())
+        })
+        .collect();
+    DataType::Struct(fields)
+}
+
+/// The best `n` price levels of each side after each update as wide fields,
+/// for the same inputs as `pl_calculate_bbo`. See `TopNBuilder`.
+#[polars_expr(output_type_func_with_kwargs = top_n_struct)]
+pub fn pl_calculate_top_n(inputs: &[Series], kwargs: TopNKwargs) -> PolarsResult<Series> {
+    _pl_calculate_top_n(inputs, &kwargs)
+}
+
+fn _pl_calculate_top_n(inputs: &[Series], kwargs: &TopNKwargs) -> PolarsResult<Series> {
+    polars_ensure!(kwargs.n > 0, ComputeError: "n must be at least 1");
+    let top_n = if kwargs.order_count || kwargs.level_timestamp {
+        top_n_with_extras(inputs, kwargs)?
+    } else {
+        replay_updates(
+            inputs,
+            kwargs.initial_state.book()?,
+            TopNBuilder::with_extras(inputs[0].len(), kwargs.n, kwargs.extras()),
+            kwargs.options,
+        )?
+    };
+    top_n.cast(&top_n_dtype(
+        &input_fields(inputs),
+        kwargs.n,
+        kwargs.extras(),
+    ))
+}
+
+impl TopNKwargs {
+    fn extras(&self) -> TopNExtras {
+        TopNExtras {
+            cumulative_qty: self.cumulative_qty,
+            order_count: self.order_count,
+            level_timestamp: self.level_timestamp,
+        }
+    }
+}
+
+/// `pl_calculate_top_n` for price, qty and is_bid columns followed by an
+/// order count column, a timestamp column, or both in that order.
+///
+/// The order count is the signed change in the number of orders resting at
+/// the update's level, e.g. 1 for a new order, -1 for a cancel and 0 for a
+/// partial fill, and is replayed into a parallel book of counts at the same
+/// prices. A level's count is dropped when the level is, and is null for
+/// levels with no counts replayed, such as those of the initial state.
+///
+/// Each level is stamped with the timestamp of the last update that
+/// modified it, see `OrderBook::track_level_timestamps`. Levels of the
+/// initial state have no timestamp until they are modified.
+fn top_n_with_extras(inputs: &[Series], kwargs: &TopNKwargs) -> PolarsResult<Series> {
+    let extras = kwargs.extras();
+    polars_ensure!(
+        inputs.len() == 3 + extras.order_count as usize + extras.level_timestamp as usize,
+        ComputeError: "order_count and timestamp are not supported with prev_price and prev_qty"
+    );
+    let updates = coerce_update_inputs(&inputs[..3])?;
+    let (price, qty, is_bid) = (updates[0].i64()?, updates[1].i64()?, updates[2].bool()?);
+    let extra_column = |present: bool, index: usize| -> PolarsResult<Option<Int64Chunked>> {
+        if !present {
+            return Ok(None);
+        }
+        let column = inputs[index].to_physical_repr().cast(&DataType::Int64)?;
+        Ok(Some(column.i64()?.rechunk()))
+    };
+    let count = extra_column(extras.order_count, 3)?;
+    let timestamp = extra_column(extras.level_timestamp, inputs.len() - 1)?;
+
+    let mut book = kwargs.initial_state.book()?;
+    if extras.level_timestamp {
+        book.track_level_timestamps();
+    }
+    let mut counts = OrderBook::default();
+    let mut builder = TopNBuilder::with_extras(price.len(), kwargs.n, extras);
+    for (row, (is_bid, price, qty)) in
+        izip!(is_bid.into_iter(), price.into_iter(), qty.into_iter()).enumerate()
+    {
+        let count = count.as_ref().and_then(|count| count.get(row));
+        if let Some(timestamp) = timestamp.as_ref().and_then(|timestamp| timestamp.get(row)) {
+            book.set_timestamp(timestamp as u64);
+        }
+        apply_update(
+            &mut book,
+            (is_bid, price, qty, None, None),
+            row,
+            kwargs.options,
+        )?;
+        if let (Some(is_bid), Some(price)) = (is_bid, price) {
+            if let (Some(_), Some(count)) = (qty, count) {
+                counts
+                    .book_side(is_bid)
+                    .apply_qty_delta(price, count)
+                    .map_err(
+                        |e| polars_err!(ComputeError: "{} in order count of row {}", e, row),
+                    )?;
+            }
+            if book.get_book_side(is_bid).get_level(price).is_none() {
+                let _ = counts.book_side(is_bid).delete_level(price);
+            }
+        }
+        builder.append(&book);
+        if extras.order_count {
+            builder.append_counts(&book, &counts);
+        }
+        if extras.level_timestamp {
+            builder.append_timestamps(&book);
+        }
+    }
+    builder.finish()
+}
+
+fn final_book_struct(input_fields: &[Field]) -> PolarsResult<Field> {
+    let fields = vec![
+        Field::new("is_bid", DataType::Boolean),
+        Field::new("price", input_fields[0].data_type().clone()),
+        Field::new("qty", input_fields[1].data_type().clone()),
+    ];
+    Ok(Field::new("final_book", DataType::Struct(fields)))
+}
+
+fn reverse_bbo_struct(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new("bbo", reverse_bbo_dtype(input_fields)))
+}
+
+fn reverse_bbo_dtype(input_fields: &[Field]) -> DataType {
+    let price_dtype = input_fields[0].data_type();
+    let qty_dtype = input_fields[1].data_type();
+    DataType::Struct(vec![
+        Field::new("best_bid", price_dtype.clone()),
+        Field::new("best_bid_qty", qty_dtype.clone()),
+        Field::new("best_ask", price_dtype.clone()),
+        Field::new("best_ask_qty", qty_dtype.clone()),
+    ])
+}
+
+/// The best bid and offer just before each update, found by walking the
+/// updates backwards from the book after the last one, e.g. a `final_book`
+/// snapshot, undoing each in turn. Takes the same inputs as
+/// `pl_calculate_bbo`. Answers questions like what the book was just before
+/// a trade without replaying from the start of the day.
+#[polars_expr(output_type_func = reverse_bbo_struct)]
+pub fn pl_calculate_bbo_reverse(
+    inputs: &[Series],
+    kwargs: ReverseBboKwargs,
+) -> PolarsResult<Series> {
+    _pl_calculate_bbo_reverse(inputs, &kwargs)
+}
+
+fn _pl_calculate_bbo_reverse(inputs: &[Series], kwargs: &ReverseBboKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() == 3 || inputs.len() == 5,
+        ComputeError: "Expected 3 or 5 input columns: price, qty, is_bid, (prev_price, prev_qty) but got {}", inputs.len()
+    );
+    polars_ensure!(
+        kwargs.options.null_policy != NullPolicy::DeleteLevel,
+        ComputeError: "null_policy \"delete_level\" can't be undone, so it isn't supported in a reverse replay"
+    );
+    let updates = coerce_update_inputs(inputs)?;
+    let price = updates[0].i64()?;
+    let qty = updates[1].i64()?;
+    let is_bid = updates[2].bool()?;
+    let no_prev = Int64Chunked::full_null("", price.len());
+    let prev_price = updates
+        .get(3)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+    let prev_qty = updates
+        .get(4)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+    let mut builder = BboBuilder::<Int64Type, Int64Type>::new(
+        price.len(),
+        bbo_field_names("struct")?,
+        CrossedPolicy::Ignore,
+        false,
+    );
+
+    let mut book = kwargs.final_state.book()?;
+    let last_row = price.len().saturating_sub(1);
+    for (i, tuple) in izip!(
+        is_bid.into_iter().rev(),
+        price.into_iter().rev(),
+        qty.into_iter().rev(),
+        prev_price.into_iter().rev(),
+        prev_qty.into_iter().rev()
+    )
+    .enumerate()
+    {
+        apply_update(
+            &mut book,
+            invert_update(tuple),
+            last_row - i,
+            kwargs.options,
+        )?;
+        builder.append(&book);
+    }
+    builder
+        .finish()?
+        .reverse()
+        .cast(&reverse_bbo_dtype(&input_fields(inputs)))
+}
+
+/// Every level of the book after the last update, for the same inputs as
+/// `pl_calculate_bbo`. Its levels can be passed back as the initial state of
+/// the next batch. See `FinalBookBuilder`.
+#[polars_expr(output_type_func = final_book_struct)]
+pub fn pl_final_book(inputs: &[Series], kwargs: FinalBookKwargs) -> PolarsResult<Series> {
+    _pl_final_book(inputs, &kwargs)
+}
+
+fn _pl_final_book(inputs: &[Series], kwargs: &FinalBookKwargs) -> PolarsResult<Series> {
+    let final_book = replay_updates(
+        inputs,
+        kwargs.initial_state.book()?,
+        FinalBookBuilder,
+        kwargs.options,
+    )?;
+    final_book.cast(final_book_struct(&input_fields(inputs))?.data_type())
+}
+
+fn validation_struct(_input_fields: &[Field]) -> PolarsResult<Field> {
+    let fields = vec![
+        Field::new("delete_missing_level", DataType::Boolean),
+        Field::new("qty_exceeds_available", DataType::Boolean),
+        Field::new("crossed", DataType::Boolean),
+        Field::new("negative_qty", DataType::Boolean),
+        Field::new("duplicate_add", DataType::Boolean),
+        Field::new("error", DataType::String),
+    ];
+    Ok(Field::new("validation", DataType::Struct(fields)))
+}
+
+/// Audit a stream of updates, for the same inputs as `pl_calculate_bbo`,
+/// flagging the problems with each row instead of failing on the first.
+/// The book is replayed as `calculate_bbo` does with `strict=False`: updates
+/// that can't be applied are skipped, and `error` says why. See
+/// `UpdateDiagnostics` for the flags.
+#[polars_expr(output_type_func = validation_struct)]
+pub fn pl_validate_book_updates(inputs: &[Series], kwargs: ValidateKwargs) -> PolarsResult<Series> {
+    _pl_validate_book_updates(inputs, &kwargs)
+}
+
+fn _pl_validate_book_updates(inputs: &[Series], kwargs: &ValidateKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() == 3 || inputs.len() == 5,
+        ComputeError: "Expected 3 or 5 input columns: price, qty, is_bid, (prev_price, prev_qty) but got {}", inputs.len()
+    );
+    let updates = coerce_update_inputs(inputs)?;
+    let price = updates[0].i64()?;
+    let qty = updates[1].i64()?;
+    let is_bid = updates[2].bool()?;
+    let no_prev = Int64Chunked::full_null("", price.len());
+    let prev_price = updates
+        .get(3)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+    let prev_qty = updates
+        .get(4)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+
+    let length = price.len();
+    let mut delete_missing_level = Vec::with_capacity(length);
+    let mut qty_exceeds_available = Vec::with_capacity(length);
+    let mut crossed = Vec::with_capacity(length);
+    let mut negative_qty = Vec::with_capacity(length);
+    let mut duplicate_add = Vec::with_capacity(length);
+    let mut errors = Vec::with_capacity(length);
+
+    let mut book = kwargs.initial_state.book()?;
+    let mut previous = None;
+    for tuple in izip!(
+        is_bid.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        prev_price.into_iter(),
+        prev_qty.into_iter()
+    ) {
+        let diagnostics = UpdateDiagnostics::new(&book, tuple, previous);
+        errors.push(try_apply_update(&mut book, tuple, kwargs.options).err());
+        delete_missing_level.push(diagnostics.delete_missing_level);
+        qty_exceeds_available.push(diagnostics.qty_exceeds_available);
+        crossed.push(book.is_crossed());
+        negative_qty.push(diagnostics.negative_qty);
+        duplicate_add.push(diagnostics.duplicate_add);
+        previous = Some(tuple);
+    }
+
+    let fields = [
+        Series::new("delete_missing_level", delete_missing_level),
+        Series::new("qty_exceeds_available", qty_exceeds_available),
+        Series::new("crossed", crossed),
+        Series::new("negative_qty", negative_qty),
+        Series::new("duplicate_add", duplicate_add),
+        Series::new("error", errors),
+    ];
+    Ok(StructChunked::new("validation", &fields)?.into_series())
+}
+
+/// The problems `pl_validate_book_updates` flags in an update, judged
+/// against the book before it is applied. Rows with a null price, qty or
+/// is_bid are never flagged, only reported through `error` if
+/// `null_policy` is "raise".
+#[derive(Debug, Default, PartialEq, Eq)]
+struct UpdateDiagnostics {
+    /// The update deletes qty from a level the book doesn't hold. For a
+    /// modify, that is the level at its previous price.
+    delete_missing_level: bool,
+    /// The update deletes more qty than its level holds.
+    qty_exceeds_available: bool,
+    /// A qty that should be a level's absolute qty is negative, i.e. the
+    /// qty or prev_qty of a row with prev_qty. Without prev_qty the qty is
+    /// a signed change, so negative qtys are deletes.
+    negative_qty: bool,
+    /// An add that repeats the previous row exactly, as from a feed message
+    /// delivered twice.
+    duplicate_add: bool,
+}
+
+impl UpdateDiagnostics {
+    fn new(
+        book: &OrderBook<i64, i64>,
+        tuple: UpdateTuple<i64, i64>,
+        previous: Option<UpdateTuple<i64, i64>>,
+    ) -> Self {
+        let mut diagnostics = UpdateDiagnostics::default();
+        let (Some(is_bid), Some(price), Some(qty), prev_price, prev_qty) = tuple else {
+            return diagnostics;
+        };
+        // The level the update deletes from, and how much it deletes.
+        let delete = match (prev_price, prev_qty) {
+            (None, None) => (qty < 0).then(|| (price, qty.saturating_neg())),
+            (None, Some(prev_qty)) => {
+                (qty < prev_qty).then(|| (price, prev_qty.saturating_sub(qty)))
+            }
+            (Some(prev_price), Some(prev_qty)) => Some((prev_price, prev_qty)),
+            (Some(_), None) => None,
+        };
+        if let Some((price, delete_qty)) = delete {
+            match book.get_book_side(is_bid).get_level(price) {
+                Some(level) => diagnostics.qty_exceeds_available = level.qty < delete_qty,
+                None => diagnostics.delete_missing_level = true,
+            }
+        }
+        diagnostics.negative_qty = prev_qty.is_some_and(|prev_qty| qty < 0 || prev_qty < 0);
+        diagnostics.duplicate_add =
+            prev_price.is_none() && prev_qty.is_none() && qty > 0 && previous == Some(tuple);
+        diagnostics
+    }
+}
+
+/// The fields of `inputs`, to cast outputs replayed on Int64 columns back to
+/// the input types declared by an output type function.
+fn input_fields(inputs: &[Series]) -> Vec<Field> {
+    inputs.iter().map(|s| s.field().into_owned()).collect()
+}
+
+/// Cast the price and qty fields of a bbo struct replayed from
+/// `coerce_update_inputs` back to the dtypes of the price and qty `inputs`,
+/// as `bbo_struct` declares them.
+fn cast_bbo_back(bbo: Series, inputs: &[Series]) -> PolarsResult<Series> {
+    let (price_dtype, qty_dtype) = (inputs[0].dtype(), inputs[1].dtype());
+    let fields = bbo
+        .struct_()?
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, s)| match (i, s.name()) {
+            (0 | 2, _) => s.cast(price_dtype),
+            (1 | 3, _) | (_, "bid_total_qty" | "ask_total_qty") => s.cast(qty_dtype),
+            _ => Ok(s.clone()),
+        })
+        .collect::<PolarsResult<Vec<Series>>>()?;
+    Ok(StructChunked::new(bbo.name(), &fields)?.into_series())
+}
+
+/// Replay price-level updates given as price, qty, is_bid and optionally
+/// prev_price and prev_qty columns, appending the book state after each
+/// update to `builder`. See `ReplayOptions` for how updates are applied.
+/// Integer columns of any width are accepted; see `coerce_update_inputs`.
+fn replay_updates<B: BookOutputBuilder>(
+    inputs: &[Series],
+    book: OrderBook<i64, i64>,
+    builder: B,
+    options: ReplayOptions,
+) -> PolarsResult<Series> {
+    match inputs.len() {
+        3 | 5 => {}
+        _ => {
+            let input_names = inputs
+                .iter()
+                .map(|s| s.name())
+                .collect::<Vec<&str>>()
+                .join(", ");
+            panic!("Expected 3 or 5 input columns: price, qty, is_bid, (prev_price, prev_qty) but got {} columns called:\n    {}", inputs.len(), input_names)
+        }
+    }
+    let inputs = &coerce_update_inputs(inputs)?;
+    replay_native::<Int64Type, Int64Type, _>(inputs, book, builder, options)
+}
+
+/// `replay_updates` on price and qty columns of the physical types `P` and
+/// `Q`, without converting them. See `NativeTypes`.
+///
+/// The replay loops take the builder as a trait object, so they are
+/// compiled once per price and qty type rather than once per builder as
+/// well. One virtual `append` per row is cheap next to the book update.
+fn replay_native<P, Q, B>(
+    inputs: &[Series],
+    mut book: OrderBook<P::Native, Q::Native>,
+    mut builder: B,
+    options: ReplayOptions,
+) -> PolarsResult<Series>
+where
+    P: PolarsNumericType,
+    Q: PolarsNumericType,
+    P::Native: BookPrice,
+    Q::Native: BookQty,
+    B: BookOutputBuilder<P::Native, Q::Native>,
+{
+    let price = inputs[0].unpack::<P>()?;
+    let qty = inputs[1].unpack::<Q>()?;
+    let is_bid = inputs[2].bool()?;
+    let prev_price = inputs.get(3);
+    let prev_qty = inputs.get(4);
+
+    match (prev_price, prev_qty) {
+        (Some(prev_price), Some(prev_qty)) => {
+            let prev_price_chunked = prev_price.unpack::<P>()?;
+            let prev_qty_chunked = prev_qty.unpack::<Q>()?;
+            replay_with_modifies(
+                price,
+                qty,
+                is_bid,
+                prev_price_chunked,
+                prev_qty_chunked,
+                &mut book,
+                &mut builder,
+                options,
+            )?
+        }
+        (None, None) => {
+            replay_simple_mutations(price, qty, is_bid, &mut book, &mut builder, options)?
+        }
+        _ => panic!(
+            "Expected both prev_price and prev_qty or neither, got: {:?} and {:?}",
+            prev_price, prev_qty
+        ),
+    }
+    builder.finish_with_book(&book)
+}
+
+/// Replay price-point add and delete mutations.
+fn replay_simple_mutations<P, Q>(
+    price_array: &ChunkedArray<P>,
+    qty_array: &ChunkedArray<Q>,
+    is_bid_array: &ChunkedArray<BooleanType>,
+    book: &mut OrderBook<P::Native, Q::Native>,
+    builder: &mut dyn BookOutputBuilder<P::Native, Q::Native>,
+    options: ReplayOptions,
+) -> PolarsResult<()>
+where
+    P: PolarsNumericType,
+    Q: PolarsNumericType,
+    P::Native: BookPrice,
+    Q::Native: BookQty,
+{
+    let has_nulls =
+        is_bid_array.null_count() + price_array.null_count() + qty_array.null_count() > 0;
+    if !has_nulls && !options.checked_qty {
+        return replay_non_null_mutations(price_array, qty_array, is_bid_array, book, builder);
+    }
+    for (row, tuple) in izip!(
+        is_bid_array.into_iter(),
+        price_array.into_iter(),
+        qty_array.into_iter()
+    )
+    .enumerate()
+    {
+        match tuple {
+            (Some(is_bid), Some(price), Some(qty)) if !options.checked_qty => {
+                apply_simple_mutation(book, is_bid, price, qty, row)?
+            }
+            (is_bid, price, qty) => {
+                apply_update(book, (is_bid, price, qty, None, None), row, options)?
+            }
+        }
+        handle_crossed(book, &*builder, tuple.0, row)?;
+        builder.append(book);
+    }
+    Ok(())
+}
+
+/// `replay_simple_mutations` for inputs without nulls, reading the values
+/// of each chunk directly rather than through per-value `Option`s. The
+/// columns' chunks needn't line up, so nothing is rechunked.
+fn replay_non_null_mutations<P, Q>(
+    price_array: &ChunkedArray<P>,
+    qty_array: &ChunkedArray<Q>,
+    is_bid_array: &ChunkedArray<BooleanType>,
+    book: &mut OrderBook<P::Native, Q::Native>,
+    builder: &mut dyn BookOutputBuilder<P::Native, Q::Native>,
+) -> PolarsResult<()>
+where
+    P: PolarsNumericType,
+    Q: PolarsNumericType,
+    P::Native: BookPrice,
+    Q::Native: BookQty,
+{
+    let is_bid_values = is_bid_array
+        .downcast_iter()
+        .flat_map(|chunk| chunk.values().iter());
+    for (row, (is_bid, price, qty)) in izip!(
+        is_bid_values,
+        chunk_values(price_array),
+        chunk_values(qty_array)
+    )
+    .enumerate()
+    {
+        apply_simple_mutation(book, is_bid, price, qty, row)?;
+        handle_crossed(book, &*builder, Some(is_bid), row)?;
+        builder.append(book);
+    }
+    Ok(())
+}
+
+/// Every value of `array`, chunk by chunk, ignoring validity.
+fn chunk_values<T: PolarsNumericType>(
+    array: &ChunkedArray<T>,
+) -> impl Iterator<Item = T::Native> + '_ {
+    array
+        .downcast_iter()
+        .flat_map(|chunk| chunk.values().iter().copied())
+}
+
+/// Replay price-point mutations which may include modifies,
+/// i.e. a delete and an add operation in a single row.
+#[allow(clippy::too_many_arguments)]
+fn replay_with_modifies<P, Q>(
+    price_array: &ChunkedArray<P>,
+    qty_array: &ChunkedArray<Q>,
+    is_bid_array: &ChunkedArray<BooleanType>,
+    prev_price_array: &ChunkedArray<P>,
+    prev_qty_array: &ChunkedArray<Q>,
+    book: &mut OrderBook<P::Native, Q::Native>,
+    builder: &mut dyn BookOutputBuilder<P::Native, Q::Native>,
+    options: ReplayOptions,
+) -> PolarsResult<()>
+where
+    P: PolarsNumericType,
+    Q: PolarsNumericType,
+    P::Native: BookPrice,
+    Q::Native: BookQty,
+{
+    for (row, tuple) in izip!(
+        is_bid_array.into_iter(),
+        price_array.into_iter(),
+        qty_array.into_iter(),
+        prev_price_array.into_iter(),
+        prev_qty_array.into_iter()
+    )
+    .enumerate()
+    {
+        apply_update(book, tuple, row, options)?;
+        handle_crossed(book, &*builder, tuple.0, row)?;
+        builder.append(book);
+    }
+    Ok(())
+}
+
+/// Replay updates like `replay_updates`, but skip those that can't be
+/// applied instead of panicking. Also returns an `error` Series describing
+/// why each skipped row was skipped, null for the rows that were applied.
+fn replay_updates_tolerant<B: BookOutputBuilder>(
+    inputs: &[Series],
+    mut book: OrderBook<i64, i64>,
+    mut builder: B,
+    options: ReplayOptions,
+) -> PolarsResult<(Series, Series)> {
+    polars_ensure!(
+        inputs.len() == 3 || inputs.len() == 5,
+        ComputeError: "Expected 3 or 5 input columns: price, qty, is_bid, (prev_price, prev_qty) but got {}", inputs.len()
+    );
+    let price = inputs[0].i64()?;
+    let qty = inputs[1].i64()?;
+    let is_bid = inputs[2].bool()?;
+    let no_prev = Int64Chunked::full_null("", price.len());
+    let prev_price = inputs
+        .get(3)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+    let prev_qty = inputs
+        .get(4)
+        .map(|s| s.i64())
+        .transpose()?
+        .unwrap_or(&no_prev);
+
+    let mut errors = Vec::with_capacity(price.len());
+    for (row, tuple) in izip!(
+        is_bid.into_iter(),
+        price.into_iter(),
+        qty.into_iter(),
+        prev_price.into_iter(),
+        prev_qty.into_iter()
+    )
+    .enumerate()
+    {
+        match try_apply_update(&mut book, tuple, options) {
+            Ok(()) => {
+                handle_crossed(&mut book, &builder, tuple.0, row)?;
+                errors.push(None);
+            }
+            Err(e) => errors.push(Some(e)),
+        }
+        builder.append(&book);
+    }
+    Ok((
+        builder.finish_with_book(&book)?,
+        Series::new("error", errors),
+    ))
+}
+
+/// Like `apply_update`, but returns why the update can't be applied instead
+/// of panicking, in which case the book is left unchanged.
+fn try_apply_update<Price: BookPrice, Qty: BookQty>(
+    book: &mut OrderBook<Price, Qty>,
+    tuple: UpdateTuple<Price, Qty>,
+    options: ReplayOptions,
+) -> Result<(), String> {
+    let (Some(is_bid), Some(price), Some(qty), prev_price, prev_qty) = tuple else {
+        return try_apply_null_update(book, tuple, options.null_policy);
+    };
+    let checked = options.checked_qty;
+    match (prev_price, prev_qty) {
+        (None, prev_qty) => {
+            let delta = sub_qty(qty, prev_qty.unwrap_or(Qty::zero()), checked)?;
+            if delta < Qty::zero() {
+                book.try_delete_qty(is_bid, price, sub_qty(Qty::zero(), delta, checked)?)
+                    .map_err(|e| e.to_string())?;
+            } else if delta > Qty::zero() {
+                add_qty(book, is_bid, price, delta, checked)?;
+            }
+        }
+        (Some(prev_price), Some(prev_qty)) => {
+            book.try_delete_qty(is_bid, prev_price, prev_qty)
+                .map_err(|e| e.to_string())?;
+            if let Err(e) = add_qty(book, is_bid, price, qty, checked) {
+                // Put back the qty the delete took, which fitted before.
+                book.add_qty(is_bid, prev_price, prev_qty);
+                return Err(e);
+            }
+        }
+        (Some(_), None) => return Err("prev_price given without prev_qty".to_string()),
+    }
+    Ok(())
+}
+
+/// `a - b`, failing on overflow rather than wrapping around if `checked`.
+fn sub_qty<Qty: BookQty>(a: Qty, b: Qty, checked: bool) -> Result<Qty, String> {
+    if !checked {
+        return Ok(a - b);
+    }
+    a.checked_sub(&b)
+        .ok_or_else(|| format!("Qty overflow computing {} - {}", a, b))
+}
+
+/// Add `qty` at the price level, failing if the level's qty would overflow
+/// and `checked`.
+fn add_qty<Price: BookPrice, Qty: BookQty>(
+    book: &mut OrderBook<Price, Qty>,
+    is_bid: bool,
+    price: Price,
+    qty: Qty,
+    checked: bool,
+) -> Result<(), String> {
+    if checked {
+        book.checked_add_qty(is_bid, price, qty)
+            .map_err(|e| e.to_string())
+    } else {
+        book.add_qty(is_bid, price, qty);
+        Ok(())
+    }
+}
+
+/// Apply an update with a null price, qty or is_bid as `null_policy` says.
+fn try_apply_null_update<Price: BookPrice, Qty: BookQty>(
+    book: &mut OrderBook<Price, Qty>,
+    tuple: UpdateTuple<Price, Qty>,
+    null_policy: NullPolicy,
+) -> Result<(), String> {
+    match (null_policy, tuple) {
+        (NullPolicy::Raise, _) => Err("price, qty and is_bid must not be null".to_string()),
+        (NullPolicy::DeleteLevel, (Some(is_bid), Some(price), None, _, _)) => book
+            .delete_level(is_bid, price)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+type UpdateTuple<Price, Qty> = (
+    Option<bool>,
+    Option<Price>,
+    Option<Qty>,
+    Option<Price>,
+    Option<Qty>,
+);
+
+/// The update that undoes `tuple`: a signed qty change is negated, a new
+/// qty and its previous qty swap, and a modify moves back to its previous
+/// price and qty. Updates with a null price, qty or is_bid, and invalid
+/// ones, are returned as they are so that applying them fails or is
+/// skipped as it would have been going forwards.
+fn invert_update<Price: BookPrice, Qty: BookQty>(
+    tuple: UpdateTuple<Price, Qty>,
+) -> UpdateTuple<Price, Qty> {
+    let (is_bid @ Some(_), Some(price), Some(qty), prev_price, prev_qty) = tuple else {
+        return tuple;
+    };
+    match (prev_price, prev_qty) {
+        (None, None) => (is_bid, Some(price), Some(-qty), None, None),
+        (None, Some(prev_qty)) => (is_bid, Some(price), Some(prev_qty), None, Some(qty)),
+        (Some(prev_price), Some(prev_qty)) => (
+            is_bid,
+            Some(prev_price),
+            Some(prev_qty),
+            Some(price),
+            Some(qty),
+        ),
+        (Some(_), None) => tuple,
+    }
+}
+
+/// Apply one `(is_bid, price, qty, prev_price, prev_qty)` update, which is a
+/// modify if it has a previous price and qty, failing with the update and its
+/// `row` if it can't be applied.
+fn apply_update<Price: BookPrice, Qty: BookQty>(
+    book: &mut OrderBook<Price, Qty>,
+    tuple: UpdateTuple<Price, Qty>,
+    row: usize,
+    options: ReplayOptions,
+) -> PolarsResult<()> {
+    try_apply_update(book, tuple, options).map_err(|e| {
+        polars_err!(
+            ComputeError: "{} in row {}: (is_bid, price, qty, prev_price, prev_qty) = {:?}", e, row, tuple
+        )
+    })
+}
+
+/// Replay price-point add and delete mutations into one book per symbol.
+/// The books are independent, so each symbol's rows are replayed on the
+/// rayon thread pool into an output from `new_builder`, and the outputs are
+/// interleaved back into the original row order.
+fn replay_by_symbol<B: BookOutputBuilder>(
+    price_array: &ChunkedArray<Int64Type>,
+    qty_array: &ChunkedArray<Int64Type>,
+    is_bid_array: &ChunkedArray<BooleanType>,
+    symbol_array: &StringChunked,
+    over_delete_policy: OverDeletePolicy,
+    new_builder: impl Fn(usize) -> PolarsResult<B> + Sync,
+) -> PolarsResult<Series> {
+    let mut rows_by_symbol: HashMap<&str, Vec<IdxSize>> = HashMap::new();
+    for (row, symbol) in symbol_array.into_iter().enumerate() {
+        let symbol = symbol.ok_or_else(
+            || polars_err!(ComputeError: "symbol must not be null, but row {} is null", row),
+        )?;
+        rows_by_symbol
+            .entry(symbol)
+            .or_default()
+            .push(row as IdxSize);
+    }
+
+    let outputs = rows_by_symbol
+        .into_values()
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|rows| {
+            let idx = IdxCa::from_slice("", &rows);
+            let price_array = price_array.take(&idx)?;
+            let qty_array = qty_array.take(&idx)?;
+            let is_bid_array = is_bid_array.take(&idx)?;
+            let mut book = OrderBook::default();
+            book.set_over_delete_policy(over_delete_policy);
+            let mut builder = new_builder(rows.len())?;
+            for (&row, tuple) in rows.iter().zip(izip!(
+                is_bid_array.into_iter(),
+                price_array.into_iter(),
+                qty_array.into_iter()
+            )) {
+                let row = row as usize;
+                if let (Some(is_bid), Some(price), Some(qty)) = tuple {
+                    apply_simple_mutation(&mut book, is_bid, price, qty, row)?;
+                    handle_crossed(&mut book, &builder, Some(is_bid), row)?;
+                    builder.append(&book);
+                } else {
+                    polars_bail!(ComputeError: "Invalid input in row {}: (is_bid, price, qty) = {:?}", row, tuple);
+                }
+            }
+            Ok((rows, builder.finish()?))
+        })
+        .collect::<PolarsResult<Vec<_>>>()?;
+
+    let mut outputs = outputs.into_iter();
+    let Some((mut rows, mut output)) = outputs.next() else {
+        return new_builder(0)?.finish();
+    };
+    for (more_rows, more_output) in outputs {
+        rows.extend(more_rows);
+        output.append(&more_output)?;
+    }
+    // Output row i came from input row rows[i], so gather by the inverse.
+    let mut order = vec![0 as IdxSize; rows.len()];
+    for (i, row) in rows.into_iter().enumerate() {
+        order[row as usize] = i as IdxSize;
+    }
+    output.take(&IdxCa::from_vec("", order))
+}
+
+/// Replay price-point mutations where some rows remove a whole level.
+fn replay_with_level_deletes<B: BookOutputBuilder>(
+    price_array: &ChunkedArray<Int64Type>,
+    qty_array: &ChunkedArray<Int64Type>,
+    is_bid_array: &ChunkedArray<BooleanType>,
+    delete_level_array: &ChunkedArray<BooleanType>,
+    mut book: OrderBook<i64, i64>,
+    mut builder: B,
+) -> PolarsResult<Series> {
+    for (row, tuple) in izip!(
+        is_bid_array.into_iter(),
+        price_array.into_iter(),
+        qty_array.into_iter(),
+        delete_level_array.into_iter()
+    )
+    .enumerate()
+    {
+        match tuple {
+            (Some(is_bid), Some(price), _, Some(true)) => {
+                book.delete_level(is_bid, price).map_err(|e| {
+                    polars_err!(
+                        ComputeError: "{} in row {}: (is_bid, price, qty, delete_level) = {:?}", e, row, tuple
+                    )
+                })?;
+            }
+            (Some(is_bid), Some(price), Some(qty), Some(false) | None) => {
+                apply_simple_mutation(&mut book, is_bid, price, qty, row)?;
+            }
+            _ => polars_bail!(
+                ComputeError: "Invalid input in row {}: (is_bid, price, qty, delete_level) = {:?}", row, tuple
+            ),
+        }
+        handle_crossed(&mut book, &builder, tuple.0, row)?;
+        builder.append(&book);
+    }
+    builder.finish_with_book(&book)
+}
+
+/// Apply the builder's crossed-book policy after the update in `row`, which
+/// touched the `is_bid` side of the book.
+fn handle_crossed<Price: BookPrice, Qty: BookQty, B: BookOutputBuilder<Price, Qty> + ?Sized>(
+    book: &mut OrderBook<Price, Qty>,
+    builder: &B,
+    is_bid: Option<bool>,
+    row: usize,
+) -> PolarsResult<()> {
+    match (builder.crossed_policy(), is_bid) {
+        (CrossedPolicy::Uncross, Some(is_bid)) => {
+            book.uncross(is_bid);
+        }
+        (CrossedPolicy::Raise, _) if book.is_crossed() => polars_bail!(
+            ComputeError: "Book crossed by the update in row {}: (bid, bid_qty, ask, ask_qty) = {:?}",
+            row, book.best_bid_and_ask()
+        ),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Apply a signed qty mutation, failing with the update and its `row` if it
+/// deletes more qty than the level holds.
+fn apply_simple_mutation<Price: BookPrice, Qty: BookQty>(
+    book: &mut OrderBook<Price, Qty>,
+    is_bid: bool,
+    price: Price,
+    qty: Qty,
+    row: usize,
+) -> PolarsResult<()> {
+    book.book_side(is_bid)
+        .apply_qty_delta(price, qty)
+        .map_err(|e| {
+            polars_err!(
+                ComputeError: "{} in row {}: (is_bid, price, qty) = {:?}", e, row, (is_bid, price, qty)
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calculate_bbo_from_simple_mutations() {
+        let mut df = df! {
+            "price" => [1i64, 2, 3, 4, 5, 9, 8, 7, 6],
+            "qty" => [10i64, 20, 30, 40, 50, 90, 80, 70, 60],
+            "is_bid" => [true, true, true, true, true, false, false, false, false],
+        }
+        .unwrap();
+        let inputs = df.get_columns();
+
+        let bbo_struct = _pl_calculate_bbo(inputs, &BboKwargs::default()).unwrap();
+        df = df
+            .with_column(bbo_struct)
+            .expect("Failed to add BBO struct series to DataFrame")
+            .unnest(["bbo"])
+            .expect("Failed to unnest BBO struct series");
+
+        let expected = df! {
+            "price" => [1i64, 2, 3, 4, 5, 9, 8, 7, 6],
+            "qty" => [10i64, 20, 30, 40, 50, 90, 80, 70, 60],
+            "is_bid" => [true, true, true, true, true, false, false, false, false],
+            "best_bid" => [1i64, 2, 3, 4, 5, 5, 5, 5, 5],
+            "best_bid_qty" => [10i64, 20, 30, 40, 50, 50, 50, 50, 50],
+            "best_ask" => [None, None, None, None, None, Some(9i64), Some(8), Some(7), Some(6)],
+            "best_ask_qty" => [None, None, None, None, None, Some(90i64), Some(80), Some(70), Some(60)],
+        }.unwrap();
+        assert_eq!(df, expected);
+    }
+
+    #[test]
+    fn test_calculate_bbo_from_misaligned_chunks() {
+        let df = df! {
+            "price" => [1i64, 2, 3, 9, 8, 7],
+            "qty" => [10i64, 20, 30, 90, 80, 70],
+            "is_bid" => [true, true, true, false, false, false],
+        }
+        .unwrap();
+        let expected = _pl_calculate_bbo(df.get_columns(), &BboKwargs::default()).unwrap();
+
+        // Split each column into differently sized chunks.
+        let chunked = |series: &Series, at: i64| {
+            let mut head = series.slice(0, at as usize);
+            head.append(&series.slice(at, series.len())).unwrap();
+            head
+        };
+        let inputs = [
+            chunked(&df["price"], 1),
+            chunked(&df["qty"], 4),
+            chunked(&df["is_bid"], 2),
+        ];
+        assert_eq!(inputs[1].n_chunks(), 2);
+        let bbo = _pl_calculate_bbo(&inputs, &BboKwargs::default()).unwrap();
+        assert!(bbo.equals_missing(&expected));
+    }
+
+    #[test]
+    fn test_calculate_bbo_with_modifies() {
+        let mut df = df! {
+            "price" => [1i64, 2, 3, 4, 5, 9, 8, 7, 6, 1, 9],
+            "qty" => [10i64, 20, 30, 40, 50, 90, 80, 70, 60, 1, 1],
+            "is_bid" => [true, true, true, true, true, false, false, false, false, true, false],
+            "prev_price" => [None, Some(1i64), Some(2), Some(3), Some(4), None, Some(9), Some(8), Some(7), Some(5), Some(6)],
+            "prev_qty" => [None, Some(10i64), Some(20), Some(30), Some(40), None, Some(90), Some(80), Some(70), Some(50), Some(60)],
+        }
+            .unwrap();
+        let inputs = df.get_columns();
+
+        let bbo_struct = _pl_calculate_bbo(inputs, &BboKwargs::default()).unwrap();
+        df = df
+            .with_column(bbo_struct)
+            .expect("Failed to add BBO struct series to DataFrame")
+            .unnest(["bbo"])
+            .expect("Failed to unnest BBO struct series");
+        let expected = df! {
+            "price" => [1i64, 2, 3, 4, 5, 9, 8, 7, 6, 1, 9],
+            "qty" => [10i64, 20, 30, 40, 50, 90, 80, 70, 60, 1, 1],
+            "is_bid" => [true, true, true, true, true, false, false, false, false, true, false],
+            "prev_price" => [None, Some(1i64), Some(2), Some(3), Some(4), None, Some(9), Some(8), Some(7), Some(5), Some(6)],
+            "prev_qty" => [None, Some(10i64), Some(20), Some(30), Some(40), None, Some(90), Some(80), Some(70), Some(50), Some(60)],
+            "best_bid" => [1i64, 2, 3, 4, 5, 5, 5, 5, 5, 1, 1],
+            "best_bid_qty" => [10i64, 20, 30, 40, 50, 50, 50, 50, 50, 1, 1],
+            "best_ask" => [None, None, None, None, None, Some(9i64), Some(8), Some(7), Some(6), Some(6), Some(9)],
+            "best_ask_qty" => [None, None, None, None, None, Some(90i64), Some(80), Some(70), Some(60), Some(60), Some(1)],
+        }
+            .unwrap();
+        assert_eq!(df, expected);
+    }
+
+    #[test]
+    fn test_calculate_bbo_with_modifies_cyclic() {
+        let mut df = df! {
+            "price" => vec![1i64, 6, 2,3,1, 5,4,6],
+            "qty" => vec![1i64, 6, 2,3,1, 5,4,6],
+            "is_bid" => vec![true, false, true, true, true, false, false, false],
+            "prev_price" => vec![None, None, Some(1i64), Some(2), Some(3), Some(6), Some(5), Some(4)],
+            "prev_qty" => vec![None, None, Some(1i64), Some(2), Some(3), Some(6), Some(5), Some(4)],
+        }.unwrap();
+
+        let inputs = df.get_columns();
+
+        let bbo_struct = _pl_calculate_bbo(inputs, &BboKwargs::default()).unwrap();
+        let df = df
+            .with_column(bbo_struct)
+            .expect("Failed to add BBO struct series to DataFrame")
+            .unnest(["bbo"])
+            .expect("Failed to unnest BBO struct series");
+
+        let expected_values = df! {
+            "price" => vec![1, 6, 2,3,1, 5,4,6],
+            "qty" => vec![1, 6, 2,3,1, 5,4,6],
+            "is_bid" => vec![true, false, true, true, true, false, false, false],
+            "prev_price" => vec![None, None, Some(1), Some(2), Some(3), Some(6), Some(5), Some(4)],
+            "prev_qty" => vec![None, None, Some(1), Some(2), Some(3), Some(6), Some(5), Some(4)],
+            "best_bid" => vec![1, 1, 2, 3, 1, 1, 1, 1],
+            "best_bid_qty" => vec![1, 1, 2, 3, 1, 1, 1, 1],
+            "best_ask" => vec![None, Some(6), Some(6), Some(6), Some(6), Some(5), Some(4), Some(6)],
+            "best_ask_qty" => vec![None, Some(6), Some(6), Some(6), Some(6), Some(5), Some(4), Some(6)],
+        }.unwrap();
+
+        assert_eq!(df, expected_values);
+    }
+
+    #[test]
+    fn test_calculate_bbo_flat_output_style() {
+        let df = df! {
+            "price" => [1i64, 9],
+            "qty" => [10i64, 90],
+            "is_bid" => [true, false],
+        }
+        .unwrap();
+        let kwargs = BboKwargs {
+            output_style: "flat".to_string(),
+            ..BboKwargs::default()
+        };
+
+        let bbo = _pl_calculate_bbo(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "bid_price_1" => [1i64, 1],
+            "bid_qty_1" => [10i64, 10],
+            "ask_price_1" => [None, Some(9i64)],
+            "ask_qty_1" => [None, Some(90i64)],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+    }
+
+    #[test]
+    fn test_calculate_bbo_unknown_output_style() {
+        let df = df! {
+            "price" => [1i64],
+            "qty" => [10i64],
+            "is_bid" => [true],
+        }
+        .unwrap();
+        let kwargs = BboKwargs {
+            output_style: "wide".to_string(),
+            ..BboKwargs::default()
+        };
+        assert!(_pl_calculate_bbo(df.get_columns(), &kwargs).is_err());
+    }
+
+    #[test]
+    fn test_calculate_bbo_signed_delta() {
+        let df = df! {
+            "price" => [5i64, 5, 5, 5, 7, 5, 7],
+            "qty_delta" => [10i64, -4, 0, 6, 0, -12, 3],
+            "is_bid" => [true, true, true, true, false, true, false],
+        }
+        .unwrap();
+
+        let bbo = _pl_calculate_bbo_signed_delta(df.get_columns(), &BboKwargs::default())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [Some(5i64), Some(5), Some(5), Some(5), Some(5), None, None],
+            "best_bid_qty" => [Some(10i64), Some(6), Some(6), Some(12), Some(12), None, None],
+            "best_ask" => [None, None, None, None, None, None, Some(7i64)],
+            "best_ask_qty" => [None, None, None, None, None, None, Some(3i64)],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+    }
+
+    #[test]
+    fn test_book_json() {
+        let df = df! {
+            "price" => [100i64, 101, 102, 103, 99, 101],
+            "qty" => [1i64, 2, 3, 4, 5, -2],
+            "is_bid" => [true, true, false, false, true, true],
+        }
+        .unwrap();
+
+        let json = replay_updates(
+            df.get_columns(),
+            OrderBook::default(),
+            BookJsonBuilder::new(df.height(), 2),
+            ReplayOptions::default(),
+        )
+        .unwrap();
+        let json: Vec<&str> = json.str().unwrap().into_no_null_iter().collect();
+        assert_eq!(
+            json,
+            vec![
+                r#"{"bids":[[100,1]],"asks":[]}"#,
+                r#"{"bids":[[101,2],[100,1]],"asks":[]}"#,
+                r#"{"bids":[[101,2],[100,1]],"asks":[[102,3]]}"#,
+                r#"{"bids":[[101,2],[100,1]],"asks":[[102,3],[103,4]]}"#,
+                r#"{"bids":[[101,2],[100,1]],"asks":[[102,3],[103,4]]}"#,
+                r#"{"bids":[[100,1],[99,5]],"asks":[[102,3],[103,4]]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_calculate_bbo_with_level_deletes() {
+        let df = df! {
+            "price" => [5i64, 4, 4, 5, 4, 9],
+            "qty" => [Some(10i64), Some(20), Some(5), None, None, Some(1)],
+            "is_bid" => [true, true, true, true, true, false],
+            "delete_level" => [false, false, false, true, true, false],
+        }
+        .unwrap();
+
+        let bbo = _pl_calculate_bbo_with_level_deletes(df.get_columns(), &BboKwargs::default())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [Some(5i64), Some(5), Some(5), Some(4), None, None],
+            "best_bid_qty" => [Some(10i64), Some(10), Some(10), Some(25), None, None],
+            "best_ask" => [None, None, None, None, None, Some(9i64)],
+            "best_ask_qty" => [None, None, None, None, None, Some(1i64)],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+    }
+
+    #[test]
+    fn test_calculate_bbo_with_modify_outcome() {
+        let df = df! {
+            "price" => [1i64, 1, 2, 3, 5],
+            "qty" => [10i64, 20, 20, 5, 5],
+            "is_bid" => [true, true, true, true, true],
+            "prev_price" => [None, Some(1i64), Some(1), Some(2), Some(3)],
+            "prev_qty" => [None, Some(10i64), Some(20), Some(20), Some(5)],
+        }
+        .unwrap();
+        let kwargs = BboKwargs {
+            include_modify_outcome: true,
+            ..BboKwargs::default()
+        };
+
+        let bbo = _pl_calculate_bbo(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let outcomes: Vec<Option<&str>> = bbo
+            .column("modify_outcome")
+            .unwrap()
+            .str()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                None,
+                Some("qty_changed"),
+                Some("price_moved"),
+                Some("price_moved_and_resized"),
+                Some("price_moved"),
+            ]
+        );
+        assert!(bbo
+            .column("best_bid")
+            .unwrap()
+            .equals(&Series::new("best_bid", [1i64, 1, 2, 3, 5])));
+    }
+
+    #[test]
+    fn test_calculate_bbo_from_initial_state() {
+        let df = df! {
+            "price" => [101i64, 100, 102, 99],
+            "qty" => [5i64, -1, -3, 7],
+            "is_bid" => [true, true, false, true],
+        }
+        .unwrap();
+        let kwargs = BboKwargs {
+            initial_state: InitialState {
+                initial_bids: vec![(100, 1), (99, 2)],
+                initial_asks: vec![(102, 3), (103, 4)],
+            },
+            ..BboKwargs::default()
+        };
+
+        let bbo = _pl_calculate_bbo(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [101i64, 101, 101, 101],
+            "best_bid_qty" => [5i64, 5, 5, 5],
+            "best_ask" => [102i64, 102, 103, 103],
+            "best_ask_qty" => [3i64, 3, 4, 4],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+
+        let df = df.head(Some(2));
+        let bbo = _pl_calculate_bbo(
+            df.get_columns(),
+            &BboKwargs {
+                initial_state: InitialState {
+                    initial_bids: vec![(102, 1)],
+                    initial_asks: vec![(102, 3)],
+                },
+                ..kwargs
+            },
+        );
+        assert!(bbo.is_err());
+    }
+
+    #[test]
+    fn test_calculate_bbo_by_symbol() {
+        let df = df! {
+            "price" => [100i64, 10, 101, 11, 10, 100],
+            "qty" => [1i64, 2, 3, 4, -2, -1],
+            "is_bid" => [true, true, false, false, true, true],
+            "symbol" => ["A", "B", "A", "B", "B", "A"],
+        }
+        .unwrap();
+
+        let bbo = _pl_calculate_bbo_by_symbol(df.get_columns(), &BboKwargs::default())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [Some(100i64), Some(10), Some(100), Some(10), None, None],
+            "best_bid_qty" => [Some(1i64), Some(2), Some(1), Some(2), None, None],
+            "best_ask" => [None, None, Some(101i64), Some(11), Some(11), Some(101)],
+            "best_ask_qty" => [None, None, Some(3i64), Some(4), Some(4), Some(3)],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+    }
+
+    #[test]
+    fn test_calculate_bbo_order_id() {
+        let df = df! {
+            "order_id" => [1i64, 2, 3, 1, 2, 3],
+            "action" => ["add", "add", "add", "replace", "execute", "cancel"],
+            "price" => [Some(100i64), Some(100), Some(102), Some(99), None, None],
+            "qty" => [Some(5i64), Some(3), Some(4), Some(5), Some(1), None],
+            "is_bid" => [Some(true), Some(true), Some(false), None, None, None],
+        }
+        .unwrap();
+
+        let bbo = _pl_calculate_bbo_order_id(df.get_columns(), &BboKwargs::default())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [100i64, 100, 100, 100, 100, 100],
+            "best_bid_qty" => [5i64, 8, 8, 3, 2, 2],
+            "best_ask" => [None, None, Some(102i64), Some(102), Some(102), None],
+            "best_ask_qty" => [None, None, Some(4i64), Some(4), Some(4), None],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+
+        let df = df! {
+            "order_id" => [1i64],
+            "action" => ["cancel"],
+            "price" => [None::<i64>],
+            "qty" => [None::<i64>],
+            "is_bid" => [None::<bool>],
+        }
+        .unwrap();
+        assert!(_pl_calculate_bbo_order_id(df.get_columns(), &BboKwargs::default()).is_err());
+    }
+
+    #[test]
+    fn test_calculate_bbo_set_level() {
+        let df = df! {
+            "price" => [100i64, 101, 101, 102, 101, 102],
+            "qty" => [5i64, 2, 7, 4, 0, 0],
+            "is_bid" => [true, true, true, false, true, false],
+        }
+        .unwrap();
+
+        let bbo = _pl_calculate_bbo_set_level(df.get_columns(), &BboKwargs::default())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [100i64, 101, 101, 101, 100, 100],
+            "best_bid_qty" => [5i64, 2, 7, 7, 5, 5],
+            "best_ask" => [None, None, None, Some(102i64), Some(102), None],
+            "best_ask_qty" => [None, None, None, Some(4i64), Some(4), None],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+    }
+
+    #[test]
+    fn test_calculate_bbo_set_level_negative_qty() {
+        let df = df! {
+            "price" => [100i64, 101],
+            "qty" => [5i64, -1],
+            "is_bid" => [true, true],
+        }
+        .unwrap();
+        let err = _pl_calculate_bbo_set_level(df.get_columns(), &BboKwargs::default()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Negative qty -1 at price level 101 in row 1"));
+    }
+
+    #[test]
+    fn test_mbo_to_mbp() {
+        let df = df! {
+            "order_id" => [1i64, 2, 1, 2, 1],
+            "action" => ["add", "add", "replace", "execute", "cancel"],
+            "price" => [Some(100i64), Some(102), Some(101), None, None],
+            "qty" => [Some(5i64), Some(3), Some(4), Some(1), None],
+            "is_bid" => [Some(true), Some(false), None, None, None],
+        }
+        .unwrap();
+
+        let mbp = _pl_mbo_to_mbp(df.get_columns())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "price" => [100i64, 102, 101, 102, 101],
+            "qty" => [5i64, 3, 4, -1, -4],
+            "is_bid" => [true, false, true, false, true],
+            "prev_price" => [None, None, Some(100i64), None, None],
+            "prev_qty" => [None, None, Some(5i64), None, None],
+        }
+        .unwrap();
+        assert_eq!(mbp, expected);
+
+        // Replaying the deltas gives the same book as the orders.
+        let bbo = _pl_calculate_bbo(mbp.get_columns(), &BboKwargs::default()).unwrap();
+        let bbo_from_orders =
+            _pl_calculate_bbo_order_id(df.get_columns(), &BboKwargs::default()).unwrap();
+        assert!(bbo.equals_missing(&bbo_from_orders));
+
+        let df = df.slice(2, 3);
+        assert!(_pl_mbo_to_mbp(df.get_columns()).is_err());
+    }
+
+    #[test]
+    fn test_calculate_mid_spread() {
+        let df = df! {
+            "price" => [100i64, 104, 101, 101],
+            "qty" => [1i64, 2, 3, -3],
+            "is_bid" => [true, false, true, true],
+        }
+        .unwrap();
+
+        let mid_spread = _pl_calculate_mid_spread(
+            df.get_columns(),
+            &MidSpreadKwargs {
+                depth_weights: vec![],
+                initial_state: InitialState::default(),
+                options: ReplayOptions::default(),
+            },
+        )
+        .unwrap()
+        .struct_()
+        .unwrap()
+        .clone()
+        .unnest();
+        let expected = df! {
+            "mid" => [None, Some(102.0), Some(102.5), Some(102.0)],
+            "spread" => [None, Some(4.0), Some(3.0), Some(4.0)],
+            "spread_bps" => [None, Some(4.0 / 102.0 * 10_000.0), Some(3.0 / 102.5 * 10_000.0), Some(4.0 / 102.0 * 10_000.0)],
+        }
+        .unwrap();
+        assert_eq!(mid_spread, expected);
+    }
+
+    #[test]
+    fn test_calculate_weighted_mid() {
+        let df = df! {
+            "price" => [100i64, 99, 101, 102],
+            "qty" => [1i64, 4, 3, 2],
+            "is_bid" => [true, true, false, false],
+        }
+        .unwrap();
+
+        let mid_spread = _pl_calculate_mid_spread(
+            df.get_columns(),
+            &MidSpreadKwargs {
+                depth_weights: vec![1.0, 0.5],
+                initial_state: InitialState::default(),
+                options: ReplayOptions::default(),
+            },
+        )
+        .unwrap()
+        .struct_()
+        .unwrap()
+        .clone()
+        .unnest();
+        // Bids: 1 @ 100 and 4 @ 99 weigh 1 + 2 = 3 at an average of 298 / 3.
+        // Asks: 3 @ 101 weigh 3, then with 2 @ 102 weigh 3 + 1 = 4 at an
+        // average of 405 / 4.
+        let bid = 298.0 / 3.0;
+        let ask = 405.0 / 4.0;
+        let expected = Series::new(
+            "weighted_mid",
+            [
+                None,
+                None,
+                Some((bid * 3.0 + 101.0 * 3.0) / 6.0),
+                Some((bid * 4.0 + ask * 3.0) / 7.0),
+            ],
+        );
+        assert_eq!(mid_spread.column("weighted_mid").unwrap(), &expected);
+
+        let kwargs = MidSpreadKwargs {
+            depth_weights: vec![-1.0],
+            initial_state: InitialState::default(),
+            options: ReplayOptions::default(),
+        };
+        assert!(_pl_calculate_mid_spread(df.get_columns(), &kwargs).is_err());
+    }
+
+    #[test]
+    fn test_calculate_queue_position() {
+        let df = df! {
+            "price" => [100i64, 100, 100, 100, 100],
+            "qty" => [10i64, 5, 5, -4, 1],
+            "is_bid" => [true, true, true, true, false],
+            "entered" => [false, true, true, true, true],
+        }
+        .unwrap();
+        let kwargs = QueuePositionKwargs {
+            order_is_bid: true,
+            order_price: 100,
+            entry_row: None,
+            model: QueueModel::Proportional,
+            initial_state: InitialState::default(),
+            options: ReplayOptions::default(),
+        };
+
+        let queue_position = _pl_calculate_queue_position(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "qty_ahead" => [None, Some(15.0), Some(15.0), Some(12.0), Some(0.0)],
+            "fill_probability" => [None, Some(0.0), Some(0.0), Some(1.0 - 12.0 / 15.0), Some(1.0)],
+            "filled" => [None, Some(false), Some(false), Some(false), Some(true)],
+        }
+        .unwrap();
+        assert!(queue_position.equals_missing(&expected));
+
+        let kwargs = QueuePositionKwargs {
+            entry_row: Some(1),
+            ..kwargs
+        };
+        let by_row = _pl_calculate_queue_position(&df.get_columns()[..3], &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        assert!(by_row.equals_missing(&expected));
+    }
+
+    #[test]
+    fn test_match_orders() {
+        let df = df! {
+            "price" => [101i64, 102, 100, 102, 101],
+            "qty" => [2i64, 3, 4, 6, 1],
+            "is_bid" => [false, false, true, true, false],
+        }
+        .unwrap();
+
+        let fills = _pl_match_orders(
+            df.get_columns(),
+            &MatchOrdersKwargs {
+                initial_state: InitialState::default(),
+                options: ReplayOptions::default(),
+            },
+        )
+        .unwrap();
+        let fills: Vec<Vec<(i64, i64)>> = fills
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|row_fills| {
+                let row_fills = row_fills.unwrap();
+                let row_fills = row_fills.struct_().unwrap().fields();
+                let prices = row_fills[0].i64().unwrap().into_no_null_iter();
+                let qtys = row_fills[1].i64().unwrap().into_no_null_iter();
+                prices.zip(qtys).collect()
+            })
+            .collect();
+        assert_eq!(
+            fills,
+            vec![
+                vec![],
+                vec![],
+                vec![],
+                vec![(101, 2), (102, 3)],
+                vec![(102, 1)],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_calculate_nbbo() {
+        let df = df! {
+            "price" => [100i64, 101, 101, 102, 101],
+            "qty" => [5i64, 2, 3, 4, -2],
+            "is_bid" => [true, true, true, false, true],
+            "venue" => ["a", "b", "c", "b", "b"],
+        }
+        .unwrap();
+
+        let nbbo = _pl_calculate_nbbo(
+            df.get_columns(),
+            &NbboKwargs {
+                options: ReplayOptions::default(),
+            },
+        )
+        .unwrap()
+        .struct_()
+        .unwrap()
+        .clone()
+        .unnest();
+        let expected = df! {
+            "best_bid" => [Some(100i64), Some(101), Some(101), Some(101), Some(101)],
+            "best_bid_qty" => [Some(5i64), Some(2), Some(5), Some(5), Some(3)],
+            "best_ask" => [None, None, None, Some(102i64), Some(102)],
+            "best_ask_qty" => [None, None, None, Some(4i64), Some(4)],
+        }
+        .unwrap();
+        assert_eq!(
+            nbbo.select(["best_bid", "best_bid_qty", "best_ask", "best_ask_qty"])
+                .unwrap(),
+            expected
+        );
+        let bid_venues: Vec<Option<Vec<Option<String>>>> = nbbo
+            .column("best_bid_venues")
+            .unwrap()
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|venues| {
+                venues.map(|venues| {
+                    venues
+                        .str()
+                        .unwrap()
+                        .into_iter()
+                        .map(|venue| venue.map(String::from))
+                        .collect()
+                })
+            })
+            .collect();
+        let venues =
+            |names: &[&str]| Some(names.iter().map(|name| Some(name.to_string())).collect());
+        assert_eq!(
+            bid_venues,
+            vec![
+                venues(&["a"]),
+                venues(&["b"]),
+                venues(&["b", "c"]),
+                venues(&["b", "c"]),
+                venues(&["c"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_calculate_implied_bbo() {
+        let df = df! {
+            "price" => [-5i64, 100, 96, 102],
+            "qty" => [4i64, 3, 1, 6],
+            "is_bid" => [true, true, true, false],
+            "contract" => ["spread", "back", "front", "back"],
+        }
+        .unwrap();
+
+        let bbo = _pl_calculate_implied_bbo(
+            df.get_columns(),
+            &ImpliedBboKwargs {
+                options: ReplayOptions::default(),
+            },
+        )
+        .unwrap()
+        .struct_()
+        .unwrap()
+        .clone()
+        .unnest();
+        let expected = df! {
+            "front_bid" => [None, Some(95i64), Some(96), Some(96)],
+            "front_bid_qty" => [None, Some(3i64), Some(1), Some(1)],
+            "front_ask" => [None::<i64>, None, None, None],
+            "front_ask_qty" => [None::<i64>, None, None, None],
+            "back_bid" => [None, Some(100i64), Some(100), Some(100)],
+            "back_bid_qty" => [None, Some(3i64), Some(3), Some(3)],
+            "back_ask" => [None, None, None, Some(102i64)],
+            "back_ask_qty" => [None, None, None, Some(6i64)],
+            "spread_bid" => [Some(-5i64), Some(-5), Some(-5), Some(-5)],
+            "spread_bid_qty" => [Some(4i64), Some(4), Some(4), Some(4)],
+            "spread_ask" => [None::<i64>, None, None, None],
+            "spread_ask_qty" => [None::<i64>, None, None, None],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+    }
+
+    #[test]
+    fn test_calculate_imbalance() {
+        let df = df! {
+            "price" => [100i64, 99, 101, 102, 98, 100],
+            "qty" => [3i64, 5, 2, 4, 7, -3],
+            "is_bid" => [true, true, false, false, true, true],
+        }
+        .unwrap();
+
+        let imbalance = _pl_calculate_imbalance(
+            df.get_columns(),
+            &ImbalanceKwargs {
+                depth: 2,
+                initial_state: InitialState::default(),
+                options: ReplayOptions::default(),
+            },
+        )
+        .unwrap();
+        let expected = Series::new(
+            "imbalance",
+            [1.0, 1.0, 6.0 / 10.0, 2.0 / 14.0, 2.0 / 14.0, 6.0 / 18.0],
+        );
+        assert!(imbalance.equals(&expected));
+
+        let df = df.head(Some(0));
+        let imbalance = _pl_calculate_imbalance(
+            df.get_columns(),
+            &ImbalanceKwargs {
+                depth: 0,
+                initial_state: InitialState::default(),
+                options: ReplayOptions::default(),
+            },
+        );
+        assert!(imbalance.is_err());
+    }
+
+    #[test]
+    fn test_calculate_vwap() {
+        let df = df! {
+            "price" => [100i64, 99, 98, 101, 100],
+            "qty" => [1i64, 3, 5, 2, -1],
+            "is_bid" => [true, true, true, false, true],
+        }
+        .unwrap();
+
+        let vwap = _pl_calculate_vwap(
+            df.get_columns(),
+            &VwapKwargs {
+                depth: 2,
+                initial_state: InitialState::default(),
+                options: ReplayOptions::default(),
+            },
+        )
+        .unwrap()
+        .struct_()
+        .unwrap()
+        .clone()
+        .unnest();
+        let expected = df! {
+            "bid_vwap" => [100.0, 397.0 / 4.0, 397.0 / 4.0, 397.0 / 4.0, 787.0 / 8.0],
+            "ask_vwap" => [None, None, None, Some(101.0), Some(101.0)],
+        }
+        .unwrap();
+        assert_eq!(vwap, expected);
+    }
+
+    #[test]
+    fn test_calculate_sweep_cost() {
+        let df = df! {
+            "price" => [101i64, 102, 100, 99, 101],
+            "qty" => [2i64, 5, 1, 4, -2],
+            "is_bid" => [false, false, true, true, false],
+        }
+        .unwrap();
+
+        let sweep_cost = _pl_calculate_sweep_cost(
+            df.get_columns(),
+            &SweepCostKwargs {
+                size: 3,
+                initial_state: InitialState::default(),
+                options: ReplayOptions::default(),
+            },
+        )
+        .unwrap()
+        .struct_()
+        .unwrap()
+        .clone()
+        .unnest();
+        let expected = df! {
+            "buy_price" => [None, Some(304.0 / 3.0), Some(304.0 / 3.0), Some(304.0 / 3.0), Some(102.0)],
+            "sell_price" => [None, None, None, Some(298.0 / 3.0), Some(298.0 / 3.0)],
+        }
+        .unwrap();
+        assert_eq!(sweep_cost, expected);
+    }
+
+    #[test]
+    fn test_calculate_depth_band() {
+        let df = df! {
+            "price" => [9_990i64, 9_980, 10_010, 10_030, 9_970],
+            "qty" => [1i64, 2, 3, 4, 5],
+            "is_bid" => [true, true, false, false, true],
+        }
+        .unwrap();
+
+        let depth_band = _pl_calculate_depth_band(
+            df.get_columns(),
+            &DepthBandKwargs {
+                bps: 20.0,
+                initial_state: InitialState::default(),
+                options: ReplayOptions::default(),
+            },
+        )
+        .unwrap()
+        .struct_()
+        .unwrap()
+        .clone()
+        .unnest();
+        // The mid is 10_000 from the third update, so the band is
+        // [9_980, 10_020].
+        let expected = df! {
+            "bid_qty" => [None, None, Some(3i64), Some(3), Some(3)],
+            "ask_qty" => [None, None, Some(3i64), Some(3), Some(3)],
+            "bid_notional" => [None, None, Some(29_950.0), Some(29_950.0), Some(29_950.0)],
+            "ask_notional" => [None, None, Some(30_030.0), Some(30_030.0), Some(30_030.0)],
+        }
+        .unwrap();
+        assert_eq!(depth_band, expected);
+    }
+
+    #[test]
+    fn test_calculate_depth_buckets() {
+        let df = df! {
+            "price" => [99i64, 101, 95, 94, 106, 112],
+            "qty" => [1i64, 2, 3, 4, 5, 6],
+            "is_bid" => [true, false, true, true, false, false],
+        }
+        .unwrap();
+
+        let buckets = _pl_calculate_depth_buckets(
+            df.get_columns(),
+            &DepthBucketsKwargs {
+                n_buckets: 2,
+                tick_width: 5,
+                initial_state: InitialState::default(),
+                options: ReplayOptions::default(),
+            },
+        )
+        .unwrap()
+        .struct_()
+        .unwrap()
+        .clone()
+        .unnest();
+        // The mid is 100, so bands are [0, 5) and [5, 10) ticks away from it.
+        let expected = df! {
+            "bid_band_1" => [None, Some(1i64), Some(1), Some(1), Some(1), Some(1)],
+            "bid_band_2" => [None, Some(0i64), Some(3), Some(7), Some(7), Some(7)],
+            "ask_band_1" => [None, Some(2i64), Some(2), Some(2), Some(2), Some(2)],
+            "ask_band_2" => [None, Some(0i64), Some(0), Some(0), Some(5), Some(5)],
+        }
+        .unwrap();
+        assert_eq!(buckets, expected);
+    }
+
+    #[test]
+    fn test_calculate_indicative_uncross() {
+        let df = df! {
+            "price" => [100i64, 101, 103, 100, 102],
+            "qty" => [5i64, 5, 4, 6, 3],
+            "is_bid" => [true, false, true, false, true],
+        }
+        .unwrap();
+
+        let uncross = _pl_calculate_indicative_uncross(
+            df.get_columns(),
+            &IndicativeUncrossKwargs {
+                initial_state: InitialState::default(),
+                options: ReplayOptions::default(),
+            },
+        )
+        .unwrap()
+        .struct_()
+        .unwrap()
+        .clone()
+        .unnest();
+        let expected = df! {
+            "price" => [None, None, Some(101i64), Some(100), Some(101)],
+            "volume" => [None, None, Some(4i64), Some(6), Some(7)],
+            "imbalance" => [None, None, Some(-1i64), Some(3), Some(-4)],
+        }
+        .unwrap();
+        assert_eq!(uncross, expected);
+    }
+
+    #[test]
+    fn test_calculate_bbo_from_actions() {
+        let df = df! {
+            "action" => ["add", "add", "trade", "modify", "delete", "clear", "add"],
+            "price" => [Some(100i64), Some(102), Some(102), Some(101), Some(101), None, Some(99)],
+            "qty" => [Some(5i64), Some(4), Some(1), Some(5), Some(2), None, Some(1)],
+            "is_bid" => [Some(true), Some(false), Some(false), Some(true), Some(true), None, Some(true)],
+            "prev_price" => [None, None, None, Some(100i64), None, None, None],
+            "prev_qty" => [None, None, None, Some(5i64), None, None, None],
+        }
+        .unwrap();
+
+        let bbo = _pl_calculate_bbo_from_actions(df.get_columns(), &BboKwargs::default())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [Some(100i64), Some(100), Some(100), Some(101), Some(101), None, Some(99)],
+            "best_bid_qty" => [Some(5i64), Some(5), Some(5), Some(5), Some(3), None, Some(1)],
+            "best_ask" => [None, Some(102i64), Some(102), Some(102), Some(102), None, None],
+            "best_ask_qty" => [None, Some(4i64), Some(3), Some(3), Some(3), None, None],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+    }
+
+    #[test]
+    fn test_calculate_bbo_from_actions_invalid_add() {
+        let df = df! {
+            "action" => ["add", "add"],
+            "price" => [100i64, 101],
+            "qty" => [5i64, 0],
+            "is_bid" => [true, true],
+        }
+        .unwrap();
+        let err =
+            _pl_calculate_bbo_from_actions(df.get_columns(), &BboKwargs::default()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("add qty must be positive in row 1"));
+
+        let df = df! {
+            "action" => ["add", "add"],
+            "price" => [100i64, 100],
+            "qty" => [i64::MAX, 1],
+            "is_bid" => [true, true],
+        }
+        .unwrap();
+        let kwargs = BboKwargs {
+            options: ReplayOptions {
+                checked_qty: true,
+                ..ReplayOptions::default()
+            },
+            ..BboKwargs::default()
+        };
+        let err = _pl_calculate_bbo_from_actions(df.get_columns(), &kwargs).unwrap_err();
+        assert!(err.to_string().contains("Qty overflow at price level 100"));
+    }
+
+    #[test]
+    fn test_calculate_bbo_from_actions_integer_widths() {
+        let df = df! {
+            "action" => ["add", "add"],
+            "price" => [100i32, 102],
+            "qty" => [5u32, 4],
+            "is_bid" => [1u8, 0],
+        }
+        .unwrap();
+        let bbo = _pl_calculate_bbo_from_actions(df.get_columns(), &BboKwargs::default())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [100i32, 100],
+            "best_bid_qty" => [5u32, 5],
+            "best_ask" => [None, Some(102i32)],
+            "best_ask_qty" => [None, Some(4u32)],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+
+        let df = df! {
+            "order_id" => [1u32, 1],
+            "action" => ["add", "cancel"],
+            "price" => [Some(100i32), None],
+            "qty" => [Some(5u32), None],
+            "is_bid" => [Some(true), None],
+        }
+        .unwrap();
+        let mbp = _pl_mbo_to_mbp(df.get_columns())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "price" => [100i32, 100],
+            "qty" => [5i64, -5],
+            "is_bid" => [true, true],
+            "prev_price" => [None::<i32>, None],
+            "prev_qty" => [None::<i64>, None],
+        }
+        .unwrap();
+        assert_eq!(mbp, expected);
+    }
+
+    #[test]
+    fn test_calculate_bbo_error_reports_row() {
+        let df = df! {
+            "price" => [100i64, 101, 100],
+            "qty" => [5i64, 2, -6],
+            "is_bid" => [true, false, true],
+        }
+        .unwrap();
+        let err = _pl_calculate_bbo(df.get_columns(), &BboKwargs::default()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Qty exceeds available in row 2: (is_bid, price, qty) = (true, 100, -6)"));
+
+        let df = df! {
+            "price" => [100i64, 101],
+            "qty" => [5i64, 2],
+            "is_bid" => [true, true],
+            "prev_price" => [None, Some(99i64)],
+            "prev_qty" => [None, Some(1i64)],
+        }
+        .unwrap();
+        let err = _pl_calculate_bbo(df.get_columns(), &BboKwargs::default()).unwrap_err();
+        assert!(err.to_string().contains("Level not found in row 1"));
+    }
+
+    #[test]
+    fn test_calculate_bbo_with_reset() {
+        let df = df! {
+            "price" => [Some(100i64), Some(101), None, Some(99), Some(98)],
+            "qty" => [Some(5i64), Some(4), None, Some(1), Some(2)],
+            "is_bid" => [Some(true), Some(false), None, Some(true), Some(true)],
+            "reset" => [false, false, true, false, true],
+        }
+        .unwrap();
+
+        let bbo = _pl_calculate_bbo_with_reset(df.get_columns(), &BboKwargs::default())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [Some(100i64), Some(100), None, Some(99), Some(98)],
+            "best_bid_qty" => [Some(5i64), Some(5), None, Some(1), Some(2)],
+            "best_ask" => [None, Some(101i64), None, None, None],
+            "best_ask_qty" => [None, Some(4i64), None, None, None],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+    }
+
+    #[test]
+    fn test_calculate_bbo_with_market_phase() {
+        let market_data = |phases: [&str; 4]| {
+            df! {
+                "price" => [100i64, 101, 100, 99],
+                "qty" => [5i64, 4, -5, 2],
+                "is_bid" => [false, true, false, true],
+                "market_phase" => phases,
+            }
+            .unwrap()
+        };
+        let kwargs = MarketPhaseKwargs {
+            clear_on_halt: true,
+            bbo: BboKwargs {
+                crossed_policy: CrossedPolicy::Raise,
+                ..BboKwargs::default()
+            },
+        };
+
+        let df = market_data(["auction", "auction", "continuous", "halted"]);
+        let bbo = _pl_calculate_bbo_with_market_phase(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [None, Some(101i64), Some(101), Some(99)],
+            "best_bid_qty" => [None, Some(4i64), Some(4), Some(2)],
+            "best_ask" => [Some(100i64), Some(100), None, None],
+            "best_ask_qty" => [Some(5i64), Some(5), None, None],
+            "market_phase" => ["auction", "auction", "continuous", "halted"],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+
+        let df = market_data(["auction", "continuous", "continuous", "halted"]);
+        let err = _pl_calculate_bbo_with_market_phase(df.get_columns(), &kwargs).unwrap_err();
+        assert!(err.to_string().contains("row 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_calculate_bbo_crossed_policy() {
+        let df = df! {
+            "price" => [100i64, 101, 102, 101],
+            "qty" => [1i64, 2, 3, 4],
+            "is_bid" => [true, false, false, true],
+        }
+        .unwrap();
+        let bbo_with_policy = |crossed_policy| {
+            let kwargs = BboKwargs {
+                crossed_policy,
+                ..BboKwargs::default()
+            };
+            _pl_calculate_bbo(df.get_columns(), &kwargs)
+        };
+
+        let bbo = bbo_with_policy(CrossedPolicy::Flag)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let crossed: Vec<Option<bool>> = bbo
+            .column("crossed")
+            .unwrap()
+            .bool()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(
+            crossed,
+            vec![Some(false), Some(false), Some(false), Some(true)]
+        );
+
+        let bbo = bbo_with_policy(CrossedPolicy::Uncross)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [100i64, 100, 100, 101],
+            "best_bid_qty" => [1i64, 1, 1, 4],
+            "best_ask" => [None, Some(101i64), Some(101), Some(102)],
+            "best_ask_qty" => [None, Some(2i64), Some(2), Some(3)],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+
+        let err = bbo_with_policy(CrossedPolicy::Raise).unwrap_err();
+        assert!(err.to_string().contains("row 3"));
+    }
+
+    #[test]
+    fn test_calculate_bbo_over_delete_policy() {
+        let df = df! {
+            "price" => [100i64, 100, 100],
+            "qty" => [5i64, -6, 2],
+            "is_bid" => [true, true, true],
+        }
+        .unwrap();
+        for (policy, expected) in [
+            (OverDeletePolicy::Clamp, [Some(5i64), None, Some(2)]),
+            (OverDeletePolicy::Ignore, [Some(5i64), Some(5), Some(7)]),
+        ] {
+            let kwargs = BboKwargs {
+                over_delete_policy: policy,
+                ..BboKwargs::default()
+            };
+            let bbo = _pl_calculate_bbo(df.get_columns(), &kwargs)
+                .unwrap()
+                .struct_()
+                .unwrap()
+                .clone()
+                .unnest();
+            assert!(bbo
+                .column("best_bid_qty")
+                .unwrap()
+                .equals_missing(&Series::new("best_bid_qty", expected)));
+        }
+        assert!(_pl_calculate_bbo(df.get_columns(), &BboKwargs::default()).is_err());
+    }
+
+    #[test]
+    fn test_calculate_bbo_native_types() {
+        let df = df! {
+            "price" => [100i32, 101, 99, 101],
+            "qty" => [5i32, 2, 3, -2],
+            "is_bid" => [true, true, false, true],
+        }
+        .unwrap();
+        let inputs = df.get_columns();
+        assert_eq!(
+            NativeTypes::of(inputs, &BboKwargs::default()),
+            Some(NativeTypes::Int32)
+        );
+        let bbo = _pl_calculate_bbo(inputs, &BboKwargs::default())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        assert!(bbo
+            .column("best_bid")
+            .unwrap()
+            .equals_missing(&Series::new("best_bid", [100i32, 101, 101, 100])));
+        assert_eq!(
+            bbo.column("best_ask_qty").unwrap().dtype(),
+            &DataType::Int32
+        );
+
+        let prices = Series::new("price", [100u32, 101, 99, 101]);
+        let inputs = [prices, inputs[1].clone(), inputs[2].clone()];
+        assert_eq!(
+            NativeTypes::of(&inputs, &BboKwargs::default()),
+            Some(NativeTypes::UInt32Price)
+        );
+        let bbo = _pl_calculate_bbo(&inputs, &BboKwargs::default())
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        assert!(bbo.column("best_ask").unwrap().equals_missing(&Series::new(
+            "best_ask",
+            [None, None, Some(99u32), Some(99)]
+        )));
+
+        let tolerant = BboKwargs {
+            strict: false,
+            ..BboKwargs::default()
+        };
+        assert_eq!(NativeTypes::of(&inputs, &tolerant), None);
+    }
+
+    #[test]
+    fn test_calculate_bbo_null_policy() {
+        let df = df! {
+            "price" => [Some(100i64), Some(101), None, Some(101)],
+            "qty" => [Some(5i64), Some(2), Some(1), None],
+            "is_bid" => [true, true, true, true],
+        }
+        .unwrap();
+        for (policy, expected) in [
+            (NullPolicy::Skip, [100i64, 101, 101, 101]),
+            (NullPolicy::DeleteLevel, [100i64, 101, 101, 100]),
+        ] {
+            let kwargs = BboKwargs {
+                options: ReplayOptions {
+                    null_policy: policy,
+                    ..ReplayOptions::default()
+                },
+                ..BboKwargs::default()
+            };
+            let bbo = _pl_calculate_bbo(df.get_columns(), &kwargs)
+                .unwrap()
+                .struct_()
+                .unwrap()
+                .clone()
+                .unnest();
+            assert!(bbo
+                .column("best_bid")
+                .unwrap()
+                .equals_missing(&Series::new("best_bid", expected)));
+        }
+        let err = _pl_calculate_bbo(df.get_columns(), &BboKwargs::default()).unwrap_err();
+        assert!(err.to_string().contains("must not be null in row 2"));
+    }
+
+    #[test]
+    fn test_calculate_bbo_checked_qty() {
+        let df = df! {
+            "price" => [100i32, 100],
+            "qty" => [2_000_000_000i32, 2_000_000_000],
+            "is_bid" => [true, true],
+        }
+        .unwrap();
+        let bbo = _pl_calculate_bbo(df.get_columns(), &BboKwargs::default()).unwrap();
+        assert_eq!(bbo.len(), 2);
+
+        let kwargs = BboKwargs {
+            options: ReplayOptions {
+                checked_qty: true,
+                ..ReplayOptions::default()
+            },
+            ..BboKwargs::default()
+        };
+        let err = _pl_calculate_bbo(df.get_columns(), &kwargs).unwrap_err();
+        assert!(err.to_string().contains("Qty overflow at price level 100"));
+    }
+
+    #[test]
+    fn test_calculate_bbo_tolerant() {
+        let df = df! {
+            "price" => [100i64, 101, 100, 100, 99],
+            "qty" => [5i64, -1, -6, -2, 3],
+            "is_bid" => [true, true, true, true, true],
+        }
+        .unwrap();
+        let kwargs = BboKwargs {
+            strict: false,
+            ..BboKwargs::default()
+        };
+
+        let bbo = _pl_calculate_bbo(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        assert!(bbo
+            .column("best_bid_qty")
+            .unwrap()
+            .equals_missing(&Series::new("best_bid_qty", [5i64, 5, 5, 3, 3])));
+        assert!(bbo.column("error").unwrap().equals_missing(&Series::new(
+            "error",
+            [
+                None,
+                Some("Level not found"),
+                Some("Qty exceeds available"),
+                None,
+                None
+            ]
+        )));
+
+        let df = df! {
+            "price" => [100i64, 101],
+            "qty" => [5i64, 2],
+            "is_bid" => [true, true],
+            "prev_price" => [None, Some(102i64)],
+            "prev_qty" => [None, Some(1i64)],
+        }
+        .unwrap();
+        let bbo = _pl_calculate_bbo(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        assert!(bbo
+            .column("best_bid")
+            .unwrap()
+            .equals_missing(&Series::new("best_bid", [100i64, 100])));
+        assert!(bbo
+            .column("error")
+            .unwrap()
+            .equals_missing(&Series::new("error", [None, Some("Level not found")])));
+    }
+
+    #[test]
+    fn test_calculate_bbo_tolerant_overflowing_modify() {
+        let df = df! {
+            "price" => [100i64, 99, 98, 99],
+            "qty" => [i64::MAX - 10, 5, 20, -5],
+            "is_bid" => [true, true, true, true],
+            "prev_price" => [None, None, Some(99i64), None],
+            "prev_qty" => [None, None, Some(5i64), None],
+        }
+        .unwrap();
+        let kwargs = BboKwargs {
+            strict: false,
+            options: ReplayOptions {
+                checked_qty: true,
+                ..ReplayOptions::default()
+            },
+            ..BboKwargs::default()
+        };
 
-    let bbo_struct = DataType::Struct(vec![
-        Field::new("best_bid", price_field.data_type().clone()),
-        Field::new("best_bid_qty", qty_field.data_type().clone()),
-        Field::new("best_ask", price_field.data_type().clone()),
-        Field::new("best_ask_qty", qty_field.data_type().clone()),
-    ]);
-    Ok(Field::new("bbo", bbo_struct))
-}
+        let bbo = _pl_calculate_bbo(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        // The modify overflows the side's total qty, so it fails and the
+        // level at 99 keeps its qty, which the last row then deletes.
+        assert!(bbo.column("error").unwrap().equals_missing(&Series::new(
+            "error",
+            [None, None, Some("Qty overflow at price level 98"), None]
+        )));
+    }
 
-#[polars_expr(output_type_func = bbo_struct)]
-pub fn pl_calculate_bbo(inputs: &[Series]) -> PolarsResult<Series> {
-    _pl_calculate_bbo(inputs)
-}
+    #[test]
+    fn test_calculate_bbo_sequence_gaps() {
+        let df = df! {
+            "price" => [100i64, 101, 99, 102],
+            "qty" => [1i64, 2, 3, 4],
+            "is_bid" => [true, false, true, false],
+            "seq_num" => [7i64, 8, 10, 11],
+        }
+        .unwrap();
+        let kwargs = BboKwargs {
+            sequence_gap_policy: Some(SequenceGapPolicy::Flag),
+            ..BboKwargs::default()
+        };
 
-fn _pl_calculate_bbo(inputs: &[Series]) -> PolarsResult<Series> {
-    match inputs.len() {
-        3 | 5 => {}
-        _ => {
-            let input_names = inputs
-                .iter()
-                .map(|s| s.name())
-                .collect::<Vec<&str>>()
-                .join(", ");
-            panic!("Expected 3 or 5 input columns: price, qty, is_bid, (prev_price, prev_qty) but got {} columns called:\n    {}", inputs.len(), input_names)
+        let bbo = _pl_calculate_bbo(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let gaps: Vec<Option<bool>> = bbo
+            .column("sequence_gap")
+            .unwrap()
+            .bool()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(
+            gaps,
+            vec![Some(false), Some(false), Some(true), Some(false)]
+        );
+        assert!(bbo.column("best_ask").unwrap().equals_missing(&Series::new(
+            "best_ask",
+            [None, Some(101i64), Some(101), Some(101)]
+        )));
+
+        let kwargs = BboKwargs {
+            sequence_gap_policy: Some(SequenceGapPolicy::Raise),
+            ..BboKwargs::default()
+        };
+        let err = _pl_calculate_bbo(df.get_columns(), &kwargs).unwrap_err();
+        assert!(err.to_string().contains("row 2: expected 9, got 10"));
+    }
+
+    #[test]
+    fn test_calculate_bbo_with_passthrough() {
+        let df = df! {
+            "price" => [100i64, 101],
+            "qty" => [1i64, 2],
+            "is_bid" => [true, false],
+            "ts" => [10i64, 20],
         }
+        .unwrap();
+        let kwargs = BboKwargs {
+            n_passthrough: 1,
+            ..BboKwargs::default()
+        };
+
+        let bbo = with_passthrough(df.get_columns(), kwargs.n_passthrough, |inputs| {
+            _pl_calculate_bbo(inputs, &kwargs)
+        })
+        .unwrap();
+        let fields = bbo.struct_().unwrap().fields();
+        assert_eq!(fields.len(), 5);
+        assert!(fields[4].equals(&Series::new("ts", [10i64, 20])));
     }
 
-    let price = inputs[0].i64()?;
-    let qty = inputs[1].i64()?;
-    let is_bid = inputs[2].bool()?;
-    let prev_price = inputs.get(3);
-    let prev_qty = inputs.get(4);
+    #[test]
+    fn test_calculate_bbo_emit_on_change() {
+        let df = df! {
+            "price" => [100i64, 98, 100, 101],
+            "qty" => [1i64, 2, 1, 3],
+            "is_bid" => [true, true, true, false],
+        }
+        .unwrap();
+        let kwargs = BboKwargs {
+            emit_on_change: true,
+            ..BboKwargs::default()
+        };
 
-    match (prev_price, prev_qty) {
-        (Some(prev_price), Some(prev_qty)) => {
-            let prev_price_chunked = prev_price.i64()?;
-            let prev_qty_chunked = prev_qty.i64()?;
-            calculate_bbo_with_modifies(price, qty, is_bid, prev_price_chunked, prev_qty_chunked)
+        let bbo = _pl_calculate_bbo(df.get_columns(), &kwargs).unwrap();
+        let bbo = DataFrame::new(vec![bbo]).unwrap().unnest(["bbo"]).unwrap();
+        assert!(bbo
+            .column("changed")
+            .unwrap()
+            .equals(&Series::new("changed", [true, false, true, true])));
+        assert!(bbo
+            .column("best_bid_qty")
+            .unwrap()
+            .equals_missing(&Series::new(
+                "best_bid_qty",
+                [Some(1i64), None, Some(2), Some(2)]
+            )));
+    }
+
+    #[test]
+    fn test_calculate_bbo_depth_totals() {
+        let df = df! {
+            "price" => [100i64, 99, 101, 100],
+            "qty" => [1i64, 2, 3, -1],
+            "is_bid" => [true, true, false, true],
         }
-        (None, None) => calculate_bbo_from_simple_mutations(price, qty, is_bid),
-        _ => panic!(
-            "Expected both prev_price and prev_qty or neither, got: {:?} and {:?}",
-            prev_price, prev_qty
-        ),
+        .unwrap();
+        let kwargs = BboKwargs {
+            include_depth_totals: true,
+            ..BboKwargs::default()
+        };
+
+        let bbo = _pl_calculate_bbo(df.get_columns(), &kwargs).unwrap();
+        let bbo = DataFrame::new(vec![bbo]).unwrap().unnest(["bbo"]).unwrap();
+        let expected = df! {
+            "bid_total_qty" => [1i64, 3, 3, 2],
+            "ask_total_qty" => [0i64, 0, 3, 3],
+            "bid_levels" => [1u32, 2, 2, 1],
+            "ask_levels" => [0u32, 0, 1, 1],
+        }
+        .unwrap();
+        assert_eq!(
+            bbo.select(["bid_total_qty", "ask_total_qty", "bid_levels", "ask_levels"])
+                .unwrap(),
+            expected
+        );
     }
-}
 
-/// Calculate the best bid and best ask prices and quantities
-/// using price-point add and delete mutations.
-fn calculate_bbo_from_simple_mutations(
-    price_array: &ChunkedArray<Int64Type>,
-    qty_array: &ChunkedArray<Int64Type>,
-    is_bid_array: &ChunkedArray<BooleanType>,
-) -> PolarsResult<Series> {
-    let length = price_array.len();
-    let mut best_bid_builder: PrimitiveChunkedBuilder<Int64Type> =
-        PrimitiveChunkedBuilder::new("best_bid", length);
-    let mut best_bid_qty_builder: PrimitiveChunkedBuilder<Int64Type> =
-        PrimitiveChunkedBuilder::new("best_bid_qty", length);
-    let mut best_ask_builder: PrimitiveChunkedBuilder<Int64Type> =
-        PrimitiveChunkedBuilder::new("best_ask", length);
-    let mut best_ask_qty_builder: PrimitiveChunkedBuilder<Int64Type> =
-        PrimitiveChunkedBuilder::new("best_ask_qty", length);
-
-    let mut book: OrderBook<i64, i64> = OrderBook::default();
-    for tuple in izip!(
-        is_bid_array.into_iter(),
-        price_array.into_iter(),
-        qty_array.into_iter()
-    ) {
-        if let (Some(is_bid), Some(price), Some(qty)) = tuple {
-            apply_simple_mutation(&mut book, is_bid, price, qty);
+    #[test]
+    fn test_calculate_bbo_asof() {
+        let df = df! {
+            "ts" => [1i64, 2, 2, 5],
+            "price" => [100i64, 101, 99, 102],
+            "qty" => [1i64, 2, 3, 4],
+            "is_bid" => [true, false, true, true],
+        }
+        .unwrap();
+        let sample_ts = Series::new("sample_ts", [0i64, 2, 4, 6]);
+        let mut inputs = df.get_columns().to_vec();
+        inputs.insert(1, sample_ts);
 
-            update_builders_one_side(
-                book.book_side(true),
-                &mut best_bid_builder,
-                &mut best_bid_qty_builder,
-            );
+        let bbo = _pl_calculate_bbo_asof(&inputs, &BboKwargs::default()).unwrap();
+        let bbo = DataFrame::new(vec![bbo]).unwrap().unnest(["bbo"]).unwrap();
+        assert!(bbo.column("best_bid").unwrap().equals_missing(&Series::new(
+            "best_bid",
+            [None, Some(100i64), Some(100), Some(102)]
+        )));
+        assert!(bbo.column("best_ask").unwrap().equals_missing(&Series::new(
+            "best_ask",
+            [None, Some(101i64), Some(101), Some(101)]
+        )));
 
-            update_builders_one_side(
-                book.book_side(false),
-                &mut best_ask_builder,
-                &mut best_ask_qty_builder,
-            );
-        } else {
-            panic!("Invalid input tuple: {:?}", tuple);
+        inputs[1] = Series::new("sample_ts", [3i64, 1]);
+        assert!(_pl_calculate_bbo_asof(&inputs, &BboKwargs::default()).is_err());
+
+        // Replay options apply to modifies as well as to plain updates.
+        let df = df! {
+            "ts" => [1i64, 2],
+            "sample_ts" => [1i64, 2],
+            "price" => [100i64, 101],
+            "qty" => [Some(5i64), None],
+            "is_bid" => [true, true],
+            "prev_price" => [None, Some(100i64)],
+            "prev_qty" => [None, Some(5i64)],
         }
+        .unwrap();
+        assert!(_pl_calculate_bbo_asof(df.get_columns(), &BboKwargs::default()).is_err());
+        let kwargs = BboKwargs {
+            options: ReplayOptions {
+                null_policy: NullPolicy::Skip,
+                ..ReplayOptions::default()
+            },
+            ..BboKwargs::default()
+        };
+        let bbo = _pl_calculate_bbo_asof(df.get_columns(), &kwargs).unwrap();
+        let bbo = DataFrame::new(vec![bbo]).unwrap().unnest(["bbo"]).unwrap();
+        assert!(bbo
+            .column("best_bid")
+            .unwrap()
+            .equals(&Series::new("best_bid", [100i64, 100])));
     }
-    let result = df!(
-        "best_bid"=>best_bid_builder.finish().into_series(),
-        "best_bid_qty"=>best_bid_qty_builder.finish().into_series(),
-        "best_ask"=>best_ask_builder.finish().into_series(),
-        "best_ask_qty"=>best_ask_qty_builder.finish().into_series()
-    )?
-    .into_struct("bbo")
-    .into_series();
-    Ok(result)
-}
 
-/// Calculate the best bid and best ask prices and quantities
-/// using price-point mutations which may include modifies, i.e.
-/// a delete and an add operation in a single row.
-fn calculate_bbo_with_modifies(
-    price_array: &ChunkedArray<Int64Type>,
-    qty_array: &ChunkedArray<Int64Type>,
-    is_bid_array: &ChunkedArray<BooleanType>,
-    prev_price_array: &ChunkedArray<Int64Type>,
-    prev_qty_array: &ChunkedArray<Int64Type>,
-) -> PolarsResult<Series> {
-    let length = price_array.len();
-    let mut best_bid_builder: PrimitiveChunkedBuilder<Int64Type> =
-        PrimitiveChunkedBuilder::new("best_bid", length);
-    let mut best_bid_qty_builder: PrimitiveChunkedBuilder<Int64Type> =
-        PrimitiveChunkedBuilder::new("best_bid_qty", length);
-    let mut best_ask_builder: PrimitiveChunkedBuilder<Int64Type> =
-        PrimitiveChunkedBuilder::new("best_ask", length);
-    let mut best_ask_qty_builder: PrimitiveChunkedBuilder<Int64Type> =
-        PrimitiveChunkedBuilder::new("best_ask_qty", length);
-
-    let mut book: OrderBook<i64, i64> = OrderBook::default();
-    for tuple in izip!(
-        is_bid_array.into_iter(),
-        price_array.into_iter(),
-        qty_array.into_iter(),
-        prev_price_array.into_iter(),
-        prev_qty_array.into_iter()
-    ) {
-        match tuple {
-            (Some(is_bid), Some(price), Some(qty), None, None) => {
-                apply_simple_mutation(&mut book, is_bid, price, qty);
-            }
-            (Some(is_bid), Some(price), Some(qty), Some(prev_price), Some(prev_qty)) => {
-                book.modify_qty(is_bid, prev_price, prev_qty, price, qty)
-            }
-            (Some(is_bid), Some(price), Some(qty), None, Some(prev_qty)) => {
-                apply_simple_mutation(&mut book, is_bid, price, qty - prev_qty);
-            }
-            _ => panic!("Invalid input tuple: {:?}", tuple),
+    #[test]
+    fn test_calculate_bbo_conflated() {
+        let df = df! {
+            "ts" => [1i64, 3, 4, 12, 25, 29],
+            "price" => [100i64, 101, 99, 102, 101, 101],
+            "qty" => [1i64, 2, 3, 4, -2, 5],
+            "is_bid" => [true, false, true, true, false, false],
         }
-        update_builders_one_side(
-            book.book_side(true),
-            &mut best_bid_builder,
-            &mut best_bid_qty_builder,
-        );
+        .unwrap();
+        let kwargs = ConflatedBboKwargs {
+            interval: 10,
+            bbo: BboKwargs::default(),
+        };
 
-        update_builders_one_side(
-            book.book_side(false),
-            &mut best_ask_builder,
-            &mut best_ask_qty_builder,
-        );
+        let bbo = _pl_calculate_bbo_conflated(df.get_columns(), &kwargs).unwrap();
+        let bbo = DataFrame::new(vec![bbo]).unwrap().unnest(["bbo"]).unwrap();
+        let expected = df! {
+            "best_bid" => [100i64, 102, 102],
+            "best_bid_qty" => [1i64, 4, 4],
+            "best_ask" => [101i64, 101, 101],
+            "best_ask_qty" => [2i64, 2, 5],
+            "interval_start" => [0i64, 10, 20],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
     }
-    let result = df!(
-        "best_bid"=>best_bid_builder.finish().into_series(),
-        "best_bid_qty"=>best_bid_qty_builder.finish().into_series(),
-        "best_ask"=>best_ask_builder.finish().into_series(),
-        "best_ask_qty"=>best_ask_qty_builder.finish().into_series()
-    )?
-    .into_struct("bbo")
-    .into_series();
-    Ok(result)
-}
 
-fn apply_simple_mutation(book: &mut OrderBook<i64, i64>, is_bid: bool, price: i64, qty: i64) {
-    if qty > 0 {
-        book.book_side(is_bid).add_qty(price, qty)
-    } else {
-        book.book_side(is_bid)
-            .delete_qty(price, qty.abs())
-            .expect("Invalid delete qty operation - likely deleted more than available qty")
+    #[test]
+    fn test_calculate_bbo_depth_stream() {
+        let df = df! {
+            "price" => [100i64, 100, 99, 101, 98],
+            "qty" => [1i64, 0, 2, 4, 3],
+            "is_bid" => [true, true, true, false, true],
+            "first_update_id" => [5i64, 8, 8, 13, 14],
+            "last_update_id" => [9i64, 12, 12, 13, 14],
+        }
+        .unwrap();
+        let mut kwargs = DepthStreamKwargs {
+            snapshot_update_id: 10,
+            bbo: BboKwargs {
+                initial_state: InitialState {
+                    initial_bids: vec![(100, 5)],
+                    initial_asks: vec![(102, 3)],
+                },
+                ..BboKwargs::default()
+            },
+        };
+
+        let bbo = _pl_calculate_bbo_depth_stream(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [100i64, 99, 99, 99, 99],
+            "best_bid_qty" => [5i64, 2, 2, 2, 2],
+            "best_ask" => [102i64, 102, 102, 101, 101],
+            "best_ask_qty" => [3i64, 3, 3, 4, 4],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
+
+        kwargs.snapshot_update_id = 6;
+        assert!(_pl_calculate_bbo_depth_stream(df.get_columns(), &kwargs).is_err());
+
+        kwargs.snapshot_update_id = 10;
+        let df = df! {
+            "price" => [99i64],
+            "qty" => [-2i64],
+            "is_bid" => [true],
+            "first_update_id" => [11i64],
+            "last_update_id" => [11i64],
+        }
+        .unwrap();
+        let err = _pl_calculate_bbo_depth_stream(df.get_columns(), &kwargs).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Negative qty -2 at price level 99 in row 0"));
     }
-}
 
-fn update_builders_one_side(
-    book_side: &BookSide<i64, i64>,
-    price_builder: &mut PrimitiveChunkedBuilder<Int64Type>,
-    qty_builder: &mut PrimitiveChunkedBuilder<Int64Type>,
-) {
-    price_builder.append_option(book_side.best_price);
-    qty_builder.append_option(book_side.best_price_qty);
-}
+    #[test]
+    fn test_calculate_ofi() {
+        let df = df! {
+            "price" => [100i64, 101, 100, 101, 99],
+            "qty" => [1i64, 2, 3, -2, 5],
+            "is_bid" => [true, false, true, false, true],
+            "ts" => [0i64, 1, 2, 3, 3],
+        }
+        .unwrap();
+        let columns = df.get_columns();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let kwargs = OfiKwargs {
+            window: None,
+            initial_state: InitialState::default(),
+            options: ReplayOptions::default(),
+        };
+        let ofi = _pl_calculate_ofi(&columns[..3], &kwargs).unwrap();
+        assert!(ofi.equals(&Series::new("ofi", [1i64, -2, 3, 2, 0])));
+
+        let kwargs = OfiKwargs {
+            window: Some(2),
+            initial_state: InitialState::default(),
+            options: ReplayOptions::default(),
+        };
+        let ofi = _pl_calculate_ofi(columns, &kwargs).unwrap();
+        assert!(ofi.equals(&Series::new("ofi", [1i64, -1, 1, 5, 5])));
+    }
 
     #[test]
-    fn test_calculate_bbo_from_simple_mutations() {
-        let mut df = df! {
-            "price" => [1i64, 2, 3, 4, 5, 9, 8, 7, 6],
-            "qty" => [10i64, 20, 30, 40, 50, 90, 80, 70, 60],
-            "is_bid" => [true, true, true, true, true, false, false, false, false],
+    fn test_snapshots_to_deltas() {
+        let list = |name: &str, rows: &[&[i64]]| {
+            let rows: Vec<Series> = rows.iter().map(|row| Series::new("", *row)).collect();
+            Series::new(name, rows)
+        };
+        let inputs = [
+            list("bid_prices", &[&[100, 99], &[100, 98], &[101, 100]]),
+            list("bid_qtys", &[&[5, 2], &[4, 1], &[3, 4]]),
+            list("ask_prices", &[&[101], &[101, 102], &[102, 103]]),
+            list("ask_qtys", &[&[3], &[3, 6], &[6, 0]]),
+        ];
+
+        let deltas = _pl_snapshots_to_deltas(&inputs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "snapshot_row" => [0 as IdxSize, 0, 0, 1, 1, 1, 1, 2, 2, 2],
+            "price" => [100i64, 99, 101, 100, 99, 98, 102, 101, 98, 101],
+            "qty" => [5i64, 2, 3, -1, -2, 1, 6, 3, -1, -3],
+            "is_bid" => [true, true, false, true, true, true, false, true, true, false],
         }
         .unwrap();
-        let inputs = df.get_columns();
+        assert_eq!(deltas, expected);
 
-        let bbo_struct = _pl_calculate_bbo(inputs).unwrap();
-        df = df
-            .with_column(bbo_struct)
-            .expect("Failed to add BBO struct series to DataFrame")
-            .unnest(["bbo"])
-            .expect("Failed to unnest BBO struct series");
+        let mut inputs = inputs;
+        inputs[1] = list("bid_qtys", &[&[5], &[4, 1], &[3, 4]]);
+        assert!(_pl_snapshots_to_deltas(&inputs).is_err());
+    }
+
+    #[test]
+    fn test_book_features() {
+        let df = df! {
+            "price" => [100i64, 101, 100, 101, 99],
+            "qty" => [1i64, 2, 3, -2, 5],
+            "is_bid" => [true, false, true, false, true],
+        }
+        .unwrap();
+        let kwargs = BookFeaturesKwargs {
+            features: ["mid", "microprice", "imbalance_1", "depth_totals", "ofi"]
+                .map(String::from)
+                .to_vec(),
+            initial_state: InitialState::default(),
+            options: ReplayOptions::default(),
+        };
 
+        let features = _pl_book_features(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
         let expected = df! {
-            "price" => [1i64, 2, 3, 4, 5, 9, 8, 7, 6],
-            "qty" => [10i64, 20, 30, 40, 50, 90, 80, 70, 60],
-            "is_bid" => [true, true, true, true, true, false, false, false, false],
-            "best_bid" => [1i64, 2, 3, 4, 5, 5, 5, 5, 5],
-            "best_bid_qty" => [10i64, 20, 30, 40, 50, 50, 50, 50, 50],
-            "best_ask" => [None, None, None, None, None, Some(9i64), Some(8), Some(7), Some(6)],
-            "best_ask_qty" => [None, None, None, None, None, Some(90i64), Some(80), Some(70), Some(60)],
-        }.unwrap();
-        assert_eq!(df, expected);
+            "mid" => [None, Some(100.5), Some(100.5), None, None],
+            "microprice" => [
+                None,
+                Some((100.0 * 2.0 + 101.0 * 1.0) / 3.0),
+                Some((100.0 * 2.0 + 101.0 * 4.0) / 6.0),
+                None,
+                None,
+            ],
+            "imbalance_1" => [1.0, -1.0 / 3.0, 2.0 / 6.0, 1.0, 1.0],
+            "bid_total_qty" => [1i64, 1, 4, 4, 9],
+            "ask_total_qty" => [0i64, 2, 2, 0, 0],
+            "ofi" => [1i64, -2, 3, 2, 0],
+        }
+        .unwrap();
+        assert_eq!(features, expected);
+
+        for features in [vec!["mid", "mid"], vec!["imbalance_0"], vec![]] {
+            let kwargs = BookFeaturesKwargs {
+                features: features.into_iter().map(String::from).collect(),
+                initial_state: InitialState::default(),
+                options: ReplayOptions::default(),
+            };
+            assert!(_pl_book_features(df.get_columns(), &kwargs).is_err());
+        }
     }
 
     #[test]
-    fn test_calculate_bbo_with_modifies() {
-        let mut df = df! {
-            "price" => [1i64, 2, 3, 4, 5, 9, 8, 7, 6, 1, 9],
-            "qty" => [10i64, 20, 30, 40, 50, 90, 80, 70, 60, 1, 1],
-            "is_bid" => [true, true, true, true, true, false, false, false, false, true, false],
-            "prev_price" => [None, Some(1i64), Some(2), Some(3), Some(4), None, Some(9), Some(8), Some(7), Some(5), Some(6)],
-            "prev_qty" => [None, Some(10i64), Some(20), Some(30), Some(40), None, Some(90), Some(80), Some(70), Some(50), Some(60)],
+    fn test_imbalance_from_initial_state() {
+        let df = df! {
+            "price" => [101i64, 99],
+            "qty" => [1i64, -2],
+            "is_bid" => [false, true],
+        }
+        .unwrap();
+        let kwargs = ImbalanceKwargs {
+            depth: 2,
+            initial_state: InitialState {
+                initial_bids: vec![(100, 3), (99, 2)],
+                initial_asks: vec![(102, 5)],
+            },
+            options: ReplayOptions::default(),
+        };
+
+        let imbalance = _pl_calculate_imbalance(df.get_columns(), &kwargs).unwrap();
+        let expected = Series::new("imbalance", [-1.0 / 11.0, -3.0 / 9.0]);
+        assert!(imbalance.equals(&expected));
+    }
+
+    #[test]
+    fn test_calculate_top_n() {
+        let df = df! {
+            "price" => [100i64, 99, 101, 102],
+            "qty" => [4i64, 2, 1, 3],
+            "is_bid" => [true, true, false, false],
         }
+        .unwrap();
+        let kwargs = TopNKwargs {
+            n: 2,
+            order_count: false,
+            level_timestamp: false,
+            cumulative_qty: false,
+            initial_state: InitialState::default(),
+            options: ReplayOptions::default(),
+        };
+
+        let top_n = _pl_calculate_top_n(df.get_columns(), &kwargs).unwrap();
+        let top_n = DataFrame::new(vec![top_n])
+            .unwrap()
+            .unnest(["top_n"])
             .unwrap();
-        let inputs = df.get_columns();
+        let expected = df! {
+            "bid_price_1" => [Some(100i64), Some(100), Some(100), Some(100)],
+            "bid_price_2" => [None, Some(99i64), Some(99), Some(99)],
+            "bid_qty_1" => [Some(4i64), Some(4), Some(4), Some(4)],
+            "bid_qty_2" => [None, Some(2i64), Some(2), Some(2)],
+            "ask_price_1" => [None, None, Some(101i64), Some(101)],
+            "ask_price_2" => [None, None, None, Some(102i64)],
+            "ask_qty_1" => [None, None, Some(1i64), Some(1)],
+            "ask_qty_2" => [None, None, None, Some(3i64)],
+        }
+        .unwrap();
+        assert!(top_n.equals_missing(&expected));
+    }
 
-        let bbo_struct = _pl_calculate_bbo(inputs).unwrap();
-        df = df
-            .with_column(bbo_struct)
-            .expect("Failed to add BBO struct series to DataFrame")
-            .unnest(["bbo"])
-            .expect("Failed to unnest BBO struct series");
+    #[test]
+    fn test_calculate_top_n_cumulative_qty() {
+        let df = df! {
+            "price" => [100i64, 99, 101, 98],
+            "qty" => [4i64, 2, 1, 3],
+            "is_bid" => [true, true, false, true],
+        }
+        .unwrap();
+        let kwargs = TopNKwargs {
+            n: 2,
+            order_count: false,
+            level_timestamp: false,
+            cumulative_qty: true,
+            initial_state: InitialState::default(),
+            options: ReplayOptions::default(),
+        };
+
+        let top_n = _pl_calculate_top_n(df.get_columns(), &kwargs).unwrap();
+        let top_n = DataFrame::new(vec![top_n])
+            .unwrap()
+            .unnest(["top_n"])
+            .unwrap();
         let expected = df! {
-            "price" => [1i64, 2, 3, 4, 5, 9, 8, 7, 6, 1, 9],
-            "qty" => [10i64, 20, 30, 40, 50, 90, 80, 70, 60, 1, 1],
-            "is_bid" => [true, true, true, true, true, false, false, false, false, true, false],
-            "prev_price" => [None, Some(1i64), Some(2), Some(3), Some(4), None, Some(9), Some(8), Some(7), Some(5), Some(6)],
-            "prev_qty" => [None, Some(10i64), Some(20), Some(30), Some(40), None, Some(90), Some(80), Some(70), Some(50), Some(60)],
-            "best_bid" => [1i64, 2, 3, 4, 5, 5, 5, 5, 5, 1, 1],
-            "best_bid_qty" => [10i64, 20, 30, 40, 50, 50, 50, 50, 50, 1, 1],
-            "best_ask" => [None, None, None, None, None, Some(9i64), Some(8), Some(7), Some(6), Some(6), Some(9)],
-            "best_ask_qty" => [None, None, None, None, None, Some(90i64), Some(80), Some(70), Some(60), Some(60), Some(1)],
+            "bid_cum_qty_1" => [Some(4i64), Some(4), Some(4), Some(4)],
+            "bid_cum_qty_2" => [None, Some(6i64), Some(6), Some(6)],
+            "ask_cum_qty_1" => [None, None, Some(1i64), Some(1)],
+            "ask_cum_qty_2" => [None::<i64>, None, None, None],
+        }
+        .unwrap();
+        assert!(top_n
+            .select([
+                "bid_cum_qty_1",
+                "bid_cum_qty_2",
+                "ask_cum_qty_1",
+                "ask_cum_qty_2"
+            ])
+            .unwrap()
+            .equals_missing(&expected));
+    }
+
+    #[test]
+    fn test_calculate_top_n_with_order_counts() {
+        let df = df! {
+            "price" => [100i64, 100, 99, 100, 101],
+            "qty" => [4i64, 2, 2, -6, 1],
+            "is_bid" => [true, true, true, true, false],
+            "order_count" => [1i64, 1, 1, -2, 1],
+        }
+        .unwrap();
+        let kwargs = TopNKwargs {
+            n: 2,
+            order_count: true,
+            level_timestamp: false,
+            cumulative_qty: false,
+            initial_state: InitialState {
+                initial_bids: vec![],
+                initial_asks: vec![(102, 3)],
+            },
+            options: ReplayOptions::default(),
+        };
+
+        let top_n = _pl_calculate_top_n(df.get_columns(), &kwargs).unwrap();
+        let top_n = DataFrame::new(vec![top_n])
+            .unwrap()
+            .unnest(["top_n"])
+            .unwrap();
+        let expected = df! {
+            "bid_ct_1" => [Some(1i64), Some(2), Some(2), Some(1), Some(1)],
+            "bid_ct_2" => [None, None, Some(1i64), None, None],
+            "ask_ct_1" => [None, None, None, None, Some(1i64)],
+            "ask_ct_2" => [None::<i64>, None, None, None, None],
+        }
+        .unwrap();
+        assert!(top_n
+            .select(["bid_ct_1", "bid_ct_2", "ask_ct_1", "ask_ct_2"])
+            .unwrap()
+            .equals_missing(&expected));
+        assert_eq!(
+            top_n.column("bid_price_1").unwrap().i64().unwrap().get(3),
+            Some(99)
+        );
+    }
+
+    #[test]
+    fn test_calculate_top_n_with_level_timestamps() {
+        let df = df! {
+            "price" => [100i64, 99, 100, 99, 101],
+            "qty" => [4i64, 2, -1, -2, 1],
+            "is_bid" => [true, true, true, true, false],
+            "ts" => [10i64, 20, 30, 40, 50],
         }
+        .unwrap();
+        let kwargs = TopNKwargs {
+            n: 2,
+            order_count: false,
+            level_timestamp: true,
+            cumulative_qty: false,
+            initial_state: InitialState {
+                initial_bids: vec![],
+                initial_asks: vec![(102, 3)],
+            },
+            options: ReplayOptions::default(),
+        };
+
+        let top_n = _pl_calculate_top_n(df.get_columns(), &kwargs).unwrap();
+        let top_n = DataFrame::new(vec![top_n])
+            .unwrap()
+            .unnest(["top_n"])
             .unwrap();
-        assert_eq!(df, expected);
+        let expected = df! {
+            "bid_ts_1" => [Some(10i64), Some(10), Some(30), Some(30), Some(30)],
+            "bid_ts_2" => [None, Some(20i64), Some(20), None, None],
+            "ask_ts_1" => [None, None, None, None, Some(50i64)],
+            "ask_ts_2" => [None::<i64>, None, None, None, None],
+        }
+        .unwrap();
+        assert!(top_n
+            .select(["bid_ts_1", "bid_ts_2", "ask_ts_1", "ask_ts_2"])
+            .unwrap()
+            .equals_missing(&expected));
     }
 
     #[test]
-    fn test_calculate_bbo_with_modifies_cyclic() {
-        let mut df = df! {
-            "price" => vec![1i64, 6, 2,3,1, 5,4,6],
-            "qty" => vec![1i64, 6, 2,3,1, 5,4,6],
-            "is_bid" => vec![true, false, true, true, true, false, false, false],
-            "prev_price" => vec![None, None, Some(1i64), Some(2), Some(3), Some(6), Some(5), Some(4)],
-            "prev_qty" => vec![None, None, Some(1i64), Some(2), Some(3), Some(6), Some(5), Some(4)],
-        }.unwrap();
+    fn test_final_book() {
+        let df = df! {
+            "price" => [101i64, 99, 100, 103],
+            "qty" => [1i64, -2, 4, 2],
+            "is_bid" => [false, true, true, false],
+        }
+        .unwrap();
+        let kwargs = FinalBookKwargs {
+            initial_state: InitialState {
+                initial_bids: vec![(99, 2)],
+                initial_asks: vec![(102, 5)],
+            },
+            options: ReplayOptions::default(),
+        };
 
-        let inputs = df.get_columns();
+        let final_book = _pl_final_book(df.get_columns(), &kwargs).unwrap();
+        let final_book = DataFrame::new(vec![final_book])
+            .unwrap()
+            .unnest(["final_book"])
+            .unwrap();
+        let expected = df! {
+            "is_bid" => [true, false, false, false],
+            "price" => [100i64, 101, 102, 103],
+            "qty" => [4i64, 1, 5, 2],
+        }
+        .unwrap();
+        assert!(final_book.equals(&expected));
+    }
 
-        let bbo_struct = _pl_calculate_bbo(inputs).unwrap();
-        let df = df
-            .with_column(bbo_struct)
-            .expect("Failed to add BBO struct series to DataFrame")
-            .unnest(["bbo"])
-            .expect("Failed to unnest BBO struct series");
+    #[test]
+    fn test_calculate_bbo_reverse() {
+        let df = df! {
+            "price" => [101i64, 99, 100, 103],
+            "qty" => [1i64, -2, 4, 2],
+            "is_bid" => [false, true, true, false],
+        }
+        .unwrap();
+        let kwargs = ReverseBboKwargs {
+            final_state: InitialState {
+                initial_bids: vec![(100, 4)],
+                initial_asks: vec![(101, 1), (102, 5), (103, 2)],
+            },
+            options: ReplayOptions::default(),
+        };
 
-        let expected_values = df! {
-            "price" => vec![1, 6, 2,3,1, 5,4,6],
-            "qty" => vec![1, 6, 2,3,1, 5,4,6],
-            "is_bid" => vec![true, false, true, true, true, false, false, false],
-            "prev_price" => vec![None, None, Some(1), Some(2), Some(3), Some(6), Some(5), Some(4)],
-            "prev_qty" => vec![None, None, Some(1), Some(2), Some(3), Some(6), Some(5), Some(4)],
-            "best_bid" => vec![1, 1, 2, 3, 1, 1, 1, 1],
-            "best_bid_qty" => vec![1, 1, 2, 3, 1, 1, 1, 1],
-            "best_ask" => vec![None, Some(6), Some(6), Some(6), Some(6), Some(5), Some(4), Some(6)],
-            "best_ask_qty" => vec![None, Some(6), Some(6), Some(6), Some(6), Some(5), Some(4), Some(6)],
-        }.unwrap();
+        let bbo = _pl_calculate_bbo_reverse(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "best_bid" => [Some(99i64), Some(99), None, Some(100)],
+            "best_bid_qty" => [Some(2i64), Some(2), None, Some(4)],
+            "best_ask" => [102i64, 101, 101, 101],
+            "best_ask_qty" => [5i64, 1, 1, 1],
+        }
+        .unwrap();
+        assert_eq!(bbo, expected);
 
-        assert_eq!(df, expected_values);
+        let modify: UpdateTuple<i64, i64> = (Some(true), Some(100), Some(3), Some(99), Some(2));
+        assert_eq!(
+            invert_update(modify),
+            (Some(true), Some(99), Some(2), Some(100), Some(3))
+        );
+        assert_eq!(invert_update(invert_update(modify)), modify);
+    }
+
+    #[test]
+    fn test_validate_book_updates() {
+        let df = df! {
+            "price" => [101i64, 100, 100, 100],
+            "qty" => [-1i64, -6, 1, 1],
+            "is_bid" => [true, true, false, false],
+        }
+        .unwrap();
+        let kwargs = ValidateKwargs {
+            initial_state: InitialState {
+                initial_bids: vec![(100, 5)],
+                initial_asks: vec![],
+            },
+            options: ReplayOptions::default(),
+        };
+
+        let validation = _pl_validate_book_updates(df.get_columns(), &kwargs)
+            .unwrap()
+            .struct_()
+            .unwrap()
+            .clone()
+            .unnest();
+        let expected = df! {
+            "delete_missing_level" => [true, false, false, false],
+            "qty_exceeds_available" => [false, true, false, false],
+            "crossed" => [false, false, true, true],
+            "negative_qty" => [false, false, false, false],
+            "duplicate_add" => [false, false, false, true],
+            "error" => [Some("Level not found"), Some("Qty exceeds available"), None, None],
+        }
+        .unwrap();
+        assert_eq!(validation, expected);
     }
 }
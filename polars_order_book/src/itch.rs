@@ -0,0 +1,59 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use polars::prelude::*;
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3_polars::PyDataFrame;
+
+use order_book::itch::{stock_symbol, ItchMessage, ItchReader};
+use order_book::order_book_with_orders::OrderBookWithOrders;
+
+use crate::output::{bbo_field_names, BboBuilder, BookOutputBuilder, CrossedPolicy};
+
+/// Replay a NASDAQ TotalView-ITCH 5.0 file for one `stock` and return a
+/// DataFrame with the `timestamp` (nanoseconds since midnight) and best bid
+/// and ask after each of its order messages. Prices are in 1/10000 dollars.
+#[pyfunction]
+pub(crate) fn read_itch_bbo(path: &str, stock: &str) -> PyResult<PyDataFrame> {
+    let file = File::open(path).map_err(|e| PyIOError::new_err(format!("{}: {}", path, e)))?;
+    let symbol = stock_symbol(stock);
+
+    let mut book: OrderBookWithOrders<i64, i64, u64> = OrderBookWithOrders::new();
+    let mut stock_locate = None;
+    let mut timestamps = Vec::new();
+    let mut builder: BboBuilder = BboBuilder::new(
+        0,
+        bbo_field_names("struct").unwrap(),
+        CrossedPolicy::Ignore,
+        false,
+    );
+    for message in ItchReader::new(BufReader::new(file)) {
+        let message = message.map_err(|e| PyValueError::new_err(e.to_string()))?;
+        match message {
+            ItchMessage::StockDirectory { stock, .. } | ItchMessage::AddOrder { stock, .. }
+                if stock == symbol =>
+            {
+                stock_locate = message.stock_locate();
+            }
+            _ => {}
+        }
+        if stock_locate.is_none() || message.stock_locate() != stock_locate {
+            continue;
+        }
+        let applied = message
+            .apply(&mut book)
+            .map_err(|e| PyValueError::new_err(format!("{} for message {:?}", e, message)))?;
+        if applied {
+            timestamps.push(message.timestamp().map(|t| t as i64));
+            builder.append(book.book());
+        }
+    }
+
+    let to_py_err = |e: PolarsError| PyValueError::new_err(e.to_string());
+    let bbo = builder.finish().map_err(to_py_err)?;
+    let mut columns = vec![Series::new("timestamp", timestamps)];
+    columns.extend(bbo.struct_().map_err(to_py_err)?.fields().iter().cloned());
+    let df = DataFrame::new(columns).map_err(to_py_err)?;
+    Ok(PyDataFrame(df))
+}
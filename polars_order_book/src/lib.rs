@@ -1,18 +1,44 @@
+mod book;
+mod dbn;
 mod expressions;
+mod itch;
+mod output;
+mod scaling;
 mod utils;
 
-#[cfg(target_os = "linux")]
+#[cfg(all(
+    feature = "jemalloc",
+    not(feature = "mimalloc"),
+    not(target_os = "windows")
+))]
 use jemallocator::Jemalloc;
+#[cfg(feature = "mimalloc")]
+use mimalloc::MiMalloc;
 
 #[global_allocator]
-#[cfg(target_os = "linux")]
+#[cfg(all(
+    feature = "jemalloc",
+    not(feature = "mimalloc"),
+    not(target_os = "windows")
+))]
 static ALLOC: Jemalloc = Jemalloc;
 
-use pyo3::types::PyModule;
-use pyo3::{pymodule, Bound, PyResult, Python};
+#[global_allocator]
+#[cfg(feature = "mimalloc")]
+static ALLOC: MiMalloc = MiMalloc;
+
+use pyo3::types::{PyModule, PyModuleMethods};
+use pyo3::{pymodule, wrap_pyfunction, Bound, PyResult, Python};
+
+use crate::book::PyOrderBook;
+use crate::dbn::read_dbn_bbo;
+use crate::itch::read_itch_bbo;
 
 #[pymodule]
 fn polars_order_book(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add_class::<PyOrderBook>()?;
+    m.add_function(wrap_pyfunction!(read_dbn_bbo, m)?)?;
+    m.add_function(wrap_pyfunction!(read_itch_bbo, m)?)?;
     Ok(())
 }
@@ -0,0 +1,1149 @@
+use std::fmt::{Debug, Display, Write};
+use std::hash::Hash;
+
+use num::{CheckedAdd, CheckedSub, Num, NumCast, Signed};
+use polars::prelude::*;
+use serde::Deserialize;
+
+use order_book::queue_position::{QueueModel, QueuePosition};
+use order_book::{book_side::BookSide, order_book::OrderBook};
+
+/// A physical price type the book can be replayed on, e.g. `i64` or `u32`.
+pub(crate) trait BookPrice: Copy + Debug + Display + Hash + Ord + NumCast {}
+
+impl<T: Copy + Debug + Display + Hash + Ord + NumCast> BookPrice for T {}
+
+/// A physical qty type the book can be replayed on, e.g. `i64` or `i32`.
+/// Qtys are signed because updates carry signed deltas.
+pub(crate) trait BookQty:
+    Copy + Debug + Display + Num + Ord + Signed + NumCast + CheckedAdd + CheckedSub
+{
+}
+
+impl<T: Copy + Debug + Display + Num + Ord + Signed + NumCast + CheckedAdd + CheckedSub> BookQty
+    for T
+{
+}
+
+/// Collects one output row from the state of the book after each update.
+pub(crate) trait BookOutputBuilder<Price = i64, Qty = i64> {
+    fn append(&mut self, book: &OrderBook<Price, Qty>);
+    fn finish(self) -> PolarsResult<Series>
+    where
+        Self: Sized;
+
+    /// Finish given the book as it stands after the last update. Builders that
+    /// report the terminal state rather than a row per update override this.
+    fn finish_with_book(self, _book: &OrderBook<Price, Qty>) -> PolarsResult<Series>
+    where
+        Self: Sized,
+    {
+        self.finish()
+    }
+
+    /// How the replay loop should handle a book left crossed by an update.
+    fn crossed_policy(&self) -> CrossedPolicy {
+        CrossedPolicy::Ignore
+    }
+}
+
+/// What to do when an update leaves the best bid at or through the best ask,
+/// e.g. because deltas arrived out of order.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CrossedPolicy {
+    /// Output the crossed book as-is.
+    #[default]
+    Ignore,
+    /// Output the crossed book with a `crossed` flag field.
+    Flag,
+    /// Remove the levels on the side opposite the update that are at or
+    /// through its best price, treating them as stale.
+    Uncross,
+    /// Fail with the index of the row that crossed the book.
+    Raise,
+}
+
+pub(crate) fn bbo_field_names(output_style: &str) -> PolarsResult<[&'static str; 4]> {
+    match output_style {
+        "struct" => Ok(["best_bid", "best_bid_qty", "best_ask", "best_ask_qty"]),
+        "flat" => Ok(["bid_price_1", "bid_qty_1", "ask_price_1", "ask_qty_1"]),
+        _ => polars_bail!(
+            ComputeError: "Unknown output_style {:?}, expected \"struct\" or \"flat\"", output_style
+        ),
+    }
+}
+
+type Bbo<Price, Qty> = (Option<Price>, Option<Qty>, Option<Price>, Option<Qty>);
+
+/// Accumulates the best bid and ask of the book after each update, as
+/// columns of the book's physical price and qty types `P` and `Q`.
+pub(crate) struct BboBuilder<P: PolarsNumericType = Int64Type, Q: PolarsNumericType = Int64Type> {
+    best_bid: PrimitiveChunkedBuilder<P>,
+    best_bid_qty: PrimitiveChunkedBuilder<Q>,
+    best_ask: PrimitiveChunkedBuilder<P>,
+    best_ask_qty: PrimitiveChunkedBuilder<Q>,
+    crossed_policy: CrossedPolicy,
+    crossed: Option<BooleanChunkedBuilder>,
+    /// The previous row's bbo and the `changed` field, when only emitting
+    /// rows on which the bbo changed.
+    on_change: Option<(Option<Bbo<P::Native, Q::Native>>, BooleanChunkedBuilder)>,
+    depth_totals: Option<DepthTotalsBuilder<Q>>,
+}
+
+/// The total qty and number of levels of each side, from the running totals
+/// kept by `BookSide`.
+struct DepthTotalsBuilder<Q: PolarsNumericType> {
+    bid_total_qty: PrimitiveChunkedBuilder<Q>,
+    ask_total_qty: PrimitiveChunkedBuilder<Q>,
+    bid_levels: PrimitiveChunkedBuilder<UInt32Type>,
+    ask_levels: PrimitiveChunkedBuilder<UInt32Type>,
+}
+
+impl<P: PolarsNumericType, Q: PolarsNumericType> BboBuilder<P, Q> {
+    /// `CrossedPolicy::Flag` adds a `crossed` field after the bbo fields.
+    ///
+    /// With `emit_on_change`, rows where the bbo is the same as on the
+    /// previous row are null in every field, and a `changed` field marks the
+    /// rows that were emitted. Deep-book feeds rarely move the top of book,
+    /// so filtering on `changed` shrinks the output considerably.
+    pub(crate) fn new(
+        length: usize,
+        names: [&str; 4],
+        crossed_policy: CrossedPolicy,
+        emit_on_change: bool,
+    ) -> Self {
+        let [bid_name, bid_qty_name, ask_name, ask_qty_name] = names;
+        BboBuilder {
+            best_bid: PrimitiveChunkedBuilder::new(bid_name, length),
+            best_bid_qty: PrimitiveChunkedBuilder::new(bid_qty_name, length),
+            best_ask: PrimitiveChunkedBuilder::new(ask_name, length),
+            best_ask_qty: PrimitiveChunkedBuilder::new(ask_qty_name, length),
+            crossed_policy,
+            crossed: (crossed_policy == CrossedPolicy::Flag)
+                .then(|| BooleanChunkedBuilder::new("crossed", length)),
+            on_change: emit_on_change
+                .then(|| (None, BooleanChunkedBuilder::new("changed", length))),
+            depth_totals: None,
+        }
+    }
+
+    /// Add `bid_total_qty`, `ask_total_qty`, `bid_levels` and `ask_levels`
+    /// fields after the others. Like the bbo fields, they are null on rows
+    /// suppressed by `emit_on_change`.
+    pub(crate) fn with_depth_totals(mut self, length: usize) -> Self {
+        self.depth_totals = Some(DepthTotalsBuilder {
+            bid_total_qty: PrimitiveChunkedBuilder::new("bid_total_qty", length),
+            ask_total_qty: PrimitiveChunkedBuilder::new("ask_total_qty", length),
+            bid_levels: PrimitiveChunkedBuilder::new("bid_levels", length),
+            ask_levels: PrimitiveChunkedBuilder::new("ask_levels", length),
+        });
+        self
+    }
+
+    /// Record whether `bbo` differs from the previous row's, returning false
+    /// if the row should be suppressed.
+    fn record_change(&mut self, bbo: Bbo<P::Native, Q::Native>) -> bool {
+        match &mut self.on_change {
+            Some((last, changed)) => {
+                let is_changed = *last != Some(bbo);
+                *last = Some(bbo);
+                changed.append_value(is_changed);
+                is_changed
+            }
+            None => true,
+        }
+    }
+}
+
+impl<P, Q> BookOutputBuilder<P::Native, Q::Native> for BboBuilder<P, Q>
+where
+    P: PolarsNumericType,
+    Q: PolarsNumericType,
+    P::Native: BookPrice,
+    Q::Native: BookQty,
+{
+    fn append(&mut self, book: &OrderBook<P::Native, Q::Native>) {
+        let bids = book.get_book_side(true);
+        let asks = book.get_book_side(false);
+        let bbo = (
+            bids.best_price,
+            bids.best_price_qty,
+            asks.best_price,
+            asks.best_price_qty,
+        );
+        if !self.record_change(bbo) {
+            self.best_bid.append_null();
+            self.best_bid_qty.append_null();
+            self.best_ask.append_null();
+            self.best_ask_qty.append_null();
+            if let Some(crossed) = &mut self.crossed {
+                crossed.append_null();
+            }
+            if let Some(totals) = &mut self.depth_totals {
+                totals.bid_total_qty.append_null();
+                totals.ask_total_qty.append_null();
+                totals.bid_levels.append_null();
+                totals.ask_levels.append_null();
+            }
+            return;
+        }
+        update_builders_one_side(bids, &mut self.best_bid, &mut self.best_bid_qty);
+        update_builders_one_side(asks, &mut self.best_ask, &mut self.best_ask_qty);
+        if let Some(crossed) = &mut self.crossed {
+            crossed.append_value(book.is_crossed());
+        }
+        if let Some(totals) = &mut self.depth_totals {
+            totals.bid_total_qty.append_value(bids.total_qty());
+            totals.ask_total_qty.append_value(asks.total_qty());
+            totals.bid_levels.append_value(bids.num_levels() as u32);
+            totals.ask_levels.append_value(asks.num_levels() as u32);
+        }
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        let mut columns = vec![
+            self.best_bid.finish().into_series(),
+            self.best_bid_qty.finish().into_series(),
+            self.best_ask.finish().into_series(),
+            self.best_ask_qty.finish().into_series(),
+        ];
+        if let Some(crossed) = self.crossed {
+            columns.push(crossed.finish().into_series());
+        }
+        if let Some((_, changed)) = self.on_change {
+            columns.push(changed.finish().into_series());
+        }
+        if let Some(totals) = self.depth_totals {
+            columns.extend([
+                totals.bid_total_qty.finish().into_series(),
+                totals.ask_total_qty.finish().into_series(),
+                totals.bid_levels.finish().into_series(),
+                totals.ask_levels.finish().into_series(),
+            ]);
+        }
+        let result = DataFrame::new(columns)?.into_struct("bbo").into_series();
+        Ok(result)
+    }
+
+    fn crossed_policy(&self) -> CrossedPolicy {
+        self.crossed_policy
+    }
+}
+
+/// The fields of `TopNBuilder` beyond prices and qtys.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TopNExtras {
+    /// `bid_cum_qty_*` and `ask_cum_qty_*`, the running sum of the qty from
+    /// the best level outwards, filled in by `append`.
+    pub(crate) cumulative_qty: bool,
+    /// `bid_ct_*` and `ask_ct_*`, filled in by `TopNBuilder::append_counts`.
+    pub(crate) order_count: bool,
+    /// `bid_ts_*` and `ask_ts_*`, filled in by
+    /// `TopNBuilder::append_timestamps`.
+    pub(crate) level_timestamp: bool,
+}
+
+pub(crate) fn top_n_field_names(n: usize, extras: TopNExtras) -> Vec<String> {
+    let cumulative_qtys = extras
+        .cumulative_qty
+        .then_some(["bid_cum_qty", "ask_cum_qty"]);
+    let counts = extras.order_count.then_some(["bid_ct", "ask_ct"]);
+    let timestamps = extras.level_timestamp.then_some(["bid_ts", "ask_ts"]);
+    ["bid_price", "bid_qty", "ask_price", "ask_qty"]
+        .into_iter()
+        .chain(cumulative_qtys.into_iter().flatten())
+        .chain(counts.into_iter().flatten())
+        .chain(timestamps.into_iter().flatten())
+        .flat_map(|prefix| (1..=n).map(move |level| format!("{}_{}", prefix, level)))
+        .collect()
+}
+
+/// Accumulates the best `n` levels of each side after each update as flat
+/// fields named by `top_n_field_names`, so that the struct unnests straight
+/// into wide columns. Levels beyond the depth of a side are null. With
+/// `n == 1` the fields match `calculate_bbo`'s "flat" output style.
+pub(crate) struct TopNBuilder {
+    n: usize,
+    cumulative_qty: bool,
+    /// bid prices, bid qtys, ask prices then ask qtys, `n` builders each,
+    /// followed by bid and ask cumulative qtys, then bid and ask order counts
+    /// and then bid and ask level timestamps if built `with_extras`.
+    columns: Vec<PrimitiveChunkedBuilder<Int64Type>>,
+}
+
+impl TopNBuilder {
+    pub(crate) fn with_extras(length: usize, n: usize, extras: TopNExtras) -> Self {
+        TopNBuilder {
+            n,
+            cumulative_qty: extras.cumulative_qty,
+            columns: top_n_field_names(n, extras)
+                .iter()
+                .map(|name| PrimitiveChunkedBuilder::new(name, length))
+                .collect(),
+        }
+    }
+
+    /// Append the number of orders at each of the best `n` levels of `book`,
+    /// read from `counts`, a parallel book holding order counts as qtys at
+    /// the same prices. Counts are null for levels `counts` doesn't hold.
+    /// Call after `append` for each row.
+    pub(crate) fn append_counts(
+        &mut self,
+        book: &OrderBook<i64, i64>,
+        counts: &OrderBook<i64, i64>,
+    ) {
+        let start = (4 + 2 * self.cumulative_qty as usize) * self.n;
+        let count_columns = self.columns[start..].chunks_mut(self.n);
+        for (is_bid, columns) in [true, false].into_iter().zip(count_columns) {
+            let levels = book.get_book_side(is_bid).top_n_levels(self.n);
+            let counts = counts.get_book_side(is_bid);
+            for (i, column) in columns.iter_mut().enumerate() {
+                let count = levels
+                    .get(i)
+                    .and_then(|level| counts.get_level(level.price))
+                    .map(|level| level.qty);
+                column.append_option(count);
+            }
+        }
+    }
+
+    /// Append when each of the best `n` levels of `book` was last modified,
+    /// which is null for levels without a timestamp. See
+    /// `OrderBook::track_level_timestamps`. Call after `append` and
+    /// `append_counts` for each row.
+    pub(crate) fn append_timestamps(&mut self, book: &OrderBook<i64, i64>) {
+        let start = self.columns.len() - 2 * self.n;
+        let timestamp_columns = self.columns[start..].chunks_mut(self.n);
+        for (is_bid, columns) in [true, false].into_iter().zip(timestamp_columns) {
+            let book_side = book.get_book_side(is_bid);
+            let levels = book_side.top_n_levels(self.n);
+            for (i, column) in columns.iter_mut().enumerate() {
+                let timestamp = levels
+                    .get(i)
+                    .and_then(|level| book_side.level_timestamp(level.price))
+                    .map(|timestamp| timestamp as i64);
+                column.append_option(timestamp);
+            }
+        }
+    }
+}
+
+impl BookOutputBuilder for TopNBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        let n = self.n;
+        let (level_columns, cumulative_columns) = self.columns.split_at_mut(4 * n);
+        // Empty unless built with cumulative qtys.
+        let mut cumulative_columns = cumulative_columns
+            .chunks_mut(n)
+            .take(2 * self.cumulative_qty as usize);
+        for (is_bid, columns) in [true, false]
+            .into_iter()
+            .zip(level_columns.chunks_mut(2 * n))
+        {
+            // Each side's top levels are sorted once per row and fill the
+            // price, qty and cumulative qty builders alike.
+            let levels = book.get_book_side(is_bid).top_n_levels(n);
+            let (price_columns, qty_columns) = columns.split_at_mut(n);
+            let mut cumulative = cumulative_columns.next();
+            let mut cumulative_qty = 0;
+            for (i, (price, qty)) in price_columns.iter_mut().zip(qty_columns).enumerate() {
+                let level = levels.get(i);
+                price.append_option(level.map(|level| level.price));
+                qty.append_option(level.map(|level| level.qty));
+                if let Some(columns) = &mut cumulative {
+                    cumulative_qty += level.map_or(0, |level| level.qty);
+                    columns[i].append_option(level.map(|_| cumulative_qty));
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        let columns = self
+            .columns
+            .into_iter()
+            .map(|column| column.finish().into_series())
+            .collect();
+        let result = DataFrame::new(columns)?.into_struct("top_n").into_series();
+        Ok(result)
+    }
+}
+
+/// Accumulates the mid price, spread and spread in basis points of the mid
+/// after each update. All three are null unless both sides are non-empty.
+pub(crate) struct MidSpreadBuilder {
+    mid: PrimitiveChunkedBuilder<Float64Type>,
+    spread: PrimitiveChunkedBuilder<Float64Type>,
+    spread_bps: PrimitiveChunkedBuilder<Float64Type>,
+    weighted_mid: Option<(Vec<f64>, PrimitiveChunkedBuilder<Float64Type>)>,
+}
+
+impl MidSpreadBuilder {
+    pub(crate) fn new(length: usize) -> Self {
+        MidSpreadBuilder {
+            mid: PrimitiveChunkedBuilder::new("mid", length),
+            spread: PrimitiveChunkedBuilder::new("spread", length),
+            spread_bps: PrimitiveChunkedBuilder::new("spread_bps", length),
+            weighted_mid: None,
+        }
+    }
+
+    /// Add a `weighted_mid` field over the best `weights.len()` levels of
+    /// each side. Each side is summarised by its weighted qty, the sum of
+    /// `weights[k] * qty` of its levels, and the average of their prices by
+    /// that weighted qty. The weighted mid is the average of the two sides'
+    /// prices, each weighted by the opposite side's weighted qty, so it leans
+    /// towards the thinner side. With a single weight it's the microprice.
+    /// Null while either side's weighted qty is zero.
+    pub(crate) fn with_depth_weights(mut self, length: usize, weights: Vec<f64>) -> Self {
+        self.weighted_mid = Some((
+            weights,
+            PrimitiveChunkedBuilder::new("weighted_mid", length),
+        ));
+        self
+    }
+}
+
+/// The weighted qty of the best `weights.len()` levels of a side and their
+/// average price by it. See `MidSpreadBuilder::with_depth_weights`.
+fn weighted_depth(book_side: &BookSide<i64, i64>, weights: &[f64]) -> (f64, f64) {
+    let levels = book_side.top_n_levels(weights.len());
+    let (qty, notional) =
+        levels
+            .iter()
+            .zip(weights)
+            .fold((0.0, 0.0), |(qty, notional), (level, weight)| {
+                let weighted_qty = weight * level.qty as f64;
+                (
+                    qty + weighted_qty,
+                    notional + weighted_qty * level.price as f64,
+                )
+            });
+    (qty, notional / qty)
+}
+
+impl BookOutputBuilder for MidSpreadBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        match book.best_bid_and_ask() {
+            Some((bid, _, ask, _)) => {
+                let mid = (bid + ask) as f64 / 2.0;
+                let spread = (ask - bid) as f64;
+                self.mid.append_value(mid);
+                self.spread.append_value(spread);
+                self.spread_bps.append_value(spread / mid * 10_000.0);
+            }
+            None => {
+                self.mid.append_null();
+                self.spread.append_null();
+                self.spread_bps.append_null();
+            }
+        }
+        if let Some((weights, weighted_mid)) = &mut self.weighted_mid {
+            let (bid_qty, bid) = weighted_depth(book.get_book_side(true), weights);
+            let (ask_qty, ask) = weighted_depth(book.get_book_side(false), weights);
+            if bid_qty > 0.0 && ask_qty > 0.0 {
+                weighted_mid.append_value((bid * ask_qty + ask * bid_qty) / (bid_qty + ask_qty));
+            } else {
+                weighted_mid.append_null();
+            }
+        }
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        let mut columns = vec![
+            self.mid.finish().into_series(),
+            self.spread.finish().into_series(),
+            self.spread_bps.finish().into_series(),
+        ];
+        if let Some((_, weighted_mid)) = self.weighted_mid {
+            columns.push(weighted_mid.finish().into_series());
+        }
+        let result = DataFrame::new(columns)?
+            .into_struct("mid_spread")
+            .into_series();
+        Ok(result)
+    }
+}
+
+/// Accumulates the estimated queue position of a hypothetical order after
+/// each update: the qty ahead of it, a heuristic fill probability and
+/// whether it is filled. The order is entered after the update in
+/// `entry_row`, and all three are null before then. See `QueuePosition`.
+pub(crate) struct QueuePositionBuilder {
+    is_bid: bool,
+    price: i64,
+    model: QueueModel,
+    entry_row: usize,
+    row: usize,
+    position: Option<QueuePosition<i64>>,
+    qty_ahead: PrimitiveChunkedBuilder<Float64Type>,
+    fill_probability: PrimitiveChunkedBuilder<Float64Type>,
+    filled: BooleanChunkedBuilder,
+}
+
+impl QueuePositionBuilder {
+    pub(crate) fn new(
+        length: usize,
+        is_bid: bool,
+        price: i64,
+        model: QueueModel,
+        entry_row: usize,
+    ) -> Self {
+        QueuePositionBuilder {
+            is_bid,
+            price,
+            model,
+            entry_row,
+            row: 0,
+            position: None,
+            qty_ahead: PrimitiveChunkedBuilder::new("qty_ahead", length),
+            fill_probability: PrimitiveChunkedBuilder::new("fill_probability", length),
+            filled: BooleanChunkedBuilder::new("filled", length),
+        }
+    }
+}
+
+impl BookOutputBuilder for QueuePositionBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        match &mut self.position {
+            Some(position) => position.observe(book),
+            None if self.row == self.entry_row => {
+                self.position = Some(QueuePosition::enter(
+                    book,
+                    self.is_bid,
+                    self.price,
+                    self.model,
+                ));
+            }
+            None => {}
+        }
+        self.row += 1;
+        self.qty_ahead
+            .append_option(self.position.as_ref().map(QueuePosition::qty_ahead));
+        self.fill_probability
+            .append_option(self.position.as_ref().map(QueuePosition::fill_probability));
+        self.filled
+            .append_option(self.position.as_ref().map(QueuePosition::is_filled));
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        let result = DataFrame::new(vec![
+            self.qty_ahead.finish().into_series(),
+            self.fill_probability.finish().into_series(),
+            self.filled.finish().into_series(),
+        ])?
+        .into_struct("queue_position")
+        .into_series();
+        Ok(result)
+    }
+}
+
+/// Accumulates the order book imbalance over the best `depth` levels of each
+/// side, `(bid_qty - ask_qty) / (bid_qty + ask_qty)`, after each update. It is
+/// null while the book is empty.
+pub(crate) struct ImbalanceBuilder {
+    depth: usize,
+    imbalance: PrimitiveChunkedBuilder<Float64Type>,
+}
+
+impl ImbalanceBuilder {
+    pub(crate) fn new(length: usize, depth: usize) -> Self {
+        ImbalanceBuilder {
+            depth,
+            imbalance: PrimitiveChunkedBuilder::new("imbalance", length),
+        }
+    }
+}
+
+impl BookOutputBuilder for ImbalanceBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        let total_qty = |is_bid| -> i64 {
+            book.get_book_side(is_bid)
+                .top_n_levels(self.depth)
+                .iter()
+                .map(|level| level.qty)
+                .sum()
+        };
+        let bid_qty = total_qty(true);
+        let ask_qty = total_qty(false);
+        if bid_qty + ask_qty == 0 {
+            self.imbalance.append_null();
+        } else {
+            self.imbalance
+                .append_value((bid_qty - ask_qty) as f64 / (bid_qty + ask_qty) as f64);
+        }
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        Ok(self.imbalance.finish().into_series())
+    }
+}
+
+/// Accumulates the Cont-Kukanov order flow imbalance of each update: the
+/// signed change in depth at the best bid minus that at the best ask.
+///
+/// With `(P, q)` the best price and qty before an update and `(P', q')`
+/// after it, the bid side contributes `q'` if `P' >= P` minus `q` if
+/// `P' <= P`, and the ask side the same with the inequalities reversed,
+/// subtracted. An empty side counts as a price worse than any other with
+/// zero qty.
+pub(crate) struct OfiBuilder {
+    prev_bid: Option<(i64, i64)>,
+    prev_ask: Option<(i64, i64)>,
+    ofi: PrimitiveChunkedBuilder<Int64Type>,
+}
+
+impl OfiBuilder {
+    /// `book` is the state before the first update.
+    pub(crate) fn new(length: usize, book: &OrderBook<i64, i64>) -> Self {
+        OfiBuilder {
+            prev_bid: best_level(book.get_book_side(true)),
+            prev_ask: best_level(book.get_book_side(false)),
+            ofi: PrimitiveChunkedBuilder::new("ofi", length),
+        }
+    }
+}
+
+impl BookOutputBuilder for OfiBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        let bid = best_level(book.get_book_side(true));
+        let ask = best_level(book.get_book_side(false));
+        // Negating prices turns the ask side into a bid side.
+        let negate = |level: Option<(i64, i64)>| level.map(|(price, qty)| (-price, qty));
+        let ofi =
+            depth_change(self.prev_bid, bid) - depth_change(negate(self.prev_ask), negate(ask));
+        self.prev_bid = bid;
+        self.prev_ask = ask;
+        self.ofi.append_value(ofi);
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        Ok(self.ofi.finish().into_series())
+    }
+}
+
+/// The best level of a side as `(price, qty)`, or None if it is empty.
+pub(crate) fn best_level(book_side: &BookSide<i64, i64>) -> Option<(i64, i64)> {
+    book_side.best_price.zip(book_side.best_price_qty)
+}
+
+/// The order flow at the best level of a side where a higher price is better.
+fn depth_change(prev: Option<(i64, i64)>, current: Option<(i64, i64)>) -> i64 {
+    match (prev, current) {
+        (Some((prev_price, prev_qty)), Some((price, qty))) => {
+            let added = if price >= prev_price { qty } else { 0 };
+            let removed = if price <= prev_price { prev_qty } else { 0 };
+            added - removed
+        }
+        (None, Some((_, qty))) => qty,
+        (Some((_, prev_qty)), None) => -prev_qty,
+        (None, None) => 0,
+    }
+}
+
+/// One feature of `BookFeaturesBuilder`, named in the kwargs as the field it
+/// adds, except `depth_totals` which adds two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BookFeature {
+    /// `mid`, the average of the best bid and ask.
+    Mid,
+    /// `spread`, the best ask minus the best bid.
+    Spread,
+    /// `microprice`, the best bid and ask averaged with each weighted by the
+    /// qty at the other.
+    Microprice,
+    /// `imbalance_{depth}`, the imbalance over the best `depth` levels of
+    /// each side. See `ImbalanceBuilder`.
+    Imbalance(usize),
+    /// `bid_total_qty` and `ask_total_qty`, the qty of every level of each
+    /// side.
+    DepthTotals,
+    /// `ofi`, the order flow imbalance of each update. See `OfiBuilder`.
+    Ofi,
+}
+
+impl BookFeature {
+    pub(crate) fn parse(name: &str) -> PolarsResult<Self> {
+        let feature = match name {
+            "mid" => BookFeature::Mid,
+            "spread" => BookFeature::Spread,
+            "microprice" => BookFeature::Microprice,
+            "depth_totals" => BookFeature::DepthTotals,
+            "ofi" => BookFeature::Ofi,
+            _ => match name.strip_prefix("imbalance_").map(str::parse) {
+                Some(Ok(depth)) if depth > 0 => BookFeature::Imbalance(depth),
+                _ => polars_bail!(
+                    ComputeError: "Unknown book feature {:?}, expected \"mid\", \"spread\", \"microprice\", \"imbalance_<depth>\", \"depth_totals\" or \"ofi\"", name
+                ),
+            },
+        };
+        Ok(feature)
+    }
+
+    /// The fields the feature adds to the output.
+    pub(crate) fn fields(&self) -> Vec<Field> {
+        match self {
+            BookFeature::Mid => vec![Field::new("mid", DataType::Float64)],
+            BookFeature::Spread => vec![Field::new("spread", DataType::Float64)],
+            BookFeature::Microprice => vec![Field::new("microprice", DataType::Float64)],
+            BookFeature::Imbalance(depth) => {
+                vec![Field::new(&format!("imbalance_{depth}"), DataType::Float64)]
+            }
+            BookFeature::DepthTotals => vec![
+                Field::new("bid_total_qty", DataType::Int64),
+                Field::new("ask_total_qty", DataType::Int64),
+            ],
+            BookFeature::Ofi => vec![Field::new("ofi", DataType::Int64)],
+        }
+    }
+}
+
+enum FeatureBuilder {
+    Float(BookFeature, PrimitiveChunkedBuilder<Float64Type>),
+    Imbalance(usize, ImbalanceBuilder),
+    DepthTotals(
+        PrimitiveChunkedBuilder<Int64Type>,
+        PrimitiveChunkedBuilder<Int64Type>,
+    ),
+    Ofi(OfiBuilder),
+}
+
+/// Accumulates a chosen set of `BookFeature`s in one replay, with fields in
+/// the order the features are given.
+pub(crate) struct BookFeaturesBuilder {
+    features: Vec<FeatureBuilder>,
+}
+
+impl BookFeaturesBuilder {
+    /// `book` is the state before the first update, needed for the OFI.
+    pub(crate) fn new(length: usize, features: &[BookFeature], book: &OrderBook<i64, i64>) -> Self {
+        let features = features
+            .iter()
+            .map(|&feature| match feature {
+                BookFeature::Mid | BookFeature::Spread | BookFeature::Microprice => {
+                    let name = feature.fields()[0].name().to_string();
+                    FeatureBuilder::Float(feature, PrimitiveChunkedBuilder::new(&name, length))
+                }
+                BookFeature::Imbalance(depth) => {
+                    FeatureBuilder::Imbalance(depth, ImbalanceBuilder::new(length, depth))
+                }
+                BookFeature::DepthTotals => FeatureBuilder::DepthTotals(
+                    PrimitiveChunkedBuilder::new("bid_total_qty", length),
+                    PrimitiveChunkedBuilder::new("ask_total_qty", length),
+                ),
+                BookFeature::Ofi => FeatureBuilder::Ofi(OfiBuilder::new(length, book)),
+            })
+            .collect();
+        BookFeaturesBuilder { features }
+    }
+}
+
+impl BookOutputBuilder for BookFeaturesBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        let bbo = book.best_bid_and_ask();
+        for feature in &mut self.features {
+            match feature {
+                FeatureBuilder::Float(feature, builder) => {
+                    builder.append_option(bbo.map(|(bid, bid_qty, ask, ask_qty)| match feature {
+                        BookFeature::Mid => (bid + ask) as f64 / 2.0,
+                        BookFeature::Spread => (ask - bid) as f64,
+                        // The microprice, the only other float feature.
+                        _ => {
+                            (bid as f64 * ask_qty as f64 + ask as f64 * bid_qty as f64)
+                                / (bid_qty + ask_qty) as f64
+                        }
+                    }))
+                }
+                FeatureBuilder::Imbalance(_, builder) => builder.append(book),
+                FeatureBuilder::DepthTotals(bid_total_qty, ask_total_qty) => {
+                    bid_total_qty.append_value(book.total_qty(true));
+                    ask_total_qty.append_value(book.total_qty(false));
+                }
+                FeatureBuilder::Ofi(builder) => builder.append(book),
+            }
+        }
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        let mut columns = Vec::new();
+        for feature in self.features {
+            match feature {
+                FeatureBuilder::Float(_, builder) => columns.push(builder.finish().into_series()),
+                FeatureBuilder::Imbalance(depth, builder) => {
+                    columns.push(builder.finish()?.with_name(&format!("imbalance_{depth}")))
+                }
+                FeatureBuilder::DepthTotals(bid_total_qty, ask_total_qty) => columns.extend([
+                    bid_total_qty.finish().into_series(),
+                    ask_total_qty.finish().into_series(),
+                ]),
+                FeatureBuilder::Ofi(builder) => columns.push(builder.finish()?),
+            }
+        }
+        let result = DataFrame::new(columns)?
+            .into_struct("book_features")
+            .into_series();
+        Ok(result)
+    }
+}
+
+/// Accumulates the volume-weighted average price of the best `depth` levels
+/// of each side after each update. A side's VWAP is null while it is empty.
+pub(crate) struct VwapBuilder {
+    depth: usize,
+    bid_vwap: PrimitiveChunkedBuilder<Float64Type>,
+    ask_vwap: PrimitiveChunkedBuilder<Float64Type>,
+}
+
+impl VwapBuilder {
+    pub(crate) fn new(length: usize, depth: usize) -> Self {
+        VwapBuilder {
+            depth,
+            bid_vwap: PrimitiveChunkedBuilder::new("bid_vwap", length),
+            ask_vwap: PrimitiveChunkedBuilder::new("ask_vwap", length),
+        }
+    }
+}
+
+impl BookOutputBuilder for VwapBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        self.bid_vwap
+            .append_option(top_n_vwap(book.get_book_side(true), self.depth));
+        self.ask_vwap
+            .append_option(top_n_vwap(book.get_book_side(false), self.depth));
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        let result = DataFrame::new(vec![
+            self.bid_vwap.finish().into_series(),
+            self.ask_vwap.finish().into_series(),
+        ])?
+        .into_struct("vwap")
+        .into_series();
+        Ok(result)
+    }
+}
+
+fn top_n_vwap(book_side: &BookSide<i64, i64>, depth: usize) -> Option<f64> {
+    let (notional, qty) =
+        book_side
+            .top_n_levels(depth)
+            .iter()
+            .fold((0i128, 0i128), |(notional, qty), level| {
+                (
+                    notional + level.price as i128 * level.qty as i128,
+                    qty + level.qty as i128,
+                )
+            });
+    (qty != 0).then(|| notional as f64 / qty as f64)
+}
+
+/// Accumulates the average price at which a market order of `size` would
+/// fill against the book after each update: `buy_price` sweeps the asks and
+/// `sell_price` the bids. A price is null if that side holds less than `size`.
+pub(crate) struct SweepCostBuilder {
+    size: i64,
+    buy_price: PrimitiveChunkedBuilder<Float64Type>,
+    sell_price: PrimitiveChunkedBuilder<Float64Type>,
+}
+
+impl SweepCostBuilder {
+    pub(crate) fn new(length: usize, size: i64) -> Self {
+        SweepCostBuilder {
+            size,
+            buy_price: PrimitiveChunkedBuilder::new("buy_price", length),
+            sell_price: PrimitiveChunkedBuilder::new("sell_price", length),
+        }
+    }
+}
+
+impl BookOutputBuilder for SweepCostBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        self.buy_price
+            .append_option(book.get_book_side(false).fill_price_for_qty(self.size));
+        self.sell_price
+            .append_option(book.get_book_side(true).fill_price_for_qty(self.size));
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        let result = DataFrame::new(vec![
+            self.buy_price.finish().into_series(),
+            self.sell_price.finish().into_series(),
+        ])?
+        .into_struct("sweep_cost")
+        .into_series();
+        Ok(result)
+    }
+}
+
+/// Accumulates the qty and notional resting within `bps` basis points of the
+/// mid on each side after each update. The band includes levels exactly on
+/// its edges. All four are null unless both sides are non-empty.
+pub(crate) struct DepthBandBuilder {
+    bps: f64,
+    bid_qty: PrimitiveChunkedBuilder<Int64Type>,
+    ask_qty: PrimitiveChunkedBuilder<Int64Type>,
+    bid_notional: PrimitiveChunkedBuilder<Float64Type>,
+    ask_notional: PrimitiveChunkedBuilder<Float64Type>,
+}
+
+impl DepthBandBuilder {
+    pub(crate) fn new(length: usize, bps: f64) -> Self {
+        DepthBandBuilder {
+            bps,
+            bid_qty: PrimitiveChunkedBuilder::new("bid_qty", length),
+            ask_qty: PrimitiveChunkedBuilder::new("ask_qty", length),
+            bid_notional: PrimitiveChunkedBuilder::new("bid_notional", length),
+            ask_notional: PrimitiveChunkedBuilder::new("ask_notional", length),
+        }
+    }
+}
+
+impl BookOutputBuilder for DepthBandBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        let Some((bid, _, ask, _)) = book.best_bid_and_ask() else {
+            self.bid_qty.append_null();
+            self.ask_qty.append_null();
+            self.bid_notional.append_null();
+            self.ask_notional.append_null();
+            return;
+        };
+        let mid = (bid + ask) as f64 / 2.0;
+        let half_width = mid * self.bps / 10_000.0;
+        let bids = book.get_book_side(true);
+        let asks = book.get_book_side(false);
+        let lowest_bid = (mid - half_width).ceil() as i64;
+        let highest_ask = (mid + half_width).floor() as i64;
+        self.bid_qty.append_value(bids.qty_better_than(lowest_bid));
+        self.ask_qty.append_value(asks.qty_better_than(highest_ask));
+        self.bid_notional
+            .append_option(bids.notional_better_than(lowest_bid));
+        self.ask_notional
+            .append_option(asks.notional_better_than(highest_ask));
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        let result = DataFrame::new(vec![
+            self.bid_qty.finish().into_series(),
+            self.ask_qty.finish().into_series(),
+            self.bid_notional.finish().into_series(),
+            self.ask_notional.finish().into_series(),
+        ])?
+        .into_struct("depth_band")
+        .into_series();
+        Ok(result)
+    }
+}
+
+pub(crate) fn depth_bucket_field_names(n_buckets: usize) -> Vec<String> {
+    ["bid_band", "ask_band"]
+        .into_iter()
+        .flat_map(|prefix| (1..=n_buckets).map(move |band| format!("{}_{}", prefix, band)))
+        .collect()
+}
+
+/// Accumulates the qty of each side in `n_buckets` bands of `tick_width`
+/// ticks away from the mid after each update, as flat fields named by
+/// `depth_bucket_field_names`. Band `k` holds the levels from `(k - 1) *
+/// tick_width` ticks away from the mid up to but excluding `k * tick_width`;
+/// levels further away are left out. Levels through the mid of a crossed
+/// book count as in the first band. All fields are null unless both sides
+/// are non-empty.
+pub(crate) struct DepthBucketsBuilder {
+    n_buckets: usize,
+    tick_width: i64,
+    /// Bid bands then ask bands, `n_buckets` builders each.
+    columns: Vec<PrimitiveChunkedBuilder<Int64Type>>,
+}
+
+impl DepthBucketsBuilder {
+    pub(crate) fn new(length: usize, n_buckets: usize, tick_width: i64) -> Self {
+        DepthBucketsBuilder {
+            n_buckets,
+            tick_width,
+            columns: depth_bucket_field_names(n_buckets)
+                .iter()
+                .map(|name| PrimitiveChunkedBuilder::new(name, length))
+                .collect(),
+        }
+    }
+}
+
+impl BookOutputBuilder for DepthBucketsBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        let Some((bid, _, ask, _)) = book.best_bid_and_ask() else {
+            self.columns
+                .iter_mut()
+                .for_each(|column| column.append_null());
+            return;
+        };
+        // Distances are in half ticks, so that a mid between two ticks
+        // stays an integer.
+        let twice_mid = bid + ask;
+        let band_width = 2 * self.tick_width;
+        let band_columns = self.columns.chunks_mut(self.n_buckets);
+        for (is_bid, columns) in [true, false].into_iter().zip(band_columns) {
+            let mut band_qtys = vec![0; self.n_buckets];
+            for level in book.get_book_side(is_bid).levels() {
+                let distance = if is_bid {
+                    twice_mid - 2 * level.price
+                } else {
+                    2 * level.price - twice_mid
+                };
+                let band = (distance.max(0) / band_width) as usize;
+                if let Some(qty) = band_qtys.get_mut(band) {
+                    *qty += level.qty;
+                }
+            }
+            for (column, qty) in columns.iter_mut().zip(band_qtys) {
+                column.append_value(qty);
+            }
+        }
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        let columns = self
+            .columns
+            .into_iter()
+            .map(|column| column.finish().into_series())
+            .collect();
+        let result = DataFrame::new(columns)?
+            .into_struct("depth_buckets")
+            .into_series();
+        Ok(result)
+    }
+}
+
+/// Accumulates the indicative auction price, the qty that would match at it
+/// and the imbalance left unmatched, positive on the bid side, after each
+/// update. All three are null while the book isn't crossed. See
+/// `OrderBook::indicative_uncross`.
+pub(crate) struct IndicativeUncrossBuilder {
+    price: PrimitiveChunkedBuilder<Int64Type>,
+    volume: PrimitiveChunkedBuilder<Int64Type>,
+    imbalance: PrimitiveChunkedBuilder<Int64Type>,
+}
+
+impl IndicativeUncrossBuilder {
+    pub(crate) fn new(length: usize) -> Self {
+        IndicativeUncrossBuilder {
+            price: PrimitiveChunkedBuilder::new("price", length),
+            volume: PrimitiveChunkedBuilder::new("volume", length),
+            imbalance: PrimitiveChunkedBuilder::new("imbalance", length),
+        }
+    }
+}
+
+impl BookOutputBuilder for IndicativeUncrossBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        let uncross = book.indicative_uncross();
+        self.price.append_option(uncross.map(|u| u.price));
+        self.volume.append_option(uncross.map(|u| u.volume));
+        self.imbalance.append_option(uncross.map(|u| u.imbalance()));
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        let result = DataFrame::new(vec![
+            self.price.finish().into_series(),
+            self.volume.finish().into_series(),
+            self.imbalance.finish().into_series(),
+        ])?
+        .into_struct("indicative_uncross")
+        .into_series();
+        Ok(result)
+    }
+}
+
+/// Snapshots every level of the book after the last update, one row per
+/// level with bids then asks, each best first. The per-update `append`s are
+/// ignored, so the output is independent of the input length.
+pub(crate) struct FinalBookBuilder;
+
+impl BookOutputBuilder for FinalBookBuilder {
+    fn append(&mut self, _book: &OrderBook<i64, i64>) {}
+
+    fn finish(self) -> PolarsResult<Series> {
+        self.finish_with_book(&OrderBook::default())
+    }
+
+    fn finish_with_book(self, book: &OrderBook<i64, i64>) -> PolarsResult<Series> {
+        let mut is_bid = BooleanChunkedBuilder::new("is_bid", 0);
+        let mut price = PrimitiveChunkedBuilder::<Int64Type>::new("price", 0);
+        let mut qty = PrimitiveChunkedBuilder::<Int64Type>::new("qty", 0);
+        for side in [true, false] {
+            for level in book.get_book_side(side).top_n_levels(usize::MAX) {
+                is_bid.append_value(side);
+                price.append_value(level.price);
+                qty.append_value(level.qty);
+            }
+        }
+        let result = DataFrame::new(vec![
+            is_bid.finish().into_series(),
+            price.finish().into_series(),
+            qty.finish().into_series(),
+        ])?
+        .into_struct("final_book")
+        .into_series();
+        Ok(result)
+    }
+}
+
+fn update_builders_one_side<P: PolarsNumericType, Q: PolarsNumericType>(
+    book_side: &BookSide<P::Native, Q::Native>,
+    price_builder: &mut PrimitiveChunkedBuilder<P>,
+    qty_builder: &mut PrimitiveChunkedBuilder<Q>,
+) {
+    price_builder.append_option(book_side.best_price);
+    qty_builder.append_option(book_side.best_price_qty);
+}
+
+/// Serialises the best `depth` levels of each side to a compact JSON string
+/// per row, e.g. `{"bids":[[101,5],[100,3]],"asks":[[102,4]]}`. Sides with
+/// fewer than `depth` levels just have shorter arrays.
+///
+/// Every row sorts the top of both sides and formats a string, so this is
+/// much slower than the structured outputs and is meant for piping into
+/// JSON-consuming systems rather than for hot paths.
+pub(crate) struct BookJsonBuilder {
+    depth: usize,
+    /// Reused for each row's string, which is copied into `rows`.
+    buf: String,
+    rows: StringChunkedBuilder,
+}
+
+impl BookJsonBuilder {
+    pub(crate) fn new(length: usize, depth: usize) -> Self {
+        BookJsonBuilder {
+            depth,
+            buf: String::new(),
+            rows: StringChunkedBuilder::new("book_json", length),
+        }
+    }
+}
+
+impl BookOutputBuilder for BookJsonBuilder {
+    fn append(&mut self, book: &OrderBook<i64, i64>) {
+        self.buf.clear();
+        self.buf.push_str("{\"bids\":");
+        write_levels_json(&mut self.buf, book.get_book_side(true), self.depth);
+        self.buf.push_str(",\"asks\":");
+        write_levels_json(&mut self.buf, book.get_book_side(false), self.depth);
+        self.buf.push('}');
+        self.rows.append_value(&self.buf);
+    }
+
+    fn finish(self) -> PolarsResult<Series> {
+        Ok(self.rows.finish().into_series())
+    }
+}
+
+fn write_levels_json(buf: &mut String, book_side: &BookSide<i64, i64>, depth: usize) {
+    buf.push('[');
+    for (i, level) in book_side.top_n_levels(depth).iter().enumerate() {
+        if i > 0 {
+            buf.push(',');
+        }
+        write!(buf, "[{},{}]", level.price, level.qty).unwrap();
+    }
+    buf.push(']');
+}
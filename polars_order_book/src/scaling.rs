@@ -0,0 +1,287 @@
+use polars::prelude::*;
+
+/// How a non-integer price or qty column is mapped to the Int64 units the
+/// book works in.
+#[derive(Clone, Copy)]
+enum Units {
+    /// Multiples of a tick or lot size, rounded to the nearest one.
+    Float(f64),
+    /// The unscaled integer mantissa, so values round-trip exactly.
+    Decimal,
+    /// An integer column of another width or signedness, cast to Int64.
+    Integer,
+}
+
+/// Run an Int64 `replay` over inputs whose price or qty columns may be
+/// floats or decimals. Float prices are converted to integer ticks of
+/// `tick_size` and float qtys to integer lots of `lot_size`, rounding to the
+/// nearest one; decimals are replayed on their unscaled mantissas. The price
+/// and qty fields of the resulting bbo struct, and its depth total qtys if
+/// any, are converted back to the input types. Integer columns other than
+/// Int64, e.g. Int32 or UInt32, are cast to Int64 and back, and an integer
+/// is_bid column is cast to Boolean.
+///
+/// Price-like inputs are at positions 0 and 3 (price, prev_price), qty-like
+/// inputs at 1 and 4 (qty, prev_qty) and is_bid at 2; other columns pass
+/// through as-is.
+pub(crate) fn replay_in_ticks(
+    inputs: &[Series],
+    tick_size: Option<f64>,
+    lot_size: Option<f64>,
+    replay: impl FnOnce(&[Series]) -> PolarsResult<Series>,
+) -> PolarsResult<Series> {
+    let price_dtype = inputs[0].dtype().clone();
+    let qty_dtype = inputs[1].dtype().clone();
+    let price_units = units_for(&inputs[0], tick_size, "tick_size")?;
+    let qty_units = units_for(&inputs[1], lot_size, "lot_size")?;
+    let is_bid_is_integer = inputs.get(2).is_some_and(|s| s.dtype().is_integer());
+    if price_units.is_none() && qty_units.is_none() && !is_bid_is_integer {
+        return replay(inputs);
+    }
+
+    let scaled_inputs = inputs
+        .iter()
+        .enumerate()
+        .map(|(i, s)| match (i, price_units, qty_units) {
+            (0 | 3, Some(units), _) | (1 | 4, _, Some(units)) if needs_units(s.dtype()) => {
+                to_units(s, units)
+            }
+            (2, _, _) if is_bid_is_integer => to_bool(s),
+            _ => Ok(s.clone()),
+        })
+        .collect::<PolarsResult<Vec<Series>>>()?;
+
+    let bbo = replay(&scaled_inputs)?;
+    let fields = bbo
+        .struct_()?
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, s)| match (i, price_units, qty_units) {
+            (0 | 2, Some(units), _) => from_units(s, units, &price_dtype),
+            (1 | 3, _, Some(units)) => from_units(s, units, &qty_dtype),
+            (_, _, Some(units)) if matches!(s.name(), "bid_total_qty" | "ask_total_qty") => {
+                from_units(s, units, &qty_dtype)
+            }
+            _ => Ok(s.clone()),
+        })
+        .collect::<PolarsResult<Vec<Series>>>()?;
+    Ok(StructChunked::new(bbo.name(), &fields)?.into_series())
+}
+
+/// Cast the update columns of `inputs` to the types replays read: integer
+/// price and qty columns to Int64 and an integer is_bid column to Boolean.
+/// Positions are as for `replay_in_ticks`. Unlike it, outputs are left for
+/// the caller to convert back.
+pub(crate) fn coerce_update_inputs(inputs: &[Series]) -> PolarsResult<Vec<Series>> {
+    inputs
+        .iter()
+        .enumerate()
+        .map(|(i, s)| match i {
+            0 | 1 | 3 | 4 if s.dtype().is_integer() && s.dtype() != &DataType::Int64 => {
+                to_units(s, Units::Integer)
+            }
+            2 if s.dtype().is_integer() => to_bool(s),
+            _ => Ok(s.clone()),
+        })
+        .collect()
+}
+
+fn needs_units(dtype: &DataType) -> bool {
+    dtype.is_float()
+        || matches!(dtype, DataType::Decimal(_, _))
+        || (dtype.is_integer() && dtype != &DataType::Int64)
+}
+
+fn units_for(s: &Series, unit_size: Option<f64>, kwarg: &str) -> PolarsResult<Option<Units>> {
+    if matches!(s.dtype(), DataType::Decimal(_, _)) {
+        return Ok(Some(Units::Decimal));
+    }
+    if s.dtype().is_integer() && s.dtype() != &DataType::Int64 {
+        return Ok(Some(Units::Integer));
+    }
+    if !s.dtype().is_float() {
+        return Ok(None);
+    }
+    match unit_size {
+        Some(size) if size > 0.0 => Ok(Some(Units::Float(size))),
+        Some(size) => polars_bail!(ComputeError: "{} must be positive, got {}", kwarg, size),
+        None => polars_bail!(
+            ComputeError: "Column {:?} is {}, so {} must be given", s.name(), s.dtype(), kwarg
+        ),
+    }
+}
+
+fn to_units(s: &Series, units: Units) -> PolarsResult<Series> {
+    let units: Int64Chunked = match units {
+        Units::Float(size) => s
+            .cast(&DataType::Float64)?
+            .f64()?
+            .into_iter()
+            .map(|v| v.map(|v| float_to_units(v, size, s.name())).transpose())
+            .collect::<PolarsResult<Int64Chunked>>()?,
+        Units::Decimal => {
+            let mantissas: &Int128Chunked = s.decimal()?;
+            mantissas
+                .into_iter()
+                .map(|v| v.map(i64::try_from).transpose())
+                .collect::<Result<Int64Chunked, _>>()
+                .map_err(|_| {
+                    polars_err!(ComputeError: "Column {:?} has values outside the Int64 range", s.name())
+                })?
+        }
+        Units::Integer => {
+            return s.strict_cast(&DataType::Int64).map_err(|_| {
+                polars_err!(ComputeError: "Column {:?} has values outside the Int64 range", s.name())
+            })
+        }
+    };
+    Ok(units.with_name(s.name()).into_series())
+}
+
+/// `value` in whole units of `size`, rounded to the nearest one. Fails on
+/// NaN and infinities, and on values too large for an Int64 once scaled,
+/// rather than saturating them.
+fn float_to_units(value: f64, size: f64, name: &str) -> PolarsResult<i64> {
+    polars_ensure!(
+        value.is_finite(),
+        ComputeError: "Column {:?} has non-finite value {}", name, value
+    );
+    let units = (value / size).round();
+    // i64::MAX as f64 rounds up to 2^63, which is itself out of range.
+    polars_ensure!(
+        (i64::MIN as f64..i64::MAX as f64).contains(&units),
+        ComputeError: "Column {:?} has value {} outside the Int64 range once scaled by {}", name, value, size
+    );
+    Ok(units as i64)
+}
+
+/// Cast an integer is_bid column to Boolean, non-zero meaning a bid.
+fn to_bool(s: &Series) -> PolarsResult<Series> {
+    s.cast(&DataType::Boolean)
+}
+
+fn from_units(s: &Series, units: Units, dtype: &DataType) -> PolarsResult<Series> {
+    match (units, dtype) {
+        (Units::Float(size), _) => {
+            let values: Float64Chunked = s
+                .i64()?
+                .into_iter()
+                .map(|v| v.map(|v| v as f64 * size))
+                .collect();
+            values.with_name(s.name()).into_series().cast(dtype)
+        }
+        (Units::Decimal, DataType::Decimal(precision, scale)) => {
+            let mantissas: Int128Chunked =
+                s.i64()?.into_iter().map(|v| v.map(i128::from)).collect();
+            Ok(mantissas
+                .with_name(s.name())
+                .into_decimal(*precision, scale.unwrap_or(0))?
+                .into_series())
+        }
+        (Units::Decimal, _) => unreachable!("Decimal units are only used for Decimal columns"),
+        (Units::Integer, _) => s.strict_cast(dtype).map_err(|_| {
+            polars_err!(ComputeError: "Output {:?} has values outside the {} range", s.name(), dtype)
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_in_ticks_round_trip() {
+        let price = Series::new("price", [1.25f64, 1.5]);
+        let qty = Series::new("qty", [0.001f64, 0.003]);
+        let bbo = replay_in_ticks(&[price, qty], Some(0.25), Some(0.001), |inputs| {
+            assert!(inputs[0].equals(&Series::new("price", [5i64, 6])));
+            assert!(inputs[1].equals(&Series::new("qty", [1i64, 3])));
+            let fields = [
+                inputs[0].clone().with_name("best_bid"),
+                inputs[1].clone().with_name("best_bid_qty"),
+            ];
+            Ok(StructChunked::new("bbo", &fields)?.into_series())
+        })
+        .unwrap();
+
+        let fields = bbo.struct_().unwrap().fields();
+        assert!(fields[0].equals(&Series::new("best_bid", [1.25f64, 1.5])));
+        assert!(fields[1].equals(&Series::new("best_bid_qty", [0.001f64, 0.003])));
+    }
+
+    #[test]
+    fn test_replay_in_ticks_invalid_floats() {
+        for value in [f64::NAN, f64::INFINITY, 1e300, -1e300] {
+            let price = Series::new("price", [1.25f64, value]);
+            let qty = Series::new("qty", [1i64, 2]);
+            let err =
+                replay_in_ticks(&[price, qty], Some(0.25), None, |_| unreachable!()).unwrap_err();
+            assert!(err.to_string().contains("\"price\""));
+        }
+    }
+
+    #[test]
+    fn test_replay_in_ticks_requires_tick_size() {
+        let price = Series::new("price", [1.25f64]);
+        let qty = Series::new("qty", [1i64]);
+        assert!(replay_in_ticks(&[price, qty], None, None, |_| unreachable!()).is_err());
+    }
+
+    #[test]
+    fn test_replay_in_ticks_decimal_round_trip() {
+        let price = Int128Chunked::from_slice("price", &[10025, 10050])
+            .into_decimal(Some(10), 2)
+            .unwrap()
+            .into_series();
+        let qty = Series::new("qty", [3i64, 4]);
+        let bbo = replay_in_ticks(&[price.clone(), qty], None, None, |inputs| {
+            assert!(inputs[0].equals(&Series::new("price", [10025i64, 10050])));
+            let fields = [inputs[0].clone().with_name("best_bid")];
+            Ok(StructChunked::new("bbo", &fields)?.into_series())
+        })
+        .unwrap();
+
+        let fields = bbo.struct_().unwrap().fields();
+        assert_eq!(fields[0].dtype(), price.dtype());
+        assert!(fields[0].equals(&price.with_name("best_bid")));
+    }
+
+    #[test]
+    fn test_replay_in_ticks_narrow_integers() {
+        let price = Series::new("price", [100i32, 101]);
+        let qty = Series::new("qty", [3u32, 4]);
+        let is_bid = Series::new("is_bid", [1u8, 0]);
+        let bbo = replay_in_ticks(&[price, qty, is_bid], None, None, |inputs| {
+            assert_eq!(inputs[0].dtype(), &DataType::Int64);
+            assert_eq!(inputs[1].dtype(), &DataType::Int64);
+            assert!(inputs[2].equals(&Series::new("is_bid", [true, false])));
+            let fields = [
+                inputs[0].clone().with_name("best_bid"),
+                inputs[1].clone().with_name("best_bid_qty"),
+            ];
+            Ok(StructChunked::new("bbo", &fields)?.into_series())
+        })
+        .unwrap();
+
+        let fields = bbo.struct_().unwrap().fields();
+        assert!(fields[0].equals(&Series::new("best_bid", [100i32, 101])));
+        assert!(fields[1].equals(&Series::new("best_bid_qty", [3u32, 4])));
+    }
+
+    #[test]
+    fn test_coerce_update_inputs() {
+        let inputs = [
+            Series::new("price", [100u64]),
+            Series::new("qty", [3i16]),
+            Series::new("is_bid", [0i32]),
+        ];
+        let coerced = coerce_update_inputs(&inputs).unwrap();
+        assert!(coerced[0].equals(&Series::new("price", [100i64])));
+        assert!(coerced[1].equals(&Series::new("qty", [3i64])));
+        assert!(coerced[2].equals(&Series::new("is_bid", [false])));
+
+        let too_large = [Series::new("price", [u64::MAX])];
+        assert!(coerce_update_inputs(&too_large).is_err());
+    }
+}
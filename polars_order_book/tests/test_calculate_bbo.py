@@ -1,8 +1,34 @@
+from datetime import datetime
+
 import polars as pl
 import pytest
 from polars.testing.asserts import assert_frame_equal
 
-from polars_order_book import calculate_bbo
+from polars_order_book import (
+    book_features,
+    calculate_bbo,
+    calculate_bbo_asof,
+    calculate_bbo_batched,
+    calculate_bbo_conflated,
+    calculate_bbo_depth_stream,
+    calculate_bbo_reverse,
+    calculate_bbo_signed_delta,
+    calculate_bbo_with_market_phase,
+    calculate_depth_band,
+    calculate_depth_buckets,
+    calculate_implied_bbo,
+    calculate_indicative_uncross,
+    calculate_mid_spread,
+    calculate_nbbo,
+    calculate_ofi,
+    calculate_queue_position,
+    calculate_top_n,
+    final_book,
+    match_orders,
+    mbo_to_mbp,
+    snapshots_to_deltas,
+    validate_book_updates,
+)
 
 
 @pytest.mark.parametrize("n", [1, 10, 100, 1000])
@@ -226,3 +252,941 @@ def test_multiple_orders_per_level_modify(n: int, is_bid: bool):
         expected,
         check_column_order=False,
     )
+
+
+def test_calculate_bbo_flat_output_style():
+    market_data = pl.DataFrame(
+        {
+            "price": [1, 2, 6, 5],
+            "qty": [1, 2, 6, 5],
+            "is_bid": [True, True, False, False],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        bbo=calculate_bbo("price", "qty", "is_bid", output_style="flat")
+    ).unnest("bbo")
+
+    expected = pl.DataFrame(
+        {
+            "bid_price_1": [1, 2, 2, 2],
+            "bid_qty_1": [1, 2, 2, 2],
+            "ask_price_1": [None, None, 6, 5],
+            "ask_qty_1": [None, None, 6, 5],
+        },
+        schema={
+            "bid_price_1": pl.Int64,
+            "bid_qty_1": pl.Int64,
+            "ask_price_1": pl.Int64,
+            "ask_qty_1": pl.Int64,
+        },
+    )
+    assert_frame_equal(result, expected)
+
+
+def test_calculate_bbo_signed_delta():
+    market_data = pl.DataFrame(
+        {
+            "price": [5, 5, 5, 5, 5],
+            "qty_delta": [10, -4, 0, 6, -12],
+            "is_bid": [False] * 5,
+        },
+        schema={"price": pl.Int64, "qty_delta": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        bbo=calculate_bbo_signed_delta("price", "qty_delta", "is_bid")
+    ).unnest("bbo")
+
+    assert result["best_ask"].to_list() == [5, 5, 5, 5, None]
+    assert result["best_ask_qty"].to_list() == [10, 6, 6, 12, None]
+    assert result["best_bid"].null_count() == 5
+
+
+def test_calculate_bbo_float_prices_and_qtys():
+    market_data = pl.DataFrame(
+        {
+            "price": [100.25, 100.5, 100.75, 100.5],
+            "qty": [0.5, 0.125, 1.0, -0.125],
+            "is_bid": [True, True, False, True],
+        },
+        schema={"price": pl.Float64, "qty": pl.Float64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        bbo=calculate_bbo(
+            "price", "qty", "is_bid", tick_size=0.25, lot_size=0.001
+        )
+    ).unnest("bbo")
+
+    assert result.schema["best_bid"] == pl.Float64
+    assert result["best_bid"].to_list() == [100.25, 100.5, 100.5, 100.25]
+    assert result["best_bid_qty"].to_list() == [0.5, 0.125, 0.125, 0.5]
+    assert result["best_ask"].to_list() == [None, None, 100.75, 100.75]
+
+
+def test_calculate_bbo_float_prices_require_tick_size():
+    market_data = pl.DataFrame(
+        {"price": [100.25], "qty": [1], "is_bid": [True]},
+        schema={"price": pl.Float64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    with pytest.raises(pl.ComputeError):
+        market_data.select(bbo=calculate_bbo("price", "qty", "is_bid"))
+
+
+def test_calculate_bbo_decimal_prices():
+    market_data = pl.DataFrame(
+        {
+            "price": ["100.25", "100.50", "100.75"],
+            "qty": [1, 2, 3],
+            "is_bid": [True, True, False],
+        },
+    ).with_columns(pl.col("price").str.to_decimal(inference_length=3))
+    result = market_data.select(
+        bbo=calculate_bbo("price", "qty", "is_bid")
+    ).unnest("bbo")
+
+    assert result.schema["best_bid"] == market_data.schema["price"]
+    assert result["best_bid"].cast(pl.String).to_list() == [
+        "100.25",
+        "100.50",
+        "100.50",
+    ]
+    assert result["best_ask"].cast(pl.String).to_list() == [None, None, "100.75"]
+
+
+def test_final_book_warm_starts_next_batch():
+    updates = pl.DataFrame(
+        {
+            "price": [99, 100, 101, 102, 103, 100],
+            "qty": [2, 4, 1, 5, 2, 3],
+            "is_bid": [True, True, False, False, False, True],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    first, second = updates.head(3), updates.tail(3)
+
+    snapshot = first.select(final_book("price", "qty", "is_bid")).unnest("final_book")
+    assert snapshot.rows() == [(True, 100, 4), (True, 99, 2), (False, 101, 1)]
+
+    resumed = second.select(
+        bbo=calculate_bbo(
+            "price",
+            "qty",
+            "is_bid",
+            initial_bids=snapshot.filter("is_bid").select("price", "qty").rows(),
+            initial_asks=snapshot.filter(~pl.col("is_bid"))
+            .select("price", "qty")
+            .rows(),
+        )
+    ).unnest("bbo")
+    full = updates.select(bbo=calculate_bbo("price", "qty", "is_bid")).unnest("bbo")
+    assert_frame_equal(resumed, full.tail(3))
+
+
+def test_calculate_top_n_wide_fields():
+    market_data = pl.DataFrame(
+        {
+            "price": [100, 99, 101],
+            "qty": [4, 2, 1],
+            "is_bid": [True, True, False],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        top_n=calculate_top_n("price", "qty", "is_bid", n=2)
+    ).unnest("top_n")
+
+    assert result.columns == [
+        "bid_price_1",
+        "bid_price_2",
+        "bid_qty_1",
+        "bid_qty_2",
+        "ask_price_1",
+        "ask_price_2",
+        "ask_qty_1",
+        "ask_qty_2",
+    ]
+    assert result.row(-1) == (100, 99, 4, 2, 101, None, 1, None)
+
+
+def test_calculate_top_n_order_counts():
+    market_data = pl.DataFrame(
+        {
+            "price": [100, 100, 99, 101],
+            "qty": [4, 2, 1, 1],
+            "is_bid": [True, True, True, False],
+            "order_count": [1, 1, 1, 1],
+        },
+        schema={
+            "price": pl.Int64,
+            "qty": pl.Int64,
+            "is_bid": pl.Boolean,
+            "order_count": pl.Int32,
+        },
+    )
+    result = market_data.select(
+        top_n=calculate_top_n(
+            "price", "qty", "is_bid", n=2, order_count="order_count"
+        )
+    ).unnest("top_n")
+
+    assert result.columns[-4:] == ["bid_ct_1", "bid_ct_2", "ask_ct_1", "ask_ct_2"]
+    assert result.schema["bid_ct_1"] == pl.Int32
+    assert result.row(-1)[-4:] == (2, 1, 1, None)
+
+
+def test_calculate_top_n_level_timestamps():
+    market_data = pl.DataFrame(
+        {
+            "ts": [datetime(2024, 1, 1, 9, 30, second) for second in range(3)],
+            "price": [100, 99, 100],
+            "qty": [4, 2, 1],
+            "is_bid": [True, True, True],
+        },
+    )
+    result = market_data.select(
+        top_n=calculate_top_n("price", "qty", "is_bid", n=2, timestamp="ts")
+    ).unnest("top_n")
+
+    ts = market_data["ts"].to_list()
+    assert result.schema["bid_ts_1"] == market_data.schema["ts"]
+    assert result["bid_ts_1"].to_list() == [ts[0], ts[0], ts[2]]
+    assert result["bid_ts_2"].to_list() == [None, ts[1], ts[1]]
+
+
+def test_calculate_bbo_passthrough_columns():
+    market_data = pl.DataFrame(
+        {
+            "ts": [10, 20, 30],
+            "price": [100, 101, 99],
+            "qty": [1, 2, 3],
+            "is_bid": [True, False, True],
+        },
+        schema={
+            "ts": pl.Int64,
+            "price": pl.Int64,
+            "qty": pl.Int64,
+            "is_bid": pl.Boolean,
+        },
+    )
+    result = market_data.select(
+        bbo=calculate_bbo("price", "qty", "is_bid", passthrough=["ts"])
+    ).unnest("bbo")
+
+    assert result.columns[-1] == "ts"
+    assert result["ts"].to_list() == [10, 20, 30]
+    assert result["best_ask"].to_list() == [None, 101, 101]
+
+
+def test_calculate_bbo_emit_on_change():
+    market_data = pl.DataFrame(
+        {
+            "price": [100, 98, 97, 101, 98],
+            "qty": [1, 2, 3, 4, -2],
+            "is_bid": [True, True, True, False, True],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        bbo=calculate_bbo("price", "qty", "is_bid", emit_on_change=True)
+    ).unnest("bbo")
+
+    assert result["changed"].to_list() == [True, False, False, True, False]
+    assert result["best_bid"].to_list() == [100, None, None, 100, None]
+    assert result.filter("changed")["best_ask"].to_list() == [None, 101]
+
+
+def test_calculate_bbo_asof():
+    market_data = pl.DataFrame(
+        {
+            "ts": [1, 2, 2, 5],
+            "price": [100, 101, 99, 102],
+            "qty": [1, 2, 3, 4],
+            "is_bid": [True, False, True, True],
+        },
+        schema={
+            "ts": pl.Int64,
+            "price": pl.Int64,
+            "qty": pl.Int64,
+            "is_bid": pl.Boolean,
+        },
+    )
+    samples = pl.Series("sample_ts", [0, 2, 4, 6], dtype=pl.Int64)
+    result = market_data.select(
+        bbo=calculate_bbo_asof("price", "qty", "is_bid", "ts", pl.lit(samples))
+    ).unnest("bbo")
+
+    assert result.height == 4
+    assert result["best_bid"].to_list() == [None, 100, 100, 102]
+    assert result["best_ask"].to_list() == [None, 101, 101, 101]
+
+
+def test_calculate_ofi():
+    market_data = pl.DataFrame(
+        {
+            "price": [100, 101, 100, 101, 99],
+            "qty": [1, 2, 3, -2, 5],
+            "is_bid": [True, False, True, False, True],
+            "ts": [0, 1, 2, 3, 3],
+        },
+        schema={
+            "price": pl.Int64,
+            "qty": pl.Int64,
+            "is_bid": pl.Boolean,
+            "ts": pl.Int64,
+        },
+    )
+    result = market_data.select(
+        ofi=calculate_ofi("price", "qty", "is_bid"),
+        rolling_ofi=calculate_ofi("price", "qty", "is_bid", ts="ts", window=2),
+    )
+
+    assert result["ofi"].to_list() == [1, -2, 3, 2, 0]
+    assert result["rolling_ofi"].to_list() == [1, -1, 1, 5, 5]
+
+
+def test_calculate_bbo_depth_stream():
+    events = pl.DataFrame(
+        {
+            "price": [100, 100, 99, 101, 98],
+            "qty": [1, 0, 2, 4, 3],
+            "is_bid": [True, True, True, False, True],
+            "first_update_id": [5, 8, 8, 13, 14],
+            "last_update_id": [9, 12, 12, 13, 14],
+        },
+        schema={
+            "price": pl.Int64,
+            "qty": pl.Int64,
+            "is_bid": pl.Boolean,
+            "first_update_id": pl.Int64,
+            "last_update_id": pl.Int64,
+        },
+    )
+
+    def bbo(snapshot_update_id: int) -> pl.DataFrame:
+        return events.select(
+            bbo=calculate_bbo_depth_stream(
+                "price",
+                "qty",
+                "is_bid",
+                "first_update_id",
+                "last_update_id",
+                snapshot_update_id=snapshot_update_id,
+                initial_bids=[(100, 5)],
+                initial_asks=[(102, 3)],
+            )
+        ).unnest("bbo")
+
+    result = bbo(10)
+    assert result["best_bid"].to_list() == [100, 99, 99, 99, 99]
+    assert result["best_ask"].to_list() == [102, 102, 102, 101, 101]
+
+    with pytest.raises(pl.ComputeError, match="Gap in update ids"):
+        bbo(6)
+
+
+def test_mbo_to_mbp():
+    orders = pl.DataFrame(
+        {
+            "order_id": [1, 2, 1, 2, 1],
+            "action": ["add", "add", "replace", "execute", "cancel"],
+            "price": [100, 102, 101, None, None],
+            "qty": [5, 3, 4, 1, None],
+            "is_bid": [True, False, None, None, None],
+        },
+        schema={
+            "order_id": pl.Int64,
+            "action": pl.String,
+            "price": pl.Int64,
+            "qty": pl.Int64,
+            "is_bid": pl.Boolean,
+        },
+    )
+    mbp = orders.select(
+        mbp=mbo_to_mbp("order_id", "action", "price", "qty", "is_bid")
+    ).unnest("mbp")
+
+    assert mbp["qty"].to_list() == [5, 3, 4, -1, -4]
+    assert mbp["prev_price"].to_list() == [None, None, 100, None, None]
+
+    bbo = mbp.select(
+        bbo=calculate_bbo("price", "qty", "is_bid", "prev_price", "prev_qty")
+    ).unnest("bbo")
+    assert bbo["best_bid"].to_list() == [100, 100, 101, 101, None]
+    assert bbo["best_ask"].to_list() == [None, 102, 102, 102, 102]
+
+
+def test_calculate_bbo_batched():
+    updates = pl.DataFrame(
+        {
+            "price": [99, 100, 101, 102, 103, 100, 101],
+            "qty": [2, 4, 1, 5, 2, 3, -1],
+            "is_bid": [True, True, False, False, False, True, False],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    batches = list(
+        calculate_bbo_batched(updates.lazy(), "price", "qty", "is_bid", batch_size=3)
+    )
+
+    assert [batch.height for batch in batches] == [3, 3, 1]
+    full = updates.with_columns(bbo=calculate_bbo("price", "qty", "is_bid"))
+    assert_frame_equal(pl.concat(batches), full)
+
+
+def test_calculate_bbo_tolerant():
+    updates = pl.DataFrame(
+        {
+            "price": [100, 101, 100, 100],
+            "qty": [5, -1, -6, -2],
+            "is_bid": [True, True, True, True],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = updates.select(
+        bbo=calculate_bbo("price", "qty", "is_bid", strict=False)
+    ).unnest("bbo")
+
+    assert result["best_bid_qty"].to_list() == [5, 5, 5, 3]
+    assert result["error"].to_list() == [
+        None,
+        "Level not found",
+        "Qty exceeds available",
+        None,
+    ]
+
+
+@pytest.mark.parametrize(
+    "over_delete_policy, expected_bid_qty",
+    [("clamp", [5, None, 2]), ("ignore", [5, 5, 7])],
+)
+def test_calculate_bbo_over_delete_policy(over_delete_policy, expected_bid_qty):
+    updates = pl.DataFrame(
+        {"price": [100, 100, 100], "qty": [5, -6, 2], "is_bid": [True, True, True]},
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = updates.select(
+        bbo=calculate_bbo(
+            "price", "qty", "is_bid", over_delete_policy=over_delete_policy
+        )
+    ).unnest("bbo")
+
+    assert result["best_bid_qty"].to_list() == expected_bid_qty
+
+
+@pytest.mark.parametrize(
+    "null_policy, expected_best_bid",
+    [("skip", [100, 101, 101, 101]), ("delete_level", [100, 101, 101, 100])],
+)
+def test_calculate_bbo_null_policy(null_policy, expected_best_bid):
+    updates = pl.DataFrame(
+        {
+            "price": [100, 101, None, 101],
+            "qty": [5, 2, 1, None],
+            "is_bid": [True, True, True, True],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = updates.select(
+        bbo=calculate_bbo("price", "qty", "is_bid", null_policy=null_policy)
+    ).unnest("bbo")
+
+    assert result["best_bid"].to_list() == expected_best_bid
+
+
+def test_calculate_bbo_narrow_integer_inputs():
+    updates = pl.DataFrame(
+        {"price": [100, 101, 99], "qty": [5, 2, 3], "is_bid": [1, 0, 1]},
+        schema={"price": pl.Int32, "qty": pl.UInt32, "is_bid": pl.UInt8},
+    )
+    result = updates.select(bbo=calculate_bbo("price", "qty", "is_bid")).unnest("bbo")
+
+    assert result.schema["best_bid"] == pl.Int32
+    assert result.schema["best_ask_qty"] == pl.UInt32
+    assert result["best_bid"].to_list() == [100, 100, 100]
+    assert result["best_ask"].to_list() == [None, 101, 101]
+
+
+@pytest.mark.parametrize("price_dtype", [pl.Int32, pl.UInt32])
+def test_calculate_bbo_native_types(price_dtype):
+    updates = pl.DataFrame(
+        {
+            "price": [100, 101, 99, 101],
+            "qty": [5, 2, 3, -2],
+            "is_bid": [True, True, False, True],
+        },
+        schema={"price": price_dtype, "qty": pl.Int32, "is_bid": pl.Boolean},
+    )
+    result = updates.select(bbo=calculate_bbo("price", "qty", "is_bid")).unnest("bbo")
+
+    assert result.schema["best_bid"] == price_dtype
+    assert result.schema["best_bid_qty"] == pl.Int32
+    assert result["best_bid"].to_list() == [100, 101, 101, 100]
+    assert result["best_ask"].to_list() == [None, None, 99, 99]
+
+
+def test_calculate_bbo_checked_qty():
+    updates = pl.DataFrame(
+        {"price": [100, 100], "qty": [2**31 - 1, 1], "is_bid": [True, True]},
+        schema={"price": pl.Int32, "qty": pl.Int32, "is_bid": pl.Boolean},
+    )
+
+    with pytest.raises(pl.ComputeError, match="Qty overflow"):
+        updates.select(calculate_bbo("price", "qty", "is_bid", checked_qty=True))
+
+
+@pytest.mark.parametrize(
+    ("model", "qty_ahead"),
+    [("pessimistic", 9.0), ("proportional", 6.0), ("optimistic", 4.0)],
+)
+def test_calculate_queue_position(model, qty_ahead):
+    market_data = pl.DataFrame(
+        {
+            "ts": [datetime(2024, 1, 1, 9, 30, second) for second in range(5)],
+            "price": [100, 100, 100, 100, 100],
+            "qty": [10, 5, -6, 3, 1],
+            "is_bid": [True, True, True, True, False],
+        },
+    )
+    result = market_data.select(
+        queue=calculate_queue_position(
+            "price",
+            "qty",
+            "is_bid",
+            order_is_bid=True,
+            order_price=100,
+            entry_time=datetime(2024, 1, 1, 9, 30),
+            timestamp="ts",
+            model=model,
+        )
+    ).unnest("queue")
+
+    assert result["qty_ahead"].to_list() == [10.0, 10.0, qty_ahead, qty_ahead, 0.0]
+    assert result["filled"].to_list() == [False, False, False, False, True]
+    assert result["fill_probability"][-1] == 1.0
+
+
+def test_match_orders():
+    market_data = pl.DataFrame(
+        {
+            "price": [101, 102, 100, 102, 101],
+            "qty": [2, 3, 4, 6, 1],
+            "is_bid": [False, False, True, True, False],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(fills=match_orders("price", "qty", "is_bid"))
+
+    assert result["fills"].to_list() == [
+        [],
+        [],
+        [],
+        [{"price": 101, "qty": 2}, {"price": 102, "qty": 3}],
+        [{"price": 102, "qty": 1}],
+    ]
+
+
+def test_calculate_indicative_uncross():
+    market_data = pl.DataFrame(
+        {
+            "price": [100, 101, 103, 100, 102],
+            "qty": [5, 5, 4, 6, 3],
+            "is_bid": [True, False, True, False, True],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        calculate_indicative_uncross("price", "qty", "is_bid").struct.unnest()
+    )
+
+    assert result.to_dict(as_series=False) == {
+        "price": [None, None, 101, 100, 101],
+        "volume": [None, None, 4, 6, 7],
+        "imbalance": [None, None, -1, 3, -4],
+    }
+
+
+
+
+def test_calculate_mid_spread_weighted_mid():
+    market_data = pl.DataFrame(
+        {
+            "price": [100, 99, 101, 102],
+            "qty": [1, 4, 3, 2],
+            "is_bid": [True, True, False, False],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        calculate_mid_spread(
+            "price", "qty", "is_bid", depth_weights=[1.0, 0.5]
+        ).struct.field("weighted_mid")
+    )
+
+    bid = 298 / 3
+    ask = 405 / 4
+    weighted_mid = result["weighted_mid"].to_list()
+    assert weighted_mid[:2] == [None, None]
+    assert weighted_mid[2:] == pytest.approx(
+        [(bid * 3 + 101 * 3) / 6, (bid * 4 + ask * 3) / 7]
+    )
+
+
+
+def test_book_features():
+    market_data = pl.DataFrame(
+        {
+            "price": [100, 101, 100, 101, 99],
+            "qty": [1, 2, 3, -2, 5],
+            "is_bid": [True, False, True, False, True],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        features=book_features(
+            "price",
+            "qty",
+            "is_bid",
+            features=["mid", "imbalance_1", "depth_totals", "ofi"],
+        )
+    ).unnest("features")
+
+    assert result.to_dict(as_series=False) == {
+        "mid": [None, 100.5, 100.5, None, None],
+        "imbalance_1": [1.0, -1 / 3, 2 / 6, 1.0, 1.0],
+        "bid_total_qty": [1, 1, 4, 4, 9],
+        "ask_total_qty": [0, 2, 2, 0, 0],
+        "ofi": [1, -2, 3, 2, 0],
+    }
+
+
+
+def test_snapshots_to_deltas():
+    snapshots = pl.DataFrame(
+        {
+            "bid_prices": [[100, 99], [100, 98], [101, 100]],
+            "bid_qtys": [[5, 2], [4, 1], [3, 4]],
+            "ask_prices": [[101], [101, 102], [102, 103]],
+            "ask_qtys": [[3], [3, 6], [6, 0]],
+        }
+    )
+    deltas = snapshots.select(
+        deltas=snapshots_to_deltas("bid_prices", "bid_qtys", "ask_prices", "ask_qtys")
+    ).unnest("deltas")
+
+    assert deltas.to_dict(as_series=False) == {
+        "snapshot_row": [0, 0, 0, 1, 1, 1, 1, 2, 2, 2],
+        "price": [100, 99, 101, 100, 99, 98, 102, 101, 98, 101],
+        "qty": [5, 2, 3, -1, -2, 1, 6, 3, -1, -3],
+        "is_bid": [True, True, False, True, True, True, False, True, True, False],
+    }
+
+    # Replaying the deltas rebuilds the last snapshot.
+    final = deltas.select(final_book("price", "qty", "is_bid")).unnest("final_book")
+    assert final.to_dict(as_series=False) == {
+        "is_bid": [True, True, False],
+        "price": [101, 100, 102],
+        "qty": [3, 4, 6],
+    }
+
+
+def test_calculate_nbbo():
+    market_data = pl.DataFrame(
+        {
+            "price": [100, 101, 101, 102, 101],
+            "qty": [5, 2, 3, 4, -2],
+            "is_bid": [True, True, True, False, True],
+            "venue": ["a", "b", "c", "b", "b"],
+        },
+        schema={
+            "price": pl.Int64,
+            "qty": pl.Int64,
+            "is_bid": pl.Boolean,
+            "venue": pl.String,
+        },
+    )
+    result = market_data.select(
+        calculate_nbbo("price", "qty", "is_bid", "venue").struct.unnest()
+    )
+
+    assert result.to_dict(as_series=False) == {
+        "best_bid": [100, 101, 101, 101, 101],
+        "best_bid_qty": [5, 2, 5, 5, 3],
+        "best_ask": [None, None, None, 102, 102],
+        "best_ask_qty": [None, None, None, 4, 4],
+        "best_bid_venues": [["a"], ["b"], ["b", "c"], ["b", "c"], ["c"]],
+        "best_ask_venues": [None, None, None, ["b"], ["b"]],
+    }
+
+
+
+def test_calculate_implied_bbo():
+    market_data = pl.DataFrame(
+        {
+            "price": [-5, 100, 96, 102],
+            "qty": [4, 3, 1, 6],
+            "is_bid": [True, True, True, False],
+            "contract": ["spread", "back", "front", "back"],
+        },
+        schema={
+            "price": pl.Int64,
+            "qty": pl.Int64,
+            "is_bid": pl.Boolean,
+            "contract": pl.String,
+        },
+    )
+    result = market_data.select(
+        calculate_implied_bbo("price", "qty", "is_bid", "contract").struct.unnest()
+    )
+
+    assert result.select(
+        "front_bid", "front_bid_qty", "spread_bid", "back_ask"
+    ).to_dict(as_series=False) == {
+        "front_bid": [None, 95, 96, 96],
+        "front_bid_qty": [None, 3, 1, 1],
+        "spread_bid": [-5, -5, -5, -5],
+        "back_ask": [None, None, None, 102],
+    }
+
+
+
+
+def test_calculate_bbo_conflated():
+    market_data = pl.DataFrame(
+        {
+            "ts": [1, 3, 4, 12, 25, 29],
+            "price": [100, 101, 99, 102, 101, 101],
+            "qty": [1, 2, 3, 4, -2, 5],
+            "is_bid": [True, False, True, True, False, False],
+        },
+        schema={
+            "ts": pl.Int64,
+            "price": pl.Int64,
+            "qty": pl.Int64,
+            "is_bid": pl.Boolean,
+        },
+    )
+    result = market_data.select(
+        bbo=calculate_bbo_conflated("price", "qty", "is_bid", "ts", interval=10)
+    ).unnest("bbo")
+
+    assert result.to_dict(as_series=False) == {
+        "best_bid": [100, 102, 102],
+        "best_bid_qty": [1, 4, 4],
+        "best_ask": [101, 101, 101],
+        "best_ask_qty": [2, 2, 5],
+        "interval_start": [0, 10, 20],
+    }
+
+
+def test_calculate_bbo_reverse():
+    market_data = pl.DataFrame(
+        {
+            "price": [101, 99, 100, 103],
+            "qty": [1, -2, 4, 2],
+            "is_bid": [False, True, True, False],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        calculate_bbo_reverse(
+            "price",
+            "qty",
+            "is_bid",
+            final_bids=[(100, 4)],
+            final_asks=[(101, 1), (102, 5), (103, 2)],
+        ).struct.unnest()
+    )
+
+    assert result.to_dict(as_series=False) == {
+        "best_bid": [99, 99, None, 100],
+        "best_bid_qty": [2, 2, None, 4],
+        "best_ask": [102, 101, 101, 101],
+        "best_ask_qty": [5, 1, 1, 1],
+    }
+
+
+def test_calculate_bbo_with_market_phase():
+    market_data = pl.DataFrame(
+        {
+            "price": [100, 101, 100, 99],
+            "qty": [5, 4, -5, 2],
+            "is_bid": [False, True, False, True],
+            "market_phase": ["auction", "auction", "continuous", "halted"],
+        },
+        schema={
+            "price": pl.Int64,
+            "qty": pl.Int64,
+            "is_bid": pl.Boolean,
+            "market_phase": pl.String,
+        },
+    )
+    result = market_data.select(
+        calculate_bbo_with_market_phase(
+            "price",
+            "qty",
+            "is_bid",
+            "market_phase",
+            clear_on_halt=True,
+            crossed_policy="raise",
+        ).struct.unnest()
+    )
+
+    assert result.to_dict(as_series=False) == {
+        "best_bid": [None, 101, 101, 99],
+        "best_bid_qty": [None, 4, 4, 2],
+        "best_ask": [100, 100, None, None],
+        "best_ask_qty": [5, 5, None, None],
+        "market_phase": ["auction", "auction", "continuous", "halted"],
+    }
+
+
+def test_calculate_bbo_depth_totals():
+    market_data = pl.DataFrame(
+        {
+            "price": [100, 99, 101, 100],
+            "qty": [1, 2, 3, -1],
+            "is_bid": [True, True, False, True],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        calculate_bbo(
+            "price", "qty", "is_bid", include_depth_totals=True
+        ).struct.unnest()
+    )
+
+    assert result.select(
+        "bid_total_qty", "ask_total_qty", "bid_levels", "ask_levels"
+    ).to_dict(as_series=False) == {
+        "bid_total_qty": [1, 3, 3, 2],
+        "ask_total_qty": [0, 0, 3, 3],
+        "bid_levels": [1, 2, 2, 1],
+        "ask_levels": [0, 0, 1, 1],
+    }
+
+
+def test_calculate_depth_band():
+    market_data = pl.DataFrame(
+        {
+            "price": [9_990, 9_980, 10_010, 10_030, 9_970],
+            "qty": [1, 2, 3, 4, 5],
+            "is_bid": [True, True, False, False, True],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        calculate_depth_band("price", "qty", "is_bid", bps=20).struct.unnest()
+    )
+
+    # The mid is 10_000 from the third update, so the band is [9_980, 10_020].
+    assert result.to_dict(as_series=False) == {
+        "bid_qty": [None, None, 3, 3, 3],
+        "ask_qty": [None, None, 3, 3, 3],
+        "bid_notional": [None, None, 29_950.0, 29_950.0, 29_950.0],
+        "ask_notional": [None, None, 30_030.0, 30_030.0, 30_030.0],
+    }
+
+
+def test_calculate_top_n_cumulative_qty():
+    market_data = pl.DataFrame(
+        {
+            "price": [100, 99, 101, 98],
+            "qty": [4, 2, 1, 3],
+            "is_bid": [True, True, False, True],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        top_n=calculate_top_n("price", "qty", "is_bid", n=2, cumulative_qty=True)
+    ).unnest("top_n")
+
+    assert result.select(
+        "bid_cum_qty_1", "bid_cum_qty_2", "ask_cum_qty_1", "ask_cum_qty_2"
+    ).to_dict(as_series=False) == {
+        "bid_cum_qty_1": [4, 4, 4, 4],
+        "bid_cum_qty_2": [None, 6, 6, 6],
+        "ask_cum_qty_1": [None, None, 1, 1],
+        "ask_cum_qty_2": [None, None, None, None],
+    }
+
+
+def test_calculate_depth_buckets():
+    market_data = pl.DataFrame(
+        {
+            "price": [99, 101, 95, 94, 106, 112],
+            "qty": [1, 2, 3, 4, 5, 6],
+            "is_bid": [True, False, True, True, False, False],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = market_data.select(
+        buckets=calculate_depth_buckets(
+            "price", "qty", "is_bid", n_buckets=2, tick_width=5
+        )
+    ).unnest("buckets")
+
+    # The mid is 100, so bands are [0, 5) and [5, 10) ticks away from it.
+    assert result.to_dict(as_series=False) == {
+        "bid_band_1": [None, 1, 1, 1, 1, 1],
+        "bid_band_2": [None, 0, 3, 7, 7, 7],
+        "ask_band_1": [None, 2, 2, 2, 2, 2],
+        "ask_band_2": [None, 0, 0, 0, 5, 5],
+    }
+
+
+def test_validate_book_updates():
+    updates = pl.DataFrame(
+        {
+            "price": [101, 100, 100, 100, 100],
+            "qty": [-1, -6, 1, 1, None],
+            "is_bid": [True, True, False, False, True],
+        },
+        schema={"price": pl.Int64, "qty": pl.Int64, "is_bid": pl.Boolean},
+    )
+    result = updates.select(
+        validation=validate_book_updates(
+            "price", "qty", "is_bid", initial_bids=[(100, 5)]
+        )
+    ).unnest("validation")
+
+    assert result.to_dict(as_series=False) == {
+        "delete_missing_level": [True, False, False, False, False],
+        "qty_exceeds_available": [False, True, False, False, False],
+        "crossed": [False, False, True, True, True],
+        "negative_qty": [False, False, False, False, False],
+        "duplicate_add": [False, False, False, True, False],
+        "error": [
+            "Level not found",
+            "Qty exceeds available",
+            None,
+            None,
+            "price, qty and is_bid must not be null",
+        ],
+    }
+
+
+def test_validate_book_updates_flags_negative_absolute_qty():
+    updates = pl.DataFrame(
+        {
+            "price": [100, 100],
+            "qty": [5, -1],
+            "is_bid": [True, True],
+            "prev_price": [None, None],
+            "prev_qty": [None, 5],
+        },
+        schema={
+            "price": pl.Int64,
+            "qty": pl.Int64,
+            "is_bid": pl.Boolean,
+            "prev_price": pl.Int64,
+            "prev_qty": pl.Int64,
+        },
+    )
+    result = updates.select(
+        validation=validate_book_updates(
+            "price", "qty", "is_bid", "prev_price", "prev_qty"
+        )
+    ).unnest("validation")
+
+    assert result["negative_qty"].to_list() == [False, True]
+    assert result["qty_exceeds_available"].to_list() == [False, True]
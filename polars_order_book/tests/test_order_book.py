@@ -0,0 +1,72 @@
+import polars as pl
+import pytest
+from polars.testing.asserts import assert_frame_equal
+
+from polars_order_book import OrderBook, diff_books
+
+
+def test_order_book():
+    book = OrderBook(initial_bids=[(99, 2)], initial_asks=[(102, 5)])
+    book.add(True, 100, 4)
+    book.add(False, 101, 1)
+    book.delete(True, 99, 2)
+    book.modify(False, 102, 5, 103, 2)
+
+    assert book.best_bid() == (100, 4)
+    assert book.best_ask() == (101, 1)
+    assert book.top_n(1) == ([(100, 4)], [(101, 1)])
+    assert_frame_equal(
+        book.to_dataframe(),
+        pl.DataFrame(
+            {
+                "is_bid": [True, False, False],
+                "price": [100, 101, 103],
+                "qty": [4, 1, 2],
+            }
+        ),
+    )
+
+    book.clear()
+    assert book.best_bid() is None
+    assert book.best_ask() is None
+
+
+def test_order_book_str_is_a_ladder():
+    book = OrderBook(initial_bids=[(99, 20), (98, 5)], initial_asks=[(101, 3)])
+    assert str(book) == "   | 101 | 3\n20 |  99 |\n 5 |  98 |"
+
+
+def test_order_book_rejects_invalid_deletes():
+    book = OrderBook()
+    book.add(True, 100, 1)
+    with pytest.raises(ValueError):
+        book.delete(True, 100, 2)
+    with pytest.raises(ValueError):
+        book.modify(True, 101, 1, 102, 1)
+    assert book.best_bid() == (100, 1)
+
+
+def test_diff_books():
+    book = pl.DataFrame(
+        {"is_bid": [True, True, False], "price": [100, 99, 101], "qty": [5, 2, 3]}
+    )
+    other = pl.DataFrame(
+        {
+            "is_bid": [True, True, False, False],
+            "price": [100, 98, 101, 102],
+            "qty": [4, 1, 3, 6],
+        }
+    )
+
+    assert diff_books(book, book).height == 0
+    assert_frame_equal(
+        diff_books(book, other),
+        pl.DataFrame(
+            {
+                "is_bid": [True, True, True, False],
+                "price": [100, 99, 98, 102],
+                "qty": [5, 2, 0, 0],
+                "other_qty": [4, 0, 1, 6],
+            }
+        ),
+    )